@@ -13,7 +13,7 @@ use std::path::PathBuf;
 use std::str::FromStr;
 
 use crate::messages::{
-    BroadcastGatewayIceCandidates, Connect, ConnectionDetails, EgressMessages,
+    BroadcastGatewayIceCandidates, ConfigUpdate, Connect, ConnectionDetails, EgressMessages,
     GatewayIceCandidates, InitClient, Messages,
 };
 use connlib_shared::{
@@ -21,7 +21,7 @@ use connlib_shared::{
     messages::{GatewayId, ResourceDescription, ResourceId},
     Callbacks,
     Error::{self},
-    Result,
+    Result, DNS_SENTINELS_V4, DNS_SENTINELS_V6,
 };
 
 use firezone_tunnel::Request;
@@ -31,8 +31,6 @@ use tokio_util::codec::{BytesCodec, FramedRead};
 use url::Url;
 
 const DNS_PORT: u16 = 53;
-const DNS_SENTINELS_V4: &str = "100.100.111.0/24";
-const DNS_SENTINELS_V6: &str = "fd00:2021:1111:8000:100:100:111:0/120";
 
 pub struct ControlPlane<CB: Callbacks> {
     pub tunnel: ClientTunnel<CB>,
@@ -95,17 +93,7 @@ impl<CB: Callbacks + 'static> ControlPlane<CB> {
             resources,
         }: InitClient,
     ) -> Result<()> {
-        let effective_dns_servers = effective_dns_servers(
-            interface.upstream_dns.clone(),
-            self.tunnel
-                .callbacks()
-                .get_system_default_resolvers()
-                .ok()
-                .flatten()
-                .unwrap_or_default(),
-        );
-
-        let sentinel_mapping = sentinel_dns_mapping(&effective_dns_servers);
+        let sentinel_mapping = self.sentinel_mapping(interface.upstream_dns.clone());
 
         if !self.tunnel_init {
             if let Err(e) = self
@@ -128,6 +116,33 @@ impl<CB: Callbacks + 'static> ControlPlane<CB> {
         Ok(())
     }
 
+    fn config_changed(&mut self, ConfigUpdate { interface }: ConfigUpdate) {
+        if !self.tunnel_init {
+            tracing::debug!("Ignoring config update before initialization");
+            return;
+        }
+
+        let sentinel_mapping = self.sentinel_mapping(interface.upstream_dns.clone());
+
+        if let Err(e) = self.tunnel.update_interface(&interface, sentinel_mapping) {
+            tracing::error!(error = ?e, "Error updating interface");
+        }
+    }
+
+    fn sentinel_mapping(&self, upstream_dns: Vec<DnsServer>) -> BiMap<IpAddr, DnsServer> {
+        let effective_dns_servers = effective_dns_servers(
+            upstream_dns,
+            self.tunnel
+                .callbacks()
+                .get_system_default_resolvers()
+                .ok()
+                .flatten()
+                .unwrap_or_default(),
+        );
+
+        sentinel_dns_mapping(&effective_dns_servers)
+    }
+
     pub fn connect(
         &mut self,
         Connect {
@@ -166,8 +181,10 @@ impl<CB: Callbacks + 'static> ControlPlane<CB> {
     }
 
     #[tracing::instrument(level = "trace", skip(self))]
-    fn resource_deleted(&self, id: ResourceId) {
-        // TODO
+    fn resource_deleted(&mut self, id: ResourceId) {
+        if let Err(e) = self.tunnel.remove_resource(id) {
+            tracing::error!(message = "Can't remove resource", error = ?e);
+        }
     }
 
     fn connection_details(
@@ -242,9 +259,7 @@ impl<CB: Callbacks + 'static> ControlPlane<CB> {
     ) -> Result<()> {
         match msg {
             Messages::Init(init) => self.init(init).await?,
-            Messages::ConfigChanged(_update) => {
-                tracing::info!("Runtime config updates not yet implemented");
-            }
+            Messages::ConfigChanged(update) => self.config_changed(update),
             Messages::ConnectionDetails(connection_details) => {
                 self.connection_details(connection_details, reference)
             }
@@ -155,8 +155,8 @@ mod test {
     use connlib_shared::{
         control::PhoenixMessage,
         messages::{
-            DnsServer, Interface, IpDnsServer, Relay, ResourceDescription, ResourceDescriptionCidr,
-            ResourceDescriptionDns, Stun, Turn,
+            DnsServer, Interface, IpDnsServer, Relay, RelayTransport, ResourceDescription,
+            ResourceDescriptionCidr, ResourceDescriptionDns, Stun, Turn,
         },
     };
 
@@ -344,6 +344,7 @@ mod test {
                         addr: "189.172.73.111:3478".parse().unwrap(),
                         username: "1686629954:C7I74wXYFdFugMYM".to_string(),
                         password: "OXXRDJ7lJN1cm+4+2BWgL87CxDrvpVrn5j3fnJHye98".to_string(),
+                        transport: RelayTransport::Udp,
                     }),
                     Relay::Stun(Stun {
                         addr: "[::1]:3478".parse().unwrap(),
@@ -355,6 +356,7 @@ mod test {
                         addr: "[::1]:3478".parse().unwrap(),
                         username: "1686629954:dpHxHfNfOhxPLfMG".to_string(),
                         password: "8Wtb+3YGxO6ia23JUeSEfZ2yFD6RhGLkbgZwqjebyKY".to_string(),
+                        transport: RelayTransport::Tcp,
                     }),
                 ],
             }),
@@ -391,7 +393,8 @@ mod test {
                                 "password": "8Wtb+3YGxO6ia23JUeSEfZ2yFD6RhGLkbgZwqjebyKY",
                                 "type": "turn",
                                 "addr": "[::1]:3478",
-                                "username": "1686629954:dpHxHfNfOhxPLfMG"
+                                "username": "1686629954:dpHxHfNfOhxPLfMG",
+                                "transport": "tcp"
                             }]
                     },
                     "status":"ok"
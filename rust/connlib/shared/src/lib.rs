@@ -25,6 +25,7 @@ use ip_network::Ipv6Network;
 use messages::Key;
 use ring::digest::{Context, SHA256};
 use secrecy::{ExposeSecret, SecretString};
+use std::collections::{HashSet, VecDeque};
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
@@ -47,6 +48,12 @@ pub type Dname = domain::base::Dname<Vec<u8>>;
 /// <https://learn.microsoft.com/en-us/windows/configuration/find-the-application-user-model-id-of-an-installed-app>
 pub const BUNDLE_ID: &str = "dev.firezone.client";
 
+/// The ranges clients pick the IPs of their DNS sentinels from.
+///
+/// Applications on a client send their DNS queries to a sentinel which connlib then resolves or forwards.
+pub const DNS_SENTINELS_V4: &str = "100.100.111.0/24";
+pub const DNS_SENTINELS_V6: &str = "fd00:2021:1111:8000:100:100:111:0/120";
+
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const LIB_NAME: &str = "connlib";
 
@@ -97,9 +104,18 @@ pub enum Mode {
     Gateway,
 }
 
+/// Hands out proxy IPs from a pool of addresses.
+///
+/// Addresses that are no longer needed can be given back via [`IpProvider::release`].
+/// They are only handed out again once the pool is exhausted, least recently released first.
+/// This gives applications the most time to forget about a previous assignment of an address.
 pub struct IpProvider {
     ipv4: Box<dyn Iterator<Item = Ipv4Addr> + Send + Sync>,
     ipv6: Box<dyn Iterator<Item = Ipv6Addr> + Send + Sync>,
+
+    released_ipv4: VecDeque<Ipv4Addr>,
+    released_ipv6: VecDeque<Ipv6Addr>,
+    in_use: HashSet<IpAddr>,
 }
 
 impl IpProvider {
@@ -107,22 +123,48 @@ impl IpProvider {
         Self {
             ipv4: Box::new(ipv4.hosts()),
             ipv6: Box::new(ipv6.subnets_with_prefix(128).map(|ip| ip.network_address())),
+            released_ipv4: VecDeque::new(),
+            released_ipv6: VecDeque::new(),
+            in_use: HashSet::new(),
         }
     }
 
     pub fn get_proxy_ip_for(&mut self, ip: &IpAddr) -> Option<IpAddr> {
         let proxy_ip = match ip {
-            IpAddr::V4(_) => self.ipv4.next().map(Into::into),
-            IpAddr::V6(_) => self.ipv6.next().map(Into::into),
+            IpAddr::V4(_) => self
+                .ipv4
+                .next()
+                .or_else(|| self.released_ipv4.pop_front())
+                .map(Into::into),
+            IpAddr::V6(_) => self
+                .ipv6
+                .next()
+                .or_else(|| self.released_ipv6.pop_front())
+                .map(Into::into),
         };
 
-        if proxy_ip.is_none() {
-            // TODO: we might want to make the iterator cyclic or another strategy to prevent ip exhaustion
-            // this might happen in ipv4 if tokens are too long lived.
-            tracing::error!("IP exhaustion: Please reset your client");
+        let Some(proxy_ip) = proxy_ip else {
+            tracing::error!("IP exhaustion: All proxy IPs are in use");
+            return None;
+        };
+
+        self.in_use.insert(proxy_ip);
+
+        Some(proxy_ip)
+    }
+
+    /// Gives back a proxy IP previously returned from [`IpProvider::get_proxy_ip_for`].
+    ///
+    /// Releasing an address that is not in use does nothing.
+    pub fn release(&mut self, proxy_ip: IpAddr) {
+        if !self.in_use.remove(&proxy_ip) {
+            return;
         }
 
-        proxy_ip
+        match proxy_ip {
+            IpAddr::V4(ip) => self.released_ipv4.push_back(ip),
+            IpAddr::V6(ip) => self.released_ipv6.push_back(ip),
+        }
     }
 }
 
@@ -268,3 +310,69 @@ fn get_websocket_path(
 
     Ok(api_url)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider() -> IpProvider {
+        IpProvider::new(
+            "100.96.0.0/30".parse().unwrap(),
+            "fd00:2021:1111:8000::/126".parse().unwrap(),
+        )
+    }
+
+    const V4: IpAddr = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
+    const V6: IpAddr = IpAddr::V6(Ipv6Addr::LOCALHOST);
+
+    #[test]
+    fn returns_none_when_exhausted() {
+        let mut provider = provider();
+
+        assert!(provider.get_proxy_ip_for(&V4).is_some());
+        assert!(provider.get_proxy_ip_for(&V4).is_some());
+        assert!(provider.get_proxy_ip_for(&V4).is_none());
+    }
+
+    #[test]
+    fn reuses_released_ips_once_exhausted() {
+        let mut provider = provider();
+
+        let first = provider.get_proxy_ip_for(&V4).unwrap();
+        provider.release(first);
+
+        let second = provider.get_proxy_ip_for(&V4).unwrap();
+        assert_ne!(first, second);
+
+        assert_eq!(provider.get_proxy_ip_for(&V4), Some(first));
+        assert!(provider.get_proxy_ip_for(&V4).is_none());
+    }
+
+    #[test]
+    fn reuses_least_recently_released_ip_first() {
+        let mut provider = provider();
+
+        let ips: Vec<_> = (0..4)
+            .map(|_| provider.get_proxy_ip_for(&V6).unwrap())
+            .collect();
+        provider.release(ips[2]);
+        provider.release(ips[0]);
+
+        assert_eq!(provider.get_proxy_ip_for(&V6), Some(ips[2]));
+        assert_eq!(provider.get_proxy_ip_for(&V6), Some(ips[0]));
+    }
+
+    #[test]
+    fn releasing_unused_ip_is_ignored() {
+        let mut provider = provider();
+
+        let ip = provider.get_proxy_ip_for(&V4).unwrap();
+        provider.release(ip);
+        provider.release(ip);
+        provider.release("100.96.0.3".parse().unwrap());
+
+        provider.get_proxy_ip_for(&V4).unwrap();
+        assert_eq!(provider.get_proxy_ip_for(&V4), Some(ip));
+        assert!(provider.get_proxy_ip_for(&V4).is_none());
+    }
+}
@@ -321,6 +321,21 @@ pub struct Turn {
     // TODO: SecretString
     /// Password for the relay
     pub password: String,
+    /// How to talk to the relay
+    #[serde(default)]
+    pub transport: RelayTransport,
+}
+
+/// The transport over which we talk to a TURN relay
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayTransport {
+    #[default]
+    Udp,
+    /// For networks that block UDP
+    Tcp,
+    /// For networks that only allow encrypted traffic, e.g. through a proxy on port 443
+    Tls,
 }
 
 /// Stun kind of relay
@@ -1,8 +1,9 @@
 use crate::{
     backoff::{self, ExponentialBackoff},
-    node::{CandidateEvent, Transmit},
+    node::{CandidateEvent, Transmit, Transport},
     ringbuffer::RingBuffer,
-    utils::earliest,
+    stream::padded_len,
+    utils::{earliest, smoothed_rtt},
 };
 use ::backoff::backoff::Backoff;
 use bytecodec::{DecodeExt as _, EncodeExt as _};
@@ -22,6 +23,9 @@ use stun_codec::{
         attributes::{
             ChannelNumber, Lifetime, RequestedTransport, XorPeerAddress, XorRelayAddress,
         },
+        errors::{
+            AllocationMismatch, AllocationQuotaReached, InsufficientCapacity, WrongCredentials,
+        },
         methods::{ALLOCATE, CHANNEL_BIND, REFRESH},
     },
     rfc8656::attributes::AdditionalAddressFamily,
@@ -31,12 +35,28 @@ use tracing::{field, Span};
 
 const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
 
+/// How long we wait before asking a relay for an allocation again after it told us it has no capacity for us.
+///
+/// Doubles with every consecutive rejection, up to [`MAX_UNAVAILABLE_RETRY_DELAY`].
+const UNAVAILABLE_RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_UNAVAILABLE_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+
+/// How long we wait before making a new allocation after deleting a stale one the relay still had for us.
+///
+/// Doubles with every consecutive mismatch, we give up after [`MAX_ALLOCATION_MISMATCHES`].
+const MISMATCH_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_ALLOCATION_MISMATCHES: u32 = 3;
+
 /// Represents a TURN allocation that refreshes itself.
 ///
 /// Allocations have a lifetime and need to be continuously refreshed to stay active.
 #[derive(Debug)]
 pub struct Allocation {
     server: SocketAddr,
+    /// How we talk to the server.
+    ///
+    /// Allocations over TCP and TLS are bound to the connection and need to be re-made once it closes, see [`Allocation::handle_stream_closed`].
+    transport: Transport,
 
     /// If present, the last address the relay observed for us.
     last_srflx_candidate: Option<Candidate>,
@@ -48,6 +68,9 @@ pub struct Allocation {
     /// When we received the allocation and how long it is valid.
     allocation_lifetime: Option<(Instant, Duration)>,
 
+    /// The smoothed round-trip time to the relay, measured from our requests, and when we last updated it.
+    rtt: Option<(Duration, Instant)>,
+
     buffered_transmits: VecDeque<Transmit<'static>>,
     events: VecDeque<CandidateEvent>,
 
@@ -66,6 +89,16 @@ pub struct Allocation {
     password: String,
     realm: Realm,
     nonce: Option<Nonce>,
+
+    /// Whether we are freeing this allocation, see [`Allocation::free`].
+    freed: bool,
+
+    /// When to retry making an allocation after the relay rejected our last attempt.
+    retry_allocate_at: Option<Instant>,
+    /// How often in a row the relay rejected us due to lack of capacity.
+    num_unavailable: u32,
+    /// How often in a row the relay told us it still has an allocation for us.
+    num_mismatches: u32,
 }
 
 /// A socket that has been allocated on a TURN server.
@@ -93,6 +126,7 @@ impl Socket {
 impl Allocation {
     pub fn new(
         server: SocketAddr,
+        transport: Transport,
         username: Username,
         password: String,
         realm: Realm,
@@ -100,6 +134,7 @@ impl Allocation {
     ) -> Self {
         let mut allocation = Self {
             server,
+            transport,
             last_srflx_candidate: Default::default(),
             ip4_allocation: Default::default(),
             ip6_allocation: Default::default(),
@@ -111,14 +146,19 @@ impl Allocation {
             realm,
             nonce: Default::default(),
             allocation_lifetime: Default::default(),
+            rtt: None,
             channel_bindings: Default::default(),
             last_now: now,
             buffered_channel_bindings: Default::default(),
             backoff: backoff::new(now, REQUEST_TIMEOUT),
             timed_out_requests: RingBuffer::new(100),
+            freed: false,
+            retry_allocate_at: None,
+            num_unavailable: 0,
+            num_mismatches: 0,
         };
 
-        tracing::debug!(%server, "Requesting new allocation");
+        tracing::debug!(%server, %transport, "Requesting new allocation");
 
         allocation.authenticate_and_queue(make_allocate_request());
 
@@ -150,6 +190,11 @@ impl Allocation {
             return;
         }
 
+        if self.retry_allocate_at.is_some() {
+            tracing::debug!("Not refreshing allocation because we are waiting to retry making one");
+            return;
+        }
+
         if self.is_suspended() {
             tracing::debug!("Attempting to make a new allocation");
 
@@ -162,6 +207,83 @@ impl Allocation {
         self.authenticate_and_queue(make_refresh_request());
     }
 
+    /// Refresh this allocation with the credentials we already have, e.g. because our local address changed.
+    ///
+    /// If the relay doesn't recognise us anymore, we will make a new allocation.
+    pub fn refresh_with_current_credentials(&mut self, now: Instant) {
+        let username = self.username.clone();
+        let password = self.password.clone();
+        let realm = self.realm.clone();
+
+        self.refresh(username, &password, realm, now);
+    }
+
+    /// Free this allocation on the relay by sending a `REFRESH` request with a lifetime of 0.
+    ///
+    /// All relay candidates and channel bindings are invalidated.
+    /// The allocation can be dropped once [`Allocation::can_be_dropped`] returns `true`.
+    pub fn free(&mut self, now: Instant) {
+        if self.freed {
+            return;
+        }
+
+        self.update_now(now);
+        self.freed = true;
+        self.retry_allocate_at = None;
+
+        let had_allocation = self.has_allocation();
+
+        self.invalidate_allocation();
+        self.buffered_channel_bindings.clear();
+
+        if !had_allocation {
+            tracing::debug!(relay = %self.server, "Dropping unused allocation before it was made");
+            return;
+        }
+
+        tracing::info!(relay = %self.server, "Freeing unused allocation");
+
+        self.authenticate_and_queue(make_delete_allocation_request());
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// Handle the connection to the server being closed.
+    ///
+    /// Allocations made over TCP or TLS are deleted by the server once the connection closes, thus we need to make a new one.
+    /// The resulting [`Transmit`]s should be sent over a new connection.
+    pub fn handle_stream_closed(&mut self, now: Instant) {
+        if !self.transport.is_stream() {
+            return;
+        }
+
+        self.update_now(now);
+        self.invalidate_allocation();
+
+        if self.freed {
+            return;
+        }
+
+        tracing::debug!(relay = %self.server, "Connection closed, making a new allocation");
+
+        self.authenticate_and_queue(make_allocate_request());
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt.map(|(rtt, _)| rtt)
+    }
+
+    pub fn is_freed(&self) -> bool {
+        self.freed
+    }
+
+    /// Whether this allocation was freed and is not waiting for a response from the relay any more.
+    pub fn can_be_dropped(&self) -> bool {
+        self.freed && self.sent_requests.is_empty()
+    }
+
     #[tracing::instrument(level = "debug", skip_all, fields(relay = %self.server, id, method, class, rtt))]
     pub fn handle_input(
         &mut self,
@@ -203,6 +325,7 @@ impl Allocation {
 
         let rtt = now.duration_since(sent_at);
         Span::current().record("rtt", field::debug(rtt));
+        self.rtt = Some(smoothed_rtt(self.rtt, rtt, now));
 
         if let Some(error) = message.get_attribute::<ErrorCode>() {
             // If we sent a nonce but receive 401 instead of 438 then our credentials are invalid.
@@ -213,6 +336,7 @@ impl Allocation {
                     "Invalid credentials, refusing to re-authenticate {}",
                     original_request.method()
                 );
+                self.handle_rejected_credentials();
 
                 return true;
             }
@@ -240,6 +364,59 @@ impl Allocation {
                 return true;
             }
 
+            if self.freed {
+                tracing::debug!(error = %error.reason_phrase(), "Failed to free allocation");
+
+                return true;
+            }
+
+            match (message.method(), error.code()) {
+                (_, WrongCredentials::CODEPOINT) => {
+                    tracing::warn!(
+                        "Credentials do not match the ones of our allocation, refusing to re-authenticate {}",
+                        original_request.method()
+                    );
+                    self.handle_rejected_credentials();
+
+                    return true;
+                }
+                (ALLOCATE, AllocationMismatch::CODEPOINT) => {
+                    if self.num_mismatches >= MAX_ALLOCATION_MISMATCHES {
+                        tracing::warn!(
+                            "Relay keeps reporting a stale allocation for us, giving up"
+                        );
+
+                        self.buffered_channel_bindings.clear();
+                        self.events.push_back(CandidateEvent::RelayUnavailable);
+
+                        return true;
+                    }
+
+                    // The relay still has an allocation for our 5-tuple, e.g. because we restarted.
+                    // Delete it first, we will make a new one once the relay confirmed the deletion.
+                    tracing::info!("Relay has a stale allocation for us, deleting it");
+                    self.num_mismatches += 1;
+                    self.authenticate_and_queue(make_delete_allocation_request());
+
+                    return true;
+                }
+                (ALLOCATE, AllocationQuotaReached::CODEPOINT | InsufficientCapacity::CODEPOINT) => {
+                    let delay = UNAVAILABLE_RETRY_DELAY
+                        .saturating_mul(2u32.saturating_pow(self.num_unavailable))
+                        .min(MAX_UNAVAILABLE_RETRY_DELAY);
+
+                    tracing::warn!(error = %error.reason_phrase(), ?delay, "Relay cannot make an allocation for us, retrying later");
+
+                    self.buffered_channel_bindings.clear();
+                    self.num_unavailable = self.num_unavailable.saturating_add(1);
+                    self.retry_allocate_at = Some(now + delay);
+                    self.events.push_back(CandidateEvent::RelayUnavailable);
+
+                    return true;
+                }
+                _ => {}
+            }
+
             match message.method() {
                 ALLOCATE => {
                     self.buffered_channel_bindings.clear();
@@ -256,15 +433,20 @@ impl Allocation {
                     self.channel_bindings.handle_failed_binding(channel);
                 }
                 REFRESH => {
+                    // The relay lost our allocation, e.g. because our address changed.
+                    // Bind the same channels again once we got a new one, our peers still want to talk to us.
+                    let peers = self.channel_bindings.bound_peers().collect::<Vec<_>>();
+
                     self.invalidate_allocation();
                     self.authenticate_and_queue(make_allocate_request());
+
+                    for peer in peers {
+                        self.bind_channel(peer, now);
+                    }
                 }
                 _ => {}
             }
 
-            // TODO: Handle error codes such as:
-            // - Failed allocations
-
             tracing::warn!(error = %error.reason_phrase(), "STUN request failed");
 
             return true;
@@ -289,9 +471,11 @@ impl Allocation {
                     return true;
                 };
 
+                // Over a stream, the relay observes the address of our TCP connection which is of no use for ICE over UDP.
                 let maybe_srflx_candidate = message
                     .attributes()
-                    .find_map(|addr| srflx_candidate(local, addr));
+                    .find_map(|addr| srflx_candidate(local, addr))
+                    .filter(|_| !self.transport.is_stream());
                 let maybe_ip4_relay_candidate = message
                     .attributes()
                     .find_map(relay_candidate(|s| s.is_ipv4()));
@@ -300,6 +484,8 @@ impl Allocation {
                     .find_map(relay_candidate(|s| s.is_ipv6()));
 
                 self.allocation_lifetime = Some((now, lifetime));
+                self.num_unavailable = 0;
+                self.num_mismatches = 0;
                 update_candidate(
                     maybe_srflx_candidate,
                     &mut self.last_srflx_candidate,
@@ -338,6 +524,18 @@ impl Allocation {
                     self.authenticate_and_queue(buffered);
                 }
             }
+            REFRESH if is_delete_allocation_request(&original_request) => {
+                if self.freed {
+                    tracing::info!("Freed allocation");
+                    return true;
+                }
+
+                let delay = MISMATCH_RETRY_DELAY
+                    .saturating_mul(2u32.saturating_pow(self.num_mismatches.saturating_sub(1)));
+
+                tracing::debug!(?delay, "Deleted stale allocation, making a new one");
+                self.retry_allocate_at = Some(now + delay);
+            }
             REFRESH => {
                 let Some(lifetime) = message.get_attribute::<Lifetime>() else {
                     tracing::warn!("Message does not contain lifetime");
@@ -433,6 +631,16 @@ impl Allocation {
             self.authenticate_and_queue(request);
         }
 
+        if self
+            .retry_allocate_at
+            .is_some_and(|retry_at| now >= retry_at)
+        {
+            tracing::debug!("Retrying to make an allocation");
+
+            self.retry_allocate_at = None;
+            self.authenticate_and_queue(make_allocate_request());
+        }
+
         if let Some(refresh_at) = self.refresh_allocation_at() {
             if (now >= refresh_at) && !self.refresh_in_flight() {
                 tracing::debug!("Allocation is due for a refresh");
@@ -457,7 +665,7 @@ impl Allocation {
             self.authenticate_and_queue(message);
         }
 
-        // TODO: Clean up unused channels
+        self.channel_bindings.remove_expired(now);
     }
 
     pub fn poll_event(&mut self) -> Option<CandidateEvent> {
@@ -479,7 +687,7 @@ impl Allocation {
             earliest_timeout = earliest(earliest_timeout, Some(*sent_at + *backoff));
         }
 
-        earliest_timeout
+        earliest(earliest_timeout, self.retry_allocate_at)
     }
 
     #[tracing::instrument(level = "debug", skip(self, now), fields(relay = %self.server))]
@@ -544,7 +752,11 @@ impl Allocation {
         now: Instant,
     ) -> Option<Vec<u8>> {
         let channel_number = self.channel_bindings.channel_to_peer(peer, now)?;
-        let channel_data = crate::channel_data::encode(channel_number, packet);
+        let mut channel_data = crate::channel_data::encode(channel_number, packet);
+
+        if self.transport.is_stream() {
+            channel_data.resize(padded_len(channel_data.len()), 0);
+        }
 
         Some(channel_data)
     }
@@ -563,6 +775,14 @@ impl Allocation {
         Some(received_at + lifetime)
     }
 
+    fn handle_rejected_credentials(&mut self) {
+        if self.freed {
+            return;
+        }
+
+        self.events.push_back(CandidateEvent::CredentialsRejected);
+    }
+
     fn invalidate_allocation(&mut self) {
         if let Some(candidate) = self.ip4_allocation.take() {
             self.events.push_back(CandidateEvent::Invalid(candidate))
@@ -717,6 +937,7 @@ impl Allocation {
             src: None,
             dst: self.server,
             payload: encode(authenticated_message).into(),
+            transport: self.transport,
         });
 
         true
@@ -783,6 +1004,21 @@ fn make_refresh_request() -> Message<Attribute> {
     message
 }
 
+/// Per TURN spec, an allocation is deleted by refreshing it with a lifetime of 0.
+fn make_delete_allocation_request() -> Message<Attribute> {
+    let mut message = make_refresh_request();
+    message.add_attribute(Lifetime::new(Duration::ZERO).expect("0 is a valid lifetime"));
+
+    message
+}
+
+fn is_delete_allocation_request(message: &Message<Attribute>) -> bool {
+    message.method() == REFRESH
+        && message
+            .get_attribute::<Lifetime>()
+            .is_some_and(|l| l.lifetime() == Duration::ZERO)
+}
+
 fn make_channel_bind_request(target: SocketAddr, channel: u16) -> Message<Attribute> {
     let mut message = Message::new(
         MessageClass::Request,
@@ -943,6 +1179,13 @@ impl ChannelBindings {
             .map(|(number, channel)| (*number, channel.peer))
     }
 
+    fn bound_peers(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.inner
+            .values()
+            .filter(|channel| channel.bound)
+            .map(|channel| channel.peer)
+    }
+
     fn channel_to_peer(&self, peer: SocketAddr, now: Instant) -> Option<u16> {
         self.inner
             .iter()
@@ -971,6 +1214,21 @@ impl ChannelBindings {
         true
     }
 
+    /// Removes all channels that have expired on the relay without us having received any data on them.
+    ///
+    /// These would never be refreshed again, thus we can forget about them and make the channel number available for other peers.
+    fn remove_expired(&mut self, now: Instant) {
+        self.inner.retain(|number, channel| {
+            if !channel.can_rebind(now) {
+                return true;
+            }
+
+            tracing::debug!(channel = %number, peer = %channel.peer, "Removing unused channel");
+
+            false
+        });
+    }
+
     fn clear(&mut self) {
         self.inner.clear();
         self.next_channel = Self::FIRST_CHANNEL;
@@ -1671,6 +1929,39 @@ mod tests {
         assert_eq!(allocate.method(), ALLOCATE);
     }
 
+    #[test]
+    fn failed_refresh_binds_channels_again_on_new_allocation() {
+        let mut allocation = Allocation::for_test(Instant::now());
+
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_test_input(
+            &allocate_response(&allocate, &[RELAY_ADDR_IP4, RELAY_ADDR_IP6]),
+            Instant::now(),
+        );
+
+        allocation.bind_channel(PEER2_IP4, Instant::now());
+        let channel_bind_msg = allocation.next_message().unwrap();
+        allocation.handle_test_input(
+            &encode(channel_bind_success(&channel_bind_msg)),
+            Instant::now(),
+        );
+
+        allocation.refresh_with_current_credentials(Instant::now());
+
+        let refresh = allocation.next_message().unwrap();
+        allocation.handle_test_input(&failed_refresh(&refresh), Instant::now());
+
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_test_input(
+            &allocate_response(&allocate, &[RELAY_ADDR_IP4, RELAY_ADDR_IP6]),
+            Instant::now(),
+        );
+
+        let channel_bind_msg = allocation.next_message().unwrap();
+        assert_eq!(channel_bind_msg.method(), CHANNEL_BIND);
+        assert_eq!(peer_address(&channel_bind_msg), PEER2_IP4);
+    }
+
     #[test]
     fn allocation_is_refreshed_after_half_its_lifetime() {
         let mut allocation = Allocation::for_test(Instant::now());
@@ -1943,6 +2234,400 @@ mod tests {
         assert!(handled);
     }
 
+    #[test]
+    fn unused_channel_is_removed_once_it_can_be_rebound() {
+        let mut channel_bindings = ChannelBindings::default();
+        let start = Instant::now();
+
+        let channel = channel_bindings.new_channel_to_peer(PEER1, start).unwrap();
+        channel_bindings.set_confirmed(channel, start);
+
+        channel_bindings.remove_expired(start + Channel::CHANNEL_LIFETIME);
+        assert!(channel_bindings.inner.contains_key(&channel));
+
+        channel_bindings
+            .remove_expired(start + Channel::CHANNEL_LIFETIME + Channel::CHANNEL_REBIND_TIMEOUT);
+        assert!(!channel_bindings.inner.contains_key(&channel));
+    }
+
+    #[test]
+    fn channel_with_activity_is_not_removed() {
+        let mut channel_bindings = ChannelBindings::default();
+        let start = Instant::now();
+
+        let channel = channel_bindings.new_channel_to_peer(PEER1, start).unwrap();
+        channel_bindings.set_confirmed(channel, start);
+        channel_bindings
+            .try_decode(
+                &crate::channel_data::encode(channel, b"foobar"),
+                start + Duration::from_secs(1),
+            )
+            .unwrap();
+
+        channel_bindings
+            .remove_expired(start + Channel::CHANNEL_LIFETIME + Channel::CHANNEL_REBIND_TIMEOUT);
+        assert!(channel_bindings.inner.contains_key(&channel));
+    }
+
+    #[test]
+    fn freeing_allocation_sends_refresh_with_zero_lifetime() {
+        let mut allocation =
+            Allocation::for_test(Instant::now()).with_allocate_response(&[RELAY_ADDR_IP4]);
+        let _ = iter::from_fn(|| allocation.poll_event()).collect::<Vec<_>>(); // Drain events.
+
+        allocation.free(Instant::now());
+
+        let refresh = allocation.next_message().unwrap();
+        assert_eq!(refresh.method(), REFRESH);
+        assert_eq!(
+            refresh.get_attribute::<Lifetime>().map(|l| l.lifetime()),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            allocation.poll_event(),
+            Some(CandidateEvent::Invalid(
+                Candidate::relayed(RELAY_ADDR_IP4, Protocol::Udp).unwrap()
+            ))
+        );
+        assert!(!allocation.can_be_dropped());
+    }
+
+    #[test]
+    fn freed_allocation_can_be_dropped_after_response() {
+        let mut allocation =
+            Allocation::for_test(Instant::now()).with_allocate_response(&[RELAY_ADDR_IP4]);
+
+        allocation.free(Instant::now());
+
+        let refresh = allocation.next_message().unwrap();
+        allocation.handle_test_input(&refresh_response(&refresh), Instant::now());
+
+        assert!(allocation.can_be_dropped());
+        assert!(allocation.poll_timeout().is_none());
+    }
+
+    #[test]
+    fn failure_to_free_allocation_does_not_make_new_allocation() {
+        let mut allocation =
+            Allocation::for_test(Instant::now()).with_allocate_response(&[RELAY_ADDR_IP4]);
+
+        allocation.free(Instant::now());
+
+        let refresh = allocation.next_message().unwrap();
+        allocation.handle_test_input(&failed_refresh(&refresh), Instant::now());
+
+        assert!(allocation.next_message().is_none());
+        assert!(allocation.can_be_dropped());
+    }
+
+    #[test]
+    fn freeing_allocation_without_allocation_sends_nothing() {
+        let mut allocation = Allocation::for_test(Instant::now());
+        let _ = allocation.next_message(); // Discard the allocate request.
+
+        allocation.free(Instant::now());
+
+        assert!(allocation.next_message().is_none());
+        assert!(allocation.can_be_dropped());
+    }
+
+    #[test]
+    fn repeated_unauthorized_emits_credentials_rejected() {
+        let mut allocation = Allocation::for_test(Instant::now());
+
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_test_input(&unauthorized_response(&allocate, "nonce1"), Instant::now());
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_test_input(&unauthorized_response(&allocate, "nonce2"), Instant::now());
+
+        assert_eq!(
+            allocation.poll_event(),
+            Some(CandidateEvent::CredentialsRejected)
+        );
+    }
+
+    #[test]
+    fn wrong_credentials_emits_credentials_rejected_and_does_not_retry() {
+        let mut allocation =
+            Allocation::for_test(Instant::now()).with_allocate_response(&[RELAY_ADDR_IP4]);
+        let _ = iter::from_fn(|| allocation.poll_event()).collect::<Vec<_>>(); // Drain events.
+
+        allocation.refresh_with_same_credentials();
+        let refresh = allocation.next_message().unwrap();
+        allocation.handle_test_input(&error_response(&refresh, WrongCredentials), Instant::now());
+
+        assert_eq!(
+            allocation.poll_event(),
+            Some(CandidateEvent::CredentialsRejected)
+        );
+        assert!(allocation.next_message().is_none());
+    }
+
+    #[test]
+    fn allocation_mismatch_on_allocate_deletes_stale_allocation_and_allocates_again() {
+        let start = Instant::now();
+        let mut allocation = Allocation::for_test(start);
+
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_test_input(&error_response(&allocate, AllocationMismatch), start);
+
+        let delete = allocation.next_message().unwrap();
+        assert!(is_delete_allocation_request(&delete));
+        assert!(allocation.next_message().is_none());
+
+        allocation.handle_test_input(&refresh_response(&delete), start);
+
+        assert!(allocation.next_message().is_none());
+        assert_eq!(
+            allocation.poll_timeout(),
+            Some(start + MISMATCH_RETRY_DELAY)
+        );
+
+        allocation.handle_timeout(start + MISMATCH_RETRY_DELAY);
+
+        let allocate = allocation.next_message().unwrap();
+        assert_eq!(allocate.method(), ALLOCATE);
+    }
+
+    #[test]
+    fn repeated_allocation_mismatches_back_off_exponentially() {
+        let mut now = Instant::now();
+        let mut allocation = Allocation::for_test(now);
+
+        for expected_delay in [1, 2, 4] {
+            let allocate = allocation.next_message().unwrap();
+            allocation.handle_test_input(&error_response(&allocate, AllocationMismatch), now);
+            let delete = allocation.next_message().unwrap();
+            allocation.handle_test_input(&refresh_response(&delete), now);
+
+            let retry_at = allocation.poll_timeout().unwrap();
+            assert_eq!(retry_at, now + Duration::from_secs(expected_delay));
+
+            now = retry_at;
+            allocation.handle_timeout(now);
+        }
+    }
+
+    #[test]
+    fn gives_up_after_max_allocation_mismatches() {
+        let mut now = Instant::now();
+        let mut allocation = Allocation::for_test(now);
+
+        for _ in 0..MAX_ALLOCATION_MISMATCHES {
+            let allocate = allocation.next_message().unwrap();
+            allocation.handle_test_input(&error_response(&allocate, AllocationMismatch), now);
+            let delete = allocation.next_message().unwrap();
+            allocation.handle_test_input(&refresh_response(&delete), now);
+
+            now = allocation.poll_timeout().unwrap();
+            allocation.handle_timeout(now);
+        }
+
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_test_input(&error_response(&allocate, AllocationMismatch), now);
+
+        assert_eq!(
+            allocation.poll_event(),
+            Some(CandidateEvent::RelayUnavailable)
+        );
+        assert!(allocation.next_message().is_none());
+    }
+
+    #[test]
+    fn successful_allocation_resets_allocation_mismatches() {
+        let mut now = Instant::now();
+        let mut allocation = Allocation::for_test_with_transport(now, Transport::Tcp);
+
+        for _ in 0..MAX_ALLOCATION_MISMATCHES {
+            let allocate = allocation.next_message().unwrap();
+            allocation.handle_test_input(&error_response(&allocate, AllocationMismatch), now);
+            let delete = allocation.next_message().unwrap();
+            allocation.handle_test_input(&refresh_response(&delete), now);
+
+            now = allocation.poll_timeout().unwrap();
+            allocation.handle_timeout(now);
+        }
+
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_test_input(&allocate_response(&allocate, &[RELAY_ADDR_IP4]), now);
+        assert!(allocation.has_allocation());
+
+        allocation.handle_stream_closed(now);
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_test_input(&error_response(&allocate, AllocationMismatch), now);
+
+        let delete = allocation.next_message().unwrap();
+        assert!(is_delete_allocation_request(&delete));
+    }
+
+    #[test]
+    fn insufficient_capacity_retries_allocation_later() {
+        let start = Instant::now();
+        let mut allocation = Allocation::for_test(start);
+
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_test_input(&error_response(&allocate, InsufficientCapacity), start);
+
+        assert_eq!(
+            allocation.poll_event(),
+            Some(CandidateEvent::RelayUnavailable)
+        );
+        assert!(allocation.next_message().is_none());
+        assert_eq!(
+            allocation.poll_timeout(),
+            Some(start + UNAVAILABLE_RETRY_DELAY)
+        );
+
+        allocation.handle_timeout(start + UNAVAILABLE_RETRY_DELAY);
+
+        let allocate = allocation.next_message().unwrap();
+        assert_eq!(allocate.method(), ALLOCATE);
+    }
+
+    #[test]
+    fn repeated_quota_errors_back_off_exponentially() {
+        let start = Instant::now();
+        let mut allocation = Allocation::for_test(start);
+
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_test_input(&error_response(&allocate, AllocationQuotaReached), start);
+
+        let now = start + UNAVAILABLE_RETRY_DELAY;
+        allocation.handle_timeout(now);
+
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_test_input(&error_response(&allocate, AllocationQuotaReached), now);
+
+        assert_eq!(
+            allocation.poll_timeout(),
+            Some(now + UNAVAILABLE_RETRY_DELAY * 2)
+        );
+    }
+
+    #[test]
+    fn requests_over_stream_use_its_transport() {
+        let mut allocation = Allocation::for_test_with_transport(Instant::now(), Transport::Tls);
+
+        let transmit = allocation.poll_transmit().unwrap();
+
+        assert_eq!(transmit.transport, Transport::Tls);
+    }
+
+    #[test]
+    fn allocation_over_stream_has_no_srflx_candidate() {
+        let allocation = Allocation::for_test_with_transport(Instant::now(), Transport::Tcp)
+            .with_allocate_response(&[RELAY_ADDR_IP4]);
+
+        assert_eq!(
+            allocation.current_candidates().collect::<Vec<_>>(),
+            vec![Candidate::relayed(RELAY_ADDR_IP4, Protocol::Udp).unwrap()]
+        );
+    }
+
+    #[test]
+    fn channel_data_over_stream_is_padded() {
+        let mut allocation = Allocation::for_test_with_transport(Instant::now(), Transport::Tcp)
+            .with_allocate_response(&[RELAY_ADDR_IP4]);
+
+        allocation.bind_channel(PEER1, Instant::now());
+        let channel_bind_msg = allocation.next_message().unwrap();
+        allocation.handle_test_input(
+            &encode(channel_bind_success(&channel_bind_msg)),
+            Instant::now(),
+        );
+
+        let channel_data = allocation
+            .encode_to_vec(PEER1, b"foo", Instant::now())
+            .unwrap();
+
+        assert_eq!(channel_data.len(), 8);
+    }
+
+    #[test]
+    fn closed_stream_invalidates_allocation_and_allocates_again() {
+        let mut allocation = Allocation::for_test_with_transport(Instant::now(), Transport::Tcp)
+            .with_allocate_response(&[RELAY_ADDR_IP4]);
+        let _ = iter::from_fn(|| allocation.poll_event()).collect::<Vec<_>>(); // Drain events.
+
+        allocation.handle_stream_closed(Instant::now());
+
+        assert_eq!(
+            allocation.poll_event(),
+            Some(CandidateEvent::Invalid(
+                Candidate::relayed(RELAY_ADDR_IP4, Protocol::Udp).unwrap()
+            ))
+        );
+        assert_eq!(allocation.next_message().unwrap().method(), ALLOCATE);
+    }
+
+    #[test]
+    fn closed_stream_does_not_affect_udp_allocation() {
+        let mut allocation =
+            Allocation::for_test(Instant::now()).with_allocate_response(&[RELAY_ADDR_IP4]);
+        let _ = iter::from_fn(|| allocation.poll_event()).collect::<Vec<_>>(); // Drain events.
+
+        allocation.handle_stream_closed(Instant::now());
+
+        assert!(allocation.poll_event().is_none());
+        assert!(allocation.next_message().is_none());
+    }
+
+    #[test]
+    fn rtt_is_measured_from_allocate_response() {
+        let start = Instant::now();
+        let mut allocation = Allocation::for_test(start);
+
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_test_input(
+            &allocate_response(&allocate, &[RELAY_ADDR_IP4]),
+            start + Duration::from_millis(40),
+        );
+
+        assert_eq!(allocation.rtt(), Some(Duration::from_millis(40)));
+    }
+
+    #[test]
+    fn rtt_of_recent_requests_is_smoothed() {
+        let start = Instant::now();
+        let mut allocation = Allocation::for_test(start);
+
+        let allocate = allocation.next_message().unwrap();
+        let now = start + Duration::from_millis(40);
+        allocation.handle_test_input(&allocate_response(&allocate, &[RELAY_ADDR_IP4]), now);
+
+        allocation.bind_channel(PEER1, now);
+        let channel_bind = allocation.next_message().unwrap();
+        allocation.handle_test_input(
+            &encode(channel_bind_success(&channel_bind)),
+            now + Duration::from_millis(120),
+        );
+
+        assert_eq!(allocation.rtt(), Some(Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn refresh_replaces_outdated_rtt() {
+        let start = Instant::now();
+        let mut allocation = Allocation::for_test(start);
+
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_test_input(
+            &allocate_response(&allocate, &[RELAY_ADDR_IP4]),
+            start + Duration::from_millis(40),
+        );
+
+        let refresh_at = allocation.poll_timeout().unwrap();
+        allocation.handle_timeout(refresh_at);
+        let refresh = allocation.next_message().unwrap();
+        assert_eq!(refresh.method(), REFRESH);
+        allocation.handle_test_input(
+            &refresh_response(&refresh),
+            refresh_at + Duration::from_millis(200),
+        );
+
+        assert_eq!(allocation.rtt(), Some(Duration::from_millis(200)));
+    }
+
     fn ch(peer: SocketAddr, now: Instant) -> Channel {
         Channel {
             peer,
@@ -2018,6 +2703,28 @@ mod tests {
         encode(message)
     }
 
+    fn error_response(request: &Message<Attribute>, error: impl Into<ErrorCode>) -> Vec<u8> {
+        let mut message = Message::new(
+            MessageClass::ErrorResponse,
+            request.method(),
+            request.transaction_id(),
+        );
+        message.add_attribute(error.into());
+
+        encode(message)
+    }
+
+    fn refresh_response(request: &Message<Attribute>) -> Vec<u8> {
+        let mut message = Message::new(
+            MessageClass::SuccessResponse,
+            REFRESH,
+            request.transaction_id(),
+        );
+        message.add_attribute(Lifetime::new(Duration::ZERO).unwrap());
+
+        encode(message)
+    }
+
     fn channel_bind_bad_request(request: &Message<Attribute>) -> Message<Attribute> {
         let mut message = Message::new(
             MessageClass::ErrorResponse,
@@ -2043,8 +2750,13 @@ mod tests {
 
     impl Allocation {
         fn for_test(start: Instant) -> Allocation {
+            Allocation::for_test_with_transport(start, Transport::Udp)
+        }
+
+        fn for_test_with_transport(start: Instant, transport: Transport) -> Allocation {
             Allocation::new(
                 RELAY,
+                transport,
                 Username::new("foobar".to_owned()).unwrap(),
                 "baz".to_owned(),
                 Realm::new("firezone".to_owned()).unwrap(),
@@ -1,7 +1,47 @@
-use std::time::Instant;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use str0m::CandidateKind;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ConnectionInfo {
     /// When this instance of [`ConnectionInfo`] was created.
     pub generated_at: Instant,
+
+    /// The kinds of the local and remote candidate of the pair that ICE nominated.
+    ///
+    /// `None` until ICE nominated a pair or if the path was changed through wireguard activity since.
+    pub selected_pair: Option<SelectedPair>,
+    /// The relay through which we are talking to the remote, if any.
+    pub relay: Option<SocketAddr>,
+
+    /// The number of bytes of IP packets sent through the tunnel.
+    pub bytes_sent: u64,
+    /// The number of IP packets sent through the tunnel.
+    pub packets_sent: u64,
+    /// The number of bytes of IP packets received through the tunnel.
+    pub bytes_received: u64,
+    /// The number of IP packets received through the tunnel.
+    pub packets_received: u64,
+
+    /// How long ago the last wireguard handshake completed.
+    pub handshake_age: Option<Duration>,
+    /// When we last received a wireguard packet (including keep-alives) from the remote.
+    pub last_received: Option<Instant>,
+    /// The round-trip time to the remote, as estimated by wireguard.
+    pub rtt: Option<Duration>,
+
+    /// The relay of the redundant path, if the connection has one.
+    ///
+    /// See [`ConnectionConfig::redundant_path`](crate::ConnectionConfig::redundant_path).
+    pub redundant_relay: Option<SocketAddr>,
+    /// How often we switched to the redundant path because the primary one lost packets.
+    pub failovers: u64,
+    /// When we last switched to the redundant path.
+    pub last_failover: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SelectedPair {
+    pub local: CandidateKind,
+    pub remote: CandidateKind,
 }
@@ -8,12 +8,14 @@ mod info;
 mod ip_packet;
 mod node;
 mod ringbuffer;
+mod stream;
 mod stun_binding;
 mod utils;
 
-pub use info::ConnectionInfo;
+pub use info::{ConnectionInfo, SelectedPair};
 pub use ip_packet::{IpPacket, MutableIpPacket};
 pub use node::{
-    Answer, Client, ClientNode, Credentials, Error, Event, Node, Offer, Server, ServerNode,
-    Transmit,
+    Answer, CandidateFilter, Client, ClientNode, ConnectionConfig, Credentials, Error, Event, Node,
+    Offer, Server, ServerNode, Transmit, Transport,
 };
+pub use stream::StreamDecoder;
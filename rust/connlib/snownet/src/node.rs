@@ -9,10 +9,11 @@ use rand::random;
 use secrecy::{ExposeSecret, Secret};
 use std::hash::Hash;
 use std::marker::PhantomData;
+use std::num::NonZeroUsize;
 use std::time::{Duration, Instant};
 use std::{
     collections::{HashMap, HashSet, VecDeque},
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     sync::Arc,
 };
 use str0m::ice::{IceAgent, IceAgentEvent, IceCreds, StunMessage, StunPacket};
@@ -21,7 +22,8 @@ use str0m::{Candidate, CandidateKind, IceConnectionState};
 
 use crate::allocation::{Allocation, Socket};
 use crate::index::IndexLfsr;
-use crate::info::ConnectionInfo;
+use crate::info::{ConnectionInfo, SelectedPair};
+use crate::stream::padded_len;
 use crate::stun_binding::StunBinding;
 use crate::utils::earliest;
 use crate::{IpPacket, MutableIpPacket};
@@ -35,6 +37,12 @@ const HANDSHAKE_RATE_LIMIT: u64 = 100;
 
 const MAX_UDP_SIZE: usize = (1 << 16) - 1;
 
+/// How many IP packets in a row the redundant path has to deliver without the primary path delivering any before we fail over.
+const FAILOVER_AFTER_MISSED_PACKETS: u32 = 3;
+
+/// For how long we pick relays by the round-trip time we measured on an allocation that we since dropped.
+const MAX_PREVIOUS_RTT_AGE: Duration = Duration::from_secs(10 * 60);
+
 /// Manages a set of wireguard connections for a server.
 pub type ServerNode<TId> = Node<Server, TId>;
 /// Manages a set of wireguard connections for a client.
@@ -56,6 +64,16 @@ pub struct Node<T, TId> {
     bindings: HashMap<SocketAddr, StunBinding>,
     allocations: HashMap<SocketAddr, Allocation>,
 
+    /// The last round-trip times of TURN servers we no longer have an allocation on and when we dropped the allocation.
+    ///
+    /// Forgotten after [`MAX_PREVIOUS_RTT_AGE`], so that we measure the server again.
+    previous_rtts: HashMap<SocketAddr, (Duration, Instant)>,
+    /// If set, new connections only use this many TURN servers, see [`Node::set_max_relays`].
+    max_relays: Option<NonZeroUsize>,
+    /// The transport to use for TURN servers, see [`Node::set_turn_transport`].
+    turn_transports: HashMap<SocketAddr, Transport>,
+    candidate_filter: CandidateFilter,
+
     connections: Connections<TId>,
     pending_events: VecDeque<Event<TId>>,
 
@@ -102,6 +120,10 @@ where
             buffer: Box::new([0u8; MAX_UDP_SIZE]),
             bindings: HashMap::default(),
             allocations: HashMap::default(),
+            previous_rtts: HashMap::default(),
+            max_relays: None,
+            turn_transports: HashMap::default(),
+            candidate_filter: CandidateFilter::default(),
             last_now: now,
             connections: Default::default(),
         }
@@ -116,6 +138,78 @@ where
         self.connections.stats(self.last_now)
     }
 
+    /// The smoothed round-trip time to each STUN and TURN server we have talked to.
+    pub fn server_rtts(&self) -> HashMap<SocketAddr, Duration> {
+        let bindings = self.bindings.iter().map(|(s, b)| (*s, b.rtt()));
+        let allocations = self.allocations.iter().map(|(s, a)| (*s, a.rtt()));
+
+        let mut rtts = self
+            .previous_rtts
+            .iter()
+            .filter(|(_, (_, at))| self.last_now.duration_since(*at) < MAX_PREVIOUS_RTT_AGE)
+            .map(|(s, (rtt, _))| (*s, *rtt))
+            .collect::<HashMap<_, _>>();
+        rtts.extend(
+            bindings
+                .chain(allocations)
+                .filter_map(|(s, rtt)| Some((s, rtt?))),
+        );
+
+        rtts
+    }
+
+    /// Restrict new connections to the `max` TURN servers with the lowest round-trip time.
+    ///
+    /// TURN servers we have not yet measured the round-trip time of are always used in addition, so that we learn it.
+    /// By default, connections use all TURN servers they are given.
+    pub fn set_max_relays(&mut self, max: Option<NonZeroUsize>) {
+        self.max_relays = max;
+    }
+
+    /// Talk to a TURN server over the given transport instead of UDP.
+    ///
+    /// This is useful on networks that block UDP.
+    /// Only applies to allocations made after this call.
+    pub fn set_turn_transport(&mut self, server: SocketAddr, transport: Transport) {
+        self.turn_transports.insert(server, transport);
+    }
+
+    /// Restrict which local and remote candidates connections use.
+    ///
+    /// Only applies to candidates added after this call, thus it should be set before creating any connections.
+    pub fn set_candidate_filter(&mut self, filter: CandidateFilter) {
+        self.candidate_filter = filter;
+    }
+
+    /// Notify the node that the TCP or TLS connection to a TURN server was closed.
+    ///
+    /// The server deletes our allocation once the connection closes, thus we make a new one.
+    /// Any [`Transmit`] to the server should be sent over a new connection.
+    pub fn handle_stream_closed(&mut self, server: SocketAddr) {
+        let Some(allocation) = self.allocations.get_mut(&server) else {
+            return;
+        };
+
+        allocation.handle_stream_closed(self.last_now);
+    }
+
+    /// Update the credentials we use for a TURN server.
+    ///
+    /// Has no effect unless we have an allocation on this server.
+    pub fn update_turn_credentials(
+        &mut self,
+        server: SocketAddr,
+        username: String,
+        password: String,
+        realm: String,
+    ) {
+        if !self.allocations.get(&server).is_some_and(|a| !a.is_freed()) {
+            return;
+        }
+
+        self.upsert_turn_servers(&HashSet::from([(server, username, password, realm)]));
+    }
+
     /// Add an address as a `host` candidate.
     ///
     /// For most network topologies, [`snownet`](crate) will automatically discover host candidates via the traffic to the configured STUN and TURN servers.
@@ -145,6 +239,18 @@ where
         Ok(())
     }
 
+    /// Closes the connection with the given ID, e.g. because we no longer need it.
+    ///
+    /// Other than for connections that fail or are closed because they are idle, there is no [`Event`] for this.
+    pub fn close_connection(&mut self, id: TId) {
+        let initial = self.connections.initial.remove(&id).is_some();
+        let established = self.connections.established.remove(&id).is_some();
+
+        if initial || established {
+            tracing::info!(%id, "Closed connection");
+        }
+    }
+
     #[tracing::instrument(level = "debug", skip_all, fields(%id))]
     pub fn add_remote_candidate(&mut self, id: TId, candidate: String) {
         let candidate = match Candidate::from_sdp_string(&candidate) {
@@ -155,6 +261,11 @@ where
             }
         };
 
+        if !self.candidate_filter.allows(&candidate) {
+            tracing::debug!(?candidate, "Candidate filter rejected remote candidate");
+            return;
+        }
+
         if let Some(agent) = self.connections.agent_mut(id) {
             agent.add_remote_candidate(candidate.clone());
         }
@@ -226,6 +337,32 @@ where
     ) -> Result<Option<(TId, MutableIpPacket<'s>)>, Error> {
         self.add_local_as_host_candidate(local)?;
 
+        self.decapsulate_from(local, from, packet, now, buffer)
+    }
+
+    /// Decapsulate a message received over a TCP or TLS connection to a TURN server.
+    ///
+    /// The message needs to be split off the stream with a [`StreamDecoder`](crate::StreamDecoder) first.
+    /// Other than [`Node::decapsulate`], this doesn't use `local` as a host candidate because it is the address of a TCP socket.
+    pub fn decapsulate_stream<'s>(
+        &mut self,
+        local: SocketAddr,
+        from: SocketAddr,
+        message: &[u8],
+        now: Instant,
+        buffer: &'s mut [u8],
+    ) -> Result<Option<(TId, MutableIpPacket<'s>)>, Error> {
+        self.decapsulate_from(local, from, message, now, buffer)
+    }
+
+    fn decapsulate_from<'s>(
+        &mut self,
+        local: SocketAddr,
+        from: SocketAddr,
+        packet: &[u8],
+        now: Instant,
+        buffer: &'s mut [u8],
+    ) -> Result<Option<(TId, MutableIpPacket<'s>)>, Error> {
         // First, check if a `StunBinding` wants the packet
         if let Some(binding) = self.bindings.get_mut(&from) {
             if binding.handle_input(from, local, packet, now) {
@@ -273,13 +410,30 @@ where
             });
         }
 
+        let path = path_of(local, from, relay_socket);
+
         for (id, conn) in self.connections.iter_established_mut() {
             if !conn.accepts(from) {
                 continue;
             }
 
-            return match conn.tunnel.decapsulate(None, packet, buffer) {
+            let result = conn.tunnel.decapsulate(None, packet, buffer);
+
+            if !matches!(result, TunnResult::Err(_)) {
+                conn.last_received = Some(now);
+            }
+            let is_duplicate = conn.wants_redundant_path
+                && matches!(result, TunnResult::Err(WireGuardError::DuplicateCounter));
+            if is_duplicate || !matches!(result, TunnResult::Err(_)) {
+                conn.mark_path_alive(path);
+            }
+
+            return match result {
                 TunnResult::Done => Ok(None),
+                // The remote sends all packets over two paths, see [`ConnectionConfig::redundant_path`].
+                TunnResult::Err(WireGuardError::DuplicateCounter) if conn.wants_redundant_path => {
+                    Ok(None)
+                }
                 TunnResult::Err(e) => Err(Error::Decapsulate(e)),
 
                 // For WriteToTunnel{V4,V6}, boringtun returns the source IP of the packet that was tunneled to us.
@@ -287,7 +441,10 @@ where
                 // In our API, we parse the packets directly as an IpPacket.
                 // Thus, the caller can query whatever data they'd like, not just the source IP so we don't return it in addition.
                 TunnResult::WriteToTunnelV4(packet, ip) => {
-                    conn.set_remote_from_wg_activity(local, from, relay_socket);
+                    conn.set_remote_from_wg_activity(path, now);
+                    conn.bytes_received += packet.len() as u64;
+                    conn.packets_received += 1;
+                    conn.last_activity = now;
 
                     let ipv4_packet =
                         MutableIpv4Packet::new(packet).expect("boringtun verifies validity");
@@ -296,7 +453,10 @@ where
                     Ok(Some((id, ipv4_packet.into())))
                 }
                 TunnResult::WriteToTunnelV6(packet, ip) => {
-                    conn.set_remote_from_wg_activity(local, from, relay_socket);
+                    conn.set_remote_from_wg_activity(path, now);
+                    conn.bytes_received += packet.len() as u64;
+                    conn.packets_received += 1;
+                    conn.last_activity = now;
 
                     let ipv6_packet =
                         MutableIpv6Packet::new(packet).expect("boringtun verifies validity");
@@ -310,7 +470,7 @@ where
                 // This should be fairly rare which is why we just allocate these and return them from `poll_transmit` instead.
                 // Overall, this results in a much nicer API for our caller and should not affect performance.
                 TunnResult::WriteToNetwork(bytes) => {
-                    conn.set_remote_from_wg_activity(local, from, relay_socket);
+                    conn.set_remote_from_wg_activity(path, now);
 
                     self.buffered_transmits.extend(conn.encapsulate(
                         bytes,
@@ -366,8 +526,25 @@ where
             }
         };
 
+        conn.bytes_sent += packet.packet().len() as u64;
+        conn.packets_sent += 1;
+        conn.last_activity = self.last_now;
+
+        // Zero the bytes after the packet in case we need to pad it for sending it to a relay over a stream.
+        let padding_end = (packet_len + 3).min(payload.len());
+        payload[packet_len..padding_end].fill(0);
+
         let packet = &payload[..packet_len];
 
+        if let Some(redundant) = conn.redundant_path {
+            self.buffered_transmits.extend(make_transmit(
+                redundant.socket,
+                packet,
+                &mut self.allocations,
+                self.last_now,
+            ));
+        }
+
         match conn.peer_socket.ok_or(Error::NotConnected)? {
             PeerSocket::Direct {
                 dest: remote,
@@ -376,18 +553,24 @@ where
                 src: Some(source),
                 dst: remote,
                 payload: Cow::Borrowed(packet),
+                transport: Transport::Udp,
             })),
             PeerSocket::Relay { relay, dest: peer } => {
                 let Some(allocation) = self.allocations.get_mut(&relay) else {
                     tracing::warn!(%relay, "No allocation");
                     return Ok(None);
                 };
-                let Some(total_length) =
+                let Some(mut total_length) =
                     allocation.encode_to_slice(peer, packet, header, self.last_now)
                 else {
                     tracing::warn!(%peer, "No channel");
                     return Ok(None);
                 };
+                let transport = allocation.transport();
+
+                if transport.is_stream() {
+                    total_length = padded_len(total_length);
+                }
 
                 // Safety: We split the slice before, but the borrow-checker doesn't allow us to re-borrow `self.buffer`.
                 // Safety: `total_length` < `buffer.len()` because it is returned from `Tunn::encapsulate`.
@@ -398,6 +581,7 @@ where
                     src: None,
                     dst: relay,
                     payload: Cow::Borrowed(channel_data_packet),
+                    transport,
                 }))
             }
         }
@@ -422,6 +606,7 @@ where
                         server,
                         candidate,
                         &mut self.connections,
+                        &self.candidate_filter,
                         &mut self.pending_events,
                     );
                 }
@@ -430,6 +615,14 @@ where
                         agent.invalidate_candidate(&candidate);
                     }
                 }
+                CandidateEvent::CredentialsRejected => {
+                    self.pending_events
+                        .push_back(Event::RelayCredentialsRejected { relay: server });
+                }
+                CandidateEvent::RelayUnavailable => {
+                    self.pending_events
+                        .push_back(Event::RelayUnavailable { relay: server });
+                }
             }
         }
 
@@ -455,6 +648,18 @@ where
                             .iter()
                             .find(|c| c.addr() == source)
                             .expect("to only nominate existing candidates");
+                        let remote_kind = conn
+                            .agent
+                            .remote_candidates()
+                            .iter()
+                            .find(|c| c.addr() == destination)
+                            .map(|c| c.kind())
+                            .unwrap_or(CandidateKind::PeerReflexive);
+
+                        conn.selected_pair = Some(SelectedPair {
+                            local: candidate.kind(),
+                            remote: remote_kind,
+                        });
 
                         let remote_socket = match candidate.kind() {
                             CandidateKind::Relayed => {
@@ -492,6 +697,7 @@ where
 
                             tracing::info!(old = ?conn.peer_socket, new = ?remote_socket, "Updating remote socket");
                             conn.peer_socket = Some(remote_socket);
+                            conn.pick_redundant_path(&mut self.allocations, self.last_now);
 
                             if is_first_connection {
                                 return Some(Event::ConnectionEstablished(id));
@@ -510,6 +716,8 @@ where
             self.pending_events.push_back(Event::ConnectionFailed(conn));
         }
 
+        self.free_unused_allocations();
+
         self.pending_events.pop_front()
     }
 
@@ -543,8 +751,8 @@ where
 
         for (id, c) in self.connections.iter_established_mut() {
             match c.handle_timeout(now, &mut self.allocations) {
-                Ok(Some(transmit)) => {
-                    self.buffered_transmits.push_back(transmit);
+                Ok(transmits) => {
+                    self.buffered_transmits.extend(transmits);
                 }
                 Err(WireGuardError::ConnectionExpired) => {
                     expired_connections.push(id);
@@ -552,7 +760,6 @@ where
                 Err(e) => {
                     tracing::warn!(%id, ?e);
                 }
-                _ => {}
             };
         }
 
@@ -563,6 +770,20 @@ where
             self.pending_events.push_back(Event::ConnectionFailed(conn))
         }
 
+        let idle_connections = self
+            .connections
+            .established
+            .iter()
+            .filter_map(|(id, conn)| conn.is_idle(now).then_some(*id))
+            .collect::<Vec<_>>();
+
+        for conn in idle_connections {
+            tracing::info!(id = %conn, "Closing idle connection");
+
+            self.connections.established.remove(&conn);
+            self.pending_events.push_back(Event::ConnectionClosed(conn))
+        }
+
         for binding in self.bindings.values_mut() {
             binding.handle_timeout(now);
         }
@@ -571,6 +792,9 @@ where
             allocation.handle_timeout(now);
         }
 
+        self.previous_rtts
+            .retain(|_, (_, at)| now.duration_since(*at) < MAX_PREVIOUS_RTT_AGE);
+
         let next_reset = *self.next_rate_limiter_reset.get_or_insert(now);
 
         if now >= next_reset {
@@ -593,6 +817,8 @@ where
             self.connections.initial.remove(&conn);
             self.pending_events.push_back(Event::ConnectionFailed(conn));
         }
+
+        self.free_unused_allocations();
     }
 
     /// Returns buffered data that needs to be sent on the socket.
@@ -625,24 +851,43 @@ where
         key: [u8; 32],
         allowed_stun_servers: HashSet<SocketAddr>,
         allowed_turn_servers: HashSet<SocketAddr>,
+        config: ConnectionConfig,
     ) -> Connection {
         agent.handle_timeout(self.last_now);
 
+        let persistent_keepalive = config
+            .persistent_keepalive
+            .map(|k| u16::try_from(k.as_secs()).unwrap_or(u16::MAX));
+
         Connection {
             agent,
             tunnel: Tunn::new(
                 self.private_key.clone(),
                 remote,
                 Some(key),
-                None,
+                persistent_keepalive,
                 self.index.next(),
                 Some(self.rate_limiter.clone()),
             ),
+            idle_timeout: config.idle_timeout,
+            last_activity: self.last_now,
+            wants_redundant_path: config.redundant_path,
+            redundant_path: None,
+            packets_missed_by_primary: 0,
+            failovers: 0,
+            last_failover: None,
             stun_servers: allowed_stun_servers,
             turn_servers: allowed_turn_servers,
             next_timer_update: self.last_now,
             peer_socket: None,
             possible_sockets: HashSet::default(),
+            pending_ice_restart: None,
+            selected_pair: None,
+            bytes_sent: 0,
+            packets_sent: 0,
+            bytes_received: 0,
+            packets_received: 0,
+            last_received: None,
         }
     }
 
@@ -664,6 +909,7 @@ where
                 conn,
                 agent,
                 host_candidate.clone(),
+                &self.candidate_filter,
                 &mut self.pending_events,
             );
         }
@@ -686,6 +932,7 @@ where
         id: TId,
         allowed_stun_servers: HashSet<SocketAddr>,
         allowed_turn_servers: HashSet<(SocketAddr, String, String, String)>,
+        config: ConnectionConfig,
     ) -> Offer {
         if self.connections.initial.remove(&id).is_some() {
             tracing::info!("Replacing existing initial connection");
@@ -695,6 +942,9 @@ where
             tracing::info!("Replacing existing established connection");
         };
 
+        let allowed_turn_servers =
+            fastest_relays(allowed_turn_servers, &self.server_rtts(), self.max_relays);
+
         self.upsert_stun_servers(&allowed_stun_servers);
         self.upsert_turn_servers(&allowed_turn_servers);
 
@@ -725,6 +975,7 @@ where
                 session_key,
                 stun_servers: allowed_stun_servers,
                 turn_servers: allowed_turn_servers,
+                config,
                 created_at: self.last_now,
             },
         );
@@ -761,12 +1012,69 @@ where
             *initial.session_key.expose_secret(),
             initial.stun_servers,
             initial.turn_servers,
+            initial.config,
         );
 
         let existing = self.connections.established.insert(id, connection);
 
         debug_assert!(existing.is_none());
     }
+
+    /// Restart ICE for an established connection, i.e. gather candidates and negotiate a new path to the remote.
+    ///
+    /// This is useful when the network changes, for example when switching from Wi-Fi to LTE.
+    /// In contrast to [`Node::new_connection`], the wireguard session is kept alive and packets continue to be sent on the current path until ICE nominates a new one.
+    ///
+    /// Our host candidates are forgotten and the server-reflexive and relayed ones are discovered again, see [`Node::add_local_host_candidate`] to add the new host candidates.
+    ///
+    /// The returned [`Credentials`] must be passed to the remote via a signalling channel.
+    /// Returns `None` if there is no established connection with this ID.
+    #[tracing::instrument(level = "info", skip_all, fields(%id))]
+    pub fn restart_ice(&mut self, id: TId) -> Option<Credentials> {
+        let conn = self.connections.established.get_mut(&id)?;
+
+        let mut agent = IceAgent::new();
+        agent.set_controlling(true);
+
+        let credentials = Credentials {
+            username: agent.local_credentials().ufrag.clone(),
+            password: agent.local_credentials().pass.clone(),
+        };
+
+        if conn.pending_ice_restart.replace(agent).is_some() {
+            tracing::info!("Replacing pending ICE restart");
+        }
+
+        let stun_servers = conn.stun_servers.clone();
+        let turn_servers = conn.turn_servers.clone();
+
+        self.regather_local_candidates(&stun_servers, &turn_servers);
+
+        Some(credentials)
+    }
+
+    /// Accept an [`Answer`] from the remote for an ICE restart previously initiated via [`Node::restart_ice`].
+    #[tracing::instrument(level = "debug", skip_all, fields(%id))]
+    pub fn accept_ice_restart_answer(&mut self, id: TId, answer: Answer) {
+        let Some(conn) = self.connections.established.get_mut(&id) else {
+            tracing::debug!("No established connection, ignoring answer");
+            return;
+        };
+        let Some(mut agent) = conn.pending_ice_restart.take() else {
+            tracing::debug!("No pending ICE restart, ignoring answer");
+            return;
+        };
+        let stun_servers = conn.stun_servers.clone();
+        let turn_servers = conn.turn_servers.clone();
+
+        agent.set_remote_credentials(IceCreds {
+            ufrag: answer.credentials.username,
+            pass: answer.credentials.password,
+        });
+
+        self.seed_agent_with_local_candidates(id, &mut agent, &stun_servers, &turn_servers);
+        self.replace_agent(id, agent);
+    }
 }
 
 impl<TId> Node<Server, TId>
@@ -785,6 +1093,7 @@ where
         remote: PublicKey,
         allowed_stun_servers: HashSet<SocketAddr>,
         allowed_turn_servers: HashSet<(SocketAddr, String, String, String)>,
+        config: ConnectionConfig,
     ) -> Answer {
         debug_assert!(
             !self.connections.initial.contains_key(&id),
@@ -795,6 +1104,9 @@ where
             tracing::info!("Replacing existing established connection");
         };
 
+        let allowed_turn_servers =
+            fastest_relays(allowed_turn_servers, &self.server_rtts(), self.max_relays);
+
         self.upsert_stun_servers(&allowed_stun_servers);
         self.upsert_turn_servers(&allowed_turn_servers);
 
@@ -830,6 +1142,7 @@ where
             *offer.session_key.expose_secret(),
             allowed_stun_servers,
             allowed_turn_servers,
+            config,
         );
         let existing = self.connections.established.insert(id, connection);
 
@@ -837,12 +1150,109 @@ where
 
         answer
     }
+
+    /// Accept an ICE restart initiated by the remote via [`Node::restart_ice`].
+    ///
+    /// The wireguard session is kept alive and packets continue to be sent on the current path until ICE nominates a new one.
+    /// The returned [`Answer`] must be passed to the remote via a signalling channel.
+    /// Returns `None` if there is no established connection with this ID.
+    #[tracing::instrument(level = "info", skip_all, fields(%id))]
+    pub fn accept_ice_restart(&mut self, id: TId, credentials: Credentials) -> Option<Answer> {
+        let conn = self.connections.established.get(&id)?;
+        let stun_servers = conn.stun_servers.clone();
+        let turn_servers = conn.turn_servers.clone();
+
+        let mut agent = IceAgent::new();
+        agent.set_controlling(false);
+        agent.set_remote_credentials(IceCreds {
+            ufrag: credentials.username,
+            pass: credentials.password,
+        });
+        let answer = Answer {
+            credentials: Credentials {
+                username: agent.local_credentials().ufrag.clone(),
+                password: agent.local_credentials().pass.clone(),
+            },
+        };
+
+        self.seed_agent_with_local_candidates(id, &mut agent, &stun_servers, &turn_servers);
+        self.replace_agent(id, agent);
+
+        Some(answer)
+    }
 }
 
 impl<T, TId> Node<T, TId>
 where
     TId: Eq + Hash + Copy + fmt::Display,
 {
+    /// Replaces the [`IceAgent`] of an established connection, keeping its wireguard session.
+    fn replace_agent(&mut self, id: TId, mut agent: IceAgent) {
+        let Some(conn) = self.connections.established.get_mut(&id) else {
+            return;
+        };
+
+        agent.handle_timeout(self.last_now);
+
+        conn.agent = agent;
+        conn.pending_ice_restart = None;
+        conn.possible_sockets.clear();
+        conn.selected_pair = None;
+
+        tracing::info!(%id, "Restarted ICE");
+    }
+
+    /// Forgets our host candidates and discovers the server-reflexive and relayed ones of the given servers again.
+    ///
+    /// After a network change, our local address is likely different and the candidates we gathered so far are stale.
+    /// Re-binding from the new address gives us a new server-reflexive candidate and makes the relays notice that our allocation moved.
+    fn regather_local_candidates(
+        &mut self,
+        stun_servers: &HashSet<SocketAddr>,
+        turn_servers: &HashSet<SocketAddr>,
+    ) {
+        self.host_candidates.clear();
+
+        for (_, binding) in self
+            .bindings
+            .iter_mut()
+            .filter(|(server, _)| stun_servers.contains(server))
+        {
+            binding.refresh(self.last_now);
+        }
+
+        for (_, allocation) in self
+            .allocations
+            .iter_mut()
+            .filter(|(server, a)| turn_servers.contains(server) && !a.is_freed())
+        {
+            allocation.refresh_with_current_credentials(self.last_now);
+        }
+    }
+
+    /// Frees all allocations that are no longer referenced by any connection.
+    fn free_unused_allocations(&mut self) {
+        let in_use = self.connections.turn_servers_in_use();
+
+        for (server, allocation) in self.allocations.iter_mut() {
+            if !in_use.contains(server) {
+                allocation.free(self.last_now);
+            }
+        }
+
+        self.allocations.retain(|server, a| {
+            if !a.can_be_dropped() {
+                return true;
+            }
+
+            if let Some(rtt) = a.rtt() {
+                self.previous_rtts.insert(*server, (rtt, self.last_now));
+            }
+
+            false
+        });
+    }
+
     fn upsert_stun_servers(&mut self, servers: &HashSet<SocketAddr>) {
         for server in servers {
             if !self.bindings.contains_key(server) {
@@ -865,14 +1275,27 @@ where
                 continue;
             };
 
-            if let Some(existing) = self.allocations.get_mut(server) {
+            if let Some(existing) = self.allocations.get_mut(server).filter(|a| !a.is_freed()) {
                 existing.refresh(username, password, realm, self.last_now);
                 continue;
             }
 
+            let transport = self
+                .turn_transports
+                .get(server)
+                .copied()
+                .unwrap_or_default();
+
             self.allocations.insert(
                 *server,
-                Allocation::new(*server, username, password.clone(), realm, self.last_now),
+                Allocation::new(
+                    *server,
+                    transport,
+                    username,
+                    password.clone(),
+                    realm,
+                    self.last_now,
+                ),
             );
 
             tracing::info!(address = %server, "Added new TURN server");
@@ -887,7 +1310,13 @@ where
         allowed_turn_servers: &HashSet<SocketAddr>,
     ) {
         for candidate in self.host_candidates.iter().cloned() {
-            add_local_candidate(connection, agent, candidate, &mut self.pending_events);
+            add_local_candidate(
+                connection,
+                agent,
+                candidate,
+                &self.candidate_filter,
+                &mut self.pending_events,
+            );
         }
 
         for candidate in self.bindings.iter().filter_map(|(server, binding)| {
@@ -901,6 +1330,7 @@ where
                 connection,
                 agent,
                 candidate.clone(),
+                &self.candidate_filter,
                 &mut self.pending_events,
             );
         }
@@ -919,6 +1349,7 @@ where
                 connection,
                 agent,
                 candidate.clone(),
+                &self.candidate_filter,
                 &mut self.pending_events,
             );
         }
@@ -945,8 +1376,8 @@ where
 {
     fn stats(&self, now: Instant) -> impl Iterator<Item = (TId, ConnectionInfo)> + '_ {
         self.established
-            .keys()
-            .map(move |id| (*id, ConnectionInfo { generated_at: now }))
+            .iter()
+            .map(move |(id, conn)| (*id, conn.stats(now)))
     }
 
     fn agent_mut(&mut self, id: TId) -> Option<&mut IceAgent> {
@@ -985,6 +1416,17 @@ where
         initial.chain(established)
     }
 
+    /// The TURN servers referenced by at least one connection.
+    fn turn_servers_in_use(&self) -> HashSet<SocketAddr> {
+        let initial = self.initial.values().flat_map(|c| c.turn_servers.iter());
+        let established = self
+            .established
+            .values()
+            .flat_map(|c| c.turn_servers.iter());
+
+        initial.chain(established).copied().collect()
+    }
+
     fn iter_established_mut(&mut self) -> impl Iterator<Item = (TId, &mut Connection)> {
         self.established.iter_mut().map(|(id, conn)| (*id, conn))
     }
@@ -994,6 +1436,26 @@ where
     }
 }
 
+/// Selects the `max` TURN servers with the lowest round-trip time, plus all we don't know the round-trip time of.
+fn fastest_relays(
+    servers: HashSet<(SocketAddr, String, String, String)>,
+    rtts: &HashMap<SocketAddr, Duration>,
+    max: Option<NonZeroUsize>,
+) -> HashSet<(SocketAddr, String, String, String)> {
+    let Some(max) = max else {
+        return servers;
+    };
+
+    let (mut measured, unmeasured) = servers
+        .into_iter()
+        .partition::<Vec<_>, _>(|(server, _, _, _)| rtts.contains_key(server));
+
+    measured.sort_by_key(|(server, _, _, _)| rtts[server]);
+    measured.truncate(max.get());
+
+    measured.into_iter().chain(unmeasured).collect()
+}
+
 /// Wraps the message as a channel data message via the relay, iff:
 ///
 /// - `relay` is in fact a relay
@@ -1017,6 +1479,7 @@ fn encode_as_channel_data(
         src: None,
         dst: relay,
         payload: Cow::Owned(payload),
+        transport: allocation.transport(),
     })
 }
 
@@ -1030,6 +1493,7 @@ fn add_candidates<TId>(
     server: SocketAddr,
     candidate: Candidate,
     connections: &mut Connections<TId>,
+    filter: &CandidateFilter,
     pending_events: &mut VecDeque<Event<TId>>,
 ) where
     TId: Copy + fmt::Display,
@@ -1038,9 +1502,11 @@ fn add_candidates<TId>(
         .initial
         .iter_mut()
         .map(|(id, c)| (*id, &c.stun_servers, &c.turn_servers, &mut c.agent));
+    // Connections with a pending ICE restart are seeded with all candidates once the remote answers, see `Node::accept_ice_restart_answer`.
     let established_connections = connections
         .established
         .iter_mut()
+        .filter(|(_, c)| c.pending_ice_restart.is_none())
         .map(|(id, c)| (*id, &c.stun_servers, &c.turn_servers, &mut c.agent));
 
     for (id, allowed_stun, allowed_turn, agent) in
@@ -1063,7 +1529,7 @@ fn add_candidates<TId>(
             CandidateKind::PeerReflexive | CandidateKind::Host => continue,
         }
 
-        add_local_candidate(id, agent, candidate.clone(), pending_events);
+        add_local_candidate(id, agent, candidate.clone(), filter, pending_events);
     }
 }
 
@@ -1071,8 +1537,14 @@ fn add_local_candidate<TId>(
     id: TId,
     agent: &mut IceAgent,
     candidate: Candidate,
+    filter: &CandidateFilter,
     pending_events: &mut VecDeque<Event<TId>>,
 ) {
+    if !filter.allows(&candidate) {
+        tracing::debug!(?candidate, "Candidate filter rejected local candidate");
+        return;
+    }
+
     let is_new = agent.add_local_candidate(candidate.clone());
 
     if is_new {
@@ -1093,6 +1565,47 @@ pub struct Answer {
     pub credentials: Credentials,
 }
 
+/// Configuration of an individual connection.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ConnectionConfig {
+    /// How often wireguard sends a keep-alive to the remote in the absence of other traffic.
+    ///
+    /// Wireguard only supports whole seconds.
+    /// Keep-alives are not considered traffic for the purposes of [`ConnectionConfig::idle_timeout`].
+    pub persistent_keepalive: Option<Duration>,
+    /// Close the connection once no traffic has flowed for this long.
+    pub idle_timeout: Option<Duration>,
+    /// Send all IP packets over a second, relayed path too.
+    ///
+    /// This doubles the bandwidth used but allows us to fail over as soon as the primary path loses a few packets in a row that the redundant one delivered,
+    /// instead of waiting for ICE to declare it dead.
+    /// Failovers are reported through [`Node::stats`].
+    pub redundant_path: bool,
+}
+
+/// Restricts the ICE candidates used by a [`Node`], see [`Node::set_candidate_filter`].
+///
+/// The filter applies to our local candidates as well as the candidates of remotes.
+#[derive(Debug, Default, Clone)]
+pub struct CandidateFilter {
+    /// Only use relayed candidates.
+    ///
+    /// Our host and server-reflexive addresses are never signalled to the remote and all traffic goes through relays.
+    pub relay_only: bool,
+    /// Never use candidates with one of these IPs, for example the addresses of docker bridges.
+    pub excluded_ips: HashSet<IpAddr>,
+}
+
+impl CandidateFilter {
+    fn allows(&self, candidate: &Candidate) -> bool {
+        if self.relay_only && candidate.kind() != CandidateKind::Relayed {
+            return false;
+        }
+
+        !self.excluded_ips.contains(&candidate.addr().ip())
+    }
+}
+
 pub struct Credentials {
     /// The ICE username (ufrag).
     pub username: String,
@@ -1115,6 +1628,27 @@ pub enum Event<TId> {
     ///
     /// All state associated with the connection has been cleared.
     ConnectionFailed(TId),
+
+    /// We closed a connection because no traffic flowed for its [`ConnectionConfig::idle_timeout`].
+    ///
+    /// All state associated with the connection has been cleared.
+    ConnectionClosed(TId),
+
+    /// A TURN server rejected the credentials we have for it.
+    ///
+    /// We won't retry until new credentials are passed via [`Node::update_turn_credentials`].
+    RelayCredentialsRejected {
+        relay: SocketAddr,
+    },
+
+    /// A TURN server cannot make an allocation for us.
+    ///
+    /// Connections will only use the other TURN servers they are allowed to use until the server accepts our allocation.
+    /// If the server lacks capacity, we retry periodically, backing off exponentially.
+    /// If it keeps reporting a stale allocation for us, we give up until the server is refreshed with new credentials.
+    RelayUnavailable {
+        relay: SocketAddr,
+    },
 }
 
 #[derive(Debug)]
@@ -1130,6 +1664,10 @@ pub struct Transmit<'a> {
     pub dst: SocketAddr,
     /// The data that should be sent.
     pub payload: Cow<'a, [u8]>,
+    /// How the packet should be sent.
+    ///
+    /// For [`Transport::Tcp`] and [`Transport::Tls`], the payload must be written to a connection to `dst`, which is established if necessary.
+    pub transport: Transport,
 }
 
 impl<'a> Transmit<'a> {
@@ -1138,6 +1676,35 @@ impl<'a> Transmit<'a> {
             src: self.src,
             dst: self.dst,
             payload: Cow::Owned(self.payload.into_owned()),
+            transport: self.transport,
+        }
+    }
+}
+
+/// The transport over which we talk to a STUN or TURN server.
+///
+/// Connections to other peers always use UDP.
+/// Relayed candidates are UDP candidates too, independent of how we talk to the TURN server.
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Transport {
+    #[default]
+    Udp,
+    Tcp,
+    Tls,
+}
+
+impl Transport {
+    pub fn is_stream(&self) -> bool {
+        matches!(self, Transport::Tcp | Transport::Tls)
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Udp => write!(f, "udp"),
+            Transport::Tcp => write!(f, "tcp"),
+            Transport::Tls => write!(f, "tls"),
         }
     }
 }
@@ -1146,6 +1713,10 @@ impl<'a> Transmit<'a> {
 pub(crate) enum CandidateEvent {
     New(Candidate),
     Invalid(Candidate),
+    /// The relay rejected our credentials.
+    CredentialsRejected,
+    /// The relay cannot make an allocation for us at the moment.
+    RelayUnavailable,
 }
 
 struct InitialConnection {
@@ -1153,6 +1724,7 @@ struct InitialConnection {
     session_key: Secret<[u8; 32]>,
     stun_servers: HashSet<SocketAddr>,
     turn_servers: HashSet<SocketAddr>,
+    config: ConnectionConfig,
 
     created_at: Instant,
 }
@@ -1163,13 +1735,36 @@ struct Connection {
     tunnel: Tunn,
     next_timer_update: Instant,
 
+    idle_timeout: Option<Duration>,
+    /// When we last sent or received an IP packet through the tunnel.
+    last_activity: Instant,
+
     // When this is `Some`, we are connected.
     peer_socket: Option<PeerSocket>,
     // Socket addresses from which we might receive data (even before we are connected).
     possible_sockets: HashSet<SocketAddr>,
 
+    /// Whether we should send all traffic over a second path too, see [`ConnectionConfig::redundant_path`].
+    wants_redundant_path: bool,
+    redundant_path: Option<RedundantPath>,
+    /// How many IP packets other paths delivered since `peer_socket` last received a wireguard packet.
+    packets_missed_by_primary: u32,
+    failovers: u64,
+    last_failover: Option<Instant>,
+    /// The agent for an ICE restart we initiated, waiting for the remote's answer.
+    pending_ice_restart: Option<IceAgent>,
+
     stun_servers: HashSet<SocketAddr>,
     turn_servers: HashSet<SocketAddr>,
+
+    /// The candidate pair that ICE nominated.
+    selected_pair: Option<SelectedPair>,
+
+    bytes_sent: u64,
+    packets_sent: u64,
+    bytes_received: u64,
+    packets_received: u64,
+    last_received: Option<Instant>,
 }
 
 /// The socket of the peer we are connected to.
@@ -1185,6 +1780,26 @@ enum PeerSocket {
     },
 }
 
+/// A second path to the remote that we keep warm by sending all traffic over it as well.
+#[derive(Debug, Clone, Copy)]
+struct RedundantPath {
+    socket: PeerSocket,
+}
+
+/// The path a packet took to reach us.
+fn path_of(local: SocketAddr, from: SocketAddr, relay_socket: Option<Socket>) -> PeerSocket {
+    match relay_socket {
+        Some(relay_socket) => PeerSocket::Relay {
+            relay: relay_socket.server(),
+            dest: from,
+        },
+        None => PeerSocket::Direct {
+            source: local,
+            dest: from,
+        },
+    }
+}
+
 impl Connection {
     /// Checks if we want to accept a packet from a certain address.
     ///
@@ -1200,34 +1815,180 @@ impl Connection {
         from_connected_remote || from_possible_remote
     }
 
-    fn set_remote_from_wg_activity(
-        &mut self,
-        local: SocketAddr,
-        dest: SocketAddr,
-        relay_socket: Option<Socket>,
-    ) {
-        let remote_socket = match relay_socket {
-            Some(relay_socket) => PeerSocket::Relay {
-                relay: relay_socket.server(),
-                dest,
-            },
-            None => PeerSocket::Direct {
-                source: local,
-                dest,
-            },
-        };
+    fn set_remote_from_wg_activity(&mut self, remote_socket: PeerSocket, now: Instant) {
+        // The remote sends every packet over its own redundant path too, so which path the second copy arrives on depends on the remote's relays.
+        // Anything arriving on another path we know of thus counts against the primary instead of changing our remote socket.
+        // A remote address we have never heard of means the remote roamed and we migrate like without a redundant path.
+        if self.redundant_path.is_some()
+            && self
+                .peer_socket
+                .is_some_and(|primary| primary != remote_socket)
+            && self.is_known_path(remote_socket)
+        {
+            self.packets_missed_by_primary += 1;
+            self.fail_over_if_primary_lost_packets(now);
+            return;
+        }
 
         if self.peer_socket != Some(remote_socket) {
             tracing::debug!(old = ?self.peer_socket, new = ?remote_socket, "Updating remote socket from WG activity");
             self.peer_socket = Some(remote_socket);
+            self.selected_pair = None; // We no longer know which candidates this path corresponds to.
+            self.packets_missed_by_primary = 0;
+        }
+    }
+
+    /// Whether `path` is our redundant path or leads to one of the remote's candidates.
+    fn is_known_path(&self, path: PeerSocket) -> bool {
+        let dest = match path {
+            PeerSocket::Direct { dest, .. } | PeerSocket::Relay { dest, .. } => dest,
+        };
+
+        self.redundant_path.is_some_and(|r| r.socket == path)
+            || self
+                .agent
+                .remote_candidates()
+                .iter()
+                .any(|c| c.addr() == dest)
+    }
+
+    fn mark_path_alive(&mut self, path: PeerSocket) {
+        if self.peer_socket == Some(path) {
+            self.packets_missed_by_primary = 0;
+        }
+    }
+
+    /// Swaps the primary and the redundant path once the primary missed [`FAILOVER_AFTER_MISSED_PACKETS`] IP packets that other paths delivered.
+    ///
+    /// Only called when we receive on another path, so we know the remote is still sending.
+    /// Idle time doesn't matter: A primary path that is merely slower than the redundant one receives its copy of each packet and resets the count.
+    fn fail_over_if_primary_lost_packets(&mut self, now: Instant) {
+        let (Some(primary), Some(redundant)) = (self.peer_socket, self.redundant_path.as_mut())
+        else {
+            return;
+        };
+
+        if self.packets_missed_by_primary < FAILOVER_AFTER_MISSED_PACKETS {
+            return;
+        }
+
+        tracing::info!(old = ?primary, new = ?redundant.socket, missed = %self.packets_missed_by_primary, "Primary path lost packets, failing over");
+
+        self.peer_socket = Some(redundant.socket);
+        self.packets_missed_by_primary = 0;
+        *redundant = RedundantPath { socket: primary };
+        self.selected_pair = None;
+        self.failovers += 1;
+        self.last_failover = Some(now);
+    }
+
+    /// Picks a relayed path to the remote that differs from `peer_socket` to use as redundant path.
+    ///
+    /// If the primary path is relayed, we relay to the same destination through a different relay.
+    /// If the primary path is direct, we relay to one of the remote's relay candidates so the second path doesn't share the remote's address with the first.
+    fn pick_redundant_path(
+        &mut self,
+        allocations: &mut HashMap<SocketAddr, Allocation>,
+        now: Instant,
+    ) {
+        if !self.wants_redundant_path {
+            return;
+        }
+
+        let Some(primary) = self.peer_socket else {
+            return;
+        };
+        let (primary_relay, dest) = match primary {
+            PeerSocket::Direct { dest, .. } => {
+                let mut remote_relays = self
+                    .agent
+                    .remote_candidates()
+                    .iter()
+                    .filter(|c| c.kind() == CandidateKind::Relayed)
+                    .map(|c| c.addr())
+                    .filter(|addr| addr.is_ipv4() == dest.is_ipv4())
+                    .collect::<Vec<_>>();
+                remote_relays.sort();
+
+                let Some(remote_relay) = remote_relays.into_iter().next() else {
+                    tracing::debug!("Remote has no relay candidate, not using a redundant path");
+                    self.redundant_path = None;
+                    return;
+                };
+
+                (None, remote_relay)
+            }
+            PeerSocket::Relay { relay, dest } => (Some(relay), dest),
+        };
+
+        let mut relays = self
+            .turn_servers
+            .iter()
+            .filter(|relay| Some(**relay) != primary_relay)
+            .copied()
+            .collect::<Vec<_>>();
+        relays.sort();
+
+        self.redundant_path = relays.into_iter().find_map(|relay| {
+            let allocation = allocations.get_mut(&relay)?;
+
+            let has_socket = match dest {
+                SocketAddr::V4(_) => allocation.ip4_socket().is_some(),
+                SocketAddr::V6(_) => allocation.ip6_socket().is_some(),
+            };
+            if !has_socket {
+                return None;
+            }
+
+            allocation.bind_channel(dest, now);
+
+            Some(RedundantPath {
+                socket: PeerSocket::Relay { relay, dest },
+            })
+        });
+
+        tracing::debug!(redundant_path = ?self.redundant_path, "Picked redundant path");
+    }
+
+    fn stats(&self, now: Instant) -> ConnectionInfo {
+        let (handshake_age, _, _, _, rtt) = self.tunnel.stats();
+
+        let relay = match self.peer_socket {
+            Some(PeerSocket::Relay { relay, .. }) => Some(relay),
+            Some(PeerSocket::Direct { .. }) | None => None,
+        };
+
+        ConnectionInfo {
+            generated_at: now,
+            selected_pair: self.selected_pair,
+            relay,
+            bytes_sent: self.bytes_sent,
+            packets_sent: self.packets_sent,
+            bytes_received: self.bytes_received,
+            packets_received: self.packets_received,
+            handshake_age,
+            last_received: self.last_received,
+            rtt: rtt.map(|millis| Duration::from_millis(u64::from(millis))),
+            redundant_relay: match self.redundant_path.map(|r| r.socket) {
+                Some(PeerSocket::Relay { relay, .. }) => Some(relay),
+                Some(PeerSocket::Direct { .. }) | None => None,
+            },
+            failovers: self.failovers,
+            last_failover: self.last_failover,
         }
     }
 
     fn poll_timeout(&mut self) -> Option<Instant> {
         let agent_timeout = self.agent.poll_timeout();
         let next_wg_timer = Some(self.next_timer_update);
+        let idle_at = self.idle_timeout.map(|t| self.last_activity + t);
 
-        earliest(agent_timeout, next_wg_timer)
+        earliest(earliest(agent_timeout, next_wg_timer), idle_at)
+    }
+
+    fn is_idle(&self, now: Instant) -> bool {
+        self.idle_timeout
+            .is_some_and(|timeout| now.duration_since(self.last_activity) >= timeout)
     }
 
     fn poll_transmit(
@@ -1252,6 +2013,7 @@ impl Connection {
                     src: Some(source),
                     dst,
                     payload: Cow::Owned(packet.into()),
+                    transport: Transport::Udp,
                 });
             };
 
@@ -1266,6 +2028,7 @@ impl Connection {
                 src: None,
                 dst: *relay,
                 payload: Cow::Owned(channel_data),
+                transport: allocation.transport(),
             });
         }
     }
@@ -1274,7 +2037,7 @@ impl Connection {
         &mut self,
         now: Instant,
         allocations: &mut HashMap<SocketAddr, Allocation>,
-    ) -> Result<Option<Transmit<'static>>, WireGuardError> {
+    ) -> Result<Vec<Transmit<'static>>, WireGuardError> {
         self.agent.handle_timeout(now);
 
         // TODO: `boringtun` is impure because it calls `Instant::now`.
@@ -1284,7 +2047,7 @@ impl Connection {
 
             // Don't update wireguard timers until we are connected.
             if self.peer_socket.is_none() {
-                return Ok(None);
+                return Ok(Vec::new());
             }
 
             /// [`boringtun`] requires us to pass buffers in where it can construct its packets.
@@ -1298,37 +2061,172 @@ impl Connection {
                 TunnResult::Done => {}
                 TunnResult::Err(e) => return Err(e),
                 TunnResult::WriteToNetwork(b) => {
-                    let Some(transmit) = self.encapsulate(b, allocations, now) else {
-                        return Ok(None);
-                    };
-
-                    return Ok(Some(transmit.into_owned()));
+                    return Ok(self.encapsulate(b, allocations, now));
                 }
                 _ => panic!("Unexpected result from update_timers"),
             };
         }
 
-        Ok(None)
+        Ok(Vec::new())
     }
 
+    /// Sends a wireguard message over the primary path and, if we have one, the redundant path.
+    ///
+    /// Sending handshakes over both paths lets them complete while the primary path is losing packets.
     fn encapsulate(
         &self,
         message: &[u8],
         allocations: &mut HashMap<SocketAddr, Allocation>,
         now: Instant,
-    ) -> Option<Transmit<'static>> {
-        match self.peer_socket? {
-            PeerSocket::Direct {
-                dest: remote,
-                source,
-            } => Some(Transmit {
-                src: Some(source),
-                dst: remote,
-                payload: Cow::Owned(message.into()),
-            }),
-            PeerSocket::Relay { relay, dest: peer } => {
-                encode_as_channel_data(relay, peer, message, allocations, now).ok()
-            }
+    ) -> Vec<Transmit<'static>> {
+        iter::once(self.peer_socket)
+            .chain(iter::once(self.redundant_path.map(|r| r.socket)))
+            .flatten()
+            .filter_map(|socket| make_transmit(socket, message, allocations, now))
+            .collect()
+    }
+}
+
+fn make_transmit(
+    socket: PeerSocket,
+    message: &[u8],
+    allocations: &mut HashMap<SocketAddr, Allocation>,
+    now: Instant,
+) -> Option<Transmit<'static>> {
+    match socket {
+        PeerSocket::Direct {
+            dest: remote,
+            source,
+        } => Some(Transmit {
+            src: Some(source),
+            dst: remote,
+            payload: Cow::Owned(message.into()),
+            transport: Transport::Udp,
+        }),
+        PeerSocket::Relay { relay, dest: peer } => {
+            encode_as_channel_data(relay, peer, message, allocations, now).ok()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    #[test]
+    fn default_candidate_filter_allows_everything() {
+        let filter = CandidateFilter::default();
+
+        assert!(filter.allows(&host_candidate()));
+        assert!(filter.allows(&relay_candidate()));
+    }
+
+    #[test]
+    fn relay_only_filter_rejects_host_candidates() {
+        let filter = CandidateFilter {
+            relay_only: true,
+            ..Default::default()
+        };
+
+        assert!(!filter.allows(&host_candidate()));
+        assert!(filter.allows(&relay_candidate()));
+    }
+
+    #[test]
+    fn candidate_filter_rejects_excluded_ips() {
+        let filter = CandidateFilter {
+            excluded_ips: HashSet::from([host_candidate().addr().ip()]),
+            ..Default::default()
+        };
+
+        assert!(!filter.allows(&host_candidate()));
+        assert!(filter.allows(&relay_candidate()));
+    }
+
+    #[test]
+    fn fastest_relays_keeps_all_without_limit() {
+        let servers = HashSet::from([relay(1), relay(2), relay(3)]);
+
+        let selected = fastest_relays(servers.clone(), &HashMap::default(), None);
+
+        assert_eq!(selected, servers);
+    }
+
+    #[test]
+    fn fastest_relays_picks_lowest_rtt() {
+        let rtts = HashMap::from([
+            (relay(1).0, Duration::from_millis(200)),
+            (relay(2).0, Duration::from_millis(20)),
+            (relay(3).0, Duration::from_millis(50)),
+        ]);
+
+        let selected = fastest_relays(
+            HashSet::from([relay(1), relay(2), relay(3)]),
+            &rtts,
+            NonZeroUsize::new(2),
+        );
+
+        assert_eq!(selected, HashSet::from([relay(2), relay(3)]));
+    }
+
+    #[test]
+    fn fastest_relays_keeps_unmeasured_relays() {
+        let rtts = HashMap::from([
+            (relay(1).0, Duration::from_millis(200)),
+            (relay(2).0, Duration::from_millis(20)),
+        ]);
+
+        let selected = fastest_relays(
+            HashSet::from([relay(1), relay(2), relay(3)]),
+            &rtts,
+            NonZeroUsize::new(1),
+        );
+
+        assert_eq!(selected, HashSet::from([relay(2), relay(3)]));
+    }
+
+    #[test]
+    fn previous_rtts_are_forgotten_after_a_while() {
+        let start = Instant::now();
+        let mut node =
+            ClientNode::<u64>::new(StaticSecret::random_from_rng(rand::thread_rng()), start);
+        node.previous_rtts
+            .insert(relay(1).0, (Duration::from_millis(20), start));
+
+        assert_eq!(
+            node.server_rtts(),
+            HashMap::from([(relay(1).0, Duration::from_millis(20))])
+        );
+
+        node.handle_timeout(start + MAX_PREVIOUS_RTT_AGE);
+
+        assert!(node.server_rtts().is_empty());
+        assert!(node.previous_rtts.is_empty());
+    }
+
+    fn host_candidate() -> Candidate {
+        Candidate::host(
+            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(172, 17, 0, 1), 5000)),
+            Protocol::Udp,
+        )
+        .unwrap()
+    }
+
+    fn relay_candidate() -> Candidate {
+        Candidate::relayed(
+            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 50000)),
+            Protocol::Udp,
+        )
+        .unwrap()
+    }
+
+    fn relay(port: u16) -> (SocketAddr, String, String, String) {
+        (
+            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)),
+            "user".to_owned(),
+            "pass".to_owned(),
+            "realm".to_owned(),
+        )
+    }
+}
@@ -0,0 +1,110 @@
+use bytes::BytesMut;
+use std::io;
+
+const STUN_HEADER_LEN: usize = 20;
+const CHANNEL_DATA_HEADER_LEN: usize = 4;
+
+/// Splits the bytes received from a TURN server over TCP or TLS into individual messages.
+///
+/// Over a stream, there are no datagram boundaries so we need to rely on the length fields in the STUN and channel data headers.
+/// Each message returned from [`StreamDecoder::next_message`] should be passed to [`Node::decapsulate_stream`](crate::Node::decapsulate_stream).
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8656#name-the-channeldata-message>.
+#[derive(Debug, Default)]
+pub struct StreamDecoder {
+    buffer: BytesMut,
+}
+
+impl StreamDecoder {
+    /// Appends bytes read from the stream.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Splits off the next complete message, stripping the padding of channel data messages.
+    ///
+    /// An error means the stream is corrupted and should be closed.
+    pub fn next_message(&mut self) -> io::Result<Option<BytesMut>> {
+        if self.buffer.len() < CHANNEL_DATA_HEADER_LEN {
+            return Ok(None);
+        }
+
+        let length = u16::from_be_bytes([self.buffer[2], self.buffer[3]]) as usize;
+
+        // De-multiplex as per <https://www.rfc-editor.org/rfc/rfc8656#name-channels-2>.
+        let (message_len, frame_len) = match self.buffer[0] {
+            0..=3 => (STUN_HEADER_LEN + length, STUN_HEADER_LEN + length),
+            64..=79 => {
+                let message_len = CHANNEL_DATA_HEADER_LEN + length;
+
+                (message_len, padded_len(message_len))
+            }
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown message type {other}"),
+                ))
+            }
+        };
+
+        if self.buffer.len() < frame_len {
+            return Ok(None);
+        }
+
+        let mut message = self.buffer.split_to(frame_len);
+        message.truncate(message_len);
+
+        Ok(Some(message))
+    }
+}
+
+/// Channel data messages MUST be padded to a multiple of 4 bytes when sent over a stream.
+///
+/// STUN messages are always a multiple of 4 bytes long.
+pub(crate) fn padded_len(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_for_complete_stun_message() {
+        let mut decoder = StreamDecoder::default();
+
+        decoder.push(&[0x01, 0x01, 0x00, 0x04]);
+        decoder.push(&[0u8; 16]);
+        assert!(decoder.next_message().unwrap().is_none());
+
+        decoder.push(&[0u8; 4]);
+        assert_eq!(decoder.next_message().unwrap().unwrap().len(), 24);
+        assert!(decoder.next_message().unwrap().is_none());
+    }
+
+    #[test]
+    fn strips_padding_of_channel_data() {
+        let mut decoder = StreamDecoder::default();
+
+        decoder.push(&[0x40, 0x00, 0x00, 0x01, 0xFF, 0x00, 0x00, 0x00]);
+        decoder.push(&[0x40, 0x01, 0x00, 0x00]);
+
+        assert_eq!(
+            &decoder.next_message().unwrap().unwrap()[..],
+            &[0x40, 0x00, 0x00, 0x01, 0xFF]
+        );
+        assert_eq!(
+            &decoder.next_message().unwrap().unwrap()[..],
+            &[0x40, 0x01, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn unknown_message_type_is_an_error() {
+        let mut decoder = StreamDecoder::default();
+
+        decoder.push(&[0xFF, 0x00, 0x00, 0x00]);
+
+        assert!(decoder.next_message().is_err());
+    }
+}
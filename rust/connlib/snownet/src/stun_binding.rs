@@ -1,7 +1,8 @@
 use crate::{
     backoff,
-    node::{CandidateEvent, Transmit},
+    node::{CandidateEvent, Transmit, Transport},
     ringbuffer::RingBuffer,
+    utils::smoothed_rtt,
 };
 use ::backoff::backoff::Backoff;
 use backoff::ExponentialBackoff;
@@ -28,6 +29,9 @@ pub struct StunBinding {
     state: State,
     last_now: Instant,
 
+    /// The smoothed round-trip time to the STUN server and when we last updated it.
+    rtt: Option<(Duration, Instant)>,
+
     backoff: ExponentialBackoff,
 
     /// When we time out requests, we remember the last [`TransactionId`]s to be able to recognize them in case they do arrive later.
@@ -52,6 +56,7 @@ impl StunBinding {
             last_candidate: None,
             state,
             last_now: now,
+            rtt: None,
             buffered_transmits: VecDeque::from([transmit]),
             events: Default::default(),
             backoff,
@@ -63,6 +68,10 @@ impl StunBinding {
         self.last_candidate.clone()
     }
 
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt.map(|(rtt, _)| rtt)
+    }
+
     pub fn handle_input(
         &mut self,
         from: SocketAddr,
@@ -94,7 +103,8 @@ impl StunBinding {
         }
 
         match self.state {
-            State::SentRequest { id, .. } if id == transaction_id => {
+            State::SentRequest { id, at, .. } if id == transaction_id => {
+                self.rtt = Some(smoothed_rtt(self.rtt, now.duration_since(at), now));
                 self.state = State::ReceivedResponse { at: now }
             }
             _ => {
@@ -173,6 +183,30 @@ impl StunBinding {
         self.buffered_transmits.push_back(transmit);
     }
 
+    /// Sends a new binding request right away, e.g. because our local address changed.
+    ///
+    /// The server-reflexive candidate is forgotten until the server responds again, the RTT is kept.
+    pub fn refresh(&mut self, now: Instant) {
+        self.last_now = now;
+        self.backoff.clock.now = now;
+        self.backoff.reset();
+
+        if let State::SentRequest { id, .. } = self.state {
+            self.timed_out_requests.push(id);
+        }
+
+        let (state, transmit) = new_binding_request(
+            self.server,
+            now,
+            self.backoff
+                .next_backoff()
+                .expect("we just reset the backoff"),
+        );
+        self.state = state;
+        self.buffered_transmits.push_back(transmit);
+        self.last_candidate = None;
+    }
+
     pub fn poll_event(&mut self) -> Option<CandidateEvent> {
         self.events.pop_front()
     }
@@ -223,6 +257,7 @@ fn new_binding_request(
         src: None,
         dst: server,
         payload: encode(request).into(),
+        transport: Transport::Udp,
     };
 
     (state, transmit)
@@ -439,6 +474,51 @@ mod tests {
         assert!(stun_binding.poll_event().is_none());
     }
 
+    #[test]
+    fn rtt_is_measured_from_binding_response() {
+        let start = Instant::now();
+        let mut stun_binding = StunBinding::new(SERVER1, start);
+
+        let request = stun_binding.poll_transmit().unwrap();
+        let response = generate_stun_response(request, MAPPED_ADDRESS);
+        stun_binding.handle_input(
+            SERVER1,
+            MAPPED_ADDRESS,
+            &response,
+            start + Duration::from_millis(30),
+        );
+
+        assert_eq!(stun_binding.rtt(), Some(Duration::from_millis(30)));
+    }
+
+    #[test]
+    fn refresh_replaces_outdated_rtt() {
+        let start = Instant::now();
+        let mut stun_binding = StunBinding::new(SERVER1, start);
+
+        let request = stun_binding.poll_transmit().unwrap();
+        let response = generate_stun_response(request, MAPPED_ADDRESS);
+        stun_binding.handle_input(
+            SERVER1,
+            MAPPED_ADDRESS,
+            &response,
+            start + Duration::from_millis(30),
+        );
+
+        let refresh_at = stun_binding.poll_timeout().unwrap();
+        stun_binding.handle_timeout(refresh_at);
+        let request = stun_binding.poll_transmit().unwrap();
+        let response = generate_stun_response(request, MAPPED_ADDRESS);
+        stun_binding.handle_input(
+            SERVER1,
+            MAPPED_ADDRESS,
+            &response,
+            refresh_at + Duration::from_millis(150),
+        );
+
+        assert_eq!(stun_binding.rtt(), Some(Duration::from_millis(150)));
+    }
+
     fn generate_stun_response(request: Transmit, mapped_address: SocketAddr) -> Vec<u8> {
         let mut decoder = stun_codec::MessageDecoder::<stun_codec::rfc5389::Attribute>::default();
 
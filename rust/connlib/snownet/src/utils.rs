@@ -1,4 +1,4 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 pub fn earliest(left: Option<Instant>, right: Option<Instant>) -> Option<Instant> {
     match (left, right) {
@@ -8,3 +8,22 @@ pub fn earliest(left: Option<Instant>, right: Option<Instant>) -> Option<Instant
         (None, Some(right)) => Some(right),
     }
 }
+
+/// Round-trip times measured longer ago than this are replaced by the next sample instead of smoothed with it.
+///
+/// We only talk to servers every few minutes once bindings and allocations are established, thus old samples no longer tell us much about the path.
+const MAX_RTT_AGE: Duration = Duration::from_secs(60);
+
+/// Updates a smoothed round-trip time with a new sample taken at `now`, as per RFC 6298.
+pub fn smoothed_rtt(
+    current: Option<(Duration, Instant)>,
+    sample: Duration,
+    now: Instant,
+) -> (Duration, Instant) {
+    match current {
+        Some((current, at)) if now.duration_since(at) < MAX_RTT_AGE => {
+            (current * 7 / 8 + sample / 8, now)
+        }
+        _ => (sample, now),
+    }
+}
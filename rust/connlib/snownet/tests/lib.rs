@@ -1,5 +1,5 @@
 use boringtun::x25519::StaticSecret;
-use snownet::{ClientNode, Event, ServerNode};
+use snownet::{ClientNode, ConnectionConfig, Event, ServerNode};
 use std::{
     collections::HashSet,
     iter,
@@ -15,7 +15,12 @@ fn connection_times_out_after_10_seconds() {
     let mut alice =
         ClientNode::<u64>::new(StaticSecret::random_from_rng(rand::thread_rng()), start);
 
-    let _ = alice.new_connection(1, HashSet::new(), HashSet::new());
+    let _ = alice.new_connection(
+        1,
+        HashSet::new(),
+        HashSet::new(),
+        ConnectionConfig::default(),
+    );
     alice.handle_timeout(start + Duration::from_secs(20));
 
     assert_eq!(alice.poll_event().unwrap(), Event::ConnectionFailed(1));
@@ -29,9 +34,20 @@ fn answer_after_stale_connection_does_not_panic() {
         ClientNode::<u64>::new(StaticSecret::random_from_rng(rand::thread_rng()), start);
     let mut bob = ServerNode::<u64>::new(StaticSecret::random_from_rng(rand::thread_rng()), start);
 
-    let offer = alice.new_connection(1, HashSet::new(), HashSet::new());
-    let answer =
-        bob.accept_connection(1, offer, alice.public_key(), HashSet::new(), HashSet::new());
+    let offer = alice.new_connection(
+        1,
+        HashSet::new(),
+        HashSet::new(),
+        ConnectionConfig::default(),
+    );
+    let answer = bob.accept_connection(
+        1,
+        offer,
+        alice.public_key(),
+        HashSet::new(),
+        HashSet::new(),
+        ConnectionConfig::default(),
+    );
 
     alice.handle_timeout(start + Duration::from_secs(10));
 
@@ -54,7 +70,12 @@ fn only_generate_candidate_event_after_answer() {
         Instant::now(),
     );
 
-    let offer = alice.new_connection(1, HashSet::new(), HashSet::new());
+    let offer = alice.new_connection(
+        1,
+        HashSet::new(),
+        HashSet::new(),
+        ConnectionConfig::default(),
+    );
 
     assert_eq!(
         alice.poll_event(),
@@ -62,8 +83,14 @@ fn only_generate_candidate_event_after_answer() {
         "no event to be emitted before accepting the answer"
     );
 
-    let answer =
-        bob.accept_connection(1, offer, alice.public_key(), HashSet::new(), HashSet::new());
+    let answer = bob.accept_connection(
+        1,
+        offer,
+        alice.public_key(),
+        HashSet::new(),
+        HashSet::new(),
+        ConnectionConfig::default(),
+    );
 
     alice.accept_answer(1, bob.public_key(), answer);
 
@@ -87,6 +114,7 @@ fn second_connection_with_same_relay_reuses_allocation() {
         1,
         HashSet::new(),
         HashSet::from([relay("user1", "pass1", "realm1")]),
+        ConnectionConfig::default(),
     );
 
     let transmit = alice.poll_transmit().unwrap();
@@ -97,11 +125,150 @@ fn second_connection_with_same_relay_reuses_allocation() {
         2,
         HashSet::new(),
         HashSet::from([relay("user1", "pass1", "realm1")]),
+        ConnectionConfig::default(),
     );
 
     assert!(alice.poll_transmit().is_none());
 }
 
+#[test]
+fn stats_of_new_connection_are_empty() {
+    let start = Instant::now();
+
+    let mut alice =
+        ClientNode::<u64>::new(StaticSecret::random_from_rng(rand::thread_rng()), start);
+    let mut bob = ServerNode::<u64>::new(StaticSecret::random_from_rng(rand::thread_rng()), start);
+
+    let offer = alice.new_connection(
+        1,
+        HashSet::new(),
+        HashSet::new(),
+        ConnectionConfig::default(),
+    );
+    let _ = bob.accept_connection(
+        1,
+        offer,
+        alice.public_key(),
+        HashSet::new(),
+        HashSet::new(),
+        ConnectionConfig::default(),
+    );
+
+    let (id, info) = bob.stats().next().unwrap();
+
+    assert_eq!(id, 1);
+    assert_eq!(info.selected_pair, None);
+    assert_eq!(info.relay, None);
+    assert_eq!(info.bytes_sent, 0);
+    assert_eq!(info.packets_received, 0);
+    assert_eq!(info.last_received, None);
+    assert_eq!(info.handshake_age, None);
+}
+
+#[test]
+fn ice_restart_keeps_connection() {
+    let start = Instant::now();
+
+    let mut alice =
+        ClientNode::<u64>::new(StaticSecret::random_from_rng(rand::thread_rng()), start);
+    let mut bob = ServerNode::<u64>::new(StaticSecret::random_from_rng(rand::thread_rng()), start);
+
+    let offer = alice.new_connection(
+        1,
+        HashSet::new(),
+        HashSet::new(),
+        ConnectionConfig::default(),
+    );
+    let answer = bob.accept_connection(
+        1,
+        offer,
+        alice.public_key(),
+        HashSet::new(),
+        HashSet::new(),
+        ConnectionConfig::default(),
+    );
+    alice.accept_answer(1, bob.public_key(), answer);
+
+    let credentials = alice.restart_ice(1).unwrap();
+    let answer = bob.accept_ice_restart(1, credentials).unwrap();
+    alice.accept_ice_restart_answer(1, answer);
+
+    assert_eq!(alice.stats().map(|(id, _)| id).collect::<Vec<_>>(), vec![1]);
+    assert_eq!(bob.stats().map(|(id, _)| id).collect::<Vec<_>>(), vec![1]);
+}
+
+#[test]
+fn cannot_restart_ice_of_unknown_connection() {
+    let mut alice = ClientNode::<u64>::new(
+        StaticSecret::random_from_rng(rand::thread_rng()),
+        Instant::now(),
+    );
+
+    let _ = alice.new_connection(
+        1,
+        HashSet::new(),
+        HashSet::new(),
+        ConnectionConfig::default(),
+    );
+
+    assert!(
+        alice.restart_ice(1).is_none(),
+        "connection is not established"
+    );
+    assert!(alice.restart_ice(2).is_none());
+}
+
+#[test]
+fn idle_connection_is_closed() {
+    let start = Instant::now();
+
+    let mut alice =
+        ClientNode::<u64>::new(StaticSecret::random_from_rng(rand::thread_rng()), start);
+    let mut bob = ServerNode::<u64>::new(StaticSecret::random_from_rng(rand::thread_rng()), start);
+
+    let config = ConnectionConfig {
+        idle_timeout: Some(Duration::from_secs(60)),
+        ..Default::default()
+    };
+
+    let offer = alice.new_connection(1, HashSet::new(), HashSet::new(), config);
+    let _ = bob.accept_connection(
+        1,
+        offer,
+        alice.public_key(),
+        HashSet::new(),
+        HashSet::new(),
+        config,
+    );
+
+    assert!(bob.poll_timeout().unwrap() <= start + Duration::from_secs(60));
+
+    bob.handle_timeout(start + Duration::from_secs(60));
+
+    assert!(iter::from_fn(|| bob.poll_event()).any(|e| e == Event::ConnectionClosed(1)));
+    assert!(bob.stats().next().is_none());
+}
+
+#[test]
+fn closed_connection_does_not_fail() {
+    let start = Instant::now();
+
+    let mut alice =
+        ClientNode::<u64>::new(StaticSecret::random_from_rng(rand::thread_rng()), start);
+
+    let _ = alice.new_connection(
+        1,
+        HashSet::new(),
+        HashSet::new(),
+        ConnectionConfig::default(),
+    );
+    alice.close_connection(1);
+    alice.handle_timeout(start + Duration::from_secs(20));
+
+    assert_eq!(alice.poll_event(), None);
+    assert_eq!(alice.stats().count(), 0);
+}
+
 fn relay(username: &str, pass: &str, realm: &str) -> (SocketAddr, String, String, String) {
     (
         RELAY,
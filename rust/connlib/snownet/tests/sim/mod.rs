@@ -0,0 +1,676 @@
+//! A deterministic, in-memory network for testing two [`Node`]s end-to-end.
+//!
+//! Alice runs a [`ClientNode`], Bob runs a [`ServerNode`] and both may sit behind a NAT.
+//! A real relay from [`firezone_relay`] acts as TURN server for both of them.
+//!
+//! Time is virtual and only advances in [`Simulation::run_until`], jumping straight to the next packet delivery or timer.
+//! Packet loss is drawn from a seeded RNG so every run of a test sees the same network.
+
+use boringtun::x25519::StaticSecret;
+use firezone_relay::{AllocationId, ClientSocket, Command, PeerSocket};
+use pnet_packet::ipv4::MutableIpv4Packet;
+use pnet_packet::Packet as _;
+use rand::rngs::StdRng;
+use rand::{Rng as _, SeedableRng as _};
+use snownet::{
+    ClientNode, ConnectionConfig, ConnectionInfo, Event, IpPacket, Node, ServerNode, Transmit,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime};
+
+/// The ID of the only connection between Alice and Bob.
+pub const CONNECTION: u64 = 1;
+
+const ALICE_PUBLIC_IP: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
+const ALICE_PRIVATE_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+const BOB_PUBLIC_IP: Ipv4Addr = Ipv4Addr::new(2, 2, 2, 2);
+const BOB_PRIVATE_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const RELAY_IP: Ipv4Addr = Ipv4Addr::new(3, 3, 3, 3);
+
+const HOST_PORT: u16 = 5000;
+const RELAY_PORT: u16 = 3478;
+
+/// The behaviour of the NAT in front of a host.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc4787> for the terminology.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatType {
+    /// The host has a public IP.
+    None,
+    /// Endpoint-independent mapping and filtering: anyone can send to a mapped port.
+    FullCone,
+    /// Endpoint-independent mapping but only remotes that we sent to can send to a mapped port.
+    PortRestricted,
+    /// Every remote gets its own mapped port and only that remote can send to it.
+    Symmetric,
+}
+
+pub struct Simulation {
+    start: Instant,
+    /// The wall-clock time at `start`, the relay works with [`SystemTime`].
+    unix_start: SystemTime,
+    now: Instant,
+
+    rng: StdRng,
+    latency: Duration,
+    loss: f64,
+    /// Drop all packets exchanged directly between Alice and Bob, i.e. not through the relay.
+    direct_path_cut: bool,
+    direct_path_delay: Duration,
+
+    /// Packets in flight, indexed by their delivery time and a sequence number to keep the order stable.
+    in_flight: BTreeMap<(Instant, u64), Packet>,
+    next_seq: u64,
+
+    pub alice: Host<ClientNode<u64>>,
+    pub bob: Host<ServerNode<u64>>,
+    relay: Relay,
+
+    buffer: Vec<u8>,
+}
+
+pub struct Host<N> {
+    pub node: N,
+    local: SocketAddr,
+    nat: Nat,
+
+    /// The IP packets decrypted from the connection.
+    pub received: Vec<Vec<u8>>,
+    /// All events other than [`Event::SignalIceCandidate`].
+    pub events: Vec<Event<u64>>,
+}
+
+struct Relay {
+    server: firezone_relay::Server<StdRng>,
+    ports: HashMap<u16, AllocationId>,
+    wake: Option<SystemTime>,
+}
+
+struct Packet {
+    src: SocketAddr,
+    dst: SocketAddr,
+    payload: Vec<u8>,
+}
+
+struct Nat {
+    kind: NatType,
+    public_ip: IpAddr,
+    next_port: u16,
+    /// Maps an internal socket (and, for symmetric NATs, the remote) to a public port.
+    mappings: HashMap<(SocketAddr, Option<SocketAddr>), u16>,
+    /// For each public port, the internal socket and all remotes it has sent to.
+    bindings: HashMap<u16, (SocketAddr, HashSet<SocketAddr>)>,
+}
+
+impl Simulation {
+    pub fn new(seed: u64, alice_nat: NatType, bob_nat: NatType) -> Self {
+        let start = Instant::now();
+        let unix_start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        let alice = Host::new(
+            ClientNode::new(StaticSecret::random_from_rng(&mut rng), start),
+            ALICE_PUBLIC_IP,
+            ALICE_PRIVATE_IP,
+            alice_nat,
+        );
+        let bob = Host::new(
+            ServerNode::new(StaticSecret::random_from_rng(&mut rng), start),
+            BOB_PUBLIC_IP,
+            BOB_PRIVATE_IP,
+            bob_nat,
+        );
+        let relay = Relay {
+            server: firezone_relay::Server::new(
+                RELAY_IP,
+                StdRng::seed_from_u64(rng.gen()),
+                49152,
+                65535,
+            ),
+            ports: HashMap::default(),
+            wake: None,
+        };
+
+        Self {
+            start,
+            unix_start,
+            now: start,
+            rng,
+            latency: Duration::ZERO,
+            loss: 0.0,
+            direct_path_cut: false,
+            direct_path_delay: Duration::ZERO,
+            in_flight: BTreeMap::default(),
+            next_seq: 0,
+            alice,
+            bob,
+            relay,
+            buffer: vec![0u8; (1 << 16) - 1],
+        }
+    }
+
+    /// Delays every packet by the given one-way latency.
+    pub fn set_latency(&mut self, latency: Duration) {
+        self.latency = latency;
+    }
+
+    /// Drops every packet with the given probability.
+    pub fn set_packet_loss(&mut self, loss: f64) {
+        self.loss = loss;
+    }
+
+    /// Drops all packets exchanged directly between Alice and Bob from now on.
+    ///
+    /// Traffic through the relay is unaffected.
+    pub fn cut_direct_path(&mut self) {
+        self.direct_path_cut = true;
+    }
+
+    /// Delays packets exchanged directly between Alice and Bob by the given time on top of the latency.
+    ///
+    /// Traffic through the relay is unaffected.
+    pub fn set_direct_path_delay(&mut self, delay: Duration) {
+        self.direct_path_delay = delay;
+    }
+
+    /// Moves Alice to a new local address, as if Alice switched networks.
+    ///
+    /// Alice's NAT forgets all mappings, thus packets to or from the old address are dropped.
+    pub fn change_alice_address(&mut self) {
+        self.alice.local = SocketAddr::new(self.alice.local.ip(), self.alice.local.port() + 1);
+        self.alice.nat.mappings.clear();
+        self.alice.nat.bindings.clear();
+    }
+
+    /// Restarts ICE from Alice's side and signals Alice's current address as host candidate.
+    ///
+    /// Credentials are exchanged instantly.
+    pub fn restart_ice(&mut self) {
+        let credentials = self.alice.node.restart_ice(CONNECTION).unwrap();
+        self.alice
+            .node
+            .add_local_host_candidate(self.alice.local)
+            .unwrap();
+
+        let answer = self
+            .bob
+            .node
+            .accept_ice_restart(CONNECTION, credentials)
+            .unwrap();
+        self.alice
+            .node
+            .accept_ice_restart_answer(CONNECTION, answer);
+    }
+
+    /// Creates the connection between Alice and Bob with the default [`ConnectionConfig`].
+    pub fn connect(&mut self) {
+        self.connect_with_config(ConnectionConfig::default());
+    }
+
+    /// Creates the connection between Alice and Bob, using the relay as TURN server.
+    ///
+    /// Offer and answer are exchanged instantly.
+    pub fn connect_with_config(&mut self, config: ConnectionConfig) {
+        let alice_relay = self.relay_credentials("alice");
+        let bob_relay = self.relay_credentials("bob");
+
+        self.alice
+            .node
+            .add_local_host_candidate(self.alice.local)
+            .unwrap();
+        self.bob
+            .node
+            .add_local_host_candidate(self.bob.local)
+            .unwrap();
+
+        let offer = self.alice.node.new_connection(
+            CONNECTION,
+            HashSet::new(),
+            HashSet::from([alice_relay]),
+            config,
+        );
+        let answer = self.bob.node.accept_connection(
+            CONNECTION,
+            offer,
+            self.alice.node.public_key(),
+            HashSet::new(),
+            HashSet::from([bob_relay]),
+            config,
+        );
+        self.alice
+            .node
+            .accept_answer(CONNECTION, self.bob.node.public_key(), answer);
+    }
+
+    /// Encrypts an IP packet with the given payload from Alice to Bob and puts it on the wire.
+    pub fn send_from_alice(&mut self, payload: &[u8]) {
+        let packet = ip_packet(ALICE_PRIVATE_IP, BOB_PRIVATE_IP, payload);
+
+        let Some(transmit) = self
+            .alice
+            .node
+            .encapsulate(CONNECTION, IpPacket::new(&packet).unwrap())
+            .unwrap()
+        else {
+            return;
+        };
+        let transmit = transmit.into_owned();
+
+        let Some((src, dst, payload)) = self.alice.translate(transmit) else {
+            return;
+        };
+        self.send(src, dst, payload);
+    }
+
+    /// Encrypts an IP packet with the given payload from Bob to Alice and puts it on the wire.
+    pub fn send_from_bob(&mut self, payload: &[u8]) {
+        let packet = ip_packet(BOB_PRIVATE_IP, ALICE_PRIVATE_IP, payload);
+
+        let Some(transmit) = self
+            .bob
+            .node
+            .encapsulate(CONNECTION, IpPacket::new(&packet).unwrap())
+            .unwrap()
+        else {
+            return;
+        };
+        let transmit = transmit.into_owned();
+
+        let Some((src, dst, payload)) = self.bob.translate(transmit) else {
+            return;
+        };
+        self.send(src, dst, payload);
+    }
+
+    /// Advances the simulation until `condition` holds.
+    ///
+    /// Returns `false` if the condition didn't hold within `timeout` of virtual time.
+    pub fn run_until(&mut self, timeout: Duration, condition: impl Fn(&Self) -> bool) -> bool {
+        let deadline = self.now + timeout;
+
+        loop {
+            self.flush();
+
+            if condition(self) {
+                return true;
+            }
+
+            let Some(next) = self.next_deadline().filter(|next| *next <= deadline) else {
+                self.now = deadline;
+                return false;
+            };
+
+            self.now = self.now.max(next);
+            self.tick();
+        }
+    }
+
+    /// Whether both sides emitted [`Event::ConnectionEstablished`].
+    pub fn is_connected(&self) -> bool {
+        self.alice.is_connected() && self.bob.is_connected()
+    }
+
+    fn relay_credentials(&self, salt: &str) -> (SocketAddr, String, String, String) {
+        let expiry = self.unix_start + Duration::from_secs(60 * 60 * 24);
+        let expiry_secs = expiry
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let password =
+            firezone_relay::generate_password(self.relay.server.auth_secret(), expiry, salt);
+
+        (
+            relay_addr(),
+            format!("{expiry_secs}:{salt}"),
+            password,
+            "firezone".to_owned(),
+        )
+    }
+
+    fn system_now(&self) -> SystemTime {
+        self.unix_start + self.now.duration_since(self.start)
+    }
+
+    fn send(&mut self, src: SocketAddr, dst: SocketAddr, payload: Vec<u8>) {
+        if self.rng.gen_bool(self.loss) {
+            return;
+        }
+
+        let is_direct = src.ip() != IpAddr::from(RELAY_IP) && dst.ip() != IpAddr::from(RELAY_IP);
+        if self.direct_path_cut && is_direct {
+            return;
+        }
+
+        let delay = if is_direct {
+            self.latency + self.direct_path_delay
+        } else {
+            self.latency
+        };
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        self.in_flight
+            .insert((self.now + delay, seq), Packet { src, dst, payload });
+    }
+
+    /// The next point in time at which something needs to happen.
+    ///
+    /// Timers that are already due are moved into the future so we always make progress.
+    fn next_deadline(&mut self) -> Option<Instant> {
+        let earliest_timer = self.now + Duration::from_millis(1);
+
+        let relay_wake = self.relay.wake.map(|wake| {
+            self.start
+                + wake
+                    .duration_since(self.unix_start)
+                    .unwrap_or(Duration::ZERO)
+        });
+
+        let timers = [
+            self.alice.node.poll_timeout(),
+            self.bob.node.poll_timeout(),
+            relay_wake,
+        ]
+        .into_iter()
+        .flatten()
+        .map(|timer| timer.max(earliest_timer));
+
+        let packets = self.in_flight.keys().map(|(deliver_at, _)| *deliver_at);
+
+        timers.chain(packets).min()
+    }
+
+    fn tick(&mut self) {
+        while let Some(entry) = self.in_flight.first_entry() {
+            if entry.key().0 > self.now {
+                break;
+            }
+
+            let packet = entry.remove();
+            self.deliver(packet);
+            self.flush();
+        }
+
+        if self
+            .alice
+            .node
+            .poll_timeout()
+            .is_some_and(|t| t <= self.now)
+        {
+            self.alice.node.handle_timeout(self.now);
+        }
+        if self.bob.node.poll_timeout().is_some_and(|t| t <= self.now) {
+            self.bob.node.handle_timeout(self.now);
+        }
+
+        let system_now = self.system_now();
+        if self.relay.wake.is_some_and(|wake| wake <= system_now) {
+            self.relay.wake = None;
+            self.relay.server.handle_deadline_reached(system_now);
+        }
+    }
+
+    fn deliver(&mut self, packet: Packet) {
+        let Packet { src, dst, payload } = packet;
+        let now = self.now;
+
+        match dst.ip() {
+            IpAddr::V4(ip) if ip == RELAY_IP => {
+                let system_now = self.system_now();
+
+                if dst.port() == RELAY_PORT {
+                    self.relay.server.handle_client_input(
+                        &payload,
+                        ClientSocket::new(src),
+                        system_now,
+                    );
+                    return;
+                }
+
+                let Some(id) = self.relay.ports.get(&dst.port()).copied() else {
+                    return;
+                };
+
+                self.relay.server.handle_peer_traffic(
+                    &payload,
+                    PeerSocket::new(src),
+                    id,
+                    system_now,
+                );
+            }
+            IpAddr::V4(ip) if ip == ALICE_PUBLIC_IP => {
+                self.alice
+                    .receive(src, dst, &payload, now, &mut self.buffer);
+            }
+            IpAddr::V4(ip) if ip == BOB_PUBLIC_IP => {
+                self.bob.receive(src, dst, &payload, now, &mut self.buffer);
+            }
+            _ => {}
+        }
+    }
+
+    /// Collects everything the nodes and the relay want to send and performs the signalling between Alice and Bob.
+    fn flush(&mut self) {
+        loop {
+            let mut packets = Vec::new();
+
+            packets.extend(self.alice.poll_transmits());
+            packets.extend(self.bob.poll_transmits());
+            packets.extend(self.poll_relay());
+
+            for candidate in self.alice.poll_candidates() {
+                self.bob.node.add_remote_candidate(CONNECTION, candidate);
+            }
+            for candidate in self.bob.poll_candidates() {
+                self.alice.node.add_remote_candidate(CONNECTION, candidate);
+            }
+
+            if packets.is_empty() {
+                return;
+            }
+
+            for (src, dst, payload) in packets {
+                self.send(src, dst, payload);
+            }
+        }
+    }
+
+    fn poll_relay(&mut self) -> Vec<(SocketAddr, SocketAddr, Vec<u8>)> {
+        let mut packets = Vec::new();
+
+        while let Some(command) = self.relay.server.next_command() {
+            match command {
+                Command::SendMessage { payload, recipient } => {
+                    packets.push((relay_addr(), recipient.into_socket(), payload));
+                }
+                Command::CreateAllocation { id, port, .. } => {
+                    self.relay.ports.insert(port, id);
+                }
+                Command::FreeAllocation { id, .. } => {
+                    self.relay.ports.retain(|_, allocation| *allocation != id);
+                }
+                Command::ForwardData { id, data, receiver } => {
+                    let Some(port) = self
+                        .relay
+                        .ports
+                        .iter()
+                        .find_map(|(port, allocation)| (*allocation == id).then_some(*port))
+                    else {
+                        continue;
+                    };
+
+                    packets.push((
+                        SocketAddr::new(RELAY_IP.into(), port),
+                        receiver.into_socket(),
+                        data,
+                    ));
+                }
+                Command::Wake { deadline } => {
+                    self.relay.wake =
+                        Some(self.relay.wake.map_or(deadline, |wake| wake.min(deadline)));
+                }
+            }
+        }
+
+        packets
+    }
+}
+
+impl<T> Host<Node<T, u64>> {
+    fn new(node: Node<T, u64>, public_ip: Ipv4Addr, private_ip: Ipv4Addr, nat: NatType) -> Self {
+        let local_ip = match nat {
+            NatType::None => public_ip,
+            NatType::FullCone | NatType::PortRestricted | NatType::Symmetric => private_ip,
+        };
+
+        Self {
+            node,
+            local: SocketAddr::new(local_ip.into(), HOST_PORT),
+            nat: Nat {
+                kind: nat,
+                public_ip: public_ip.into(),
+                next_port: 40000,
+                mappings: HashMap::default(),
+                bindings: HashMap::default(),
+            },
+            received: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.events
+            .contains(&Event::ConnectionEstablished(CONNECTION))
+    }
+
+    pub fn connection_info(&self) -> Option<ConnectionInfo> {
+        self.node
+            .stats()
+            .find_map(|(id, info)| (id == CONNECTION).then_some(info))
+    }
+
+    fn receive(
+        &mut self,
+        from: SocketAddr,
+        to: SocketAddr,
+        payload: &[u8],
+        now: Instant,
+        buffer: &mut [u8],
+    ) {
+        let Some(local) = self.nat.inbound(from, to) else {
+            return;
+        };
+
+        match self.node.decapsulate(local, from, payload, now, buffer) {
+            Ok(Some((_, packet))) => self.received.push(packet.packet().to_vec()),
+            Ok(None) => {}
+            Err(e) => tracing::debug!("Failed to decapsulate: {e}"),
+        }
+    }
+
+    fn poll_transmits(&mut self) -> Vec<(SocketAddr, SocketAddr, Vec<u8>)> {
+        let mut packets = Vec::new();
+
+        while let Some(transmit) = self.node.poll_transmit() {
+            packets.extend(self.translate(transmit));
+        }
+
+        packets
+    }
+
+    fn poll_candidates(&mut self) -> Vec<String> {
+        let mut candidates = Vec::new();
+
+        while let Some(event) = self.node.poll_event() {
+            match event {
+                Event::SignalIceCandidate { candidate, .. } => candidates.push(candidate),
+                other => self.events.push(other),
+            }
+        }
+
+        candidates
+    }
+
+    /// Sends a [`Transmit`] through our NAT, returning the public source, the destination and the payload.
+    ///
+    /// Transmits from an address we no longer have are dropped.
+    fn translate(
+        &mut self,
+        transmit: Transmit<'static>,
+    ) -> Option<(SocketAddr, SocketAddr, Vec<u8>)> {
+        let src = transmit.src.unwrap_or(self.local);
+        if src != self.local {
+            return None;
+        }
+
+        let src = self.nat.outbound(src, transmit.dst);
+
+        Some((src, transmit.dst, transmit.payload.into_owned()))
+    }
+}
+
+impl Nat {
+    fn outbound(&mut self, src: SocketAddr, dst: SocketAddr) -> SocketAddr {
+        let remote = match self.kind {
+            NatType::None => return src,
+            NatType::FullCone | NatType::PortRestricted => None,
+            NatType::Symmetric => Some(dst),
+        };
+
+        let port = *self.mappings.entry((src, remote)).or_insert_with(|| {
+            let port = self.next_port;
+            self.next_port += 1;
+
+            port
+        });
+
+        self.bindings
+            .entry(port)
+            .or_insert_with(|| (src, HashSet::new()))
+            .1
+            .insert(dst);
+
+        SocketAddr::new(self.public_ip, port)
+    }
+
+    /// Translates the destination of an incoming packet, if the NAT lets it through.
+    fn inbound(&self, from: SocketAddr, to: SocketAddr) -> Option<SocketAddr> {
+        if self.kind == NatType::None {
+            return Some(to);
+        }
+
+        let (internal, contacted) = self.bindings.get(&to.port())?;
+
+        match self.kind {
+            NatType::None | NatType::FullCone => Some(*internal),
+            NatType::PortRestricted | NatType::Symmetric => {
+                contacted.contains(&from).then_some(*internal)
+            }
+        }
+    }
+}
+
+fn relay_addr() -> SocketAddr {
+    SocketAddr::new(RELAY_IP.into(), RELAY_PORT)
+}
+
+/// Builds a minimal IPv4 packet around the given payload.
+fn ip_packet(src: Ipv4Addr, dst: Ipv4Addr, payload: &[u8]) -> Vec<u8> {
+    let len = 20 + payload.len();
+    let mut buffer = vec![0u8; len];
+
+    let mut packet = MutableIpv4Packet::new(&mut buffer).unwrap();
+    packet.set_version(4);
+    packet.set_header_length(5);
+    packet.set_total_length(len as u16);
+    packet.set_ttl(64);
+    packet.set_source(src);
+    packet.set_destination(dst);
+    packet.set_payload(payload);
+
+    buffer
+}
@@ -0,0 +1,183 @@
+mod sim;
+
+use sim::{NatType, Simulation};
+use snownet::{CandidateFilter, ConnectionConfig};
+use std::time::Duration;
+use str0m::CandidateKind;
+
+#[test]
+fn hosts_without_nat_connect_directly() {
+    let mut sim = Simulation::new(0, NatType::None, NatType::None);
+
+    sim.connect();
+
+    assert!(sim.run_until(Duration::from_secs(10), |sim| sim.is_connected()));
+    assert!(!is_relayed(&sim));
+}
+
+#[test]
+fn full_cone_nats_are_traversed_without_relay() {
+    let mut sim = Simulation::new(1, NatType::FullCone, NatType::FullCone);
+
+    sim.connect();
+
+    assert!(sim.run_until(Duration::from_secs(10), |sim| sim.is_connected()));
+    assert!(!is_relayed(&sim));
+}
+
+#[test]
+fn port_restricted_nats_are_traversed_without_relay() {
+    let mut sim = Simulation::new(2, NatType::PortRestricted, NatType::PortRestricted);
+
+    sim.connect();
+
+    assert!(sim.run_until(Duration::from_secs(10), |sim| sim.is_connected()));
+    assert!(!is_relayed(&sim));
+}
+
+#[test]
+fn symmetric_nats_fall_back_to_relay() {
+    let mut sim = Simulation::new(3, NatType::Symmetric, NatType::Symmetric);
+
+    sim.connect();
+
+    assert!(sim.run_until(Duration::from_secs(10), |sim| sim.is_connected()));
+    assert!(is_relayed(&sim));
+
+    assert!(send_from_alice(&mut sim, b"ping"));
+    assert!(send_from_bob(&mut sim, b"pong"));
+}
+
+#[test]
+fn packets_arrive_over_lossy_link_with_latency() {
+    let mut sim = Simulation::new(4, NatType::FullCone, NatType::Symmetric);
+    sim.set_latency(Duration::from_millis(50));
+
+    sim.connect();
+
+    assert!(sim.run_until(Duration::from_secs(10), |sim| sim.is_connected()));
+
+    // boringtun's timers run on wall-clock time, so a lost handshake would never be retransmitted in the simulation.
+    assert!(send_from_alice(&mut sim, b"handshake"));
+
+    sim.set_packet_loss(0.1);
+
+    assert!(send_from_alice(&mut sim, b"ping"));
+    assert!(send_from_bob(&mut sim, b"pong"));
+}
+
+#[test]
+fn relay_only_filter_forces_relayed_connection() {
+    let mut sim = Simulation::new(5, NatType::None, NatType::None);
+    sim.alice.node.set_candidate_filter(CandidateFilter {
+        relay_only: true,
+        ..Default::default()
+    });
+
+    sim.connect();
+
+    assert!(sim.run_until(Duration::from_secs(10), |sim| sim.is_connected()));
+    assert!(is_relayed(&sim));
+}
+
+#[test]
+fn redundant_path_takes_over_when_direct_path_breaks() {
+    let mut sim = Simulation::new(6, NatType::FullCone, NatType::FullCone);
+
+    sim.connect_with_config(ConnectionConfig {
+        redundant_path: true,
+        ..Default::default()
+    });
+
+    assert!(sim.run_until(Duration::from_secs(10), |sim| sim.is_connected()));
+    assert!(!is_relayed(&sim));
+
+    let info = sim.alice.connection_info().unwrap();
+    assert!(info.redundant_relay.is_some());
+    assert_eq!(info.failovers, 0);
+
+    sim.cut_direct_path();
+
+    assert!(send_from_alice(&mut sim, b"ping"));
+    assert!(send_from_bob(&mut sim, b"pong1"));
+    assert!(send_from_bob(&mut sim, b"pong2"));
+    assert!(send_from_bob(&mut sim, b"pong3"));
+
+    let info = sim.alice.connection_info().unwrap();
+    assert!(info.relay.is_some());
+    assert_eq!(info.failovers, 1);
+}
+
+#[test]
+fn redundant_path_does_not_take_over_from_slower_primary_at_low_packet_rate() {
+    let mut sim = Simulation::new(7, NatType::FullCone, NatType::FullCone);
+    sim.set_latency(Duration::from_millis(10));
+
+    sim.connect_with_config(ConnectionConfig {
+        redundant_path: true,
+        ..Default::default()
+    });
+
+    assert!(sim.run_until(Duration::from_secs(10), |sim| sim.is_connected()));
+    assert!(!is_relayed(&sim));
+
+    // Every packet now reaches the remote over the redundant path first.
+    sim.set_direct_path_delay(Duration::from_millis(100));
+
+    for payload in [b"ping1", b"ping2", b"ping3", b"ping4", b"ping5"] {
+        sim.run_until(Duration::from_secs(20), |_| false);
+
+        assert!(send_from_alice(&mut sim, payload));
+        assert!(send_from_bob(&mut sim, payload));
+    }
+
+    let alice = sim.alice.connection_info().unwrap();
+    let bob = sim.bob.connection_info().unwrap();
+    assert!(alice.relay.is_none());
+    assert_eq!(alice.failovers, 0);
+    assert_eq!(bob.failovers, 0);
+}
+
+#[test]
+fn relayed_traffic_resumes_after_ice_restart_on_new_address() {
+    let mut sim = Simulation::new(8, NatType::Symmetric, NatType::Symmetric);
+
+    sim.connect();
+
+    assert!(sim.run_until(Duration::from_secs(10), |sim| sim.is_connected()));
+    assert!(send_from_alice(&mut sim, b"ping"));
+    assert!(is_relayed(&sim));
+
+    sim.change_alice_address();
+    sim.restart_ice();
+
+    assert!(send_from_alice(&mut sim, b"ping from new address"));
+    assert!(send_from_bob(&mut sim, b"pong to new address"));
+    assert!(is_relayed(&sim));
+}
+
+/// Sends packets until one arrives, giving up after 10 attempts.
+fn send_from_alice(sim: &mut Simulation, payload: &'static [u8]) -> bool {
+    (0..10).any(|_| {
+        sim.send_from_alice(payload);
+        sim.run_until(Duration::from_secs(1), |sim| {
+            sim.bob.received.iter().any(|p| p.ends_with(payload))
+        })
+    })
+}
+
+/// Sends packets until one arrives, giving up after 10 attempts.
+fn send_from_bob(sim: &mut Simulation, payload: &'static [u8]) -> bool {
+    (0..10).any(|_| {
+        sim.send_from_bob(payload);
+        sim.run_until(Duration::from_secs(1), |sim| {
+            sim.alice.received.iter().any(|p| p.ends_with(payload))
+        })
+    })
+}
+
+fn is_relayed(sim: &Simulation) -> bool {
+    let pair = sim.alice.connection_info().unwrap().selected_pair.unwrap();
+
+    pair.local == CandidateKind::Relayed || pair.remote == CandidateKind::Relayed
+}
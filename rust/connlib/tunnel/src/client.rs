@@ -17,11 +17,12 @@ use ip_network_table::IpNetworkTable;
 use itertools::Itertools;
 use snownet::Client;
 
-use hickory_resolver::config::{NameServerConfig, Protocol, ResolverConfig};
+use hickory_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
+use hickory_resolver::proto::op::MessageType;
 use hickory_resolver::TokioAsyncResolver;
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet, VecDeque};
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::Duration;
@@ -31,6 +32,7 @@ use tokio::time::{Instant, Interval, MissedTickBehavior};
 const IPV4_RESOURCES: &str = "100.96.0.0/11";
 const IPV6_RESOURCES: &str = "fd00:2021:1111:8000::/107";
 const MAX_CONNECTION_REQUEST_DELAY: Duration = Duration::from_secs(10);
+const GATEWAY_DNS_QUERY_TIMEOUT: Duration = Duration::from_secs(60);
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct DnsResource {
@@ -55,18 +57,32 @@ where
     ///
     /// Once added, when a packet for the resource is intercepted a new data channel will be created
     /// and packets will be wrapped with wireguard and sent through it.
+    ///
+    /// If a resource with the same ID already exists, it is replaced.
+    /// The next packet for the resource will then ask the gateway to authorize the new resource.
     pub fn add_resource(
         &mut self,
         resource_description: ResourceDescription,
     ) -> connlib_shared::Result<()> {
-        if self
+        if let Some(existing) = self
             .role_state
             .resource_ids
-            .contains_key(&resource_description.id())
+            .get(&resource_description.id())
+            .cloned()
         {
-            // TODO
-            tracing::info!("Resource updates aren't implemented yet");
-            return Ok(());
+            if existing == resource_description {
+                return Ok(());
+            }
+
+            if is_rename(&existing, &resource_description) {
+                tracing::info!(id = %existing.id(), "Renaming resource");
+                self.role_state.rename_resource(resource_description);
+
+                return self.update_resource_list();
+            }
+
+            tracing::info!(id = %existing.id(), "Updating resource");
+            self.teardown_resource(&existing)?;
         }
 
         match &resource_description {
@@ -88,6 +104,49 @@ where
             .resource_ids
             .insert(resource_description.id(), resource_description);
 
+        self.update_resource_list()
+    }
+
+    /// Removes the resource with the given ID from the tunnel.
+    ///
+    /// This removes its routes and, if no other resource uses the same gateway, the connection to the gateway.
+    pub fn remove_resource(&mut self, id: ResourceId) -> connlib_shared::Result<()> {
+        let Some(resource) = self.role_state.resource_ids.remove(&id) else {
+            tracing::debug!(%id, "Cannot remove unknown resource");
+            return Ok(());
+        };
+
+        self.teardown_resource(&resource)?;
+
+        self.update_resource_list()
+    }
+
+    fn teardown_resource(&mut self, resource: &ResourceDescription) -> connlib_shared::Result<()> {
+        if let Some(gateway) = self.role_state.on_resource_removed(resource) {
+            tracing::debug!(%gateway, "Gateway no longer used by any resource, closing connection");
+
+            self.connections_state.peers_by_id.remove(&gateway);
+            self.connections_state.node.close_connection(gateway);
+            self.role_state
+                .peers_by_ip
+                .retain(|_, p| p.conn_id != gateway);
+        }
+
+        let address_unused = self.role_state.remove_resource_address(resource);
+
+        if let ResourceDescription::Cidr(cidr) = resource {
+            if !address_unused {
+                tracing::debug!(address = %cidr.address, "Address still used by another resource, keeping route");
+                return Ok(());
+            }
+
+            self.remove_route(cidr.address)?;
+        }
+
+        Ok(())
+    }
+
+    fn update_resource_list(&self) -> connlib_shared::Result<()> {
         self.callbacks.on_update_resources(
             self.role_state
                 .resource_ids
@@ -106,19 +165,82 @@ where
         config: &InterfaceConfig,
         dns_mapping: BiMap<IpAddr, DnsServer>,
     ) -> connlib_shared::Result<()> {
-        let device = Device::new(
+        let device = self.new_device(config, &dns_mapping)?;
+
+        self.device = Some(device);
+        self.no_device_waker.wake();
+
+        self.role_state.set_dns_mapping(dns_mapping);
+
+        self.callbacks.on_tunnel_ready()?;
+
+        tracing::debug!("background_loop_started");
+
+        Ok(())
+    }
+
+    /// Applies a changed interface configuration while the tunnel is running.
+    ///
+    /// A new device with the new addresses, DNS sentinels and all routes is created first and only replaces the old one if that succeeds.
+    /// Connections to gateways are kept.
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub fn update_interface(
+        &mut self,
+        config: &InterfaceConfig,
+        dns_mapping: BiMap<IpAddr, DnsServer>,
+    ) -> connlib_shared::Result<()> {
+        let stale_sentinels = self
+            .role_state
+            .dns_mapping
+            .left_values()
+            .filter(|sentinel| !dns_mapping.contains_left(sentinel))
+            .copied()
+            .collect_vec();
+
+        let device = self.new_device(config, &dns_mapping)?;
+
+        self.device = Some(device);
+        self.no_device_waker.wake();
+
+        // On some platforms, the new device is the same interface as the old one and still has the routes of sentinels we no longer use.
+        for sentinel in stale_sentinels {
+            if let Err(e) = self.remove_route(sentinel.into()) {
+                tracing::warn!(err = ?e, %sentinel, "couldn't remove route for stale sentinel");
+            }
+        }
+
+        self.replace_dns_mapping(dns_mapping);
+
+        tracing::debug!("interface_updated");
+
+        Ok(())
+    }
+
+    /// Switches to the given DNS sentinels and upstream resolvers without touching any connections.
+    fn replace_dns_mapping(&mut self, dns_mapping: BiMap<IpAddr, DnsServer>) {
+        for (_, peer) in self.role_state.peers_by_ip.iter() {
+            peer.transform.set_dns(dns_mapping.clone());
+        }
+
+        self.role_state.set_dns_mapping(dns_mapping);
+    }
+
+    /// Creates a device and adds the routes for the DNS sentinels, the resource ranges and all CIDR resources to it.
+    fn new_device(
+        &self,
+        config: &InterfaceConfig,
+        dns_mapping: &BiMap<IpAddr, DnsServer>,
+    ) -> connlib_shared::Result<Device> {
+        let mut device = Device::new(
             config,
             // We can just sort in here because sentinel ips are created in order
             dns_mapping.left_values().copied().sorted().collect(),
             self.callbacks(),
         )?;
 
-        self.device = Some(device);
-        self.no_device_waker.wake();
-
         let mut errs = Vec::new();
         for sentinel in dns_mapping.left_values() {
-            if let Err(e) = self.add_route((*sentinel).into()) {
+            if let Err(e) = add_route(&mut device, (*sentinel).into(), self.callbacks()) {
                 tracing::warn!(err = ?e, %sentinel , "couldn't add route for sentinel");
                 errs.push(e);
             }
@@ -128,17 +250,32 @@ where
             return Err(errs.pop().unwrap());
         }
 
-        self.role_state.set_dns_mapping(dns_mapping);
-
-        let res_v4 = self.add_route(IPV4_RESOURCES.parse().unwrap());
-        let res_v6 = self.add_route(IPV6_RESOURCES.parse().unwrap());
+        let res_v4 = add_route(
+            &mut device,
+            IPV4_RESOURCES.parse().unwrap(),
+            self.callbacks(),
+        );
+        let res_v6 = add_route(
+            &mut device,
+            IPV6_RESOURCES.parse().unwrap(),
+            self.callbacks(),
+        );
         res_v4.or(res_v6)?;
 
-        self.callbacks.on_tunnel_ready()?;
+        for (route, _) in self.role_state.cidr_resources.iter() {
+            if let Err(e) = add_route(&mut device, route, self.callbacks()) {
+                tracing::warn!(err = ?e, %route, "couldn't add route");
+            }
+        }
 
-        tracing::debug!("background_loop_started");
+        Ok(device)
+    }
 
-        Ok(())
+    /// Sends all DNS queries that are waiting to be resolved by a gateway.
+    pub(crate) fn send_gateway_dns_queries(&mut self) {
+        while let Some((gateway, query)) = self.role_state.next_gateway_dns_query() {
+            self.connections_state.send(gateway, query.into());
+        }
     }
 
     /// Clean up a connection to a resource.
@@ -150,11 +287,24 @@ where
 
     #[tracing::instrument(level = "trace", skip(self))]
     pub fn add_route(&mut self, route: IpNetwork) -> connlib_shared::Result<()> {
-        let maybe_new_device = self
-            .device
-            .as_ref()
-            .ok_or(Error::ControlProtocolError)?
-            .add_route(route, self.callbacks())?;
+        let Some(device) = self.device.as_ref() else {
+            return Ok(()); // `new_device` adds the routes of all CIDR resources.
+        };
+        let maybe_new_device = device.add_route(route, self.callbacks())?;
+
+        if let Some(new_device) = maybe_new_device {
+            self.device = Some(new_device);
+        }
+
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub fn remove_route(&mut self, route: IpNetwork) -> connlib_shared::Result<()> {
+        let Some(device) = self.device.as_ref() else {
+            return Ok(());
+        };
+        let maybe_new_device = device.remove_route(route, self.callbacks())?;
 
         if let Some(new_device) = maybe_new_device {
             self.device = Some(new_device);
@@ -187,6 +337,10 @@ pub struct ClientState {
     cidr_resources: IpNetworkTable<ResourceDescriptionCidr>,
     pub resource_ids: HashMap<ResourceId, ResourceDescription>,
     pub deferred_dns_queries: HashMap<(DnsResource, Rtype), IpPacket<'static>>,
+    /// DNS queries for DNS resources that need to be sent to the resource's gateway.
+    gateway_dns_queries: VecDeque<(GatewayId, IpPacket<'static>)>,
+    /// DNS queries a gateway is resolving for us, by the socket and ID of the query.
+    pending_gateway_dns_queries: HashMap<(SocketAddr, u16), PendingGatewayDnsQuery>,
 
     pub peers_by_ip: IpNetworkTable<Arc<Peer<GatewayId, PacketTransformClient>>>,
 
@@ -201,10 +355,19 @@ pub struct ClientState {
 
     dns_mapping: BiMap<IpAddr, DnsServer>,
     dns_resolvers: HashMap<IpAddr, TokioAsyncResolver>,
+    pub(crate) tcp_dns: dns::TcpResponder,
 
     buffered_packets: VecDeque<Packet<'static>>,
 }
 
+struct PendingGatewayDnsQuery {
+    peer: Arc<Peer<GatewayId, PacketTransformClient>>,
+    /// The DNS resource the query is for.
+    resource: ResourceId,
+    query: IpPacket<'static>,
+    sent_at: Instant,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct AwaitingConnectionDetails {
     total_attemps: usize,
@@ -244,6 +407,16 @@ impl ClientState {
         &mut self,
         packet: MutableIpPacket<'a>,
     ) -> Result<Option<Packet<'a>>, (MutableIpPacket<'a>, IpAddr)> {
+        if dns::is_tcp_query(&self.dns_mapping, &packet.as_immutable()) {
+            if let Some(upstream) = self.upstream_dns_behind_resource(packet.destination()) {
+                return Err((packet, upstream));
+            }
+
+            self.handle_dns_over_tcp(packet.as_immutable());
+
+            return Ok(None);
+        }
+
         match dns::parse(
             &self.dns_resources,
             &self.dns_resources_internal_ips,
@@ -252,21 +425,14 @@ impl ClientState {
         ) {
             Some(dns::ResolveStrategy::LocalResponse(query)) => Ok(Some(query)),
             Some(dns::ResolveStrategy::ForwardQuery(query)) => {
-                // There's an edge case here, where the resolver's ip has been resolved before as
-                // a dns resource... we will ignore that weird case for now.
-                // Assuming a single upstream dns until #3123 lands
-                if let Some(upstream_dns) = self.dns_mapping.get_by_left(&query.query.destination())
+                if let Some(upstream) = self.upstream_dns_behind_resource(query.query.destination())
                 {
-                    if self
-                        .cidr_resources
-                        .longest_match(upstream_dns.ip())
-                        .is_some()
-                    {
-                        return Err((packet, upstream_dns.ip()));
-                    }
+                    return Err((packet, upstream));
                 }
 
-                self.add_pending_dns_query(query);
+                if !self.add_pending_dns_query(query) {
+                    self.fail_tcp_dns_query(packet.as_immutable());
+                }
 
                 Ok(None)
             }
@@ -277,6 +443,11 @@ impl ClientState {
 
                 Ok(None)
             }
+            Some(dns::ResolveStrategy::ForwardToGateway(resource)) => {
+                self.forward_to_gateway(resource, packet.as_immutable().to_owned());
+
+                Ok(None)
+            }
             None => {
                 let dest = packet.destination();
                 Err((packet, dest))
@@ -284,6 +455,208 @@ impl ClientState {
         }
     }
 
+    /// Returns the upstream DNS server of the given sentinel if it is only reachable through a CIDR resource.
+    ///
+    /// Queries to such a server are sent through the tunnel instead of being resolved locally.
+    fn upstream_dns_behind_resource(&self, sentinel: IpAddr) -> Option<IpAddr> {
+        // There's an edge case here, where the resolver's ip has been resolved before as
+        // a dns resource... we will ignore that weird case for now.
+        // Assuming a single upstream dns until #3123 lands
+        let upstream = self.dns_mapping.get_by_left(&sentinel)?.ip();
+
+        self.cidr_resources
+            .longest_match(upstream)
+            .is_some()
+            .then_some(upstream)
+    }
+
+    fn handle_dns_over_tcp(&mut self, segment: IpPacket<'_>) {
+        let received = self
+            .tcp_dns
+            .handle_segment(&segment, std::time::Instant::now());
+        self.buffered_packets.extend(received.segments);
+
+        for mut query in received.queries {
+            let Some(query) = MutableIpPacket::new(&mut query) else {
+                continue;
+            };
+
+            match self.handle_dns(query) {
+                Ok(Some(response)) => self.send_dns_response(response.into_owned()),
+                Ok(None) => {}
+                Err((query, _)) => {
+                    tracing::debug!("Dropping unsupported DNS query over TCP");
+                    self.fail_tcp_dns_query(query.as_immutable());
+                }
+            }
+        }
+    }
+
+    /// Answers a query we won't resolve with SERVFAIL if it arrived over TCP, so its stream doesn't wait for the response forever.
+    fn fail_tcp_dns_query(&mut self, query: IpPacket<'_>) {
+        if !self.tcp_dns.is_pending(&query) {
+            return;
+        }
+
+        let Some(response) = dns::build_servfail_response(query) else {
+            return;
+        };
+
+        self.send_dns_response(response);
+    }
+
+    /// Sends a DNS query for a DNS resource to the resource's gateway, connecting to it first if necessary.
+    fn forward_to_gateway(&mut self, resource: (DnsResource, Rtype), query: IpPacket<'static>) {
+        let peer = self
+            .resources_gateways
+            .get(&resource.0.id)
+            .and_then(|gateway| self.peer_by_gateway(*gateway));
+
+        let Some(peer) = peer else {
+            self.on_connection_intent_dns(&resource.0);
+            self.deferred_dns_queries.insert(resource, query);
+
+            return;
+        };
+
+        self.send_to_gateway(peer, resource.0.id, query);
+    }
+
+    fn send_to_gateway(
+        &mut self,
+        peer: Arc<Peer<GatewayId, PacketTransformClient>>,
+        resource: ResourceId,
+        query: IpPacket<'static>,
+    ) {
+        let Some(key) = dns_query_key(&query) else {
+            return;
+        };
+
+        self.pending_gateway_dns_queries
+            .retain(|_, q| q.sent_at.elapsed() < GATEWAY_DNS_QUERY_TIMEOUT);
+        self.pending_gateway_dns_queries.insert(
+            key,
+            PendingGatewayDnsQuery {
+                peer: peer.clone(),
+                resource,
+                query: query.to_owned(),
+                sent_at: Instant::now(),
+            },
+        );
+
+        // Not transformed on purpose: the gateway resolves queries for DNS resources itself.
+        self.gateway_dns_queries.push_back((peer.conn_id, query));
+    }
+
+    pub(crate) fn next_gateway_dns_query(&mut self) -> Option<(GatewayId, IpPacket<'static>)> {
+        self.gateway_dns_queries.pop_front()
+    }
+
+    /// Forwards the deferred queries of the given resource that need to be resolved by its gateway.
+    pub(crate) fn forward_deferred_dns_queries(
+        &mut self,
+        resource: ResourceId,
+        peer: &Arc<Peer<GatewayId, PacketTransformClient>>,
+    ) {
+        let queries = self
+            .deferred_dns_queries
+            .keys()
+            .filter(|(r, qtype)| r.id == resource && dns::is_resolved_by_gateway(*qtype))
+            .cloned()
+            .collect_vec();
+
+        for key in queries {
+            if let Some(query) = self.deferred_dns_queries.remove(&key) {
+                self.send_to_gateway(peer.clone(), resource, query);
+            }
+        }
+    }
+
+    /// Attempt to handle the given packet as a gateway's response to a DNS query we sent it.
+    ///
+    /// Gateways answer from the sentinel DNS server the query was sent to, which is never an allowed IP of the peer.
+    /// Every packet from a sentinel is thus consumed here: unless it answers a query pending at this exact gateway, it is dropped.
+    /// Addresses in an accepted response are replaced with proxy IPs before it is sent to the application.
+    ///
+    /// Returns `false` if the packet doesn't come from a sentinel.
+    pub(crate) fn handle_gateway_dns_response(
+        &mut self,
+        gateway: GatewayId,
+        response: &IpPacket,
+    ) -> bool {
+        if !self.dns_mapping.contains_left(&response.source()) {
+            return false;
+        }
+
+        let Some(pending) = dns_query_key(response).and_then(|key| {
+            let pending = self.pending_gateway_dns_queries.get(&key)?;
+            if !is_gateway_dns_response(pending, gateway, response) {
+                return None;
+            }
+
+            self.pending_gateway_dns_queries.remove(&key)
+        }) else {
+            tracing::debug!(%gateway, source = %response.source(), "Dropping unsolicited DNS response");
+            return true;
+        };
+
+        let Some(message) = dns::as_dns_message(response) else {
+            return true;
+        };
+        let peer = pending.peer;
+        let resource = pending.resource;
+
+        let over_tcp = self.tcp_dns.is_pending(&pending.query);
+
+        let dns_resources = &self.dns_resources;
+        let dns_resources_internal_ips = &mut self.dns_resources_internal_ips;
+        let peers_by_ip = &mut self.peers_by_ip;
+        let ip_provider = &mut self.ip_provider;
+
+        let response =
+            dns::build_response_from_gateway(pending.query, message, over_tcp, |name, ip| {
+                let address = Dname::vec_from_str(&name.to_string()).ok()?;
+                let description = dns::get_description(&address, dns_resources)?;
+                // The gateway only gets to tell us about names of the resource we asked it for.
+                if description.id != resource {
+                    return None;
+                }
+                let proxy_ip = peer.transform.get_or_assign_translation(&ip, ip_provider)?;
+
+                peer.add_allowed_ip(proxy_ip.into());
+                peers_by_ip.insert(proxy_ip, peer.clone());
+                dns_resources_internal_ips
+                    .entry(DnsResource::from_description(&description, address))
+                    .or_default()
+                    .insert(proxy_ip);
+
+                Some(proxy_ip)
+            });
+
+        match response {
+            Ok(Some(response)) => self.send_dns_response(response),
+            Ok(None) => {}
+            Err(e) => tracing::warn!("Failed to build DNS response from gateway: {e}"),
+        }
+
+        true
+    }
+
+    fn peer_by_gateway(
+        &self,
+        gateway: GatewayId,
+    ) -> Option<Arc<Peer<GatewayId, PacketTransformClient>>> {
+        self.peers_by_ip
+            .iter()
+            .find_map(|(_, p)| (p.conn_id == gateway).then_some(p.clone()))
+    }
+
+    /// Queues a DNS response to be written to the device, sending it over TCP if that is how its query arrived.
+    pub(crate) fn send_dns_response(&mut self, response: Packet<'static>) {
+        let packets = self.tcp_dns.handle_response(response);
+        self.buffered_packets.extend(packets);
+    }
+
     pub(crate) fn get_awaiting_connection_domain(
         &self,
         resource: &ResourceId,
@@ -370,6 +743,126 @@ impl ClientState {
         }))
     }
 
+    /// Forgets all connection state of a removed resource.
+    ///
+    /// Returns the gateway of the resource if no other resource uses it.
+    fn on_resource_removed(&mut self, resource: &ResourceDescription) -> Option<GatewayId> {
+        let id = resource.id();
+
+        self.awaiting_connection.remove(&id);
+        self.awaiting_connection_timers.remove(id);
+        self.deferred_dns_queries.retain(|(r, _), _| r.id != id);
+
+        match resource {
+            ResourceDescription::Dns(_) => {
+                let mut released = Vec::new();
+                self.dns_resources_internal_ips.retain(|r, ips| {
+                    if r.id != id {
+                        return true;
+                    }
+
+                    released.extend(ips.drain());
+                    false
+                });
+
+                self.release_proxy_ips(released);
+            }
+            ResourceDescription::Cidr(cidr) => {
+                // A resource sharing the address may be behind another gateway, its next packet sets up the route again.
+                if let Some(peer) = self.peers_by_ip.remove(cidr.address) {
+                    peer.remove_allowed_ip(cidr.address);
+                }
+            }
+        }
+
+        let gateway = self.resources_gateways.remove(&id)?;
+
+        if self.resources_gateways.values().any(|g| *g == gateway) {
+            return None;
+        }
+
+        self.gateway_awaiting_connection.remove(&gateway);
+        self.gateway_awaiting_connection_timers.remove(gateway);
+
+        Some(gateway)
+    }
+
+    /// Replaces a resource whose address didn't change, see [`is_rename`].
+    fn rename_resource(&mut self, resource: ResourceDescription) {
+        let id = resource.id();
+
+        match &resource {
+            ResourceDescription::Dns(dns) => {
+                if let Some(existing) = self.dns_resources.get_mut(&dns.address) {
+                    if existing.id == id {
+                        *existing = dns.clone();
+                    }
+                }
+            }
+            ResourceDescription::Cidr(cidr) => {
+                if self
+                    .cidr_resources
+                    .exact_match(cidr.address)
+                    .is_some_and(|existing| existing.id == id)
+                {
+                    self.cidr_resources.insert(cidr.address, cidr.clone());
+                }
+            }
+        }
+
+        self.resource_ids.insert(id, resource);
+    }
+
+    /// Another resource with the same address as `resource`, if any.
+    fn resource_sharing_address(
+        &self,
+        resource: &ResourceDescription,
+    ) -> Option<ResourceDescription> {
+        self.resource_ids
+            .values()
+            .find(|other| {
+                other.id() != resource.id()
+                    && match (resource, other) {
+                        (ResourceDescription::Dns(a), ResourceDescription::Dns(b)) => {
+                            a.address == b.address
+                        }
+                        (ResourceDescription::Cidr(a), ResourceDescription::Cidr(b)) => {
+                            a.address == b.address
+                        }
+                        _ => false,
+                    }
+            })
+            .cloned()
+    }
+
+    /// Removes the address of a resource, handing it over to another resource with the same address if there is one.
+    ///
+    /// Returns whether no resource uses the address anymore.
+    fn remove_resource_address(&mut self, resource: &ResourceDescription) -> bool {
+        match (resource, self.resource_sharing_address(resource)) {
+            (ResourceDescription::Dns(_), Some(ResourceDescription::Dns(other))) => {
+                self.dns_resources.insert(other.address.clone(), other);
+
+                false
+            }
+            (ResourceDescription::Cidr(_), Some(ResourceDescription::Cidr(other))) => {
+                self.cidr_resources.insert(other.address, other);
+
+                false
+            }
+            (ResourceDescription::Dns(dns), _) => {
+                self.dns_resources.remove(&dns.address);
+
+                true
+            }
+            (ResourceDescription::Cidr(cidr), _) => {
+                self.cidr_resources.remove(cidr.address);
+
+                true
+            }
+        }
+    }
+
     pub fn on_connection_failed(&mut self, resource: ResourceId) {
         self.awaiting_connection.remove(&resource);
         self.awaiting_connection_timers.remove(resource);
@@ -571,12 +1064,46 @@ impl ClientState {
 
     pub fn cleanup_connected_gateway(&mut self, gateway_id: &GatewayId) {
         self.peers_by_ip.retain(|_, p| p.conn_id != *gateway_id);
-        self.dns_resources_internal_ips.retain(|resource, _| {
-            !self
+        self.pending_gateway_dns_queries
+            .retain(|_, q| q.peer.conn_id != *gateway_id);
+
+        let mut released = Vec::new();
+        self.dns_resources_internal_ips.retain(|resource, ips| {
+            let is_connected_to_gateway = self
                 .resources_gateways
                 .get(&resource.id)
-                .is_some_and(|r_gateway_id| r_gateway_id == gateway_id)
+                .is_some_and(|r_gateway_id| r_gateway_id == gateway_id);
+
+            if is_connected_to_gateway {
+                released.extend(ips.drain());
+            }
+
+            !is_connected_to_gateway
         });
+
+        self.release_proxy_ips(released);
+    }
+
+    /// Gives the given proxy IPs back to the [`IpProvider`] unless another DNS resource still uses them.
+    ///
+    /// The translations of released IPs are removed from their peer so they can safely be handed out again.
+    pub(crate) fn release_proxy_ips(&mut self, ips: impl IntoIterator<Item = IpAddr>) {
+        for ip in ips {
+            if self
+                .dns_resources_internal_ips
+                .values()
+                .any(|ips| ips.contains(&ip))
+            {
+                continue;
+            }
+
+            if let Some(peer) = self.peers_by_ip.remove(IpNetwork::from(ip)) {
+                peer.remove_allowed_ip(ip.into());
+                peer.transform.remove_translation(&ip);
+            }
+
+            self.ip_provider.release(ip);
+        }
     }
 
     fn get_cidr_resource_by_destination(&self, destination: IpAddr) -> Option<ResourceDescription> {
@@ -585,11 +1112,12 @@ impl ClientState {
             .map(|(_, res)| ResourceDescription::Cidr(res.clone()))
     }
 
-    fn add_pending_dns_query(&mut self, query: DnsQuery) {
+    /// Starts resolving the query with its upstream DNS server, returns whether the query was accepted.
+    fn add_pending_dns_query(&mut self, query: DnsQuery) -> bool {
         let upstream = query.query.destination();
         let Some(resolver) = self.dns_resolvers.get(&upstream).cloned() else {
             tracing::warn!(%upstream, "Dropping DNS query because of unknown upstream DNS server");
-            return;
+            return false;
         };
 
         let query = query.into_owned();
@@ -607,8 +1135,11 @@ impl ClientState {
             )
             .is_err()
         {
-            tracing::warn!("Too many DNS queries, dropping existing one");
+            tracing::warn!("Too many DNS queries, dropping new one");
+            return false;
         }
+
+        true
     }
 
     pub fn poll_next_event(&mut self, cx: &mut Context<'_>) -> Poll<Event<GatewayId>> {
@@ -695,8 +1226,13 @@ impl ClientState {
 
             match self.forwarded_dns_queries.poll_unpin(cx) {
                 Poll::Ready((Ok(response), query)) => {
-                    match dns::build_response_from_resolve_result(query.query, response) {
-                        Ok(Some(packet)) => return Poll::Ready(Event::SendPacket(packet)),
+                    let over_tcp = self.tcp_dns.is_pending(&query.query);
+
+                    match dns::build_response_from_resolve_result(query.query, response, over_tcp) {
+                        Ok(Some(packet)) => {
+                            self.send_dns_response(packet);
+                            continue;
+                        }
                         Ok(None) => continue,
                         Err(e) => {
                             tracing::warn!("Failed to build DNS response from lookup result: {e}");
@@ -716,6 +1252,54 @@ impl ClientState {
     }
 }
 
+/// Adds a route to `device`, replacing it if the platform had to re-create the device for that.
+fn add_route(
+    device: &mut Device,
+    route: IpNetwork,
+    callbacks: &impl Callbacks<Error = Error>,
+) -> connlib_shared::Result<()> {
+    if let Some(new_device) = device.add_route(route, callbacks)? {
+        *device = new_device;
+    }
+
+    Ok(())
+}
+
+/// Whether only the display name of a resource changed, in which case its routes and connection stay valid.
+fn is_rename(existing: &ResourceDescription, new: &ResourceDescription) -> bool {
+    match (existing, new) {
+        (ResourceDescription::Dns(existing), ResourceDescription::Dns(new)) => {
+            existing.address == new.address
+        }
+        (ResourceDescription::Cidr(existing), ResourceDescription::Cidr(new)) => {
+            existing.address == new.address
+        }
+        _ => false,
+    }
+}
+
+/// Whether `response` is the answer of `gateway` to the given pending query.
+fn is_gateway_dns_response(
+    pending: &PendingGatewayDnsQuery,
+    gateway: GatewayId,
+    response: &IpPacket,
+) -> bool {
+    pending.peer.conn_id == gateway && dns::is_response_to(&pending.query, response)
+}
+
+/// Identifies a DNS query and its response by the application's socket and the ID of the message.
+fn dns_query_key(packet: &IpPacket) -> Option<(SocketAddr, u16)> {
+    let datagram = packet.as_udp()?;
+    let message = dns::as_dns_message(packet)?;
+
+    let application = match message.message_type() {
+        MessageType::Query => (packet.source(), datagram.get_source()),
+        MessageType::Response => (packet.destination(), datagram.get_destination()),
+    };
+
+    Some((application.into(), message.id()))
+}
+
 fn create_resolvers(
     sentinel_mapping: BiMap<IpAddr, DnsServer>,
 ) -> HashMap<IpAddr, TokioAsyncResolver> {
@@ -724,9 +1308,15 @@ fn create_resolvers(
         .map(|(sentinel, srv)| {
             let mut resolver_config = ResolverConfig::new();
             resolver_config.add_name_server(NameServerConfig::new(srv.address(), Protocol::Udp));
+            // Used to retry queries whose response over UDP is truncated.
+            resolver_config.add_name_server(NameServerConfig::new(srv.address(), Protocol::Tcp));
+
+            let mut resolver_opts = ResolverOpts::default();
+            resolver_opts.edns0 = true;
+
             (
                 sentinel,
-                TokioAsyncResolver::tokio(resolver_config, Default::default()),
+                TokioAsyncResolver::tokio(resolver_config, resolver_opts),
             )
         })
         .collect()
@@ -762,10 +1352,367 @@ impl Default for ClientState {
             resource_ids: Default::default(),
             peers_by_ip: IpNetworkTable::new(),
             deferred_dns_queries: Default::default(),
+            gateway_dns_queries: Default::default(),
+            pending_gateway_dns_queries: Default::default(),
             refresh_dns_timer: interval,
             dns_mapping: Default::default(),
             dns_resolvers: Default::default(),
+            tcp_dns: Default::default(),
             buffered_packets: Default::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ClientTunnel;
+    use boringtun::x25519::StaticSecret;
+    use connlib_shared::messages::IpDnsServer;
+    use hickory_resolver::proto::op::{Message, Query};
+    use hickory_resolver::proto::rr::{Name, RecordType};
+    use std::str::FromStr;
+
+    const APPLICATION: &str = "100.64.0.1:50000";
+    const SENTINEL: &str = "100.100.111.1:53";
+
+    fn gateway(id: &str) -> GatewayId {
+        GatewayId::from_str(id).unwrap()
+    }
+
+    fn srv_message(id: u16, message_type: MessageType) -> Message {
+        let mut message = Message::new();
+        message.set_id(id);
+        message.set_message_type(message_type);
+        message.add_query(Query::query(
+            Name::from_ascii("_sip._udp.foo.com.").unwrap(),
+            RecordType::SRV,
+        ));
+
+        message
+    }
+
+    fn datagram(src: &str, dst: &str, message: &Message) -> IpPacket<'static> {
+        dns::build_datagram(src.parse().unwrap(), dst.parse().unwrap(), message)
+    }
+
+    fn pending_query(gateway: GatewayId) -> PendingGatewayDnsQuery {
+        PendingGatewayDnsQuery {
+            peer: Arc::new(Peer::new(vec![], gateway, PacketTransformClient::default())),
+            resource: ResourceId::from_str("73037362-715d-4a83-a749-f18eadd970e6").unwrap(),
+            query: datagram(APPLICATION, SENTINEL, &srv_message(1, MessageType::Query)),
+            sent_at: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn accepts_response_to_pending_query_from_same_gateway() {
+        let gateway = gateway("c4bb3d79-afa7-4660-8918-06c38fda3a4a");
+        let response = datagram(
+            SENTINEL,
+            APPLICATION,
+            &srv_message(1, MessageType::Response),
+        );
+
+        assert!(is_gateway_dns_response(
+            &pending_query(gateway),
+            gateway,
+            &response
+        ));
+    }
+
+    #[test]
+    fn rejects_response_from_other_gateway() {
+        let pending = pending_query(gateway("c4bb3d79-afa7-4660-8918-06c38fda3a4a"));
+        let response = datagram(
+            SENTINEL,
+            APPLICATION,
+            &srv_message(1, MessageType::Response),
+        );
+
+        assert!(!is_gateway_dns_response(
+            &pending,
+            gateway("c4bb3d79-afa7-4660-8918-06c38fda3a4b"),
+            &response
+        ));
+    }
+
+    #[test]
+    fn rejects_response_with_other_id() {
+        let gateway = gateway("c4bb3d79-afa7-4660-8918-06c38fda3a4a");
+        let response = datagram(
+            SENTINEL,
+            APPLICATION,
+            &srv_message(2, MessageType::Response),
+        );
+
+        assert!(!is_gateway_dns_response(
+            &pending_query(gateway),
+            gateway,
+            &response
+        ));
+    }
+
+    #[test]
+    fn address_shared_by_two_cidr_resources_is_kept_until_both_are_removed() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _guard = runtime.enter();
+
+        let mut state = ClientState::default();
+        let first = cidr_resource("73037362-715d-4a83-a749-f18eadd970e6", "10.0.0.0/24");
+        let second = cidr_resource("03000143-e25e-45c7-aafb-144990e57dcd", "10.0.0.0/24");
+        add_resource(&mut state, &first);
+        add_resource(&mut state, &second);
+
+        state.resource_ids.remove(&first.id());
+        assert!(!state.remove_resource_address(&first));
+        assert_eq!(
+            state
+                .cidr_resources
+                .longest_match("10.0.0.1".parse::<IpAddr>().unwrap())
+                .map(|(_, r)| r.id),
+            Some(second.id())
+        );
+
+        state.resource_ids.remove(&second.id());
+        assert!(state.remove_resource_address(&second));
+        assert!(state
+            .cidr_resources
+            .longest_match("10.0.0.1".parse::<IpAddr>().unwrap())
+            .is_none());
+    }
+
+    #[test]
+    fn changing_address_of_cidr_resource_moves_its_route() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _guard = runtime.enter();
+
+        let (mut tunnel, _) = tunnel();
+        let gateway = gateway("c4bb3d79-afa7-4660-8918-06c38fda3a4a");
+        let resource = cidr_resource("73037362-715d-4a83-a749-f18eadd970e6", "10.0.0.0/24");
+        tunnel.add_resource(resource.clone()).unwrap();
+        let peer = connect(&mut tunnel, &resource, gateway);
+
+        tunnel
+            .add_resource(cidr_resource(
+                "73037362-715d-4a83-a749-f18eadd970e6",
+                "10.1.0.0/24",
+            ))
+            .unwrap();
+
+        // The device is created with a route for each CIDR resource.
+        let old: IpNetwork = "10.0.0.0/24".parse().unwrap();
+        let new: IpNetwork = "10.1.0.0/24".parse().unwrap();
+        assert!(tunnel.role_state.cidr_resources.exact_match(old).is_none());
+        assert!(tunnel.role_state.cidr_resources.exact_match(new).is_some());
+        assert!(tunnel.role_state.peers_by_ip.exact_match(old).is_none());
+        assert!(!peer.is_allowed("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn renaming_only_resource_of_gateway_keeps_connection() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _guard = runtime.enter();
+
+        let (mut tunnel, callbacks) = tunnel();
+        let gateway = gateway("c4bb3d79-afa7-4660-8918-06c38fda3a4a");
+        let resource = cidr_resource("73037362-715d-4a83-a749-f18eadd970e6", "10.0.0.0/24");
+        tunnel.add_resource(resource.clone()).unwrap();
+        connect(&mut tunnel, &resource, gateway);
+
+        let ResourceDescription::Cidr(mut renamed) = resource else {
+            unreachable!()
+        };
+        renamed.name = "Renamed".to_owned();
+        tunnel
+            .add_resource(ResourceDescription::Cidr(renamed.clone()))
+            .unwrap();
+
+        assert!(tunnel.connections_state.peers_by_id.contains_key(&gateway));
+        assert!(tunnel
+            .role_state
+            .peers_by_ip
+            .exact_match("10.0.0.0/24".parse::<IpNetwork>().unwrap())
+            .is_some());
+        assert_eq!(
+            callbacks.resource_updates.lock().last(),
+            Some(&vec![ResourceDescription::Cidr(renamed)])
+        );
+    }
+
+    #[test]
+    fn removing_dns_resource_releases_peer_and_proxy_ips() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _guard = runtime.enter();
+
+        let (mut tunnel, callbacks) = tunnel();
+        let gateway = gateway("c4bb3d79-afa7-4660-8918-06c38fda3a4a");
+        let resource = ResourceDescription::Dns(ResourceDescriptionDns {
+            id: ResourceId::from_str("73037362-715d-4a83-a749-f18eadd970e6").unwrap(),
+            address: "example.com".to_owned(),
+            name: "example.com".to_owned(),
+        });
+        tunnel.add_resource(resource.clone()).unwrap();
+        let peer = connect(&mut tunnel, &resource, gateway);
+
+        let proxy_ip = peer
+            .transform
+            .get_or_assign_translation(
+                &"93.184.216.34".parse().unwrap(),
+                &mut tunnel.role_state.ip_provider,
+            )
+            .unwrap();
+        peer.add_allowed_ip(proxy_ip.into());
+        tunnel.role_state.peers_by_ip.insert(proxy_ip, peer.clone());
+        tunnel.role_state.dns_resources_internal_ips.insert(
+            DnsResource {
+                id: resource.id(),
+                address: Dname::vec_from_str("example.com").unwrap(),
+            },
+            HashSet::from([proxy_ip]),
+        );
+
+        tunnel.remove_resource(resource.id()).unwrap();
+
+        assert!(tunnel.connections_state.peers_by_id.is_empty());
+        assert!(tunnel
+            .role_state
+            .peers_by_ip
+            .exact_match(proxy_ip)
+            .is_none());
+        assert!(tunnel.role_state.dns_resources_internal_ips.is_empty());
+        assert!(!peer.is_allowed(proxy_ip));
+        assert_eq!(callbacks.resource_updates.lock().last(), Some(&vec![]));
+    }
+
+    #[derive(Clone, Default)]
+    struct TestCallbacks {
+        resource_updates: Arc<parking_lot::Mutex<Vec<Vec<ResourceDescription>>>>,
+    }
+
+    impl Callbacks for TestCallbacks {
+        type Error = std::convert::Infallible;
+
+        fn on_update_resources(
+            &self,
+            resource_list: Vec<ResourceDescription>,
+        ) -> Result<(), Self::Error> {
+            self.resource_updates.lock().push(resource_list);
+
+            Ok(())
+        }
+
+        #[cfg(target_os = "android")]
+        fn protect_file_descriptor(&self, _: std::os::fd::RawFd) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn config_changed_replaces_dns_mapping_and_keeps_connections() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _guard = runtime.enter();
+
+        let (mut tunnel, _) = tunnel();
+        let gateway = gateway("c4bb3d79-afa7-4660-8918-06c38fda3a4a");
+        let resource = cidr_resource("73037362-715d-4a83-a749-f18eadd970e6", "10.0.0.0/24");
+        tunnel.add_resource(resource.clone()).unwrap();
+        connect(&mut tunnel, &resource, gateway);
+        tunnel
+            .role_state
+            .set_dns_mapping(dns_mapping("100.100.111.1", "1.1.1.1:53"));
+
+        tunnel.replace_dns_mapping(dns_mapping("100.100.111.2", "8.8.8.8:53"));
+
+        assert_eq!(
+            tunnel.role_state.dns_mapping(),
+            dns_mapping("100.100.111.2", "8.8.8.8:53")
+        );
+        assert_eq!(
+            tunnel.role_state.dns_resolvers.keys().collect::<Vec<_>>(),
+            vec![&"100.100.111.2".parse::<IpAddr>().unwrap()]
+        );
+        assert!(tunnel.connections_state.peers_by_id.contains_key(&gateway));
+    }
+
+    fn dns_mapping(sentinel: &str, upstream: &str) -> BiMap<IpAddr, DnsServer> {
+        BiMap::from_iter([(
+            sentinel.parse().unwrap(),
+            DnsServer::IpPort(IpDnsServer {
+                address: upstream.parse().unwrap(),
+            }),
+        )])
+    }
+
+    /// A tunnel without a device, which is enough to observe how it tracks resources.
+    fn tunnel() -> (ClientTunnel<TestCallbacks>, TestCallbacks) {
+        let callbacks = TestCallbacks::default();
+        let tunnel = ClientTunnel::new(
+            StaticSecret::random_from_rng(rand_core::OsRng),
+            callbacks.clone(),
+        )
+        .unwrap();
+
+        (tunnel, callbacks)
+    }
+
+    /// Sets up the state as if we connected to `gateway` for `resource`.
+    fn connect(
+        tunnel: &mut ClientTunnel<TestCallbacks>,
+        resource: &ResourceDescription,
+        gateway: GatewayId,
+    ) -> Arc<Peer<GatewayId, PacketTransformClient>> {
+        let peer = Arc::new(Peer::new(vec![], gateway, PacketTransformClient::default()));
+
+        if let ResourceDescription::Cidr(cidr) = resource {
+            peer.add_allowed_ip(cidr.address);
+            tunnel
+                .role_state
+                .peers_by_ip
+                .insert(cidr.address, peer.clone());
+        }
+
+        tunnel
+            .role_state
+            .resources_gateways
+            .insert(resource.id(), gateway);
+        tunnel
+            .connections_state
+            .peers_by_id
+            .insert(gateway, peer.clone());
+
+        peer
+    }
+
+    fn cidr_resource(id: &str, address: &str) -> ResourceDescription {
+        ResourceDescription::Cidr(ResourceDescriptionCidr {
+            id: ResourceId::from_str(id).unwrap(),
+            address: address.parse().unwrap(),
+            name: address.to_owned(),
+        })
+    }
+
+    fn add_resource(state: &mut ClientState, resource: &ResourceDescription) {
+        let ResourceDescription::Cidr(cidr) = resource else {
+            unreachable!()
+        };
+
+        state.cidr_resources.insert(cidr.address, cidr.clone());
+        state.resource_ids.insert(resource.id(), resource.clone());
+    }
+}
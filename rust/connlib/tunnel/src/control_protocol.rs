@@ -3,11 +3,12 @@ use ip_network_table::IpNetworkTable;
 use std::{collections::HashSet, fmt, hash::Hash, net::SocketAddr, sync::Arc};
 
 use connlib_shared::{
-    messages::{Relay, RequestConnection, ReuseConnection},
+    messages::{Relay, RelayTransport, RequestConnection, ReuseConnection},
     Callbacks,
 };
 
 use crate::{peer::Peer, Tunnel, REALM};
+use snownet::Transport;
 
 mod client;
 pub mod gateway;
@@ -28,6 +29,38 @@ where
             .node
             .add_remote_candidate(conn_id, ice_candidate);
     }
+
+    /// Applies what the portal told us about the relays.
+    ///
+    /// Tells the node how to talk to each TURN relay, see [`snownet::Node::set_turn_transport`], and hands fresh credentials to relays that rejected ours.
+    fn update_relays(&mut self, relays: &[Relay]) {
+        for relay in relays {
+            let Relay::Turn(turn) = relay else {
+                continue;
+            };
+
+            let transport = match turn.transport {
+                RelayTransport::Udp => Transport::Udp,
+                RelayTransport::Tcp => Transport::Tcp,
+                RelayTransport::Tls => Transport::Tls,
+            };
+
+            self.connections_state
+                .node
+                .set_turn_transport(turn.addr, transport);
+
+            if self.connections_state.rejected_relays.remove(&turn.addr) {
+                tracing::info!(relay = %turn.addr, "Retrying relay with new credentials");
+
+                self.connections_state.node.update_turn_credentials(
+                    turn.addr,
+                    turn.username.clone(),
+                    turn.password.clone(),
+                    REALM.to_string(),
+                );
+            }
+        }
+    }
 }
 
 fn insert_peers<TId: Copy, TTransform>(
@@ -12,7 +12,7 @@ use connlib_shared::{
 use domain::base::Rtype;
 use ip_network::IpNetwork;
 use secrecy::{ExposeSecret, Secret};
-use snownet::Client;
+use snownet::{Client, ConnectionConfig};
 
 use crate::{
     client::DnsResource,
@@ -55,6 +55,8 @@ where
             .parse()
             .map_err(|_| Error::InvalidReference)?;
 
+        self.update_relays(&relays);
+
         if let Some(connection) =
             self.role_state
                 .attempt_to_reuse_connection(resource_id, gateway_id, reference)?
@@ -77,9 +79,8 @@ where
             stun(&relays, |addr| {
                 self.connections_state.sockets.can_handle(addr)
             }),
-            turn(&relays, |addr| {
-                self.connections_state.sockets.can_handle(addr)
-            }),
+            turn(&relays, |addr| self.connections_state.is_relay_usable(addr)),
+            ConnectionConfig::default(),
         );
 
         Ok(Request::NewConnection(RequestConnection {
@@ -125,7 +126,11 @@ where
         self.connections_state
             .peers_by_id
             .insert(gateway_id, Arc::clone(&peer));
-        insert_peers(&mut self.role_state.peers_by_ip, &peer_ips, peer);
+        insert_peers(&mut self.role_state.peers_by_ip, &peer_ips, peer.clone());
+
+        self.role_state
+            .forward_deferred_dns_queries(resource_id, &peer);
+        self.send_gateway_dns_queries();
 
         Ok(())
     }
@@ -194,9 +199,13 @@ where
             })
             .collect();
 
-        self.role_state
+        let previous_addrs = self
+            .role_state
             .dns_resources_internal_ips
-            .insert(resource_description.clone(), addrs.clone());
+            .insert(resource_description.clone(), addrs.clone())
+            .unwrap_or_default();
+        self.role_state
+            .release_proxy_ips(previous_addrs.difference(&addrs).copied());
 
         let ips: Vec<IpNetwork> = addrs.iter().copied().map(Into::into).collect();
         for ip in &ips {
@@ -245,7 +254,12 @@ where
         };
 
         let peer_ips = self.dns_response(&resource_id, &domain_response, &peer)?;
-        insert_peers(&mut self.role_state.peers_by_ip, &peer_ips, peer);
+        insert_peers(&mut self.role_state.peers_by_ip, &peer_ips, peer.clone());
+
+        self.role_state
+            .forward_deferred_dns_queries(resource_id, &peer);
+        self.send_gateway_dns_queries();
+
         Ok(())
     }
 }
@@ -264,8 +278,10 @@ fn send_dns_answer(
         let Some(packet) = dns::create_local_answer(addrs, packet) else {
             return;
         };
-        if let Err(e) = device.write(packet) {
-            tracing::error!(err = ?e, "error writing packet: {e:#?}");
+        for packet in role_state.tcp_dns.handle_response(packet) {
+            if let Err(e) = device.write(packet) {
+                tracing::error!(err = ?e, "error writing packet: {e:#?}");
+            }
         }
     }
 }
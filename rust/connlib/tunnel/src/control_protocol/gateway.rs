@@ -16,8 +16,13 @@ use connlib_shared::{
 };
 use ip_network::IpNetwork;
 use secrecy::{ExposeSecret as _, Secret};
-use snownet::{Credentials, Server};
-use std::sync::Arc;
+use snownet::{ConnectionConfig, Credentials, Server};
+use std::{sync::Arc, time::Duration};
+
+/// Close connections to clients once no traffic has flowed for this long.
+///
+/// Clients re-connect on demand. This frees the state of clients that went away without telling us.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
 
 /// Description of a resource that maps to a DNS record which had its domain already resolved.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -75,6 +80,8 @@ where
             ResourceDescription::Cidr(ref cidr) => vec![cidr.address],
         };
 
+        self.update_relays(&relays);
+
         let answer = self.connections_state.node.accept_connection(
             client,
             snownet::Offer {
@@ -88,9 +95,11 @@ where
             stun(&relays, |addr| {
                 self.connections_state.sockets.can_handle(addr)
             }),
-            turn(&relays, |addr| {
-                self.connections_state.sockets.can_handle(addr)
-            }),
+            turn(&relays, |addr| self.connections_state.is_relay_usable(addr)),
+            ConnectionConfig {
+                idle_timeout: Some(IDLE_TIMEOUT),
+                ..Default::default()
+            },
         );
 
         self.new_peer(
@@ -149,10 +158,13 @@ where
             ResourceDescription::Cidr(cidr) => (vec![cidr.address], cidr.id),
         };
 
+        // The portal may have changed the resource's addresses, so we replace everything we know about it.
+        peer.transform.remove_resource(&resource_id);
         for address in &addresses {
             peer.transform
                 .add_resource(*address, resource.clone(), expires_at);
         }
+        peer.transform.add_dns_resource(&resource, expires_at);
 
         tracing::info!(%client, resource = %resource_id, expires = ?expires_at.map(|e| e.to_rfc3339()), "Allowing access to resource");
 
@@ -166,6 +178,16 @@ where
         None
     }
 
+    pub fn remove_access(&mut self, client: &ClientId, resource: &ResourceId) {
+        let Some(peer) = self.connections_state.peers_by_id.get(client) else {
+            return;
+        };
+
+        peer.transform.remove_resource(resource);
+
+        tracing::info!(%client, %resource, "Removing access to resource");
+    }
+
     fn new_peer(
         &mut self,
         ips: Vec<IpNetwork>,
@@ -182,6 +204,7 @@ where
             PacketTransformGateway::default(),
         ));
 
+        peer.transform.add_dns_resource(&resource, expires_at);
         for address in resource_addresses {
             peer.transform
                 .add_resource(address, resource.clone(), expires_at);
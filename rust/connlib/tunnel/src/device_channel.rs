@@ -158,6 +158,34 @@ impl Device {
         Ok(None)
     }
 
+    #[cfg(target_family = "unix")]
+    pub(crate) fn remove_route(
+        &self,
+        route: IpNetwork,
+        callbacks: &impl Callbacks<Error = Error>,
+    ) -> Result<Option<Device>, Error> {
+        let Some(tun) = self.tun.remove_route(route, callbacks)? else {
+            return Ok(None);
+        };
+        let mtu = ioctl::interface_mtu_by_name(tun.name())?;
+
+        Ok(Some(Device {
+            mtu,
+            tun,
+            mtu_refreshed_at: Instant::now(),
+        }))
+    }
+
+    #[cfg(target_family = "windows")]
+    pub(crate) fn remove_route(
+        &self,
+        route: IpNetwork,
+        _: &impl Callbacks<Error = Error>,
+    ) -> Result<Option<Device>, Error> {
+        self.tun.remove_route(route)?;
+        Ok(None)
+    }
+
     #[cfg(target_family = "unix")]
     fn refresh_mtu(&mut self) -> io::Result<()> {
         let mtu = ioctl::interface_mtu_by_name(self.tun.name())?;
@@ -40,6 +40,7 @@ const TUN_DEV_MAJOR: u32 = 10;
 const TUN_DEV_MINOR: u32 = 200;
 const DEFAULT_MTU: u32 = 1280;
 const FILE_ALREADY_EXISTS: i32 = -17;
+const NO_SUCH_PROCESS: i32 = -3;
 const FIREZONE_TABLE: u32 = 0x2021_fd00;
 
 // Safety: We know that this is a valid C string.
@@ -190,14 +191,71 @@ impl Tun {
             }
         };
 
+        self.push_worker(add_route_worker.boxed());
+
+        Ok(None)
+    }
+
+    pub fn remove_route(&self, route: IpNetwork, _: &impl Callbacks) -> Result<Option<Self>> {
+        let handle = self.handle.clone();
+
+        let remove_route_worker = async move {
+            let index = handle
+                .link()
+                .get()
+                .match_name(IFACE_NAME.to_string())
+                .execute()
+                .try_next()
+                .await?
+                .ok_or(Error::NoIface)?
+                .header
+                .index;
+
+            let req = handle
+                .route()
+                .add()
+                .output_interface(index)
+                .protocol(RouteProtocol::Static)
+                .scope(RouteScope::Universe)
+                .table_id(FIREZONE_TABLE);
+            let message = match route {
+                IpNetwork::V4(ipnet) => req
+                    .v4()
+                    .destination_prefix(ipnet.network_address(), ipnet.netmask())
+                    .message_mut()
+                    .clone(),
+                IpNetwork::V6(ipnet) => req
+                    .v6()
+                    .destination_prefix(ipnet.network_address(), ipnet.netmask())
+                    .message_mut()
+                    .clone(),
+            };
+
+            match handle.route().del(message).execute().await {
+                Ok(_) => Ok(()),
+                Err(NetlinkError(err)) if err.raw_code() == NO_SUCH_PROCESS => Ok(()),
+                Err(err) => {
+                    tracing::error!(%route, "failed to remove route: {err:#?}");
+                    Ok(())
+                }
+            }
+        };
+
+        self.push_worker(remove_route_worker.boxed());
+
+        Ok(None)
+    }
+
+    /// Runs the given worker after all current ones completed.
+    fn push_worker(&self, worker: BoxFuture<'static, Result<()>>) {
         let mut guard = self.worker.lock();
         match guard.take() {
-            None => *guard = Some(add_route_worker.boxed()),
+            None => *guard = Some(worker),
             Some(current_worker) => {
                 *guard = Some(
                     async move {
                         current_worker.await?;
-                        add_route_worker.await?;
+                        worker.await?;
 
                         Ok(())
                     }
@@ -205,8 +263,6 @@ impl Tun {
                 )
             }
         }
-
-        Ok(None)
     }
 
     pub fn name(&self) -> &str {
@@ -13,8 +13,8 @@ use tokio::sync::mpsc;
 use windows::Win32::{
     NetworkManagement::{
         IpHelper::{
-            CreateIpForwardEntry2, GetIpInterfaceEntry, InitializeIpForwardEntry,
-            SetIpInterfaceEntry, MIB_IPFORWARD_ROW2, MIB_IPINTERFACE_ROW,
+            CreateIpForwardEntry2, DeleteIpForwardEntry2, GetIpInterfaceEntry,
+            InitializeIpForwardEntry, SetIpInterfaceEntry, MIB_IPFORWARD_ROW2, MIB_IPINTERFACE_ROW,
         },
         Ndis::NET_LUID_LH,
     },
@@ -155,6 +155,43 @@ impl Tun {
     // It's okay if this blocks until the route is added in the OS.
     pub fn add_route(&self, route: IpNetwork) -> Result<()> {
         tracing::debug!("add_route {route}");
+        let row = self.forward_row(route);
+
+        // SAFETY: Windows shouldn't store the reference anywhere, it's just a way to pass lots of arguments at once. And no other thread sees this variable.
+        match unsafe { CreateIpForwardEntry2(&row) } {
+            Ok(_) => {}
+            Err(e) => {
+                if e.code().0 as u32 == 0x80071392 {
+                    // "Object already exists" error
+                    tracing::warn!("Failed to add duplicate route, ignoring");
+                } else {
+                    Err(e)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove_route(&self, route: IpNetwork) -> Result<()> {
+        tracing::debug!("remove_route {route}");
+        let row = self.forward_row(route);
+
+        // SAFETY: Windows shouldn't store the reference anywhere, it's just a way to pass lots of arguments at once. And no other thread sees this variable.
+        match unsafe { DeleteIpForwardEntry2(&row) } {
+            Ok(_) => {}
+            Err(e) => {
+                if e.code().0 as u32 == 0x80070490 {
+                    // "Element not found" error
+                    tracing::warn!("Failed to remove non-existing route, ignoring");
+                } else {
+                    Err(e)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn forward_row(&self, route: IpNetwork) -> MIB_IPFORWARD_ROW2 {
         let mut row = MIB_IPFORWARD_ROW2::default();
         // SAFETY: Windows shouldn't store the reference anywhere, it's just setting defaults
         unsafe { InitializeIpForwardEntry(&mut row) };
@@ -174,19 +211,7 @@ impl Tun {
         row.InterfaceIndex = self.iface_idx;
         row.Metric = 0;
 
-        // SAFETY: Windows shouldn't store the reference anywhere, it's just a way to pass lots of arguments at once. And no other thread sees this variable.
-        match unsafe { CreateIpForwardEntry2(&row) } {
-            Ok(_) => {}
-            Err(e) => {
-                if e.code().0 as u32 == 0x80071392 {
-                    // "Object already exists" error
-                    tracing::warn!("Failed to add duplicate route, ignoring");
-                } else {
-                    Err(e)?;
-                }
-            }
-        }
-        Ok(())
+        row
     }
 
     pub fn poll_read(&self, buf: &mut [u8], cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
@@ -1,33 +1,46 @@
 use crate::client::DnsResource;
 use crate::device_channel::Packet;
-use crate::ip_packet::{to_dns, IpPacket, MutableIpPacket, Version};
+use crate::ip_packet::{to_dns, IpPacket, MutableIpPacket, Version, DNS_PORT};
 use connlib_shared::error::ConnlibError;
 use connlib_shared::messages::{DnsServer, ResourceDescriptionDns};
-use connlib_shared::Dname;
+use connlib_shared::{Dname, DNS_SENTINELS_V4, DNS_SENTINELS_V6};
 use domain::base::RelativeDname;
 use domain::base::{
     iana::{Class, Rcode, Rtype},
     Message, MessageBuilder, Question, ToDname,
 };
 use hickory_resolver::lookup::Lookup;
-use hickory_resolver::proto::op::{Message as TrustDnsMessage, MessageType};
-use hickory_resolver::proto::rr::RecordType;
+use hickory_resolver::proto::op::{Edns, Message as TrustDnsMessage, MessageType, ResponseCode};
+use hickory_resolver::proto::rr::rdata::svcb::{IpHint, SvcParamValue, SVCB};
+use hickory_resolver::proto::rr::{rdata, Name, RData, Record, RecordType};
+use ip_network::IpNetwork;
 use itertools::Itertools;
 use pnet_packet::{udp::MutableUdpPacket, MutablePacket, Packet as UdpPacket, PacketSize};
 use std::collections::{HashMap, HashSet};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
+mod tcp;
+
+pub(crate) use tcp::TcpResponder;
+
 const DNS_TTL: u32 = 1;
 const UDP_HEADER_SIZE: usize = 8;
 const REVERSE_DNS_ADDRESS_END: &str = "arpa";
 const REVERSE_DNS_ADDRESS_V4: &str = "in-addr";
 const REVERSE_DNS_ADDRESS_V6: &str = "ip6";
 
+/// The largest DNS response we send over UDP, regardless of what the client supports.
+///
+/// Larger responses would be fragmented, see <https://www.dnsflagday.net/2020/>.
+const MAX_UDP_PAYLOAD: u16 = 1232;
+
 #[derive(Debug)]
 pub(crate) enum ResolveStrategy<T, U, V> {
     LocalResponse(T),
     ForwardQuery(U),
     DeferredResponse(V),
+    /// The query is for a DNS resource but needs to be resolved by the gateway, see [`is_resolved_by_gateway`].
+    ForwardToGateway(V),
 }
 
 #[derive(Debug)]
@@ -115,6 +128,12 @@ pub(crate) fn parse<'a>(
                     question.qtype(),
                 )))
             }
+            Some(ResolveStrategy::ForwardToGateway(resource)) => {
+                return Some(ResolveStrategy::ForwardToGateway((
+                    resource,
+                    question.qtype(),
+                )))
+            }
             None => None,
         };
     let response = build_dns_with_answer(message, question.qname(), &resource)?;
@@ -123,6 +142,17 @@ pub(crate) fn parse<'a>(
     )?))
 }
 
+/// Whether the given packet is a segment of a DNS query over TCP to one of our sentinels.
+pub(crate) fn is_tcp_query(
+    dns_mapping: &bimap::BiMap<IpAddr, DnsServer>,
+    packet: &IpPacket,
+) -> bool {
+    dns_mapping.contains_left(&packet.destination())
+        && packet
+            .as_tcp()
+            .is_some_and(|segment| segment.get_destination() == DNS_PORT)
+}
+
 pub(crate) fn create_local_answer<'a>(
     ips: &HashSet<IpAddr>,
     packet: IpPacket<'a>,
@@ -146,7 +176,10 @@ pub(crate) fn create_local_answer<'a>(
                 .map(domain::rdata::Aaaa::new)
                 .collect(),
         ),
-        _ => unreachable!(),
+        _ => {
+            tracing::debug!(%qtype, "Cannot answer query with proxy IPs");
+            return None;
+        }
     };
 
     let response = build_dns_with_answer(message, question.qname(), &Some(resource.clone()))?;
@@ -154,15 +187,22 @@ pub(crate) fn create_local_answer<'a>(
     build_response(packet, response)
 }
 
+/// Builds the response to a forwarded query.
+///
+/// Responses to queries over UDP are truncated if they exceed what the client advertised via EDNS0, or 512 bytes without it.
 pub(crate) fn build_response_from_resolve_result(
     original_pkt: IpPacket<'_>,
     response: hickory_resolver::error::ResolveResult<Lookup>,
+    over_tcp: bool,
 ) -> Result<Option<Packet>, ConnlibError> {
     let Some(mut message) = as_dns_message(&original_pkt) else {
         debug_assert!(false, "The original message should be a DNS query for us to ever call write_dns_lookup_response");
         return Ok(None);
     };
 
+    let max_len = max_response_len(&message, over_tcp);
+    let has_edns = message.extensions().is_some();
+
     message.set_message_type(MessageType::Response);
 
     let response = match response.map_err(|err| err.kind().clone()) {
@@ -183,11 +223,188 @@ pub(crate) fn build_response_from_resolve_result(
         }
     };
 
-    let packet = build_response(original_pkt, response.to_vec()?);
+    if has_edns {
+        let mut edns = Edns::new();
+        edns.set_max_payload(MAX_UDP_PAYLOAD);
+        response.set_edns(edns);
+    }
+
+    let packet = build_response(original_pkt, encode_response(response, max_len)?);
 
     Ok(packet)
 }
 
+/// Builds a SERVFAIL response to a query we won't resolve.
+pub(crate) fn build_servfail_response(original_pkt: IpPacket<'_>) -> Option<Packet<'static>> {
+    let mut message = as_dns_message(&original_pkt)?;
+    message.set_message_type(MessageType::Response);
+    message.set_response_code(ResponseCode::ServFail);
+
+    build_response(original_pkt, message.to_vec().ok()?)
+}
+
+/// Builds the response to a query that was resolved by the gateway.
+///
+/// All addresses in the response are replaced with the proxy IPs returned by `proxy_ip`.
+/// Records whose address cannot be replaced are dropped.
+pub(crate) fn build_response_from_gateway(
+    original_pkt: IpPacket<'_>,
+    mut response: TrustDnsMessage,
+    over_tcp: bool,
+    mut proxy_ip: impl FnMut(&Name, IpAddr) -> Option<IpAddr>,
+) -> Result<Option<Packet<'static>>, ConnlibError> {
+    let Some(query) = as_dns_message(&original_pkt) else {
+        return Ok(None);
+    };
+
+    let answers = rewrite_addresses(response.take_answers(), &mut proxy_ip);
+    let additionals = rewrite_addresses(response.take_additionals(), &mut proxy_ip);
+    response.insert_answers(answers);
+    response.insert_additionals(additionals);
+
+    let response = encode_response(&mut response, max_response_len(&query, over_tcp))?;
+
+    Ok(build_response(original_pkt, response))
+}
+
+/// Replaces the addresses of A and AAAA records as well as the address hints of HTTPS records.
+fn rewrite_addresses(
+    records: Vec<Record>,
+    proxy_ip: &mut impl FnMut(&Name, IpAddr) -> Option<IpAddr>,
+) -> Vec<Record> {
+    records
+        .into_iter()
+        .filter_map(|mut record| {
+            let name = record.name().clone();
+
+            let data = match record.data()? {
+                RData::A(a) => RData::A(rdata::A(get_v4(proxy_ip(&name, a.0.into())?)?)),
+                RData::AAAA(aaaa) => {
+                    RData::AAAA(rdata::AAAA(get_v6(proxy_ip(&name, aaaa.0.into())?)?))
+                }
+                RData::HTTPS(https) => {
+                    let target = hints_name(&name, https);
+                    let params = https
+                        .svc_params()
+                        .iter()
+                        .cloned()
+                        .map(|(key, value)| {
+                            let value = match value {
+                                SvcParamValue::Ipv4Hint(IpHint(hints)) => {
+                                    SvcParamValue::Ipv4Hint(IpHint(
+                                        hints
+                                            .into_iter()
+                                            .filter_map(|a| get_v4(proxy_ip(&target, a.0.into())?))
+                                            .map(rdata::A)
+                                            .collect(),
+                                    ))
+                                }
+                                SvcParamValue::Ipv6Hint(IpHint(hints)) => {
+                                    SvcParamValue::Ipv6Hint(IpHint(
+                                        hints
+                                            .into_iter()
+                                            .filter_map(|aaaa| {
+                                                get_v6(proxy_ip(&target, aaaa.0.into())?)
+                                            })
+                                            .map(rdata::AAAA)
+                                            .collect(),
+                                    ))
+                                }
+                                other => other,
+                            };
+
+                            (key, value)
+                        })
+                        .collect();
+
+                    RData::HTTPS(rdata::HTTPS(SVCB::new(
+                        https.svc_priority(),
+                        https.target_name().clone(),
+                        params,
+                    )))
+                }
+                _ => return Some(record),
+            };
+
+            record.set_data(Some(data));
+
+            Some(record)
+        })
+        .collect()
+}
+
+/// Returns the addresses in the given record together with the name they belong to.
+pub(crate) fn record_addresses(record: &Record) -> Vec<(Name, IpAddr)> {
+    let name = record.name();
+
+    match record.data() {
+        Some(RData::A(a)) => vec![(name.clone(), a.0.into())],
+        Some(RData::AAAA(aaaa)) => vec![(name.clone(), aaaa.0.into())],
+        Some(RData::HTTPS(https)) => {
+            let target = hints_name(name, https);
+
+            https
+                .svc_params()
+                .iter()
+                .flat_map(|(_, value)| match value {
+                    SvcParamValue::Ipv4Hint(IpHint(hints)) => {
+                        hints.iter().map(|a| IpAddr::from(a.0)).collect()
+                    }
+                    SvcParamValue::Ipv6Hint(IpHint(hints)) => {
+                        hints.iter().map(|aaaa| IpAddr::from(aaaa.0)).collect()
+                    }
+                    _ => vec![],
+                })
+                .map(|ip| (target.clone(), ip))
+                .collect()
+        }
+        _ => vec![],
+    }
+}
+
+/// The name the address hints of an HTTPS record belong to.
+///
+/// A target name of `.` refers to the owner of the record, see RFC 9460.
+fn hints_name(owner: &Name, https: &SVCB) -> Name {
+    if https.target_name().is_root() {
+        return owner.clone();
+    }
+
+    https.target_name().clone()
+}
+
+/// The maximum length of the response to the given query or `None` if it doesn't have one.
+fn max_response_len(query: &TrustDnsMessage, over_tcp: bool) -> Option<usize> {
+    if over_tcp {
+        return None;
+    }
+
+    Some(usize::from(query.max_payload().min(MAX_UDP_PAYLOAD)))
+}
+
+/// Encodes the response, dropping all records and setting the TC bit if it doesn't fit into `max_len` bytes.
+///
+/// The client is expected to retry the query over TCP, see <https://www.rfc-editor.org/rfc/rfc2181#section-9>.
+fn encode_response(
+    response: &mut TrustDnsMessage,
+    max_len: Option<usize>,
+) -> Result<Vec<u8>, ConnlibError> {
+    let encoded = response.to_vec()?;
+
+    let Some(max_len) = max_len.filter(|max_len| encoded.len() > *max_len) else {
+        return Ok(encoded);
+    };
+
+    tracing::debug!(len = %encoded.len(), %max_len, "Truncating DNS response");
+
+    response.take_answers();
+    response.take_name_servers();
+    response.take_additionals();
+    response.set_truncated(true);
+
+    Ok(response.to_vec()?)
+}
+
 fn build_response(original_pkt: IpPacket<'_>, mut dns_answer: Vec<u8>) -> Option<Packet<'static>> {
     let version = original_pkt.version();
     let response_len = dns_answer.len();
@@ -292,7 +509,7 @@ pub fn is_subdomain(name: &Dname, resource: &str) -> bool {
     name == &resource
 }
 
-fn get_description(
+pub(crate) fn get_description(
     name: &Dname,
     dns_resources: &HashMap<String, ResourceDescriptionDns>,
 ) -> Option<ResourceDescriptionDns> {
@@ -398,8 +615,14 @@ fn resource_from_question<N: ToDname>(
             )))
         }
         _ => {
-            if get_description(&name, dns_resources).is_some() {
-                return None;
+            if let Some(description) = get_description(&name, dns_resources) {
+                if !is_resolved_by_gateway(qtype) {
+                    return None;
+                }
+
+                return Some(ResolveStrategy::ForwardToGateway(
+                    DnsResource::from_description(&description, name),
+                ));
             };
 
             Some(ResolveStrategy::forward(name.to_string(), qtype))
@@ -407,11 +630,85 @@ fn resource_from_question<N: ToDname>(
     }
 }
 
+/// Whether queries of this type for DNS resources are sent to the gateway of the resource.
+///
+/// Only the gateway can see the records of internal domains.
+/// Any addresses in the response are replaced with proxy IPs by the client.
+pub(crate) fn is_resolved_by_gateway(qtype: Rtype) -> bool {
+    matches!(qtype, Rtype::Srv | Rtype::Txt | Rtype::Cname | Rtype::Https)
+}
+
+/// Returns the name and record type the given DNS query is for.
+pub(crate) fn query_question(packet: &IpPacket) -> Option<(Dname, Rtype)> {
+    let datagram = packet.as_udp()?;
+    let message = to_dns(&datagram)?;
+
+    if message.header().qr() {
+        return None;
+    }
+
+    let question = message.first_question()?;
+
+    Some((ToDname::to_vec(question.qname()), question.qtype()))
+}
+
+/// Whether the given IP is in the range clients pick their DNS sentinels from.
+pub(crate) fn is_sentinel(ip: IpAddr) -> bool {
+    [DNS_SENTINELS_V4, DNS_SENTINELS_V6]
+        .into_iter()
+        .any(|range| {
+            range
+                .parse::<IpNetwork>()
+                .is_ok_and(|range| range.contains(ip))
+        })
+}
+
+/// How specific the address of a DNS resource is, a name matching several resources belongs to the most specific one.
+///
+/// Like [`get_description`], an exact address beats a `?.` address which beats a `*.` address.
+/// Among addresses of the same kind, the one with the most labels is the most specific.
+pub(crate) fn specificity(resource: &str) -> (u8, usize) {
+    let labels = |domain: &str| domain.trim_end_matches('.').split('.').count();
+
+    if let Some(parent) = resource.strip_prefix("?.") {
+        return (1, labels(parent));
+    }
+
+    if let Some(parent) = resource.strip_prefix("*.") {
+        return (0, labels(parent));
+    }
+
+    (2, labels(resource))
+}
+
 pub(crate) fn as_dns_message(pkt: &IpPacket) -> Option<TrustDnsMessage> {
     let datagram = pkt.as_udp()?;
     TrustDnsMessage::from_vec(datagram.payload()).ok()
 }
 
+/// Whether `response` answers `query`.
+///
+/// The response must come back from the server and port the query was sent to, be addressed to the socket it was sent from and carry the same ID and question.
+pub(crate) fn is_response_to(query: &IpPacket, response: &IpPacket) -> bool {
+    let (Some(query_datagram), Some(response_datagram)) = (query.as_udp(), response.as_udp())
+    else {
+        return false;
+    };
+    let (Some(query_message), Some(response_message)) =
+        (as_dns_message(query), as_dns_message(response))
+    else {
+        return false;
+    };
+
+    response.source() == query.destination()
+        && response.destination() == query.source()
+        && response_datagram.get_source() == query_datagram.get_destination()
+        && response_datagram.get_destination() == query_datagram.get_source()
+        && response_message.message_type() == MessageType::Response
+        && response_message.id() == query_message.id()
+        && response_message.queries() == query_message.queries()
+}
+
 fn reverse_dns_addr(name: &str) -> Option<IpAddr> {
     let mut dns_parts = name.split('.').rev();
     if dns_parts.next()? != REVERSE_DNS_ADDRESS_END {
@@ -459,14 +756,52 @@ fn get_v6(ip: IpAddr) -> Option<Ipv6Addr> {
     }
 }
 
+/// Builds a UDP datagram carrying the given DNS message.
+#[cfg(test)]
+pub(crate) fn build_datagram(
+    src: std::net::SocketAddr,
+    dst: std::net::SocketAddr,
+    message: &TrustDnsMessage,
+) -> IpPacket<'static> {
+    let payload = message.to_vec().unwrap();
+
+    let packet = tcp::build_ip_packet(
+        src,
+        dst,
+        pnet_packet::ip::IpNextHeaderProtocols::Udp,
+        UDP_HEADER_SIZE + payload.len(),
+        |buf| {
+            let mut datagram = MutableUdpPacket::new(buf).unwrap();
+            datagram.set_source(src.port());
+            datagram.set_destination(dst.port());
+            datagram.set_length((UDP_HEADER_SIZE + payload.len()) as u16);
+            datagram.set_payload(&payload);
+        },
+    );
+
+    IpPacket::owned(packet).unwrap()
+}
+
 #[cfg(test)]
 mod test {
     use connlib_shared::{messages::ResourceDescriptionDns, Dname};
+    use hickory_resolver::proto::op::Message as TrustDnsMessage;
+    use hickory_resolver::proto::rr::rdata::svcb::{IpHint, SvcParamKey, SvcParamValue, SVCB};
+    use hickory_resolver::proto::rr::{rdata, Name, RData, Record};
 
     use crate::dns::is_subdomain;
 
-    use super::{get_description, reverse_dns_addr};
-    use std::{collections::HashMap, net::Ipv4Addr};
+    use super::{
+        build_datagram, encode_response, get_description, is_response_to, record_addresses,
+        reverse_dns_addr, rewrite_addresses,
+    };
+    use crate::ip_packet::IpPacket;
+    use hickory_resolver::proto::op::{MessageType, Query};
+    use hickory_resolver::proto::rr::RecordType;
+    use std::{
+        collections::HashMap,
+        net::{IpAddr, Ipv4Addr},
+    };
 
     fn foo() -> ResourceDescriptionDns {
         serde_json::from_str(
@@ -762,4 +1097,225 @@ mod test {
             "?.foo.com"
         ));
     }
+
+    fn response_with_answers(num_answers: u8) -> TrustDnsMessage {
+        let mut response = TrustDnsMessage::new();
+        response.add_answers((0..num_answers).map(|i| {
+            Record::from_rdata(
+                Name::from_ascii("example.com.").unwrap(),
+                300,
+                RData::A(rdata::A::new(10, 0, 0, i)),
+            )
+        }));
+
+        response
+    }
+
+    #[test]
+    fn response_within_limit_is_not_truncated() {
+        let mut response = response_with_answers(10);
+
+        let encoded = encode_response(&mut response, Some(512)).unwrap();
+
+        let decoded = TrustDnsMessage::from_vec(&encoded).unwrap();
+        assert!(!decoded.truncated());
+        assert_eq!(decoded.answers().len(), 10);
+    }
+
+    #[test]
+    fn oversized_response_is_truncated() {
+        let mut response = response_with_answers(100);
+
+        let encoded = encode_response(&mut response, Some(512)).unwrap();
+
+        let decoded = TrustDnsMessage::from_vec(&encoded).unwrap();
+        assert!(decoded.truncated());
+        assert!(decoded.answers().is_empty());
+        assert!(encoded.len() <= 512);
+    }
+
+    #[test]
+    fn response_over_tcp_is_never_truncated() {
+        let mut response = response_with_answers(100);
+
+        let encoded = encode_response(&mut response, None).unwrap();
+
+        let decoded = TrustDnsMessage::from_vec(&encoded).unwrap();
+        assert!(!decoded.truncated());
+        assert_eq!(decoded.answers().len(), 100);
+    }
+
+    fn https_record(owner: &str, target: &str, hint: Ipv4Addr) -> Record {
+        Record::from_rdata(
+            Name::from_ascii(owner).unwrap(),
+            300,
+            RData::HTTPS(rdata::HTTPS(SVCB::new(
+                1,
+                Name::from_ascii(target).unwrap(),
+                vec![(
+                    SvcParamKey::Ipv4Hint,
+                    SvcParamValue::Ipv4Hint(IpHint(vec![rdata::A(hint)])),
+                )],
+            ))),
+        )
+    }
+
+    #[test]
+    fn rewrites_addresses_to_proxy_ips() {
+        let records = response_with_answers(2).take_answers();
+
+        let rewritten = rewrite_addresses(records, &mut |_, ip| match ip {
+            IpAddr::V4(ip) => Some(Ipv4Addr::new(100, 96, 0, ip.octets()[3]).into()),
+            IpAddr::V6(_) => None,
+        });
+
+        assert_eq!(
+            rewritten
+                .iter()
+                .map(|r| r.data().cloned())
+                .collect::<Vec<_>>(),
+            vec![
+                Some(RData::A(rdata::A::new(100, 96, 0, 0))),
+                Some(RData::A(rdata::A::new(100, 96, 0, 1)))
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_records_without_proxy_ip() {
+        let records = response_with_answers(2).take_answers();
+
+        let rewritten = rewrite_addresses(records, &mut |_, _| None);
+
+        assert!(rewritten.is_empty());
+    }
+
+    #[test]
+    fn keeps_records_without_addresses() {
+        let txt = Record::from_rdata(
+            Name::from_ascii("example.com.").unwrap(),
+            300,
+            RData::TXT(rdata::TXT::new(vec!["foo".to_owned()])),
+        );
+
+        let rewritten = rewrite_addresses(vec![txt.clone()], &mut |_, _| None);
+
+        assert_eq!(rewritten, vec![txt]);
+    }
+
+    #[test]
+    fn rewrites_https_address_hints() {
+        let record = https_record(
+            "example.com.",
+            "svc.example.com.",
+            Ipv4Addr::new(10, 0, 0, 1),
+        );
+
+        let rewritten = rewrite_addresses(vec![record], &mut |name, _| {
+            assert_eq!(name, &Name::from_ascii("svc.example.com.").unwrap());
+            Some(Ipv4Addr::new(100, 96, 0, 1).into())
+        });
+
+        assert_eq!(
+            record_addresses(&rewritten[0]),
+            vec![(
+                Name::from_ascii("svc.example.com.").unwrap(),
+                Ipv4Addr::new(100, 96, 0, 1).into()
+            )]
+        );
+    }
+
+    #[test]
+    fn https_address_hints_of_root_target_belong_to_owner() {
+        let record = https_record("example.com.", ".", Ipv4Addr::new(10, 0, 0, 1));
+
+        assert_eq!(
+            record_addresses(&record),
+            vec![(
+                Name::from_ascii("example.com.").unwrap(),
+                Ipv4Addr::new(10, 0, 0, 1).into()
+            )]
+        );
+    }
+
+    const APPLICATION: &str = "100.64.0.1:50000";
+    const SENTINEL: &str = "100.100.111.1:53";
+
+    fn srv_query(id: u16, name: &str) -> TrustDnsMessage {
+        let mut query = TrustDnsMessage::new();
+        query.set_id(id);
+        query.add_query(Query::query(
+            Name::from_ascii(name).unwrap(),
+            RecordType::SRV,
+        ));
+
+        query
+    }
+
+    fn srv_response(id: u16, name: &str) -> TrustDnsMessage {
+        let mut response = srv_query(id, name);
+        response.set_message_type(MessageType::Response);
+
+        response
+    }
+
+    fn datagram(src: &str, dst: &str, message: &TrustDnsMessage) -> IpPacket<'static> {
+        build_datagram(src.parse().unwrap(), dst.parse().unwrap(), message)
+    }
+
+    #[test]
+    fn response_with_same_id_and_question_answers_query() {
+        let query = datagram(APPLICATION, SENTINEL, &srv_query(1, "_sip._udp.foo.com."));
+        let response = datagram(
+            SENTINEL,
+            APPLICATION,
+            &srv_response(1, "_sip._udp.foo.com."),
+        );
+
+        assert!(is_response_to(&query, &response));
+    }
+
+    #[test]
+    fn response_with_other_id_does_not_answer_query() {
+        let query = datagram(APPLICATION, SENTINEL, &srv_query(1, "_sip._udp.foo.com."));
+        let response = datagram(
+            SENTINEL,
+            APPLICATION,
+            &srv_response(2, "_sip._udp.foo.com."),
+        );
+
+        assert!(!is_response_to(&query, &response));
+    }
+
+    #[test]
+    fn response_for_other_name_does_not_answer_query() {
+        let query = datagram(APPLICATION, SENTINEL, &srv_query(1, "_sip._udp.foo.com."));
+        let response = datagram(
+            SENTINEL,
+            APPLICATION,
+            &srv_response(1, "_sip._udp.bar.com."),
+        );
+
+        assert!(!is_response_to(&query, &response));
+    }
+
+    #[test]
+    fn response_from_other_server_does_not_answer_query() {
+        let query = datagram(APPLICATION, SENTINEL, &srv_query(1, "_sip._udp.foo.com."));
+        let response = datagram(
+            "100.100.111.2:53",
+            APPLICATION,
+            &srv_response(1, "_sip._udp.foo.com."),
+        );
+
+        assert!(!is_response_to(&query, &response));
+    }
+
+    #[test]
+    fn query_does_not_answer_itself() {
+        let query = datagram(APPLICATION, SENTINEL, &srv_query(1, "_sip._udp.foo.com."));
+        let echoed = datagram(SENTINEL, APPLICATION, &srv_query(1, "_sip._udp.foo.com."));
+
+        assert!(!is_response_to(&query, &echoed));
+    }
 }
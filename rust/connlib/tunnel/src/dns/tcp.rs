@@ -0,0 +1,607 @@
+//! A minimal userspace TCP responder for DNS queries sent to our sentinel IPs.
+//!
+//! Applications fall back to TCP if a response over UDP is truncated and some always use TCP.
+//! All segments are exchanged with the local device so we don't implement retransmissions, congestion control or window management:
+//! Each segment is acknowledged right away and each response is written to the device in one go.
+//!
+//! DNS messages received on a stream are turned into UDP packets from the client to the sentinel.
+//! That allows them to be resolved exactly like queries over UDP.
+//! The corresponding responses need to be passed to [`TcpResponder::handle_response`] to be sent back on the stream.
+
+use crate::device_channel::Packet;
+use crate::ip_packet::{IpPacket, MutableIpPacket};
+use pnet_packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet_packet::ipv4::MutableIpv4Packet;
+use pnet_packet::ipv6::MutableIpv6Packet;
+use pnet_packet::tcp::{MutableTcpPacket, TcpFlags};
+use pnet_packet::udp::MutableUdpPacket;
+use pnet_packet::{MutablePacket as _, Packet as _};
+use rand_core::{OsRng, RngCore};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const TCP_HEADER_LEN: usize = 20;
+const UDP_HEADER_LEN: usize = 8;
+const TTL: u8 = 64;
+
+/// Fits into the 1280 bytes MTU of our device, even with an IPv6 header.
+const MAX_SEGMENT_SIZE: usize = 1200;
+const MAX_CONNECTIONS: usize = 100;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Quad {
+    client: SocketAddr,
+    server: SocketAddr,
+}
+
+struct Connection {
+    /// The next sequence number we are going to send.
+    snd_nxt: u32,
+    /// The next sequence number we expect from the client.
+    rcv_nxt: u32,
+
+    /// Bytes of the stream that don't form a complete DNS message yet.
+    buffer: Vec<u8>,
+    /// The IDs of the queries we still owe a response, with how many queries on the stream used each ID.
+    pending_queries: HashMap<u16, usize>,
+    /// Whether the client closed its side of the connection.
+    fin_received: bool,
+
+    last_seen: Instant,
+}
+
+/// What happened as a result of a segment from the client.
+#[derive(Default)]
+pub(crate) struct Received {
+    /// Segments to write back to the device.
+    pub(crate) segments: Vec<Packet<'static>>,
+    /// DNS queries received on the stream, as UDP packets from the client to the sentinel.
+    pub(crate) queries: Vec<Vec<u8>>,
+}
+
+#[derive(Default)]
+pub(crate) struct TcpResponder {
+    connections: HashMap<Quad, Connection>,
+}
+
+impl TcpResponder {
+    /// Handles a TCP segment sent by the client to one of our sentinel IPs.
+    pub(crate) fn handle_segment(&mut self, packet: &IpPacket<'_>, now: Instant) -> Received {
+        self.connections
+            .retain(|_, c| now.duration_since(c.last_seen) < IDLE_TIMEOUT);
+
+        let mut received = Received::default();
+
+        let Some(segment) = packet.as_tcp() else {
+            return received;
+        };
+        let quad = Quad {
+            client: SocketAddr::new(packet.source(), segment.get_source()),
+            server: SocketAddr::new(packet.destination(), segment.get_destination()),
+        };
+        let flags = segment.get_flags();
+        let seq = segment.get_sequence();
+        let fin = flags & TcpFlags::FIN != 0;
+
+        if flags & TcpFlags::RST != 0 {
+            self.connections.remove(&quad);
+            return received;
+        }
+
+        if flags & TcpFlags::SYN != 0 {
+            if self.connections.len() >= MAX_CONNECTIONS && !self.connections.contains_key(&quad) {
+                tracing::debug!(client = %quad.client, "Too many DNS connections over TCP");
+
+                received.segments.push(build_segment(
+                    quad,
+                    0,
+                    seq.wrapping_add(1),
+                    TcpFlags::RST | TcpFlags::ACK,
+                    &[],
+                ));
+                return received;
+            }
+
+            let mut connection = Connection {
+                snd_nxt: OsRng.next_u32(),
+                rcv_nxt: seq.wrapping_add(1),
+                buffer: Vec::new(),
+                pending_queries: HashMap::new(),
+                fin_received: false,
+                last_seen: now,
+            };
+            received
+                .segments
+                .push(connection.segment(quad, TcpFlags::SYN | TcpFlags::ACK, &[]));
+            self.connections.insert(quad, connection);
+
+            return received;
+        }
+
+        let Some(connection) = self.connections.get_mut(&quad) else {
+            // A bare ACK is most likely the last one of a connection we already closed.
+            if !segment.payload().is_empty() || fin {
+                received.segments.push(build_segment(
+                    quad,
+                    segment.get_acknowledgement(),
+                    0,
+                    TcpFlags::RST,
+                    &[],
+                ));
+            }
+
+            return received;
+        };
+        connection.last_seen = now;
+
+        let payload = segment.payload();
+
+        if payload.is_empty() && !fin {
+            return received;
+        }
+
+        if seq != connection.rcv_nxt {
+            // Duplicate or out-of-order segment, tell the client what we've got so far.
+            received
+                .segments
+                .push(connection.segment(quad, TcpFlags::ACK, &[]));
+            return received;
+        }
+
+        connection.rcv_nxt = connection.rcv_nxt.wrapping_add(payload.len() as u32);
+        connection.buffer.extend_from_slice(payload);
+
+        while let Some(message) = connection.next_message() {
+            let Some(id) = message.get(..2) else {
+                continue;
+            };
+
+            *connection
+                .pending_queries
+                .entry(u16::from_be_bytes([id[0], id[1]]))
+                .or_default() += 1;
+            received.queries.push(build_ip_packet(
+                quad.client,
+                quad.server,
+                IpNextHeaderProtocols::Udp,
+                UDP_HEADER_LEN + message.len(),
+                |buf| {
+                    let mut datagram = MutableUdpPacket::new(buf)
+                        .expect("Developer error: buffer should fit UDP header");
+                    datagram.set_source(quad.client.port());
+                    datagram.set_destination(quad.server.port());
+                    datagram.set_length((UDP_HEADER_LEN + message.len()) as u16);
+                    datagram.set_payload(&message);
+                },
+            ));
+        }
+
+        if fin {
+            connection.rcv_nxt = connection.rcv_nxt.wrapping_add(1);
+            connection.fin_received = true;
+        }
+
+        received
+            .segments
+            .push(connection.segment(quad, TcpFlags::ACK, &[]));
+
+        if connection.fin_received && connection.pending_queries.is_empty() {
+            received
+                .segments
+                .push(connection.segment(quad, TcpFlags::FIN | TcpFlags::ACK, &[]));
+            self.connections.remove(&quad);
+        }
+
+        received
+    }
+
+    /// Whether the given query, as returned from [`TcpResponder::handle_segment`], is still waiting for a response.
+    pub(crate) fn is_pending(&self, query: &IpPacket<'_>) -> bool {
+        let Some((client, server, id)) = dns_over_udp(query) else {
+            return false;
+        };
+
+        self.connections
+            .get(&Quad { client, server })
+            .is_some_and(|c| c.pending_queries.contains_key(&id))
+    }
+
+    /// Sends a response back on the stream its query arrived on.
+    ///
+    /// Returns the packets to write to the device: Either the TCP segments carrying the response or the response itself if its query didn't arrive over TCP.
+    pub(crate) fn handle_response(&mut self, response: Packet<'static>) -> Vec<Packet<'static>> {
+        let Some((quad, stream)) = self.pending_response(&response) else {
+            return vec![response];
+        };
+
+        let connection = self
+            .connections
+            .get_mut(&quad)
+            .expect("Developer error: connection should exist for pending response");
+
+        let mut segments = stream
+            .chunks(MAX_SEGMENT_SIZE)
+            .map(|chunk| connection.segment(quad, TcpFlags::PSH | TcpFlags::ACK, chunk))
+            .collect::<Vec<_>>();
+
+        if connection.fin_received && connection.pending_queries.is_empty() {
+            segments.push(connection.segment(quad, TcpFlags::FIN | TcpFlags::ACK, &[]));
+            self.connections.remove(&quad);
+        }
+
+        segments
+    }
+
+    /// Marks the response as sent and returns its connection together with the response prefixed by its length.
+    fn pending_response(&mut self, response: &Packet<'_>) -> Option<(Quad, Vec<u8>)> {
+        let (Packet::Ipv4(bytes) | Packet::Ipv6(bytes)) = response;
+        let packet = IpPacket::new(bytes)?;
+        let (server, client, id) = dns_over_udp(&packet)?;
+        let quad = Quad { client, server };
+
+        let pending_queries = &mut self.connections.get_mut(&quad)?.pending_queries;
+        let count = pending_queries.get_mut(&id)?;
+        *count -= 1;
+        if *count == 0 {
+            pending_queries.remove(&id);
+        }
+
+        let message = packet.as_udp()?.payload().to_vec();
+        let mut stream = Vec::with_capacity(2 + message.len());
+        stream.extend_from_slice(&(message.len() as u16).to_be_bytes());
+        stream.extend_from_slice(&message);
+
+        Some((quad, stream))
+    }
+}
+
+impl Connection {
+    /// Splits off the next DNS message from the stream.
+    ///
+    /// Over TCP, each message is prefixed by its length, see <https://www.rfc-editor.org/rfc/rfc1035#section-4.2.2>.
+    fn next_message(&mut self) -> Option<Vec<u8>> {
+        let len = u16::from_be_bytes([*self.buffer.first()?, *self.buffer.get(1)?]) as usize;
+
+        if self.buffer.len() < 2 + len {
+            return None;
+        }
+
+        let message = self.buffer[2..2 + len].to_vec();
+        self.buffer.drain(..2 + len);
+
+        Some(message)
+    }
+
+    fn segment(&mut self, quad: Quad, flags: u8, payload: &[u8]) -> Packet<'static> {
+        let segment = build_segment(quad, self.snd_nxt, self.rcv_nxt, flags, payload);
+
+        let mut len = payload.len() as u32;
+        if flags & (TcpFlags::SYN | TcpFlags::FIN) != 0 {
+            len += 1;
+        }
+        self.snd_nxt = self.snd_nxt.wrapping_add(len);
+
+        segment
+    }
+}
+
+/// Returns source, destination and ID of a DNS message inside a UDP packet.
+fn dns_over_udp(packet: &IpPacket<'_>) -> Option<(SocketAddr, SocketAddr, u16)> {
+    let datagram = packet.as_udp()?;
+    let id = datagram.payload().get(..2)?;
+
+    Some((
+        SocketAddr::new(packet.source(), datagram.get_source()),
+        SocketAddr::new(packet.destination(), datagram.get_destination()),
+        u16::from_be_bytes([id[0], id[1]]),
+    ))
+}
+
+fn build_segment(quad: Quad, seq: u32, ack: u32, flags: u8, payload: &[u8]) -> Packet<'static> {
+    let packet = build_ip_packet(
+        quad.server,
+        quad.client,
+        IpNextHeaderProtocols::Tcp,
+        TCP_HEADER_LEN + payload.len(),
+        |buf| {
+            let mut segment =
+                MutableTcpPacket::new(buf).expect("Developer error: buffer should fit TCP header");
+            segment.set_source(quad.server.port());
+            segment.set_destination(quad.client.port());
+            segment.set_sequence(seq);
+            segment.set_acknowledgement(ack);
+            segment.set_data_offset((TCP_HEADER_LEN / 4) as u8);
+            segment.set_flags(flags);
+            segment.set_window(u16::MAX);
+            segment.set_payload(payload);
+        },
+    );
+
+    match quad.server {
+        SocketAddr::V4(_) => Packet::Ipv4(packet.into()),
+        SocketAddr::V6(_) => Packet::Ipv6(packet.into()),
+    }
+}
+
+pub(super) fn build_ip_packet(
+    src: SocketAddr,
+    dst: SocketAddr,
+    protocol: IpNextHeaderProtocol,
+    payload_len: usize,
+    fill_payload: impl FnOnce(&mut [u8]),
+) -> Vec<u8> {
+    let mut buf = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut buf = vec![0u8; IPV4_HEADER_LEN + payload_len];
+            let mut packet = MutableIpv4Packet::new(&mut buf)
+                .expect("Developer error: buffer should fit IPv4 header");
+            packet.set_version(4);
+            packet.set_header_length((IPV4_HEADER_LEN / 4) as u8);
+            packet.set_total_length((IPV4_HEADER_LEN + payload_len) as u16);
+            packet.set_ttl(TTL);
+            packet.set_next_level_protocol(protocol);
+            packet.set_source(src);
+            packet.set_destination(dst);
+            fill_payload(packet.payload_mut());
+
+            buf
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let mut buf = vec![0u8; IPV6_HEADER_LEN + payload_len];
+            let mut packet = MutableIpv6Packet::new(&mut buf)
+                .expect("Developer error: buffer should fit IPv6 header");
+            packet.set_version(6);
+            packet.set_payload_length(payload_len as u16);
+            packet.set_next_header(protocol);
+            packet.set_hop_limit(TTL);
+            packet.set_source(src);
+            packet.set_destination(dst);
+            fill_payload(packet.payload_mut());
+
+            buf
+        }
+        _ => unreachable!("Both addresses of a connection always have the same IP version"),
+    };
+
+    MutableIpPacket::new(&mut buf)
+        .expect("Developer error: we just built a valid IP packet")
+        .update_checksum();
+
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet_packet::tcp::TcpPacket;
+
+    const CLIENT: &str = "100.64.0.1:50000";
+    const SENTINEL: &str = "100.100.111.1:53";
+
+    fn quad() -> Quad {
+        Quad {
+            client: CLIENT.parse().unwrap(),
+            server: SENTINEL.parse().unwrap(),
+        }
+    }
+
+    fn from_client(seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let quad = quad();
+
+        build_ip_packet(
+            quad.client,
+            quad.server,
+            IpNextHeaderProtocols::Tcp,
+            TCP_HEADER_LEN + payload.len(),
+            |buf| {
+                let mut segment = MutableTcpPacket::new(buf).unwrap();
+                segment.set_source(quad.client.port());
+                segment.set_destination(quad.server.port());
+                segment.set_sequence(seq);
+                segment.set_data_offset(5);
+                segment.set_flags(flags);
+                segment.set_window(u16::MAX);
+                segment.set_payload(payload);
+            },
+        )
+    }
+
+    fn response_to_client(message: &[u8]) -> Packet<'static> {
+        let quad = quad();
+
+        let packet = build_ip_packet(
+            quad.server,
+            quad.client,
+            IpNextHeaderProtocols::Udp,
+            UDP_HEADER_LEN + message.len(),
+            |buf| {
+                let mut datagram = MutableUdpPacket::new(buf).unwrap();
+                datagram.set_source(quad.server.port());
+                datagram.set_destination(quad.client.port());
+                datagram.set_length((UDP_HEADER_LEN + message.len()) as u16);
+                datagram.set_payload(message);
+            },
+        );
+
+        Packet::Ipv4(packet.into())
+    }
+
+    fn handle(responder: &mut TcpResponder, packet: Vec<u8>) -> Received {
+        responder.handle_segment(&IpPacket::new(&packet).unwrap(), Instant::now())
+    }
+
+    /// Returns sequence number, acknowledgement number, flags and payload of the segment.
+    fn parse(packet: &Packet<'_>) -> (u32, u32, u8, Vec<u8>) {
+        let (Packet::Ipv4(bytes) | Packet::Ipv6(bytes)) = packet;
+        let packet = IpPacket::new(bytes).unwrap();
+        let segment = TcpPacket::new(packet.payload()).unwrap();
+
+        (
+            segment.get_sequence(),
+            segment.get_acknowledgement(),
+            segment.get_flags(),
+            segment.payload().to_vec(),
+        )
+    }
+
+    /// Performs the handshake and returns the next sequence number of the server.
+    fn connect(responder: &mut TcpResponder) -> u32 {
+        let received = handle(responder, from_client(1000, TcpFlags::SYN, &[]));
+        let (seq, ..) = parse(&received.segments[0]);
+
+        handle(responder, from_client(1001, TcpFlags::ACK, &[]));
+
+        seq.wrapping_add(1)
+    }
+
+    #[test]
+    fn syn_is_answered_with_syn_ack() {
+        let mut responder = TcpResponder::default();
+
+        let received = handle(&mut responder, from_client(1000, TcpFlags::SYN, &[]));
+
+        let (_, ack, flags, _) = parse(&received.segments[0]);
+        assert_eq!(ack, 1001);
+        assert_eq!(flags, TcpFlags::SYN | TcpFlags::ACK);
+        assert!(received.queries.is_empty());
+    }
+
+    #[test]
+    fn query_split_across_segments_is_reassembled() {
+        let mut responder = TcpResponder::default();
+        connect(&mut responder);
+
+        let received = handle(
+            &mut responder,
+            from_client(1001, TcpFlags::ACK, &[0, 4, 0xAB, 0xCD]),
+        );
+        assert!(received.queries.is_empty());
+        assert_eq!(parse(&received.segments[0]).1, 1005);
+
+        let received = handle(
+            &mut responder,
+            from_client(1005, TcpFlags::ACK | TcpFlags::PSH, &[1, 2]),
+        );
+        assert_eq!(received.queries.len(), 1);
+        assert_eq!(parse(&received.segments[0]).1, 1007);
+
+        let query = IpPacket::new(&received.queries[0]).unwrap();
+        assert_eq!(query.as_udp().unwrap().payload(), &[0xAB, 0xCD, 1, 2]);
+        assert!(responder.is_pending(&query));
+    }
+
+    #[test]
+    fn response_is_sent_with_length_prefix() {
+        let mut responder = TcpResponder::default();
+        let seq = connect(&mut responder);
+        handle(
+            &mut responder,
+            from_client(1001, TcpFlags::ACK | TcpFlags::PSH, &[0, 2, 0xAB, 0xCD]),
+        );
+
+        let segments = responder.handle_response(response_to_client(&[0xAB, 0xCD, 0x80]));
+
+        assert_eq!(segments.len(), 1);
+        let (response_seq, ack, flags, payload) = parse(&segments[0]);
+        assert_eq!(response_seq, seq);
+        assert_eq!(ack, 1005);
+        assert_eq!(flags, TcpFlags::PSH | TcpFlags::ACK);
+        assert_eq!(payload, vec![0, 3, 0xAB, 0xCD, 0x80]);
+    }
+
+    #[test]
+    fn large_response_is_split_into_segments() {
+        let mut responder = TcpResponder::default();
+        let seq = connect(&mut responder);
+        handle(
+            &mut responder,
+            from_client(1001, TcpFlags::ACK | TcpFlags::PSH, &[0, 2, 0xAB, 0xCD]),
+        );
+
+        let mut message = vec![0xAB, 0xCD];
+        message.resize(3000, 0);
+        let segments = responder.handle_response(response_to_client(&message));
+
+        assert_eq!(segments.len(), 3);
+        assert_eq!(
+            parse(&segments[1]).0,
+            seq.wrapping_add(MAX_SEGMENT_SIZE as u32)
+        );
+        assert_eq!(
+            segments.iter().map(|s| parse(s).3.len()).sum::<usize>(),
+            3002
+        );
+    }
+
+    #[test]
+    fn response_to_udp_query_is_passed_through() {
+        let mut responder = TcpResponder::default();
+        connect(&mut responder);
+
+        let segments = responder.handle_response(response_to_client(&[0xAB, 0xCD, 0x80]));
+
+        assert_eq!(segments.len(), 1);
+        let (Packet::Ipv4(bytes) | Packet::Ipv6(bytes)) = &segments[0];
+        assert!(IpPacket::new(bytes).unwrap().as_udp().is_some());
+    }
+
+    #[test]
+    fn connection_is_closed_after_last_response() {
+        let mut responder = TcpResponder::default();
+        connect(&mut responder);
+        handle(
+            &mut responder,
+            from_client(1001, TcpFlags::ACK | TcpFlags::PSH, &[0, 2, 0xAB, 0xCD]),
+        );
+
+        let received = handle(
+            &mut responder,
+            from_client(1005, TcpFlags::ACK | TcpFlags::FIN, &[]),
+        );
+        assert_eq!(received.segments.len(), 1);
+        assert_eq!(parse(&received.segments[0]).1, 1006);
+
+        let segments = responder.handle_response(response_to_client(&[0xAB, 0xCD, 0x80]));
+
+        assert_eq!(segments.len(), 2);
+        assert_eq!(parse(&segments[1]).2, TcpFlags::FIN | TcpFlags::ACK);
+        assert!(responder.connections.is_empty());
+    }
+
+    #[test]
+    fn responses_to_queries_with_same_id_are_sent_on_stream() {
+        let mut responder = TcpResponder::default();
+        connect(&mut responder);
+        handle(
+            &mut responder,
+            from_client(
+                1001,
+                TcpFlags::ACK | TcpFlags::PSH,
+                &[0, 2, 0xAB, 0xCD, 0, 2, 0xAB, 0xCD],
+            ),
+        );
+
+        let first = responder.handle_response(response_to_client(&[0xAB, 0xCD, 0x80]));
+        let second = responder.handle_response(response_to_client(&[0xAB, 0xCD, 0x80]));
+
+        assert_eq!(parse(&first[0]).3, vec![0, 3, 0xAB, 0xCD, 0x80]);
+        assert_eq!(parse(&second[0]).3, vec![0, 3, 0xAB, 0xCD, 0x80]);
+    }
+
+    #[test]
+    fn data_for_unknown_connection_is_reset() {
+        let mut responder = TcpResponder::default();
+
+        let received = handle(
+            &mut responder,
+            from_client(1001, TcpFlags::ACK | TcpFlags::PSH, &[0, 2, 0xAB, 0xCD]),
+        );
+
+        assert_eq!(parse(&received.segments[0]).2, TcpFlags::RST);
+        assert!(received.queries.is_empty());
+    }
+}
@@ -1,9 +1,14 @@
-use crate::device_channel::Device;
-use crate::ip_packet::MutableIpPacket;
-use crate::peer::{PacketTransformGateway, Peer};
-use crate::{peer_by_ip, Tunnel};
+use crate::control_protocol::gateway::ResourceDescription;
+use crate::device_channel::{Device, Packet};
+use crate::ip_packet::{IpPacket, MutableIpPacket};
+use crate::peer::{ExpiryingResource, PacketTransformGateway, Peer};
+use crate::{dns, peer_by_ip, Tunnel, DNS_QUERIES_QUEUE_SIZE};
 use connlib_shared::messages::{ClientId, Interface as InterfaceConfig};
-use connlib_shared::Callbacks;
+use connlib_shared::{Callbacks, Dname};
+use futures_bounded::FuturesTupleSet;
+use hickory_resolver::error::ResolveResult;
+use hickory_resolver::lookup::Lookup;
+use hickory_resolver::TokioAsyncResolver;
 use ip_network_table::IpNetworkTable;
 use itertools::Itertools;
 use snownet::Server;
@@ -49,6 +54,18 @@ pub struct GatewayState {
     #[allow(clippy::type_complexity)]
     pub peers_by_ip: IpNetworkTable<Arc<Peer<ClientId, PacketTransformGateway>>>,
     expire_interval: Interval,
+
+    /// Resolves the DNS queries clients send us for their DNS resources.
+    dns_resolver: Option<TokioAsyncResolver>,
+    #[allow(clippy::type_complexity)]
+    dns_queries: FuturesTupleSet<
+        ResolveResult<Lookup>,
+        (
+            Arc<Peer<ClientId, PacketTransformGateway>>,
+            IpPacket<'static>,
+            ExpiryingResource,
+        ),
+    >,
 }
 
 impl GatewayState {
@@ -64,6 +81,100 @@ impl GatewayState {
         Some((peer.conn_id, packet))
     }
 
+    /// Attempt to handle the given packet from a client as a DNS query for one of its DNS resources.
+    ///
+    /// Returns the packet back if it isn't one.
+    pub(crate) fn handle_dns_query<'a>(&mut self, packet: Packet<'a>) -> Option<Packet<'a>> {
+        let (Packet::Ipv4(bytes) | Packet::Ipv6(bytes)) = &packet;
+        let Some(query) = IpPacket::new(bytes) else {
+            return Some(packet);
+        };
+
+        let Some(peer) = self
+            .peers_by_ip
+            .longest_match(query.source())
+            .map(|(_, p)| p.clone())
+        else {
+            return Some(packet);
+        };
+
+        let Some(resource) = peer.transform.dns_resource_for_query(&query) else {
+            return Some(packet);
+        };
+
+        let question = dns::as_dns_message(&query)?.queries().first()?.clone();
+        let resolver = self.dns_resolver.clone()?;
+
+        if self
+            .dns_queries
+            .try_push(
+                async move {
+                    resolver
+                        .lookup(question.name().clone(), question.query_type())
+                        .await
+                },
+                (peer, query.to_owned(), resource),
+            )
+            .is_err()
+        {
+            tracing::warn!("Too many DNS queries, dropping existing one");
+        }
+
+        None
+    }
+
+    /// Returns the next response to a DNS query from a client.
+    ///
+    /// Addresses in the response that belong to the queried resource are authorized for the client.
+    pub(crate) fn poll_dns_response(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<(ClientId, IpPacket<'static>)> {
+        loop {
+            let (result, (peer, query, (resource, expires_at))) =
+                ready!(self.dns_queries.poll_unpin(cx));
+
+            let result = match result {
+                Ok(result) => result,
+                Err(timeout) => {
+                    tracing::warn!(client = %peer.conn_id, "DNS query timed out: {timeout}");
+                    continue;
+                }
+            };
+
+            if let (Ok(lookup), ResourceDescription::Dns(r)) = (&result, &resource) {
+                for (name, ip) in lookup.records().iter().flat_map(dns::record_addresses) {
+                    let Ok(name) = Dname::vec_from_str(&name.to_string()) else {
+                        continue;
+                    };
+
+                    if !dns::is_subdomain(&name, &r.domain) {
+                        continue;
+                    }
+
+                    peer.transform
+                        .add_resource(ip.into(), resource.clone(), expires_at);
+                }
+            }
+
+            // The client truncates the response if necessary once it replaced the addresses.
+            match dns::build_response_from_resolve_result(query, result, true) {
+                Ok(Some(Packet::Ipv4(response) | Packet::Ipv6(response))) => {
+                    let Some(response) = IpPacket::owned(response.into_owned()) else {
+                        continue;
+                    };
+
+                    return Poll::Ready((peer.conn_id, response));
+                }
+                Ok(None) => continue,
+                Err(e) => {
+                    tracing::warn!("Failed to build DNS response from lookup result: {e}");
+                    continue;
+                }
+            }
+        }
+    }
+
     pub fn poll(&mut self, cx: &mut Context<'_>) -> Poll<Vec<ClientId>> {
         ready!(self.expire_interval.poll_tick(cx));
         Poll::Ready(self.expire_resources().collect_vec())
@@ -88,9 +199,219 @@ impl Default for GatewayState {
     fn default() -> Self {
         let mut expire_interval = interval(Duration::from_secs(1));
         expire_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let dns_resolver = TokioAsyncResolver::tokio_from_system_conf()
+            .map_err(|e| tracing::warn!("Failed to create DNS resolver for DNS resources: {e}"))
+            .ok();
+
         Self {
             peers_by_ip: IpNetworkTable::new(),
             expire_interval,
+            dns_resolver,
+            dns_queries: FuturesTupleSet::new(Duration::from_secs(60), DNS_QUERIES_QUEUE_SIZE),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control_protocol::gateway::ResolvedResourceDescriptionDns;
+    use chrono::Utc;
+    use connlib_shared::messages::ResourceId;
+    use hickory_resolver::proto::op::{Message, Query};
+    use hickory_resolver::proto::rr::{Name, RecordType};
+    use pnet_packet::Packet as _;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+
+    const CLIENT: &str = "100.64.0.1:50000";
+    const SENTINEL: &str = "100.100.111.1:53";
+
+    fn dns_resource(id: &str, domain: &str) -> ResourceDescription {
+        ResourceDescription::Dns(ResolvedResourceDescriptionDns {
+            id: ResourceId::from_str(id).unwrap(),
+            domain: domain.to_owned(),
+            name: domain.to_owned(),
+            addresses: vec![],
+        })
+    }
+
+    fn peer(resources: &[ResourceDescription]) -> Arc<Peer<ClientId, PacketTransformGateway>> {
+        let client: SocketAddr = CLIENT.parse().unwrap();
+        let peer = Peer::new(
+            vec![client.ip().into()],
+            serde_json::from_str("\"c4bb3d79-afa7-4660-8918-06c38fda3a4a\"").unwrap(),
+            PacketTransformGateway::default(),
+        );
+
+        for resource in resources {
+            peer.transform.add_dns_resource(resource, None);
+        }
+
+        Arc::new(peer)
+    }
+
+    fn query(dst: &str, name: &str, qtype: RecordType) -> IpPacket<'static> {
+        let mut message = Message::new();
+        message.add_query(Query::query(Name::from_ascii(name).unwrap(), qtype));
+
+        dns::build_datagram(CLIENT.parse().unwrap(), dst.parse().unwrap(), &message)
+    }
+
+    fn untransform(
+        peer: &Peer<ClientId, PacketTransformGateway>,
+        packet: &IpPacket,
+    ) -> connlib_shared::Result<()> {
+        let client: SocketAddr = CLIENT.parse().unwrap();
+        let mut buf = packet.packet().to_vec();
+
+        peer.untransform(client.ip(), &mut buf).map(|_| ())
+    }
+
+    #[test]
+    fn query_for_dns_resource_is_intercepted() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _guard = runtime.enter();
+
+        let mut state = GatewayState::default();
+        let peer = peer(&[dns_resource(
+            "c4bb3d79-afa7-4660-8918-06c38fda3a4a",
+            "*.foo.com",
+        )]);
+        state
+            .peers_by_ip
+            .insert(CLIENT.parse::<SocketAddr>().unwrap().ip(), peer);
+
+        let query = query(SENTINEL, "_sip._udp.foo.com.", RecordType::SRV);
+
+        assert!(state
+            .handle_dns_query(Packet::Ipv4(query.packet().to_vec().into()))
+            .is_none());
+    }
+
+    #[test]
+    fn query_for_other_domain_is_not_intercepted() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _guard = runtime.enter();
+
+        let mut state = GatewayState::default();
+        let peer = peer(&[dns_resource(
+            "c4bb3d79-afa7-4660-8918-06c38fda3a4a",
+            "*.foo.com",
+        )]);
+        state
+            .peers_by_ip
+            .insert(CLIENT.parse::<SocketAddr>().unwrap().ip(), peer);
+
+        let query = query(SENTINEL, "_sip._udp.bar.com.", RecordType::SRV);
+
+        assert!(state
+            .handle_dns_query(Packet::Ipv4(query.packet().to_vec().into()))
+            .is_some());
+    }
+
+    #[test]
+    fn accepts_query_for_dns_resource() {
+        let peer = peer(&[dns_resource(
+            "c4bb3d79-afa7-4660-8918-06c38fda3a4a",
+            "*.foo.com",
+        )]);
+
+        let query = query(SENTINEL, "_sip._udp.foo.com.", RecordType::SRV);
+
+        assert!(untransform(&peer, &query).is_ok());
+    }
+
+    #[test]
+    fn rejects_query_for_unauthorized_domain() {
+        let peer = peer(&[dns_resource(
+            "c4bb3d79-afa7-4660-8918-06c38fda3a4a",
+            "*.foo.com",
+        )]);
+
+        let query = query(SENTINEL, "_sip._udp.bar.com.", RecordType::SRV);
+
+        assert!(untransform(&peer, &query).is_err());
+    }
+
+    #[test]
+    fn rejects_query_not_sent_to_sentinel() {
+        let peer = peer(&[dns_resource(
+            "c4bb3d79-afa7-4660-8918-06c38fda3a4a",
+            "*.foo.com",
+        )]);
+
+        let query = query("10.0.0.1:53", "_sip._udp.foo.com.", RecordType::SRV);
+
+        assert!(untransform(&peer, &query).is_err());
+    }
+
+    #[test]
+    fn rejects_query_for_record_type_not_resolved_by_gateway() {
+        let peer = peer(&[dns_resource(
+            "c4bb3d79-afa7-4660-8918-06c38fda3a4a",
+            "*.foo.com",
+        )]);
+
+        let query = query(SENTINEL, "app.foo.com.", RecordType::A);
+
+        assert!(untransform(&peer, &query).is_err());
+    }
+
+    #[test]
+    fn rejects_query_for_expired_resource() {
+        let peer = peer(&[]);
+        peer.transform.add_dns_resource(
+            &dns_resource("c4bb3d79-afa7-4660-8918-06c38fda3a4a", "*.foo.com"),
+            Some(Utc::now() - chrono::Duration::seconds(1)),
+        );
+
+        let query = query(SENTINEL, "_sip._udp.foo.com.", RecordType::SRV);
+
+        assert!(untransform(&peer, &query).is_err());
+    }
+
+    #[test]
+    fn most_specific_resource_is_picked() {
+        let wildcard = dns_resource("c4bb3d79-afa7-4660-8918-06c38fda3a4a", "*.foo.com");
+        let question_mark = dns_resource("c4bb3d79-afa7-4660-8918-06c38fda3a4b", "?.foo.com");
+        let exact = dns_resource("c4bb3d79-afa7-4660-8918-06c38fda3a4c", "app.foo.com");
+        let peer = peer(&[wildcard.clone(), question_mark.clone(), exact.clone()]);
+
+        let resource_for = |name| {
+            peer.transform
+                .dns_resource_for_query(&query(SENTINEL, name, RecordType::SRV))
+                .map(|(resource, _)| resource)
+        };
+
+        assert_eq!(resource_for("app.foo.com."), Some(exact));
+        assert_eq!(resource_for("web.foo.com."), Some(question_mark));
+        assert_eq!(resource_for("_sip._udp.foo.com."), Some(wildcard));
+    }
+
+    #[test]
+    fn ties_are_broken_by_resource_id() {
+        let first = dns_resource("c4bb3d79-afa7-4660-8918-06c38fda3a4a", "*.foo.com");
+        let second = dns_resource("c4bb3d79-afa7-4660-8918-06c38fda3a4b", "*.foo.com");
+
+        for resources in [
+            [first.clone(), second.clone()],
+            [second.clone(), first.clone()],
+        ] {
+            let peer = peer(&resources);
+
+            let resource = peer
+                .transform
+                .dns_resource_for_query(&query(SENTINEL, "_sip._udp.foo.com.", RecordType::SRV))
+                .map(|(resource, _)| resource);
+
+            assert_eq!(resource, Some(first.clone()));
         }
     }
 }
@@ -11,7 +11,7 @@ use pnet_packet::{
     MutablePacket, Packet, PacketSize,
 };
 
-const DNS_PORT: u16 = 53;
+pub(crate) const DNS_PORT: u16 = 53;
 
 #[derive(Debug, PartialEq)]
 pub enum MutableIpPacket<'a> {
@@ -210,6 +210,16 @@ impl<'a> From<IpPacket<'a>> for snownet::IpPacket<'a> {
 }
 
 impl<'a> IpPacket<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Option<IpPacket<'a>> {
+        let packet = match data.first()? >> 4 {
+            4 => Ipv4Packet::new(data)?.into(),
+            6 => Ipv6Packet::new(data)?.into(),
+            _ => return None,
+        };
+
+        Some(packet)
+    }
+
     pub(crate) fn owned(data: Vec<u8>) -> Option<IpPacket<'static>> {
         let packet = match data[0] >> 4 {
             4 => Ipv4Packet::owned(data)?.into(),
@@ -257,6 +267,19 @@ impl<'a> IpPacket<'a> {
             .flatten()
     }
 
+    pub(crate) fn as_tcp(&self) -> Option<TcpPacket> {
+        self.is_tcp()
+            .then(|| TcpPacket::new(self.payload()))
+            .flatten()
+    }
+
+    pub(crate) fn source(&self) -> IpAddr {
+        match self {
+            Self::Ipv4Packet(p) => p.get_source().into(),
+            Self::Ipv6Packet(p) => p.get_source().into(),
+        }
+    }
+
     pub fn destination(&self) -> IpAddr {
         match self {
             Self::Ipv4Packet(p) => p.get_destination().into(),
@@ -13,16 +13,16 @@ use futures_util::{future::BoxFuture, task::AtomicWaker, FutureExt};
 use ip_network_table::IpNetworkTable;
 use peer::{PacketTransform, PacketTransformClient, PacketTransformGateway, Peer, PeerStats};
 use pnet_packet::Packet;
-use snownet::{IpPacket, Node, Server};
+use snownet::{IpPacket, Node, Server, Transport};
 use sockets::{Received, Sockets};
 use std::{
     collections::{HashMap, HashSet},
     fmt,
     hash::Hash,
-    net::IpAddr,
+    net::{IpAddr, SocketAddr},
     sync::Arc,
     task::{ready, Context, Poll},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 pub use client::ClientState;
@@ -43,6 +43,9 @@ const DNS_QUERIES_QUEUE_SIZE: usize = 100;
 
 const REALM: &str = "firezone";
 
+/// For how long new connections don't use a relay that told us it has no capacity for us.
+const UNAVAILABLE_RELAY_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[cfg(target_os = "linux")]
 const FIREZONE_MARK: u32 = 0xfd002021;
 
@@ -93,7 +96,9 @@ where
             _ => (),
         }
 
-        match self.connections_state.poll_sockets(cx) {
+        match self.connections_state.poll_sockets(cx, |gateway, packet| {
+            self.role_state.handle_gateway_dns_response(gateway, packet)
+        }) {
             Poll::Ready(packet) => {
                 device.write(packet)?;
                 cx.waker().wake_by_ref();
@@ -105,7 +110,13 @@ where
 
         match device.poll_read(&mut self.read_buf, cx)? {
             Poll::Ready(Some(packet)) => {
-                let Some((peer_id, packet)) = self.role_state.encapsulate(packet) else {
+                let encapsulated = self.role_state.encapsulate(packet);
+
+                while let Some((gateway, query)) = self.role_state.next_gateway_dns_query() {
+                    self.connections_state.send(gateway, query.into());
+                }
+
+                let Some((peer_id, packet)) = encapsulated else {
                     cx.waker().wake_by_ref();
                     return Poll::Pending;
                 };
@@ -144,6 +155,14 @@ where
             Poll::Pending => {}
         }
 
+        match self.role_state.poll_dns_response(cx) {
+            Poll::Ready((client, response)) => {
+                self.connections_state.send(client, response.into());
+                cx.waker().wake_by_ref();
+            }
+            Poll::Pending => {}
+        }
+
         let Some(device) = self.device.as_mut() else {
             self.no_device_waker.register(cx.waker());
             return Poll::Pending;
@@ -158,9 +177,11 @@ where
             _ => (),
         }
 
-        match self.connections_state.poll_sockets(cx) {
+        match self.connections_state.poll_sockets(cx, |_, _| false) {
             Poll::Ready(packet) => {
-                device.write(packet)?;
+                if let Some(packet) = self.role_state.handle_dns_query(packet) {
+                    device.write(packet)?;
+                }
                 cx.waker().wake_by_ref();
             }
             Poll::Pending => {}
@@ -217,7 +238,8 @@ where
     #[tracing::instrument(level = "trace", skip(private_key, callbacks))]
     pub fn new(private_key: StaticSecret, callbacks: CB) -> Result<Self> {
         let callbacks = CallbackErrorFacade(callbacks);
-        let connections_state = ConnectionState::new(private_key)?;
+        #[cfg_attr(not(target_os = "android"), allow(unused_mut))]
+        let mut connections_state = ConnectionState::new(private_key)?;
 
         // TODO: Eventually, this should move into the `connlib-client-android` crate.
         #[cfg(target_os = "android")]
@@ -228,6 +250,17 @@ where
             if let Some(ip6_socket) = connections_state.sockets.ip6_socket_fd() {
                 callbacks.protect_file_descriptor(ip6_socket)?;
             }
+
+            let callbacks = callbacks.clone();
+            connections_state
+                .sockets
+                .set_protect_socket(Arc::new(move |socket| {
+                    use std::os::fd::AsRawFd;
+
+                    callbacks
+                        .protect_file_descriptor(socket.as_raw_fd())
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+                }));
         }
 
         Ok(Self {
@@ -245,10 +278,16 @@ where
     }
 
     pub fn stats(&self) -> HashMap<TId, PeerStats<TId>> {
+        let mut connections = self
+            .connections_state
+            .node
+            .stats()
+            .collect::<HashMap<_, _>>();
+
         self.connections_state
             .peers_by_id
             .iter()
-            .map(|(&id, p)| (id, p.stats()))
+            .map(|(&id, p)| (id, p.stats(connections.remove(&id))))
             .collect()
     }
 }
@@ -259,6 +298,11 @@ struct ConnectionState<TRole, TId, TTransform> {
     peers_by_id: HashMap<TId, Arc<Peer<TId, TTransform>>>,
     connection_pool_timeout: BoxFuture<'static, std::time::Instant>,
     sockets: Sockets,
+
+    /// Relays that rejected our credentials, we hand them the next ones the portal sends us.
+    rejected_relays: HashSet<SocketAddr>,
+    /// Relays that cannot make an allocation for us and until when new connections don't use them.
+    unavailable_relays: HashMap<SocketAddr, Instant>,
 }
 
 impl<TRole, TId, TTransform> ConnectionState<TRole, TId, TTransform>
@@ -273,9 +317,21 @@ where
             peers_by_id: HashMap::new(),
             connection_pool_timeout: sleep_until(std::time::Instant::now()).boxed(),
             sockets: Sockets::new()?,
+            rejected_relays: HashSet::new(),
+            unavailable_relays: HashMap::new(),
         })
     }
 
+    /// Whether new connections should use this relay.
+    fn is_relay_usable(&self, relay: &SocketAddr) -> bool {
+        let is_unavailable = self
+            .unavailable_relays
+            .get(relay)
+            .is_some_and(|until| Instant::now() < *until);
+
+        self.sockets.can_handle(relay) && !is_unavailable
+    }
+
     fn send(&mut self, id: TId, packet: IpPacket) {
         let to = packet.destination();
 
@@ -295,7 +351,15 @@ where
         Ok(())
     }
 
-    fn poll_sockets<'a>(&'a mut self, cx: &mut Context<'_>) -> Poll<device_channel::Packet<'a>> {
+    /// Reads the next packet from the sockets.
+    ///
+    /// `consume` sees every decapsulated packet before it is checked against the peer's allowed IPs.
+    /// Packets it returns `true` for are not returned.
+    fn poll_sockets<'a>(
+        &'a mut self,
+        cx: &mut Context<'_>,
+        consume: impl FnOnce(TId, &ip_packet::IpPacket) -> bool,
+    ) -> Poll<device_channel::Packet<'a>> {
         let received = match ready!(self.sockets.poll_recv_from(cx)) {
             Ok(received) => received,
             Err(e) => {
@@ -310,15 +374,27 @@ where
             local,
             from,
             packet,
+            transport,
         } = received;
 
-        let (conn_id, packet) = match self.node.decapsulate(
-            local,
-            from,
-            packet,
-            std::time::Instant::now(),
-            self.write_buf.as_mut(),
-        ) {
+        let result = match transport {
+            Transport::Udp => self.node.decapsulate(
+                local,
+                from,
+                packet,
+                std::time::Instant::now(),
+                self.write_buf.as_mut(),
+            ),
+            Transport::Tcp | Transport::Tls => self.node.decapsulate_stream(
+                local,
+                from,
+                packet,
+                std::time::Instant::now(),
+                self.write_buf.as_mut(),
+            ),
+        };
+
+        let (conn_id, packet) = match result {
             Ok(Some(packet)) => packet,
             Ok(None) => {
                 cx.waker().wake_by_ref();
@@ -334,6 +410,12 @@ where
 
         tracing::trace!(target: "wire", %local, %from, bytes = %packet.packet().len(), "read new packet");
 
+        if ip_packet::IpPacket::new(packet.packet()).is_some_and(|packet| consume(conn_id, &packet))
+        {
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+
         let Some(peer) = self.peers_by_id.get(&conn_id) else {
             tracing::error!(%conn_id, %local, %from, "Couldn't find connection");
 
@@ -361,6 +443,10 @@ where
             tracing::warn!("Failed to poll sockets for readiness: {e}");
         };
 
+        while let Some(server) = self.sockets.next_closed_stream() {
+            self.node.handle_stream_closed(server);
+        }
+
         while let Some(transmit) = self.node.poll_transmit() {
             if let Err(e) = self.sockets.try_send(&transmit) {
                 tracing::warn!(src = ?transmit.src, dst = %transmit.dst, "Failed to send UDP packet: {e}");
@@ -377,11 +463,25 @@ where
                     candidate,
                 });
             }
-            Some(snownet::Event::ConnectionFailed(id)) => {
+            Some(snownet::Event::ConnectionFailed(id))
+            | Some(snownet::Event::ConnectionClosed(id)) => {
                 self.peers_by_id.remove(&id);
                 return Poll::Ready(Event::StopPeer(id));
             }
-            _ => {}
+            Some(snownet::Event::RelayCredentialsRejected { relay }) => {
+                tracing::warn!(%relay, "Relay rejected our credentials, waiting for new ones from the portal");
+
+                self.rejected_relays.insert(relay);
+                cx.waker().wake_by_ref();
+            }
+            Some(snownet::Event::RelayUnavailable { relay }) => {
+                tracing::warn!(%relay, "Relay has no capacity for us, not using it for new connections");
+
+                self.unavailable_relays
+                    .insert(relay, Instant::now() + UNAVAILABLE_RELAY_TIMEOUT);
+                cx.waker().wake_by_ref();
+            }
+            Some(snownet::Event::ConnectionEstablished(_)) | None => {}
         }
 
         if let Poll::Ready(instant) = self.connection_pool_timeout.poll_unpin(cx) {
@@ -1,4 +1,5 @@
 use std::borrow::Cow;
+use std::cmp::Reverse;
 use std::collections::HashMap;
 use std::net::IpAddr;
 use std::sync::Arc;
@@ -8,18 +9,21 @@ use arc_swap::ArcSwap;
 use bimap::BiMap;
 use boringtun::noise::Tunn;
 use chrono::{DateTime, Utc};
-use connlib_shared::messages::DnsServer;
-use connlib_shared::IpProvider;
+use connlib_shared::messages::{DnsServer, ResourceId};
+use connlib_shared::{Dname, IpProvider};
 use connlib_shared::{Error, Result};
 use ip_network::IpNetwork;
 use ip_network_table::IpNetworkTable;
 use parking_lot::{Mutex, RwLock};
 use pnet_packet::Packet;
+use snownet::ConnectionInfo;
 
 use crate::control_protocol::gateway::ResourceDescription;
+use crate::dns;
+use crate::ip_packet::IpPacket;
 use crate::{device_channel, ip_packet::MutableIpPacket};
 
-type ExpiryingResource = (ResourceDescription, Option<DateTime<Utc>>);
+pub(crate) type ExpiryingResource = (ResourceDescription, Option<DateTime<Utc>>);
 
 // The max time a dns request can be configured to live in resolvconf
 // is 30 seconds. See resolvconf(5) timeout.
@@ -36,6 +40,7 @@ pub struct Peer<TId, TTransform> {
 pub struct PeerStats<TId> {
     pub allowed_ips: Vec<IpNetwork>,
     pub conn_id: TId,
+    pub connection: Option<ConnectionInfo>,
 }
 
 impl<TId, TTransform> Peer<TId, TTransform>
@@ -43,11 +48,12 @@ where
     TId: Copy,
     TTransform: PacketTransform,
 {
-    pub fn stats(&self) -> PeerStats<TId> {
+    pub fn stats(&self, connection: Option<ConnectionInfo>) -> PeerStats<TId> {
         let allowed_ips = self.allowed_ips.read().iter().map(|(ip, _)| ip).collect();
         PeerStats {
             allowed_ips,
             conn_id: self.conn_id,
+            connection,
         }
     }
 
@@ -73,7 +79,11 @@ where
         self.allowed_ips.write().insert(ip, ());
     }
 
-    fn is_allowed(&self, addr: IpAddr) -> bool {
+    pub(crate) fn remove_allowed_ip(&self, ip: IpNetwork) {
+        self.allowed_ips.write().remove(ip);
+    }
+
+    pub(crate) fn is_allowed(&self, addr: IpAddr) -> bool {
         self.allowed_ips.read().longest_match(addr).is_some()
     }
 
@@ -99,12 +109,15 @@ where
 
 pub struct PacketTransformGateway {
     resources: RwLock<IpNetworkTable<ExpiryingResource>>,
+    /// The DNS resources the client may send DNS queries for, see [`PacketTransformGateway::dns_resource_for`].
+    dns_resources: RwLock<HashMap<ResourceId, ExpiryingResource>>,
 }
 
 impl Default for PacketTransformGateway {
     fn default() -> Self {
         Self {
             resources: RwLock::new(IpNetworkTable::new()),
+            dns_resources: RwLock::new(HashMap::new()),
         }
     }
 }
@@ -133,6 +146,10 @@ impl PacketTransformClient {
         Some(proxy_ip)
     }
 
+    pub fn remove_translation(&self, proxy_ip: &IpAddr) {
+        self.translations.write().remove_by_left(proxy_ip);
+    }
+
     pub fn expire_dns_track(&self) {
         self.mangled_dns_ids
             .lock()
@@ -146,13 +163,80 @@ impl PacketTransformClient {
 
 impl PacketTransformGateway {
     pub(crate) fn is_emptied(&self) -> bool {
-        self.resources.read().is_empty()
+        self.resources.read().is_empty() && self.dns_resources.read().is_empty()
     }
 
     pub(crate) fn expire_resources(&self) {
         self.resources
             .write()
             .retain(|_, (_, e)| !e.is_some_and(|e| e <= Utc::now()));
+        self.dns_resources
+            .write()
+            .retain(|_, (_, e)| !e.is_some_and(|e| e <= Utc::now()));
+    }
+
+    pub(crate) fn is_resource(&self, ip: IpAddr) -> bool {
+        self.resources.read().longest_match(ip).is_some()
+    }
+
+    /// Allows the client to send DNS queries for the given resource, if it is a DNS resource.
+    ///
+    /// The gateway resolves those queries itself, see [`GatewayState`](crate::GatewayState).
+    pub(crate) fn add_dns_resource(
+        &self,
+        resource: &ResourceDescription,
+        expires_at: Option<DateTime<Utc>>,
+    ) {
+        let ResourceDescription::Dns(dns_resource) = resource else {
+            return;
+        };
+
+        self.dns_resources
+            .write()
+            .insert(dns_resource.id, (resource.clone(), expires_at));
+    }
+
+    /// Returns the DNS resource the given DNS query is for, if the client may have us resolve it.
+    ///
+    /// Only queries sent to a DNS sentinel for record types that are resolved by the gateway qualify, see [`dns::is_resolved_by_gateway`].
+    pub(crate) fn dns_resource_for_query(&self, query: &IpPacket) -> Option<ExpiryingResource> {
+        if !dns::is_sentinel(query.destination()) {
+            return None;
+        }
+
+        let (name, qtype) = dns::query_question(query)?;
+        if !dns::is_resolved_by_gateway(qtype) {
+            return None;
+        }
+
+        self.dns_resource_for(&name)
+    }
+
+    /// Returns the most specific DNS resource the given domain belongs to, if the client is allowed to access it.
+    ///
+    /// Ties are broken by the ID of the resource so the same resource is picked every time.
+    fn dns_resource_for(&self, name: &Dname) -> Option<ExpiryingResource> {
+        self.dns_resources
+            .read()
+            .values()
+            .filter(|(_, expires_at)| !expires_at.is_some_and(|e| e <= Utc::now()))
+            .filter_map(|resource| match &resource.0 {
+                ResourceDescription::Dns(r) if dns::is_subdomain(name, &r.domain) => {
+                    Some((dns::specificity(&r.domain), Reverse(r.id), resource))
+                }
+                _ => None,
+            })
+            .max_by_key(|(specificity, id, _)| (*specificity, *id))
+            .map(|(_, _, resource)| resource.clone())
+    }
+
+    /// Removes all addresses and the DNS resource entry of the given resource.
+    pub(crate) fn remove_resource(&self, id: &ResourceId) {
+        self.resources.write().retain(|_, (r, _)| match r {
+            ResourceDescription::Dns(r) => r.id != *id,
+            ResourceDescription::Cidr(r) => r.id != *id,
+        });
+        self.dns_resources.write().remove(id);
     }
 
     pub(crate) fn add_resource(
@@ -185,7 +269,10 @@ impl PacketTransform for PacketTransformGateway {
             return Err(Error::BadPacket);
         };
 
-        if self.resources.read().longest_match(dst).is_some() {
+        let is_dns_resource_query =
+            || IpPacket::new(packet).is_some_and(|p| self.dns_resource_for_query(&p).is_some());
+
+        if self.is_resource(dst) || is_dns_resource_query() {
             let packet = make_packet(packet, addr);
             Ok((packet, *addr))
         } else {
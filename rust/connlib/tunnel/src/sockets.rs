@@ -1,20 +1,47 @@
-use bytes::Bytes;
+use bytes::{Buf, Bytes, BytesMut};
 use core::slice;
+use futures::future::BoxFuture;
+use futures::task::noop_waker_ref;
+use futures_util::FutureExt;
 use quinn_udp::{RecvMeta, UdpSockRef, UdpSocketState};
-use socket2::{SockAddr, Type};
+use socket2::{Domain, Protocol, SockAddr, Type};
 use std::{
+    collections::HashMap,
     io::{self, IoSliceMut},
     net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    pin::Pin,
+    sync::Arc,
     task::{ready, Context, Poll},
+    time::Duration,
 };
-use tokio::{io::Interest, net::UdpSocket};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, Interest, ReadBuf},
+    net::{TcpSocket, UdpSocket},
+    time::{sleep, Sleep},
+};
+use tokio_rustls::rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
 
 use crate::{Error, Result, MAX_UDP_SIZE};
-use snownet::Transmit;
+use snownet::{StreamDecoder, Transmit, Transport};
+
+/// How long we wait before reconnecting to a relay after the first failure.
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_millis(250);
+/// We double the delay for every failure in a row, up to this maximum.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+/// How many bytes we buffer for a relay before dropping packets, e.g. whilst we are connecting.
+const MAX_STREAM_WRITE_BUFFER: usize = 1024 * 1024;
+
+/// Called with every TCP socket before it connects, e.g. to exclude it from the tunnel on Android.
+pub type ProtectSocket = Arc<dyn Fn(&socket2::Socket) -> io::Result<()> + Send + Sync>;
 
 pub struct Sockets {
     socket_v4: Option<Socket<MAX_UDP_SIZE>>,
     socket_v6: Option<Socket<MAX_UDP_SIZE>>,
+    /// Connections to relays that we talk to over TCP or TLS, see [`Transport::is_stream`].
+    streams: HashMap<SocketAddr, Stream>,
+    tls: TlsConnector,
+    protect_socket: Option<ProtectSocket>,
 }
 
 impl Sockets {
@@ -44,6 +71,9 @@ impl Sockets {
         Ok(Self {
             socket_v4: socket_v4.ok(),
             socket_v6: socket_v6.ok(),
+            streams: HashMap::new(),
+            tls: tls_connector(),
+            protect_socket: None,
         })
     }
 
@@ -68,6 +98,12 @@ impl Sockets {
         self.socket_v6.as_ref().map(|s| s.socket.as_raw_fd())
     }
 
+    /// Sets the function to call with the sockets of all future connections to relays.
+    #[cfg(target_os = "android")]
+    pub fn set_protect_socket(&mut self, protect_socket: ProtectSocket) {
+        self.protect_socket = Some(protect_socket);
+    }
+
     pub fn poll_send_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         if let Some(socket) = self.socket_v4.as_mut() {
             ready!(socket.poll_send_ready(cx))?;
@@ -81,7 +117,20 @@ impl Sockets {
     }
 
     pub fn try_send(&mut self, transmit: &Transmit) -> Result<usize> {
-        tracing::trace!(target: "wire", action = "write", to = %transmit.dst, src = ?transmit.src, bytes = %transmit.payload.len());
+        tracing::trace!(target: "wire", action = "write", to = %transmit.dst, src = ?transmit.src, transport = %transmit.transport, bytes = %transmit.payload.len());
+
+        if transmit.transport.is_stream() {
+            let stream = self.streams.entry(transmit.dst).or_insert_with(|| {
+                Stream::connect(
+                    transmit.dst,
+                    transmit.transport,
+                    self.tls.clone(),
+                    self.protect_socket.clone(),
+                )
+            });
+
+            return Ok(stream.try_send(&transmit.payload)?);
+        }
 
         match transmit.dst {
             SocketAddr::V4(_) => {
@@ -107,7 +156,31 @@ impl Sockets {
             return Poll::Ready(packet);
         }
 
-        Poll::Pending
+        let Some(server) = self
+            .streams
+            .iter_mut()
+            .find_map(|(server, stream)| stream.poll_recv(cx).is_ready().then_some(*server))
+        else {
+            return Poll::Pending;
+        };
+        let stream = &self.streams[&server];
+
+        Poll::Ready(Ok(Received {
+            local: stream.local().expect("connected to receive"),
+            from: server,
+            packet: &stream.message,
+            transport: stream.transport,
+        }))
+    }
+
+    /// Returns a relay whose TCP or TLS connection closed since the last call.
+    ///
+    /// The relay deleted our allocation in that case, see [`snownet::Node::handle_stream_closed`].
+    /// We reconnect on our own once the backoff elapsed.
+    pub fn next_closed_stream(&mut self) -> Option<SocketAddr> {
+        self.streams
+            .iter_mut()
+            .find_map(|(server, stream)| std::mem::take(&mut stream.closed).then_some(*server))
     }
 }
 
@@ -115,6 +188,233 @@ pub struct Received<'a> {
     pub local: SocketAddr,
     pub from: SocketAddr,
     pub packet: &'a [u8],
+    /// UDP or, for relays we talk to over TCP or TLS, a single message split off the stream.
+    pub transport: Transport,
+}
+
+/// A TCP or TLS connection to a relay that reconnects with an exponential backoff.
+struct Stream {
+    server: SocketAddr,
+    transport: Transport,
+    tls: TlsConnector,
+    protect_socket: Option<ProtectSocket>,
+    state: StreamState,
+    /// Bytes we still need to write, e.g. because we are not yet connected.
+    write_buf: BytesMut,
+    decoder: StreamDecoder,
+    /// The last message we split off the stream, see [`Sockets::poll_recv_from`].
+    message: BytesMut,
+    /// How often in a row we failed to connect or lost the connection.
+    num_failures: u32,
+    /// Whether the connection closed and the caller didn't learn about it yet, see [`Sockets::next_closed_stream`].
+    closed: bool,
+}
+
+/// A connected TCP stream, possibly wrapped in TLS.
+trait Connection: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> Connection for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+enum StreamState {
+    Connecting(BoxFuture<'static, io::Result<(Box<dyn Connection>, SocketAddr)>>),
+    Connected {
+        stream: Box<dyn Connection>,
+        local: SocketAddr,
+    },
+    Backoff(Pin<Box<Sleep>>),
+}
+
+impl Stream {
+    fn connect(
+        server: SocketAddr,
+        transport: Transport,
+        tls: TlsConnector,
+        protect_socket: Option<ProtectSocket>,
+    ) -> Self {
+        tracing::debug!(%server, %transport, "Connecting to relay");
+
+        let state = StreamState::Connecting(
+            connect(server, transport, tls.clone(), protect_socket.clone()).boxed(),
+        );
+
+        Self {
+            server,
+            transport,
+            tls,
+            protect_socket,
+            state,
+            write_buf: BytesMut::new(),
+            decoder: StreamDecoder::default(),
+            message: BytesMut::new(),
+            num_failures: 0,
+            closed: false,
+        }
+    }
+
+    fn local(&self) -> Option<SocketAddr> {
+        match &self.state {
+            StreamState::Connected { local, .. } => Some(*local),
+            StreamState::Connecting(_) | StreamState::Backoff(_) => None,
+        }
+    }
+
+    /// Queues the given bytes and writes as many as we can without blocking.
+    fn try_send(&mut self, payload: &[u8]) -> io::Result<usize> {
+        if self.write_buf.len() + payload.len() > MAX_STREAM_WRITE_BUFFER {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "write buffer of relay connection is full",
+            ));
+        }
+
+        self.write_buf.extend_from_slice(payload);
+
+        // We don't have a waker here, `poll_recv` registers one in case we cannot write everything right away.
+        if let Poll::Ready(Err(e)) = self.poll_flush(&mut Context::from_waker(noop_waker_ref())) {
+            self.fail(e);
+        }
+
+        Ok(payload.len())
+    }
+
+    /// Writes [`Stream::write_buf`] to the connection, if we have one.
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let StreamState::Connected { stream, .. } = &mut self.state else {
+            return Poll::Ready(Ok(()));
+        };
+
+        while !self.write_buf.is_empty() {
+            match ready!(Pin::new(&mut *stream).poll_write(cx, &self.write_buf))? {
+                0 => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                n => self.write_buf.advance(n),
+            }
+        }
+
+        // TLS buffers encrypted records until they are flushed.
+        Pin::new(stream).poll_flush(cx)
+    }
+
+    /// Drives the connection and returns `Ready` once we split a message off the stream into [`Stream::message`].
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            match &mut self.state {
+                StreamState::Backoff(sleep) => {
+                    ready!(sleep.poll_unpin(cx));
+
+                    tracing::debug!(server = %self.server, transport = %self.transport, "Reconnecting to relay");
+
+                    self.state = StreamState::Connecting(
+                        connect(
+                            self.server,
+                            self.transport,
+                            self.tls.clone(),
+                            self.protect_socket.clone(),
+                        )
+                        .boxed(),
+                    );
+                }
+                StreamState::Connecting(connect) => match ready!(connect.poll_unpin(cx)) {
+                    Ok((stream, local)) => {
+                        tracing::info!(server = %self.server, transport = %self.transport, %local, "Connected to relay");
+
+                        self.state = StreamState::Connected { stream, local };
+                        self.num_failures = 0;
+                    }
+                    Err(e) => self.fail(e),
+                },
+                StreamState::Connected { .. } => {
+                    if let Poll::Ready(Err(e)) = self.poll_flush(cx) {
+                        self.fail(e);
+                        continue;
+                    }
+
+                    match self.decoder.next_message() {
+                        Ok(Some(message)) => {
+                            self.message = message;
+                            return Poll::Ready(());
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            self.fail(e);
+                            continue;
+                        }
+                    }
+
+                    let StreamState::Connected { stream, .. } = &mut self.state else {
+                        unreachable!()
+                    };
+
+                    let mut buf = [0u8; 4096];
+                    let mut buf = ReadBuf::new(&mut buf);
+
+                    match ready!(Pin::new(stream).poll_read(cx, &mut buf)) {
+                        Ok(()) if buf.filled().is_empty() => {
+                            self.fail(io::ErrorKind::UnexpectedEof.into())
+                        }
+                        Ok(()) => self.decoder.push(buf.filled()),
+                        Err(e) => self.fail(e),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Drops the connection and everything we buffered, and reconnects after a backoff.
+    fn fail(&mut self, error: io::Error) {
+        let delay = INITIAL_RECONNECT_DELAY
+            .saturating_mul(2u32.saturating_pow(self.num_failures))
+            .min(MAX_RECONNECT_DELAY);
+
+        tracing::info!(server = %self.server, ?delay, "Connection to relay failed: {error}");
+
+        self.state = StreamState::Backoff(Box::pin(sleep(delay)));
+        self.write_buf.clear();
+        self.decoder = StreamDecoder::default();
+        self.num_failures = self.num_failures.saturating_add(1);
+        self.closed = true;
+    }
+}
+
+/// Connects to a relay and, for [`Transport::Tls`], performs the TLS handshake.
+///
+/// The relay is only known by its address, thus we verify its certificate against the IP.
+async fn connect(
+    server: SocketAddr,
+    transport: Transport,
+    tls: TlsConnector,
+    protect_socket: Option<ProtectSocket>,
+) -> io::Result<(Box<dyn Connection>, SocketAddr)> {
+    let socket = make_tcp_socket(server)?;
+
+    if let Some(protect_socket) = protect_socket {
+        protect_socket(&socket)?;
+    }
+
+    let stream = TcpSocket::from_std_stream(socket.into())
+        .connect(server)
+        .await?;
+    let local = stream.local_addr()?;
+
+    if transport != Transport::Tls {
+        return Ok((Box::new(stream), local));
+    }
+
+    let stream = tls
+        .connect(ServerName::IpAddress(server.ip().into()), stream)
+        .await?;
+
+    Ok((Box::new(stream), local))
+}
+
+fn tls_connector() -> TlsConnector {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    TlsConnector::from(Arc::new(config))
 }
 
 struct Socket<const N: usize> {
@@ -184,6 +484,7 @@ impl<const N: usize> Socket<N> {
                     local,
                     from: meta.addr,
                     packet: &mut buffer[..meta.len],
+                    transport: Transport::Udp,
                 }));
             }
         }
@@ -212,6 +513,23 @@ impl<const N: usize> Socket<N> {
     }
 }
 
+fn make_tcp_socket(server: SocketAddr) -> io::Result<socket2::Socket> {
+    let socket = socket2::Socket::new(
+        Domain::for_address(server),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+
+    #[cfg(target_os = "linux")]
+    {
+        socket.set_mark(crate::FIREZONE_MARK)?;
+    }
+
+    socket.set_nonblocking(true)?;
+
+    Ok(socket)
+}
+
 fn make_socket(addr: impl Into<SocketAddr>) -> Result<std::net::UdpSocket> {
     let addr: SockAddr = addr.into().into();
     let socket = socket2::Socket::new(addr.domain(), Type::DGRAM, None)?;
@@ -231,3 +549,136 @@ fn make_socket(addr: impl Into<SocketAddr>) -> Result<std::net::UdpSocket> {
 
     Ok(socket.into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::{poll_fn, select, Either};
+    use std::borrow::Cow;
+    use std::future::Future;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn talks_to_relay_over_tcp_and_reports_closed_connection() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server = listener.local_addr().unwrap();
+            let mut sockets = Sockets::new().unwrap();
+
+            sockets
+                .try_send(&Transmit {
+                    src: None,
+                    dst: server,
+                    payload: Cow::Borrowed(&[0x40, 0x00, 0x00, 0x01, 0xFF, 0x00, 0x00, 0x00]),
+                    transport: Transport::Tcp,
+                })
+                .unwrap();
+
+            let (mut relay, _) = drive(&mut sockets, listener.accept()).await.unwrap();
+
+            let mut channel_data = [0u8; 8];
+            drive(&mut sockets, relay.read_exact(&mut channel_data))
+                .await
+                .unwrap();
+            assert_eq!(
+                channel_data,
+                [0x40, 0x00, 0x00, 0x01, 0xFF, 0x00, 0x00, 0x00]
+            );
+
+            let binding_request = [0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xA4, 0x42];
+            relay.write_all(&binding_request).await.unwrap();
+            relay.write_all(&[0u8; 12]).await.unwrap();
+
+            let (from, packet, transport) = poll_fn(|cx| {
+                sockets
+                    .poll_recv_from(cx)
+                    .map_ok(|r| (r.from, r.packet.to_vec(), r.transport))
+            })
+            .await
+            .unwrap();
+            assert_eq!(from, server);
+            assert_eq!(packet.len(), 20);
+            assert_eq!(&packet[..8], &binding_request);
+            assert_eq!(transport, Transport::Tcp);
+            assert_eq!(sockets.next_closed_stream(), None);
+
+            drop(relay);
+
+            let closed = poll_fn(|cx| {
+                let _ = sockets.poll_recv_from(cx);
+
+                match sockets.next_closed_stream() {
+                    Some(closed) => Poll::Ready(closed),
+                    None => Poll::Pending,
+                }
+            })
+            .await;
+            assert_eq!(closed, server);
+        });
+    }
+
+    #[test]
+    fn failed_tls_handshake_reports_closed_connection() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server = listener.local_addr().unwrap();
+            let mut sockets = Sockets::new().unwrap();
+
+            sockets
+                .try_send(&Transmit {
+                    src: None,
+                    dst: server,
+                    payload: Cow::Borrowed(&[0x40, 0x00, 0x00, 0x00]),
+                    transport: Transport::Tls,
+                })
+                .unwrap();
+
+            let (mut relay, _) = drive(&mut sockets, listener.accept()).await.unwrap();
+
+            // Not a TLS server, so the first bytes are the handshake and not our channel data.
+            let mut client_hello = [0u8; 1];
+            drive(&mut sockets, relay.read_exact(&mut client_hello))
+                .await
+                .unwrap();
+            assert_eq!(client_hello, [0x16]);
+
+            drop(relay);
+
+            let closed = poll_fn(|cx| {
+                let _ = sockets.poll_recv_from(cx);
+
+                match sockets.next_closed_stream() {
+                    Some(closed) => Poll::Ready(closed),
+                    None => Poll::Pending,
+                }
+            })
+            .await;
+            assert_eq!(closed, server);
+        });
+    }
+
+    /// Polls the sockets whilst waiting for `future`, e.g. to let them connect to a relay.
+    async fn drive<T>(sockets: &mut Sockets, future: impl Future<Output = T>) -> T {
+        let poll_sockets = poll_fn(|cx| {
+            let _ = sockets.poll_recv_from(cx);
+
+            Poll::<()>::Pending
+        });
+
+        match select(Box::pin(future), Box::pin(poll_sockets)).await {
+            Either::Left((output, _)) => output,
+            Either::Right(((), _)) => unreachable!("never ready"),
+        }
+    }
+}
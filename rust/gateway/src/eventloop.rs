@@ -1,6 +1,6 @@
 use crate::messages::{
     AllowAccess, BroadcastClientIceCandidates, ClientIceCandidates, ConnectionReady,
-    EgressMessages, IngressMessages, RequestConnection,
+    EgressMessages, IngressMessages, RejectAccess, RequestConnection,
 };
 use crate::CallbackHandler;
 use anyhow::{anyhow, bail, Result};
@@ -188,6 +188,17 @@ impl Eventloop {
 
                     continue;
                 }
+                Poll::Ready(phoenix_channel::Event::InboundMessage {
+                    msg:
+                        IngressMessages::RejectAccess(RejectAccess {
+                            client_id,
+                            resource_id,
+                        }),
+                    ..
+                }) => {
+                    self.tunnel.remove_access(&client_id, &resource_id);
+                    continue;
+                }
                 Poll::Ready(phoenix_channel::Event::InboundMessage {
                     msg:
                         IngressMessages::IceCandidates(ClientIceCandidates {
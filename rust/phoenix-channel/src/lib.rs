@@ -225,6 +225,11 @@ where
         self.pending_join_requests.insert(request_id);
     }
 
+    /// Replaces the payload used when re-joining the room after a reconnect.
+    pub fn set_init_req(&mut self, init_req: TInitReq) {
+        self.init_req = init_req;
+    }
+
     /// Send a message to a topic.
     pub fn send(&mut self, topic: impl Into<String>, message: impl Serialize) -> OutboundRequestId {
         self.send_message(topic, message)
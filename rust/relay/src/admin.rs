@@ -0,0 +1,186 @@
+//! A local HTTP API for operators to inspect and revoke allocations.
+//!
+//! The [`Server`](crate::Server) is owned by the event-loop, thus the API forwards each [`Request`] via a channel and awaits the response.
+
+use crate::{AllocationId, AllocationInfo, ChannelInfo};
+use anyhow::Result;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use futures::channel::{mpsc, oneshot};
+use futures::SinkExt;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
+/// A request from the admin API that needs to be handled by the event-loop.
+#[derive(Debug)]
+pub enum Request {
+    ListAllocations {
+        reply: oneshot::Sender<Vec<AllocationInfo>>,
+    },
+    RevokeAllocation {
+        id: AllocationId,
+        reply: oneshot::Sender<bool>,
+    },
+    RevokeAllocationsOfUsername {
+        username: String,
+        reply: oneshot::Sender<usize>,
+    },
+    /// Start draining the relay, see [`Server::start_draining`](crate::Server::start_draining).
+    Drain { reply: oneshot::Sender<SystemTime> },
+}
+
+/// Serves the admin API:
+///
+/// - `GET /allocations`: Lists all allocations.
+/// - `DELETE /allocations/:id`: Revokes a single allocation.
+/// - `DELETE /usernames/:username/allocations`: Revokes all allocations of a username.
+/// - `POST /drain`: Stops accepting new allocations and shuts down once all existing allocations are gone.
+///
+/// This API is unauthenticated and must only be exposed on a local interface.
+pub async fn serve(addr: impl Into<SocketAddr>, requests: mpsc::Sender<Request>) -> Result<()> {
+    let addr = addr.into();
+
+    let service = Router::new()
+        .route("/allocations", get(list_allocations))
+        .route("/allocations/:id", delete(revoke_allocation))
+        .route(
+            "/usernames/:username/allocations",
+            delete(revoke_allocations_of_username),
+        )
+        .route("/drain", post(drain))
+        .with_state(requests)
+        .into_make_service();
+
+    axum::serve(tokio::net::TcpListener::bind(addr).await?, service).await?;
+
+    Ok(())
+}
+
+async fn list_allocations(
+    State(requests): State<mpsc::Sender<Request>>,
+) -> Result<Json<Vec<Allocation>>, StatusCode> {
+    let allocations = send(requests, |reply| Request::ListAllocations { reply }).await?;
+
+    Ok(Json(
+        allocations.into_iter().map(Allocation::from).collect(),
+    ))
+}
+
+async fn revoke_allocation(
+    State(requests): State<mpsc::Sender<Request>>,
+    Path(id): Path<String>,
+) -> StatusCode {
+    let Ok(id) = id.parse() else {
+        return StatusCode::BAD_REQUEST;
+    };
+
+    match send(requests, |reply| Request::RevokeAllocation { id, reply }).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(code) => code,
+    }
+}
+
+async fn revoke_allocations_of_username(
+    State(requests): State<mpsc::Sender<Request>>,
+    Path(username): Path<String>,
+) -> Result<Json<Revoked>, StatusCode> {
+    let revoked = send(requests, |reply| Request::RevokeAllocationsOfUsername {
+        username,
+        reply,
+    })
+    .await?;
+
+    Ok(Json(Revoked { revoked }))
+}
+
+async fn drain(
+    State(requests): State<mpsc::Sender<Request>>,
+) -> Result<Json<Draining>, StatusCode> {
+    let deadline = send(requests, |reply| Request::Drain { reply }).await?;
+
+    Ok(Json(Draining {
+        deadline: unix_timestamp(deadline),
+    }))
+}
+
+/// Sends a request to the event-loop and waits for its reply.
+async fn send<T>(
+    mut requests: mpsc::Sender<Request>,
+    make_request: impl FnOnce(oneshot::Sender<T>) -> Request,
+) -> Result<T, StatusCode> {
+    let (reply, response) = oneshot::channel();
+
+    requests
+        .send(make_request(reply))
+        .await
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+
+    response.await.map_err(|_| StatusCode::SERVICE_UNAVAILABLE)
+}
+
+#[derive(Serialize)]
+struct Allocation {
+    id: String,
+    client: String,
+    relay_addresses: Vec<SocketAddr>,
+    /// Seconds since the UNIX epoch.
+    expires_at: u64,
+    username: String,
+    channels: Vec<Channel>,
+    relayed_bytes: u64,
+}
+
+#[derive(Serialize)]
+struct Channel {
+    number: u16,
+    peer: SocketAddr,
+    /// Seconds since the UNIX epoch.
+    expires_at: u64,
+    bound: bool,
+}
+
+#[derive(Serialize)]
+struct Revoked {
+    revoked: usize,
+}
+
+#[derive(Serialize)]
+struct Draining {
+    /// Seconds since the UNIX epoch.
+    deadline: u64,
+}
+
+impl From<AllocationInfo> for Allocation {
+    fn from(info: AllocationInfo) -> Self {
+        Self {
+            id: info.id.to_string(),
+            client: info.client.to_string(),
+            relay_addresses: info.relay_addresses,
+            expires_at: unix_timestamp(info.expires_at),
+            username: info.username,
+            channels: info.channels.into_iter().map(Channel::from).collect(),
+            relayed_bytes: info.relayed_bytes,
+        }
+    }
+}
+
+impl From<ChannelInfo> for Channel {
+    fn from(info: ChannelInfo) -> Self {
+        Self {
+            number: info.number,
+            peer: info.peer.into_socket(),
+            expires_at: unix_timestamp(info.expires_at),
+            bound: info.bound,
+        }
+    }
+}
+
+fn unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
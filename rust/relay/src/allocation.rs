@@ -1,6 +1,6 @@
 use crate::server::AllocationId;
 use crate::udp_socket::UdpSocket;
-use crate::{AddressFamily, PeerSocket};
+use crate::{AddressFamily, FastPath, PeerSocket};
 use anyhow::{bail, Result};
 use futures::channel::mpsc;
 use futures::{SinkExt, StreamExt};
@@ -12,46 +12,59 @@ const MAX_BUFFERED_ITEMS: usize = 1000;
 
 pub struct Allocation {
     id: AllocationId,
+    family: AddressFamily,
 
     /// The handle to the task that is running the allocation.
     ///
     /// Stored here to make resource-cleanup easy.
     handle: task::JoinHandle<()>,
     sender: mpsc::Sender<(Vec<u8>, PeerSocket)>,
+    fast_path: FastPath,
 }
 
 impl Allocation {
+    /// Binds the socket of the allocation and registers it with the [`FastPath`].
     pub fn new(
         relay_data_sender: mpsc::Sender<(Vec<u8>, PeerSocket, AllocationId)>,
+        fast_path: FastPath,
         id: AllocationId,
         family: AddressFamily,
         port: u16,
-    ) -> Self {
+    ) -> Result<Self> {
         let (client_to_peer_sender, client_to_peer_receiver) = mpsc::channel(MAX_BUFFERED_ITEMS);
 
-        let task = tokio::spawn(async move {
-            let Err(e) = forward_incoming_relay_data(
-                relay_data_sender,
-                client_to_peer_receiver,
-                id,
-                family,
-                port,
-            )
-            .await
-            else {
-                unreachable!()
-            };
-
-            tracing::warn!(allocation = %id, %family, "Allocation task failed: {e:#}");
-
-            // With the task stopping, the channel will be closed and any attempt to send data to it will fail.
+        let socket = UdpSocket::bind(family, port)?;
+        fast_path.add_relay_socket(id, family, socket.sender());
+
+        let task = tokio::spawn({
+            let fast_path = fast_path.clone();
+
+            async move {
+                let Err(e) = forward_incoming_relay_data(
+                    socket,
+                    relay_data_sender,
+                    client_to_peer_receiver,
+                    fast_path,
+                    id,
+                )
+                .await
+                else {
+                    unreachable!()
+                };
+
+                tracing::warn!(allocation = %id, %family, "Allocation task failed: {e:#}");
+
+                // With the task stopping, the channel will be closed and any attempt to send data to it will fail.
+            }
         });
 
-        Self {
+        Ok(Self {
             id,
+            family,
             handle: task,
             sender: client_to_peer_sender,
-        }
+            fast_path,
+        })
     }
 
     /// Send data to a peer on this allocation.
@@ -83,24 +96,29 @@ impl Allocation {
 
 impl Drop for Allocation {
     fn drop(&mut self) {
+        self.fast_path.remove_relay_socket(self.id, self.family);
         self.handle.abort();
     }
 }
 
 async fn forward_incoming_relay_data(
+    mut socket: UdpSocket,
     mut relayed_data_sender: mpsc::Sender<(Vec<u8>, PeerSocket, AllocationId)>,
     mut client_to_peer_receiver: mpsc::Receiver<(Vec<u8>, PeerSocket)>,
+    fast_path: FastPath,
     id: AllocationId,
-    family: AddressFamily,
-    port: u16,
 ) -> Result<Infallible> {
-    let mut socket = UdpSocket::bind(family, port)?;
-
     loop {
         tokio::select! {
             result = socket.recv() => {
                 let (data, sender) = result?;
-                relayed_data_sender.send((data.to_vec(), PeerSocket::new(sender), id)).await?;
+                let sender = PeerSocket::new(sender);
+
+                if fast_path.relay_to_client(data, sender, id) {
+                    continue;
+                }
+
+                relayed_data_sender.send((data.to_vec(), sender, id)).await?;
             }
 
             Some((data, recipient)) = client_to_peer_receiver.next() => {
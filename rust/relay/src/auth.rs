@@ -6,7 +6,8 @@ use sha2::digest::FixedOutput;
 use sha2::Sha256;
 use std::borrow::ToOwned;
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::iter;
 use std::time::{Duration, SystemTime};
 use stun_codec::rfc5389::attributes::{MessageIntegrity, Realm, Username};
 use uuid::Uuid;
@@ -15,21 +16,11 @@ use uuid::Uuid;
 pub static FIREZONE: Lazy<Realm> = Lazy::new(|| Realm::new("firezone".to_owned()).unwrap());
 
 pub trait MessageIntegrityExt {
-    fn verify(
-        &self,
-        relay_secret: &SecretString,
-        username: &str,
-        now: SystemTime,
-    ) -> Result<(), Error>;
+    fn verify(&self, secrets: &AuthSecrets, username: &str, now: SystemTime) -> Result<(), Error>;
 }
 
 impl MessageIntegrityExt for MessageIntegrity {
-    fn verify(
-        &self,
-        relay_secret: &SecretString,
-        username: &str,
-        now: SystemTime,
-    ) -> Result<(), Error> {
+    fn verify(&self, secrets: &AuthSecrets, username: &str, now: SystemTime) -> Result<(), Error> {
         let (expiry_unix_timestamp, salt) = split_username(username)?;
         let expired = systemtime_from_unix(expiry_unix_timestamp);
 
@@ -37,20 +28,106 @@ impl MessageIntegrityExt for MessageIntegrity {
             return Err(Error::Expired);
         }
 
-        let password = generate_password(relay_secret, expired, salt);
+        if secrets.is_revoked(username) {
+            return Err(Error::Revoked);
+        }
 
-        self.check_long_term_credential(
-            &Username::new(format!("{}:{}", expiry_unix_timestamp, salt))
-                .map_err(|_| Error::InvalidUsername)?,
-            &FIREZONE,
-            &password,
-        )
-        .map_err(|_| Error::InvalidPassword)?;
+        let username = Username::new(format!("{}:{}", expiry_unix_timestamp, salt))
+            .map_err(|_| Error::InvalidUsername)?;
+
+        let is_valid = secrets.valid_secrets(now).any(|relay_secret| {
+            let password = generate_password(relay_secret, expired, salt);
+
+            self.check_long_term_credential(&username, &FIREZONE, &password)
+                .is_ok()
+        });
+
+        if !is_valid {
+            return Err(Error::InvalidPassword);
+        }
 
         Ok(())
     }
 }
 
+/// The secrets used to verify the credentials of clients.
+///
+/// Upon rotation, the previous secret remains valid for an overlap window.
+/// This allows clients to keep using credentials that were stamped with the previous secret until the portal hands out new ones.
+pub struct AuthSecrets {
+    current: SecretString,
+    /// The previous secret and until when it remains valid.
+    previous: Option<(SecretString, SystemTime)>,
+    /// Usernames that are rejected even if their credentials are valid.
+    revoked_usernames: HashSet<String>,
+}
+
+impl AuthSecrets {
+    pub fn new(current: SecretString) -> Self {
+        Self {
+            current,
+            previous: None,
+            revoked_usernames: Default::default(),
+        }
+    }
+
+    /// Restores the secrets from a [`Snapshot`](crate::Snapshot).
+    pub fn restore(
+        current: SecretString,
+        previous: Option<(SecretString, SystemTime)>,
+        revoked_usernames: impl IntoIterator<Item = String>,
+    ) -> Self {
+        Self {
+            current,
+            previous,
+            revoked_usernames: revoked_usernames.into_iter().collect(),
+        }
+    }
+
+    pub fn current(&self) -> &SecretString {
+        &self.current
+    }
+
+    /// Returns the previous secret if it is still valid.
+    pub fn previous(&self, now: SystemTime) -> Option<(&SecretString, SystemTime)> {
+        self.previous
+            .as_ref()
+            .filter(|(_, valid_until)| *valid_until >= now)
+            .map(|(secret, valid_until)| (secret, *valid_until))
+    }
+
+    pub fn revoked_usernames(&self) -> impl Iterator<Item = &str> {
+        self.revoked_usernames.iter().map(String::as_str)
+    }
+
+    /// Replaces the current secret, keeping the previous one valid until `overlap_until`.
+    pub fn rotate(&mut self, new: SecretString, overlap_until: SystemTime) {
+        let previous = std::mem::replace(&mut self.current, new);
+
+        self.previous = Some((previous, overlap_until));
+    }
+
+    /// Revokes a username until it expires.
+    pub fn revoke_username(&mut self, username: &str, now: SystemTime) {
+        // Credentials are only valid until their expiry anyway, so we can forget about revoked usernames once they expired.
+        self.revoked_usernames.retain(|revoked| {
+            split_username(revoked).map_or(false, |(expiry, _)| systemtime_from_unix(expiry) >= now)
+        });
+
+        self.revoked_usernames.insert(username.to_owned());
+    }
+
+    fn is_revoked(&self, username: &str) -> bool {
+        self.revoked_usernames.contains(username)
+    }
+
+    fn valid_secrets(&self, now: SystemTime) -> impl Iterator<Item = &SecretString> {
+        let previous = self.previous(now).map(|(secret, _)| secret);
+
+        iter::once(&self.current).chain(previous)
+    }
+}
+
 /// Tracks valid nonces for the TURN relay.
 ///
 /// The semantic nature of nonces is an implementation detail of the relay in TURN.
@@ -71,6 +148,19 @@ impl Nonces {
         self.inner.insert(nonce, Self::NUM_REQUESTS);
     }
 
+    /// Restores a nonce from a [`Snapshot`](crate::Snapshot).
+    pub fn restore(&mut self, nonce: Uuid, remaining_requests: u64) {
+        self.inner
+            .insert(nonce, remaining_requests.min(Self::NUM_REQUESTS));
+    }
+
+    /// Returns all nonces together with the number of remaining requests.
+    pub fn iter(&self) -> impl Iterator<Item = (Uuid, u64)> + '_ {
+        self.inner
+            .iter()
+            .map(|(nonce, remaining_requests)| (*nonce, *remaining_requests))
+    }
+
     /// Record the usage of a nonce in a request.
     pub fn handle_nonce_used(&mut self, nonce: Uuid) -> Result<(), Error> {
         let mut entry = match self.inner.entry(nonce) {
@@ -98,6 +188,7 @@ pub enum Error {
     InvalidPassword,
     InvalidUsername,
     InvalidNonce,
+    Revoked,
 }
 
 pub(crate) fn split_username(username: &str) -> Result<(u64, &str), Error> {
@@ -112,7 +203,10 @@ pub(crate) fn split_username(username: &str) -> Result<(u64, &str), Error> {
     Ok((expiry_unix_timestamp, username_salt))
 }
 
-pub(crate) fn generate_password(
+/// Generates the password for a username of the form `<expiry>:<username_salt>`.
+///
+/// This is what the portal does when handing out credentials for a relay.
+pub fn generate_password(
     relay_secret: &SecretString,
     expiry: SystemTime,
     username_salt: &str,
@@ -182,7 +276,7 @@ mod tests {
         );
 
         let result = message_integrity.verify(
-            &RELAY_SECRET_1.parse().unwrap(),
+            &AuthSecrets::new(RELAY_SECRET_1.parse().unwrap()),
             "1685200000:n23JJ2wKKtt30oXi",
             systemtime_from_unix(1685200000 - 1000),
         );
@@ -199,7 +293,7 @@ mod tests {
         );
 
         let result = message_integrity.verify(
-            &RELAY_SECRET_1.parse().unwrap(),
+            &AuthSecrets::new(RELAY_SECRET_1.parse().unwrap()),
             "1685199000:n23JJ2wKKtt30oXi",
             systemtime_from_unix(1685200000),
         );
@@ -216,7 +310,7 @@ mod tests {
         );
 
         let result = message_integrity.verify(
-            &RELAY_SECRET_1.parse().unwrap(),
+            &AuthSecrets::new(RELAY_SECRET_1.parse().unwrap()),
             "1685200000:n23JJ2wKKtt30oXi",
             systemtime_from_unix(168520000 + 1000),
         );
@@ -233,7 +327,7 @@ mod tests {
         );
 
         let result = message_integrity.verify(
-            &RELAY_SECRET_1.parse().unwrap(),
+            &AuthSecrets::new(RELAY_SECRET_1.parse().unwrap()),
             "foobar",
             systemtime_from_unix(168520000 + 1000),
         );
@@ -241,6 +335,51 @@ mod tests {
         assert_eq!(result.unwrap_err(), Error::InvalidUsername)
     }
 
+    #[test]
+    fn previous_secret_is_valid_during_overlap() {
+        let message_integrity = message_integrity(
+            &RELAY_SECRET_1.parse().unwrap(),
+            1685200000,
+            "n23JJ2wKKtt30oXi",
+        );
+        let now = systemtime_from_unix(1685200000 - 1000);
+
+        let mut secrets = AuthSecrets::new(RELAY_SECRET_1.parse().unwrap());
+        secrets.rotate(
+            RELAY_SECRET_2.parse().unwrap(),
+            now + Duration::from_secs(60),
+        );
+
+        message_integrity
+            .verify(&secrets, "1685200000:n23JJ2wKKtt30oXi", now)
+            .expect("credentials to be valid");
+
+        let result = message_integrity.verify(
+            &secrets,
+            "1685200000:n23JJ2wKKtt30oXi",
+            now + Duration::from_secs(61),
+        );
+
+        assert_eq!(result.unwrap_err(), Error::InvalidPassword)
+    }
+
+    #[test]
+    fn revoked_username_is_not_valid() {
+        let message_integrity = message_integrity(
+            &RELAY_SECRET_1.parse().unwrap(),
+            1685200000,
+            "n23JJ2wKKtt30oXi",
+        );
+        let now = systemtime_from_unix(1685200000 - 1000);
+
+        let mut secrets = AuthSecrets::new(RELAY_SECRET_1.parse().unwrap());
+        secrets.revoke_username("1685200000:n23JJ2wKKtt30oXi", now);
+
+        let result = message_integrity.verify(&secrets, "1685200000:n23JJ2wKKtt30oXi", now);
+
+        assert_eq!(result.unwrap_err(), Error::Revoked)
+    }
+
     #[test]
     fn nonces_are_valid_for_10_requests() {
         let mut nonces = Nonces::default();
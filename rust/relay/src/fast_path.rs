@@ -0,0 +1,382 @@
+//! Relaying of channel data without going through the event-loop.
+//!
+//! All allocation and channel state is owned by the [`Server`](crate::Server) which runs on a single task.
+//! To spread the data plane across several threads, the [`Server`](crate::Server) publishes all bound channels of UDP clients into a [`FastPath`].
+//! The socket tasks consult it for every datagram and relay channel data directly, only handing everything else to the event-loop.
+//!
+//! Because the [`Server`](crate::Server) updates the [`FastPath`] in the same step as its own state, a channel can never be used on the fast path after the [`Server`](crate::Server) considers it unbound.
+//! Likewise, each channel shares the [`Quotas`] of its allocation and username with the [`Server`](crate::Server), so rate limits apply to all traffic regardless of which task relays it.
+
+use crate::rate_limit::Quotas;
+use crate::udp_socket::UdpSender;
+use crate::{AddressFamily, AllocationId, ChannelData, ClientSocket, PeerSocket, Transport};
+use opentelemetry::metrics::Counter;
+use opentelemetry::KeyValue;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+/// The routes for relaying channel data, shared between the [`Server`](crate::Server) and all socket tasks.
+#[derive(Clone)]
+pub struct FastPath {
+    routes: Arc<RwLock<Routes>>,
+
+    data_relayed_counter: Counter<u64>,
+    data_relayed: Arc<AtomicU64>, // Keep a separate counter because `Counter` doesn't expose the current value :(
+    rate_limited_packets_counter: Counter<u64>,
+}
+
+#[derive(Default)]
+struct Routes {
+    /// Bound channels, indexed by the client's address and the channel number.
+    by_client_and_channel: HashMap<(SocketAddr, u16), ToPeer>,
+    /// Bound channels, indexed by allocation and peer.
+    by_allocation_and_peer: HashMap<(AllocationId, PeerSocket), ToClient>,
+
+    /// The sockets of all allocations.
+    relay_sockets: HashMap<(AllocationId, AddressFamily), UdpSender>,
+    /// The sockets listening on port 3478, used to send data to clients.
+    main_sockets: HashMap<AddressFamily, UdpSender>,
+}
+
+struct ToPeer {
+    allocation: AllocationId,
+    peer: PeerSocket,
+    relayed_bytes: Arc<AtomicU64>,
+    quotas: Quotas,
+}
+
+struct ToClient {
+    client: SocketAddr,
+    channel: u16,
+    relayed_bytes: Arc<AtomicU64>,
+    quotas: Quotas,
+}
+
+impl FastPath {
+    pub(crate) fn new(
+        data_relayed_counter: Counter<u64>,
+        rate_limited_packets_counter: Counter<u64>,
+    ) -> Self {
+        Self {
+            routes: Default::default(),
+            data_relayed_counter,
+            data_relayed: Default::default(),
+            rate_limited_packets_counter,
+        }
+    }
+
+    /// Registers the socket of an allocation so channel data can be sent through it.
+    pub fn add_relay_socket(&self, id: AllocationId, family: AddressFamily, socket: UdpSender) {
+        self.write().relay_sockets.insert((id, family), socket);
+    }
+
+    pub fn remove_relay_socket(&self, id: AllocationId, family: AddressFamily) {
+        self.write().relay_sockets.remove(&(id, family));
+    }
+
+    /// Registers a socket listening on port 3478 so channel data can be sent to clients through it.
+    ///
+    /// With `SO_REUSEPORT`, it doesn't matter which of the sockets bound to the port we use.
+    pub fn set_main_socket(&self, family: AddressFamily, socket: UdpSender) {
+        self.write().main_sockets.insert(family, socket);
+    }
+
+    /// Attempts to relay a datagram received from a client on port 3478 to a peer.
+    ///
+    /// Returns `false` if the datagram is not channel data for a bound channel, in which case it needs to be handled by the [`Server`](crate::Server).
+    pub fn relay_to_peer(&self, datagram: &[u8], sender: SocketAddr) -> bool {
+        let Ok(message) = ChannelData::parse(datagram) else {
+            return false;
+        };
+
+        let routes = self.read();
+
+        let Some(route) = routes
+            .by_client_and_channel
+            .get(&(sender, message.channel()))
+        else {
+            return false;
+        };
+        let Some(socket) = routes
+            .relay_sockets
+            .get(&(route.allocation, route.peer.family()))
+        else {
+            return false;
+        };
+
+        let data = message.data();
+
+        if !self.consume_quota(&route.quotas, data.len()) {
+            return true;
+        }
+
+        if let Err(e) = socket.try_send_to(data, route.peer.into_socket()) {
+            log_send_error(e, route.peer.into_socket());
+            return true;
+        }
+
+        self.record_relayed_bytes(data.len(), Some(&route.relayed_bytes));
+
+        true
+    }
+
+    /// Attempts to relay a datagram received on the given allocation to the client.
+    ///
+    /// Returns `false` if the client has no channel bound to the peer, in which case it needs to be handled by the [`Server`](crate::Server).
+    pub fn relay_to_client(&self, datagram: &[u8], peer: PeerSocket, id: AllocationId) -> bool {
+        let routes = self.read();
+
+        let Some(route) = routes.by_allocation_and_peer.get(&(id, peer)) else {
+            return false;
+        };
+        let Some(socket) = routes
+            .main_sockets
+            .get(&ClientSocket::new(route.client).family())
+        else {
+            return false;
+        };
+
+        if !self.consume_quota(&route.quotas, datagram.len()) {
+            return true;
+        }
+
+        let message = ChannelData::new(route.channel, datagram).to_bytes();
+
+        if let Err(e) = socket.try_send_to(&message, route.client) {
+            log_send_error(e, route.client);
+            return true;
+        }
+
+        self.record_relayed_bytes(datagram.len(), Some(&route.relayed_bytes));
+
+        true
+    }
+
+    pub(crate) fn add_channel(
+        &self,
+        client: ClientSocket,
+        channel: u16,
+        allocation: AllocationId,
+        peer: PeerSocket,
+        relayed_bytes: Arc<AtomicU64>,
+        quotas: Quotas,
+    ) {
+        // Clients talking TCP or TLS are served by their connection's task.
+        if client.transport() != Transport::Udp {
+            return;
+        }
+
+        let mut routes = self.write();
+
+        routes.by_client_and_channel.insert(
+            (client.into_socket(), channel),
+            ToPeer {
+                allocation,
+                peer,
+                relayed_bytes: relayed_bytes.clone(),
+                quotas: quotas.clone(),
+            },
+        );
+        routes.by_allocation_and_peer.insert(
+            (allocation, peer),
+            ToClient {
+                client: client.into_socket(),
+                channel,
+                relayed_bytes,
+                quotas,
+            },
+        );
+    }
+
+    pub(crate) fn remove_channel(&self, client: ClientSocket, channel: u16) {
+        let mut routes = self.write();
+
+        let Some(route) = routes
+            .by_client_and_channel
+            .remove(&(client.into_socket(), channel))
+        else {
+            return;
+        };
+
+        routes
+            .by_allocation_and_peer
+            .remove(&(route.allocation, route.peer));
+    }
+
+    pub(crate) fn remove_allocation(&self, id: AllocationId) {
+        let mut routes = self.write();
+
+        routes
+            .by_client_and_channel
+            .retain(|_, route| route.allocation != id);
+        routes
+            .by_allocation_and_peer
+            .retain(|(allocation, _), _| *allocation != id);
+    }
+
+    pub(crate) fn record_relayed_bytes(&self, num_bytes: usize, allocation: Option<&AtomicU64>) {
+        self.data_relayed_counter.add(num_bytes as u64, &[]);
+        self.data_relayed
+            .fetch_add(num_bytes as u64, Ordering::Relaxed);
+
+        if let Some(allocation) = allocation {
+            allocation.fetch_add(num_bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Drops the packet if it exceeds the rate limits of its allocation or username.
+    fn consume_quota(&self, quotas: &Quotas, num_bytes: usize) -> bool {
+        let Err(scope) = quotas.try_consume(num_bytes, SystemTime::now()) else {
+            return true;
+        };
+
+        tracing::debug!(target: "relay", "{} exceeded its rate limit, dropping {num_bytes} bytes", scope.as_str());
+        self.rate_limited_packets_counter
+            .add(1, &[KeyValue::new("scope", scope.as_str())]);
+
+        false
+    }
+
+    pub(crate) fn num_relayed_bytes(&self) -> u64 {
+        self.data_relayed.load(Ordering::Relaxed)
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, Routes> {
+        self.routes.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, Routes> {
+        self.routes.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    #[cfg(test)]
+    fn route_to_peer(
+        &self,
+        client: SocketAddr,
+        channel: u16,
+    ) -> Option<(AllocationId, PeerSocket)> {
+        self.read()
+            .by_client_and_channel
+            .get(&(client, channel))
+            .map(|route| (route.allocation, route.peer))
+    }
+
+    #[cfg(test)]
+    fn route_to_client(&self, id: AllocationId, peer: PeerSocket) -> Option<(SocketAddr, u16)> {
+        self.read()
+            .by_allocation_and_peer
+            .get(&(id, peer))
+            .map(|route| (route.client, route.channel))
+    }
+}
+
+/// All our data is relayed over UDP, thus we simply drop the packet if the socket is not keeping up.
+fn log_send_error(e: io::Error, recipient: SocketAddr) {
+    if e.kind() == io::ErrorKind::WouldBlock {
+        tracing::debug!(target: "relay", %recipient, "Send buffer is full, dropping packet");
+        return;
+    }
+
+    tracing::warn!(target: "relay", %recipient, "Failed to relay packet: {e}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    const CLIENT: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 5000));
+    const PEER: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6000));
+
+    #[test]
+    fn bound_channel_routes_both_directions() {
+        let fast_path = make_fast_path();
+        let id = "AID-1".parse().unwrap();
+
+        fast_path.add_channel(
+            ClientSocket::new(CLIENT),
+            0x4000,
+            id,
+            PeerSocket::new(PEER),
+            Default::default(),
+            Default::default(),
+        );
+
+        assert_eq!(
+            fast_path.route_to_peer(CLIENT, 0x4000),
+            Some((id, PeerSocket::new(PEER)))
+        );
+        assert_eq!(
+            fast_path.route_to_client(id, PeerSocket::new(PEER)),
+            Some((CLIENT, 0x4000))
+        );
+    }
+
+    #[test]
+    fn removing_channel_removes_both_directions() {
+        let fast_path = make_fast_path();
+        let id = "AID-1".parse().unwrap();
+
+        fast_path.add_channel(
+            ClientSocket::new(CLIENT),
+            0x4000,
+            id,
+            PeerSocket::new(PEER),
+            Default::default(),
+            Default::default(),
+        );
+        fast_path.remove_channel(ClientSocket::new(CLIENT), 0x4000);
+
+        assert_eq!(fast_path.route_to_peer(CLIENT, 0x4000), None);
+        assert_eq!(fast_path.route_to_client(id, PeerSocket::new(PEER)), None);
+    }
+
+    #[test]
+    fn removing_allocation_removes_its_channels() {
+        let fast_path = make_fast_path();
+        let id = "AID-1".parse().unwrap();
+
+        fast_path.add_channel(
+            ClientSocket::new(CLIENT),
+            0x4000,
+            id,
+            PeerSocket::new(PEER),
+            Default::default(),
+            Default::default(),
+        );
+        fast_path.remove_allocation(id);
+
+        assert_eq!(fast_path.route_to_peer(CLIENT, 0x4000), None);
+        assert_eq!(fast_path.route_to_client(id, PeerSocket::new(PEER)), None);
+    }
+
+    #[test]
+    fn channels_of_stream_clients_are_not_routed() {
+        let fast_path = make_fast_path();
+        let id = "AID-1".parse().unwrap();
+
+        fast_path.add_channel(
+            ClientSocket::with_transport(CLIENT, Transport::Tcp),
+            0x4000,
+            id,
+            PeerSocket::new(PEER),
+            Default::default(),
+            Default::default(),
+        );
+
+        assert_eq!(fast_path.route_to_peer(CLIENT, 0x4000), None);
+    }
+
+    fn make_fast_path() -> FastPath {
+        let meter = opentelemetry_api::global::meter("relay");
+
+        FastPath::new(
+            meter.u64_counter("data_relayed_bytes").init(),
+            meter.u64_counter("rate_limited_packets_total").init(),
+        )
+    }
+}
@@ -1,16 +1,69 @@
 use anyhow::Result;
+use axum::extract::State;
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
 use axum::routing::get;
 use axum::Router;
+use prometheus::{Encoder as _, Registry, TextEncoder};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-pub async fn serve(addr: impl Into<SocketAddr>) -> Result<()> {
+/// The health of the relay, as reported by the `/healthz` endpoint.
+#[derive(Debug, Clone, Default)]
+pub struct Health {
+    draining: Arc<AtomicBool>,
+}
+
+impl Health {
+    pub fn set_draining(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+}
+
+/// Serves the health-check endpoint at `/healthz` and our metrics in the Prometheus text format at `/metrics`.
+///
+/// Once draining, `/healthz` responds with `503 Service Unavailable` so load balancers stop sending new clients our way.
+pub async fn serve(addr: impl Into<SocketAddr>, registry: Registry, health: Health) -> Result<()> {
     let addr = addr.into();
 
     let service = Router::new()
-        .route("/healthz", get(|| async { "" }))
+        .route(
+            "/healthz",
+            get(move || async move {
+                if health.is_draining() {
+                    return (StatusCode::SERVICE_UNAVAILABLE, "draining");
+                }
+
+                (StatusCode::OK, "")
+            }),
+        )
+        .route("/metrics", get(metrics))
+        .with_state(registry)
         .into_make_service();
 
     axum::serve(tokio::net::TcpListener::bind(addr).await?, service).await?;
 
     Ok(())
 }
+
+async fn metrics(State(registry): State<Registry>) -> impl IntoResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+
+    if let Err(e) = encoder.encode(&registry.gather(), &mut buffer) {
+        tracing::warn!(target: "relay", "Failed to encode metrics: {e}");
+
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    (
+        [(header::CONTENT_TYPE, encoder.format_type().to_owned())],
+        buffer,
+    )
+        .into_response()
+}
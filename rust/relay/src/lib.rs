@@ -1,24 +1,33 @@
 mod allocation;
 mod auth;
+mod fast_path;
 mod net_ext;
+mod rate_limit;
 mod server;
 mod sleep;
+mod stream;
 mod time_events;
 mod udp_socket;
 
+pub mod admin;
 pub mod health_check;
 #[cfg(feature = "proptest")]
 pub mod proptest;
 
 pub use allocation::Allocation;
+pub use auth::generate_password;
+pub use fast_path::FastPath;
 pub use net_ext::IpAddrExt;
+pub use rate_limit::{RateLimit, RateLimits};
 pub use server::{
-    Allocate, AllocationId, Attribute, Binding, ChannelBind, ChannelData, ClientMessage, Command,
-    CreatePermission, Refresh, Server,
+    Allocate, AllocationId, AllocationInfo, Attribute, Binding, ChannelBind, ChannelData,
+    ChannelInfo, ClientMessage, Command, CreatePermission, Refresh, SendIndication, Server,
+    Snapshot,
 };
 pub use sleep::Sleep;
+pub use stream::{accept_streams, make_tls_acceptor, StreamEvent};
 pub use stun_codec::rfc8656::attributes::AddressFamily;
-pub use udp_socket::UdpSocket;
+pub use udp_socket::{UdpSender, UdpSocket};
 
 pub(crate) use time_events::TimeEvents;
 
@@ -71,23 +80,52 @@ impl From<(Ipv4Addr, Ipv6Addr)> for IpStack {
     }
 }
 
+/// The transport over which a client talks to the relay.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Udp => write!(f, "udp"),
+            Transport::Tcp => write!(f, "tcp"),
+            Transport::Tls => write!(f, "tls"),
+        }
+    }
+}
+
 /// New-type for a client's socket.
 ///
 /// From the [spec](https://www.rfc-editor.org/rfc/rfc8656#section-2-4.4):
 ///
 /// > A STUN client that implements this specification.
-#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
-pub struct ClientSocket(SocketAddr);
+///
+/// Together with the [`Transport`], the client's address uniquely identifies a client to the relay.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct ClientSocket(SocketAddr, Transport);
 
 impl ClientSocket {
+    /// Constructs a new [`ClientSocket`] for a client talking to us via UDP.
     pub fn new(addr: SocketAddr) -> Self {
-        Self(addr)
+        Self(addr, Transport::Udp)
+    }
+
+    pub fn with_transport(addr: SocketAddr, transport: Transport) -> Self {
+        Self(addr, transport)
     }
 
     pub fn into_socket(self) -> SocketAddr {
         self.0
     }
 
+    pub fn transport(&self) -> Transport {
+        self.1
+    }
+
     pub fn family(&self) -> AddressFamily {
         match self.0 {
             SocketAddr::V4(_) => AddressFamily::V4,
@@ -98,7 +136,10 @@ impl ClientSocket {
 
 impl fmt::Display for ClientSocket {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.0.fmt(f)
+        match self.1 {
+            Transport::Udp => self.0.fmt(f),
+            Transport::Tcp | Transport::Tls => write!(f, "{}/{}", self.0, self.1),
+        }
     }
 }
 
@@ -107,7 +148,7 @@ impl fmt::Display for ClientSocket {
 /// From the [spec](https://www.rfc-editor.org/rfc/rfc8656#section-2-4.8):
 ///
 /// > A host with which the TURN client wishes to communicate. The TURN server relays traffic between the TURN client and its peer(s). The peer does not interact with the TURN server using the protocol defined in this document; rather, the peer receives data sent by the TURN server, and the peer sends data towards the TURN server.
-#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
 pub struct PeerSocket(SocketAddr);
 
 impl PeerSocket {
@@ -1,9 +1,11 @@
 use anyhow::{anyhow, bail, Context, Result};
 use backoff::ExponentialBackoffBuilder;
 use clap::Parser;
+use firezone_relay::health_check::Health;
 use firezone_relay::{
-    AddressFamily, Allocation, AllocationId, ClientSocket, Command, IpStack, PeerSocket, Server,
-    Sleep, UdpSocket,
+    admin, AddressFamily, Allocation, AllocationId, ClientSocket, Command, FastPath, IpStack,
+    PeerSocket, RateLimit, RateLimits, Server, Sleep, Snapshot, StreamEvent, Transport, UdpSender,
+    UdpSocket,
 };
 use futures::channel::mpsc;
 use futures::{future, FutureExt, SinkExt, StreamExt};
@@ -12,14 +14,19 @@ use opentelemetry_otlp::WithExportConfig;
 use phoenix_channel::{Error, Event, PhoenixChannel, SecureUrl};
 use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
-use secrecy::{Secret, SecretString};
+use secrecy::{ExposeSecret, Secret, SecretString};
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::convert::Infallible;
+use std::io;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::task::{ready, Poll};
 use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio_rustls::TlsAcceptor;
 use tracing::{level_filters::LevelFilter, Instrument, Subscriber};
 use tracing_core::Dispatch;
 use tracing_stackdriver::CloudTraceConfiguration;
@@ -27,6 +34,7 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilte
 use url::Url;
 
 const STATS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -39,8 +47,28 @@ struct Args {
     /// The address of the local interface where we should serve our health-check endpoint.
     ///
     /// The actual health-check endpoint will be at `http://<health_check_addr>/healthz`.
+    /// Metrics in the Prometheus format are served at `http://<health_check_addr>/metrics`.
     #[arg(long, env, hide = true, default_value = "0.0.0.0:8080")]
     health_check_addr: SocketAddr,
+    /// The address of the local interface where we should serve the admin API.
+    ///
+    /// The admin API allows listing and revoking allocations and is unauthenticated, thus it must never be exposed publicly.
+    /// If omitted, the admin API is disabled.
+    #[arg(long, env)]
+    admin_addr: Option<SocketAddr>,
+    /// How many seconds we keep serving existing allocations at most once we start draining.
+    ///
+    /// Draining starts upon receiving SIGTERM or via the admin API.
+    /// During that time, we reject new allocations and shut down as soon as all existing allocations are gone.
+    #[arg(long, env, default_value = "3600")]
+    drain_timeout_secs: u64,
+    /// Path to a file where we persist the state of all allocations.
+    ///
+    /// The state is written every minute and on shutdown and restored on startup, allowing clients to keep their allocations across a restart.
+    /// The file contains the secret used to verify client credentials and is thus only readable by the relay's user.
+    /// If omitted, all allocations are lost on restart.
+    #[arg(long, env)]
+    state_file: Option<PathBuf>,
     // See https://www.rfc-editor.org/rfc/rfc8656.html#name-allocations
     /// The lowest port used for TURN allocations.
     #[arg(long, env, hide = true, default_value = "49152")]
@@ -48,6 +76,48 @@ struct Args {
     /// The highest port used for TURN allocations.
     #[arg(long, env, hide = true, default_value = "65535")]
     highest_port: u16,
+    /// The port on which we should accept TURN over TCP.
+    ///
+    /// If omitted, we won't accept TCP connections.
+    #[arg(long, env)]
+    tcp_port: Option<u16>,
+    /// The port on which we should accept TURN over TLS.
+    ///
+    /// If omitted, we won't accept TLS connections.
+    #[arg(long, env, requires_all = ["tls_cert_file", "tls_key_file"])]
+    tls_port: Option<u16>,
+    /// Path to the PEM-encoded certificate chain used for TURN over TLS.
+    #[arg(long, env)]
+    tls_cert_file: Option<PathBuf>,
+    /// Path to the PEM-encoded private key used for TURN over TLS.
+    #[arg(long, env)]
+    tls_key_file: Option<PathBuf>,
+    /// How many sockets we bind to UDP port 3478, each served by its own task.
+    ///
+    /// The kernel distributes clients between the sockets via `SO_REUSEPORT`, allowing channel data to be relayed on several threads at once.
+    /// Defaults to the number of available CPU cores.
+    #[arg(long, env)]
+    udp_workers: Option<NonZeroUsize>,
+    /// The maximum number of bytes per second a single allocation may relay.
+    ///
+    /// If omitted, allocations are not limited.
+    #[arg(long, env)]
+    allocation_max_bytes_per_sec: Option<u64>,
+    /// The maximum number of packets per second a single allocation may relay.
+    ///
+    /// If omitted, allocations are not limited.
+    #[arg(long, env)]
+    allocation_max_packets_per_sec: Option<u64>,
+    /// The maximum number of bytes per second all allocations of a single username may relay.
+    ///
+    /// If omitted, usernames are not limited.
+    #[arg(long, env)]
+    username_max_bytes_per_sec: Option<u64>,
+    /// The maximum number of packets per second all allocations of a single username may relay.
+    ///
+    /// If omitted, usernames are not limited.
+    #[arg(long, env)]
+    username_max_packets_per_sec: Option<u64>,
     #[arg(
         long,
         env = "FIREZONE_API_URL",
@@ -73,6 +143,7 @@ struct Args {
     /// Which OTLP collector we should connect to.
     ///
     /// If set, we will report traces and metrics to this collector via gRPC.
+    /// Metrics are always available at the `/metrics` endpoint of the health-check server.
     #[arg(long, env, hide = true)]
     otlp_grpc_endpoint: Option<SocketAddr>,
 
@@ -96,6 +167,7 @@ async fn main() -> Result<()> {
     let args = Args::parse();
 
     setup_tracing(&args).await?;
+    let metrics_registry = setup_metrics(&args)?;
 
     let public_addr = match (args.public_ip4_addr, args.public_ip6_addr) {
         (Some(ip4), Some(ip6)) => IpStack::Dual { ip4, ip6 },
@@ -106,12 +178,44 @@ async fn main() -> Result<()> {
         }
     };
 
-    let server = Server::new(
+    let tls = match (args.tls_port, &args.tls_cert_file, &args.tls_key_file) {
+        (Some(port), Some(cert_file), Some(key_file)) => Some((
+            port,
+            firezone_relay::make_tls_acceptor(cert_file, key_file)
+                .context("Failed to set up TLS")?,
+        )),
+        _ => None,
+    };
+
+    let mut server = Server::new(
         public_addr,
         make_rng(args.rng_seed),
         args.lowest_port,
         args.highest_port,
-    );
+    )
+    .with_rate_limits(RateLimits {
+        per_allocation: RateLimit {
+            bytes_per_sec: args.allocation_max_bytes_per_sec,
+            packets_per_sec: args.allocation_max_packets_per_sec,
+        },
+        per_username: RateLimit {
+            bytes_per_sec: args.username_max_bytes_per_sec,
+            packets_per_sec: args.username_max_packets_per_sec,
+        },
+    })
+    .with_drain_timeout(Duration::from_secs(args.drain_timeout_secs));
+
+    if let Some(state_file) = args.state_file.as_deref() {
+        match read_snapshot(state_file) {
+            Ok(Some(snapshot)) => {
+                server.restore(snapshot, SystemTime::now());
+            }
+            Ok(None) => {}
+            Err(e) => {
+                tracing::warn!(target: "relay", "Failed to restore state from {}: {e:#}", state_file.display());
+            }
+        }
+    }
 
     let channel = if let Some(token) = args.token.as_ref() {
         let base_url = args.api_url.clone();
@@ -128,15 +232,52 @@ async fn main() -> Result<()> {
         None
     };
 
-    let mut eventloop = Eventloop::new(server, channel, public_addr)?;
+    let num_udp_workers = args
+        .udp_workers
+        .or_else(|| std::thread::available_parallelism().ok())
+        .unwrap_or(NonZeroUsize::MIN);
+
+    let (admin_request_sender, admin_request_receiver) = mpsc::channel(10);
+    let health = Health::default();
+
+    let mut eventloop = Eventloop::new(
+        server,
+        channel,
+        public_addr,
+        num_udp_workers,
+        args.tcp_port,
+        tls,
+        admin_request_receiver,
+        health.clone(),
+        args.state_file.clone(),
+    )?;
+
+    tokio::spawn(firezone_relay::health_check::serve(
+        args.health_check_addr,
+        metrics_registry,
+        health,
+    ));
+    if let Some(admin_addr) = args.admin_addr {
+        tokio::spawn(firezone_relay::admin::serve(
+            admin_addr,
+            admin_request_sender,
+        ));
+
+        tracing::info!(target: "relay", "Serving admin API on {admin_addr}");
+    }
 
-    tokio::spawn(firezone_relay::health_check::serve(args.health_check_addr));
+    tracing::info!(target: "relay", "Listening for incoming traffic on UDP port 3478 with {num_udp_workers} workers");
+    if let Some(tcp_port) = args.tcp_port {
+        tracing::info!(target: "relay", "Listening for incoming traffic on TCP port {tcp_port}");
+    }
+    if let Some(tls_port) = args.tls_port {
+        tracing::info!(target: "relay", "Listening for incoming traffic on TLS port {tls_port}");
+    }
 
-    tracing::info!(target: "relay", "Listening for incoming traffic on UDP port 3478");
+    let result = future::poll_fn(|cx| eventloop.poll(cx)).await;
+    eventloop.persist_state(SystemTime::now());
 
-    future::poll_fn(|cx| eventloop.poll(cx))
-        .await
-        .context("event loop failed")?;
+    result.context("event loop failed")?;
 
     Ok(())
 }
@@ -164,7 +305,7 @@ async fn setup_tracing(args: &Args) -> Result<()> {
 
             let exporter = opentelemetry_otlp::new_exporter()
                 .tonic()
-                .with_endpoint(grpc_endpoint.clone());
+                .with_endpoint(grpc_endpoint);
 
             let tracer =
                 opentelemetry_otlp::new_pipeline()
@@ -178,18 +319,6 @@ async fn setup_tracing(args: &Args) -> Result<()> {
 
             tracing::trace!(target: "relay", "Successfully initialized trace provider on tokio runtime");
 
-            let exporter = opentelemetry_otlp::new_exporter()
-                .tonic()
-                .with_endpoint(grpc_endpoint);
-
-            opentelemetry_otlp::new_pipeline()
-                .metrics(opentelemetry::runtime::Tokio)
-                .with_exporter(exporter)
-                .build()
-                .context("Failed to create OTLP metrics pipeline")?;
-
-            tracing::trace!(target: "relay", "Successfully initialized metric controller on tokio runtime");
-
             tracing_subscriber::registry()
                 .with(log_layer(args))
                 .with(
@@ -210,6 +339,49 @@ async fn setup_tracing(args: &Args) -> Result<()> {
     Ok(())
 }
 
+/// Sets up our metrics infrastructure.
+///
+/// Metrics are always exposed in the Prometheus format via the returned [`prometheus::Registry`].
+/// If the user has specified `Args.otlp_grpc_endpoint`, they are additionally exported to that OTLP collector.
+fn setup_metrics(args: &Args) -> Result<prometheus::Registry> {
+    let registry = prometheus::Registry::new();
+
+    let prometheus_exporter = opentelemetry_prometheus::exporter()
+        .with_registry(registry.clone())
+        .build()
+        .context("Failed to create Prometheus exporter")?;
+
+    let mut meter_provider = sdk::metrics::MeterProvider::builder()
+        .with_reader(prometheus_exporter)
+        .with_resource(sdk::Resource::new(vec![KeyValue::new(
+            "service.name",
+            "relay",
+        )]));
+
+    if let Some(endpoint) = args.otlp_grpc_endpoint {
+        let exporter = opentelemetry_otlp::MetricsExporterBuilder::from(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(format!("http://{endpoint}")),
+        )
+        .build_metrics_exporter(
+            Box::new(sdk::metrics::reader::DefaultTemporalitySelector::new()),
+            Box::new(sdk::metrics::reader::DefaultAggregationSelector::new()),
+        )
+        .context("Failed to create OTLP metrics exporter")?;
+
+        meter_provider = meter_provider.with_reader(
+            sdk::metrics::PeriodicReader::builder(exporter, opentelemetry::runtime::Tokio).build(),
+        );
+
+        tracing::trace!(target: "relay", "Successfully initialized OTLP metrics exporter on tokio runtime");
+    }
+
+    opentelemetry_api::global::set_meter_provider(meter_provider.build());
+
+    Ok(registry)
+}
+
 /// Constructs the base log layer.
 ///
 /// The user has a choice between:
@@ -248,9 +420,7 @@ async fn connect_to_portal(
     token: &SecretString,
     mut url: Url,
     stamp_secret: &SecretString,
-) -> Result<Option<PhoenixChannel<JoinMessage, (), ()>>> {
-    use secrecy::ExposeSecret;
-
+) -> Result<Option<PhoenixChannel<JoinMessage, IngressMessages, ()>>> {
     if !url.path().is_empty() {
         tracing::warn!(target: "relay", "Overwriting path component of portal URL with '/relay/websocket'");
     }
@@ -292,6 +462,37 @@ struct JoinMessage {
     stamp_secret: String,
 }
 
+#[derive(serde::Deserialize, Debug)]
+#[serde(rename_all = "snake_case", tag = "event", content = "payload")]
+enum IngressMessages {
+    RotateStampSecret(RotateStampSecret),
+    RevokeUsernames(RevokeUsernames),
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct RotateStampSecret {
+    stamp_secret: SecretString,
+    /// For how many seconds credentials stamped with the previous secret remain valid.
+    overlap_secs: u64,
+}
+
+#[derive(serde::Deserialize, Debug, PartialEq)]
+struct RevokeUsernames {
+    usernames: Vec<String>,
+}
+
+#[derive(serde::Serialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "snake_case", tag = "event", content = "payload")]
+enum EgressMessages {
+    Draining(Draining),
+}
+
+#[derive(serde::Serialize, PartialEq, Debug, Clone)]
+struct Draining {
+    /// Seconds since the UNIX epoch after which we delete all remaining allocations.
+    deadline: u64,
+}
+
 #[cfg(debug_assertions)]
 fn make_rng(seed: Option<u64>) -> StdRng {
     let Some(seed) = seed else {
@@ -316,58 +517,118 @@ struct Eventloop<R> {
     inbound_data_receiver: mpsc::Receiver<(Vec<u8>, ClientSocket)>,
     outbound_ip4_data_sender: mpsc::Sender<(Vec<u8>, ClientSocket)>,
     outbound_ip6_data_sender: mpsc::Sender<(Vec<u8>, ClientSocket)>,
+    stream_event_receiver: mpsc::Receiver<StreamEvent>,
+    /// The senders for all clients connected via TCP or TLS.
+    streams: HashMap<ClientSocket, mpsc::Sender<Vec<u8>>>,
+    admin_request_receiver: mpsc::Receiver<admin::Request>,
+    sigterm: Signal,
+    sigint: Signal,
+    health: Health,
     server: Server<R>,
-    channel: Option<PhoenixChannel<JoinMessage, (), ()>>,
+    channel: Option<PhoenixChannel<JoinMessage, IngressMessages, ()>>,
     allocations: HashMap<(AllocationId, AddressFamily), Allocation>,
+    fast_path: FastPath,
     relay_data_sender: mpsc::Sender<(Vec<u8>, PeerSocket, AllocationId)>,
     relay_data_receiver: mpsc::Receiver<(Vec<u8>, PeerSocket, AllocationId)>,
     sleep: Sleep,
 
     stats_log_interval: tokio::time::Interval,
     last_num_bytes_relayed: u64,
+
+    state_file: Option<PathBuf>,
+    snapshot_interval: tokio::time::Interval,
 }
 
 impl<R> Eventloop<R>
 where
     R: Rng,
 {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         server: Server<R>,
-        channel: Option<PhoenixChannel<JoinMessage, (), ()>>,
+        channel: Option<PhoenixChannel<JoinMessage, IngressMessages, ()>>,
         public_address: IpStack,
+        num_udp_workers: NonZeroUsize,
+        tcp_port: Option<u16>,
+        tls: Option<(u16, TlsAcceptor)>,
+        admin_request_receiver: mpsc::Receiver<admin::Request>,
+        health: Health,
+        state_file: Option<PathBuf>,
     ) -> Result<Self> {
         let (relay_data_sender, relay_data_receiver) = mpsc::channel(1);
         let (inbound_data_sender, inbound_data_receiver) = mpsc::channel(1000);
         let (outbound_ip4_data_sender, outbound_ip4_data_receiver) = mpsc::channel(1000);
         let (outbound_ip6_data_sender, outbound_ip6_data_receiver) = mpsc::channel(1000);
+        let (stream_event_sender, stream_event_receiver) = mpsc::channel(1000);
+
+        let fast_path = server.fast_path();
 
         if public_address.as_v4().is_some() {
-            tokio::spawn(main_udp_socket_task(
+            spawn_main_udp_sockets(
                 AddressFamily::V4,
+                num_udp_workers,
+                &fast_path,
                 inbound_data_sender.clone(),
                 outbound_ip4_data_receiver,
-            ));
+            )?;
         }
         if public_address.as_v6().is_some() {
-            tokio::spawn(main_udp_socket_task(
+            spawn_main_udp_sockets(
                 AddressFamily::V6,
-                inbound_data_sender,
+                num_udp_workers,
+                &fast_path,
+                inbound_data_sender.clone(),
                 outbound_ip6_data_receiver,
-            ));
+            )?;
+        }
+
+        let stream_listeners = tcp_port
+            .map(|port| (port, None))
+            .into_iter()
+            .chain(tls.map(|(port, acceptor)| (port, Some(acceptor))));
+
+        for (port, tls_acceptor) in stream_listeners {
+            if public_address.as_v4().is_some() {
+                tokio::spawn(firezone_relay::accept_streams(
+                    AddressFamily::V4,
+                    port,
+                    tls_acceptor.clone(),
+                    inbound_data_sender.clone(),
+                    stream_event_sender.clone(),
+                ));
+            }
+            if public_address.as_v6().is_some() {
+                tokio::spawn(firezone_relay::accept_streams(
+                    AddressFamily::V6,
+                    port,
+                    tls_acceptor,
+                    inbound_data_sender.clone(),
+                    stream_event_sender.clone(),
+                ));
+            }
         }
 
         Ok(Self {
             inbound_data_receiver,
             outbound_ip4_data_sender,
             outbound_ip6_data_sender,
+            stream_event_receiver,
+            streams: Default::default(),
+            admin_request_receiver,
+            sigterm: signal(SignalKind::terminate()).context("Failed to listen for SIGTERM")?,
+            sigint: signal(SignalKind::interrupt()).context("Failed to listen for SIGINT")?,
+            health,
             server,
             channel,
             allocations: Default::default(),
+            fast_path,
             relay_data_sender,
             relay_data_receiver,
             sleep: Sleep::default(),
             stats_log_interval: tokio::time::interval(STATS_LOG_INTERVAL),
             last_num_bytes_relayed: 0,
+            state_file,
+            snapshot_interval: tokio::time::interval(SNAPSHOT_INTERVAL),
         })
     }
 
@@ -389,6 +650,25 @@ where
                         let span = tracing::error_span!("Command::SendMessage");
                         let _guard = span.enter();
 
+                        if recipient.transport() != Transport::Udp {
+                            let Entry::Occupied(mut stream) = self.streams.entry(recipient) else {
+                                tracing::debug!(target: "relay", %recipient, "Dropping message because connection is closed");
+                                continue;
+                            };
+
+                            if let Err(e) = stream.get_mut().try_send(payload) {
+                                if e.is_disconnected() {
+                                    stream.remove();
+                                }
+
+                                if e.is_full() {
+                                    tracing::warn!(target: "relay", %recipient, "Dropping message because connection is not keeping up");
+                                }
+                            }
+
+                            continue;
+                        }
+
                         let sender = match recipient.family() {
                             AddressFamily::V4 => &mut self.outbound_ip4_data_sender,
                             AddressFamily::V6 => &mut self.outbound_ip6_data_sender,
@@ -412,10 +692,22 @@ where
                             tracing::error_span!("Command::CreateAllocation", %id, %family, %port);
                         let _guard = span.enter();
 
-                        self.allocations.insert(
-                            (id, family),
-                            Allocation::new(self.relay_data_sender.clone(), id, family, port),
-                        );
+                        match Allocation::new(
+                            self.relay_data_sender.clone(),
+                            self.fast_path.clone(),
+                            id,
+                            family,
+                            port,
+                        ) {
+                            Ok(allocation) => {
+                                self.allocations.insert((id, family), allocation);
+                            }
+                            Err(e) => {
+                                tracing::warn!(target: "relay", "Failed to create allocation: {e:#}");
+
+                                self.server.handle_allocation_failed(id);
+                            }
+                        }
                     }
                     Command::FreeAllocation { id, family } => {
                         let span = tracing::error_span!("Command::FreeAllocation", %id, %family);
@@ -470,21 +762,74 @@ where
                 continue; // Attempt to process more commands.
             }
 
-            // Priority 2: Handle time-sensitive tasks:
+            if self.server.is_draining() && self.server.num_allocations() == 0 {
+                tracing::info!(target: "relay", "All allocations are gone, shutting down");
+
+                return Poll::Ready(Ok(()));
+            }
+
+            // Priority 2: Start draining upon SIGTERM, shut down immediately upon SIGINT
+            if self.sigterm.poll_recv(cx).is_ready() {
+                tracing::info!(target: "relay", "Received SIGTERM");
+
+                self.start_draining(now);
+                continue; // Handle potentially new commands.
+            }
+            if self.sigint.poll_recv(cx).is_ready() {
+                tracing::info!(target: "relay", "Received SIGINT, shutting down");
+
+                return Poll::Ready(Ok(()));
+            }
+
+            // Priority 3: Handle time-sensitive tasks:
             if self.sleep.poll_unpin(cx).is_ready() {
                 self.server.handle_deadline_reached(now);
                 continue; // Handle potentially new commands.
             }
 
-            // Priority 3: Handle relayed data (we prioritize latency for existing allocations over making new ones)
+            // Priority 4: Handle relayed data (we prioritize latency for existing allocations over making new ones)
             if let Poll::Ready(Some((data, sender, allocation))) =
                 self.relay_data_receiver.poll_next_unpin(cx)
             {
-                self.server.handle_peer_traffic(&data, sender, allocation);
+                self.server
+                    .handle_peer_traffic(&data, sender, allocation, now);
+                continue; // Handle potentially new commands.
+            }
+
+            // Priority 5: Track TCP & TLS connections (before reading from them so we know where to send responses)
+            if let Poll::Ready(Some(event)) = self.stream_event_receiver.poll_next_unpin(cx) {
+                match event {
+                    StreamEvent::Connected { client, sender } => {
+                        self.streams.insert(client, sender);
+                    }
+                    StreamEvent::Disconnected { client } => {
+                        self.streams.remove(&client);
+                        self.server.handle_client_disconnected(client);
+                    }
+                }
                 continue; // Handle potentially new commands.
             }
 
-            // Priority 4: Accept new allocations / answer STUN requests etc
+            // Priority 6: Handle admin requests (rare but we want to react to abuse even under load)
+            if let Poll::Ready(Some(request)) = self.admin_request_receiver.poll_next_unpin(cx) {
+                match request {
+                    admin::Request::ListAllocations { reply } => {
+                        let _ = reply.send(self.server.allocations());
+                    }
+                    admin::Request::RevokeAllocation { id, reply } => {
+                        let _ = reply.send(self.server.revoke_allocation(id));
+                    }
+                    admin::Request::RevokeAllocationsOfUsername { username, reply } => {
+                        let _ = reply.send(self.server.revoke_allocations_of_username(&username));
+                    }
+                    admin::Request::Drain { reply } => {
+                        let _ = reply.send(self.start_draining(now));
+                    }
+                }
+                continue; // Handle potentially new commands.
+            }
+
+            // Priority 7: Accept new allocations / answer STUN requests etc
             if let Poll::Ready(Some((buffer, sender))) =
                 self.inbound_data_receiver.poll_next_unpin(cx)
             {
@@ -492,7 +837,7 @@ where
                 continue; // Handle potentially new commands.
             }
 
-            // Priority 5: Handle portal messages
+            // Priority 8: Handle portal messages
             match self.channel.as_mut().map(|c| c.poll(cx)) {
                 Some(Poll::Ready(Ok(Event::Disconnect(reason)))) => {
                     return Poll::Ready(Err(anyhow!("Connection closed by portal: {reason}")));
@@ -524,10 +869,12 @@ where
                     continue;
                 }
                 Some(Poll::Ready(Ok(
-                    Event::InboundMessage { msg: (), .. } | Event::InboundReq { req: (), .. },
-                )))
-                | Some(Poll::Pending)
-                | None => {}
+                    Event::InboundMessage { msg, .. } | Event::InboundReq { req: msg, .. },
+                ))) => {
+                    self.handle_portal_message(msg, now);
+                    continue;
+                }
+                Some(Poll::Pending) | None => {}
             }
 
             if self.stats_log_interval.poll_tick(cx).is_ready() {
@@ -543,9 +890,115 @@ where
                 tracing::info!(target: "relay", "Allocations = {num_allocations} Channels = {num_channels} Throughput = {}", fmt_human_throughput(avg_throughput as f64));
             }
 
+            if self.snapshot_interval.poll_tick(cx).is_ready() {
+                self.persist_state(now);
+                continue;
+            }
+
             return Poll::Pending;
         }
     }
+
+    fn handle_portal_message(&mut self, msg: IngressMessages, now: SystemTime) {
+        match msg {
+            IngressMessages::RotateStampSecret(RotateStampSecret {
+                stamp_secret,
+                overlap_secs,
+            }) => {
+                // Make sure we announce the new secret if we ever need to re-join.
+                if let Some(channel) = self.channel.as_mut() {
+                    channel.set_init_req(JoinMessage {
+                        stamp_secret: stamp_secret.expose_secret().to_string(),
+                    });
+                }
+
+                self.server.rotate_auth_secret(
+                    stamp_secret,
+                    Duration::from_secs(overlap_secs),
+                    now,
+                );
+            }
+            IngressMessages::RevokeUsernames(RevokeUsernames { usernames }) => {
+                for username in usernames {
+                    let num_revoked = self.server.revoke_username(&username, now);
+
+                    tracing::info!(target: "relay", %username, "Revoked username and {num_revoked} allocations");
+                }
+            }
+        }
+    }
+
+    /// Writes the state of the server to the state file, if configured.
+    fn persist_state(&self, now: SystemTime) {
+        let Some(state_file) = self.state_file.as_deref() else {
+            return;
+        };
+
+        if let Err(e) = write_snapshot(state_file, &self.server.snapshot(now)) {
+            tracing::warn!(target: "relay", "Failed to persist state to {}: {e:#}", state_file.display());
+        }
+    }
+
+    /// Starts draining the relay and notifies the portal so it stops handing us out to clients.
+    fn start_draining(&mut self, now: SystemTime) -> SystemTime {
+        let was_draining = self.server.is_draining();
+        let deadline = self.server.start_draining(now);
+
+        if was_draining {
+            return deadline;
+        }
+
+        self.health.set_draining();
+
+        if let Some(channel) = self.channel.as_mut() {
+            channel.send(
+                "relay",
+                EgressMessages::Draining(Draining {
+                    deadline: deadline
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                }),
+            );
+        }
+
+        deadline
+    }
+}
+
+fn read_snapshot(path: &Path) -> Result<Option<Snapshot>> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let snapshot = serde_json::from_reader(io::BufReader::new(file))?;
+
+    Ok(Some(snapshot))
+}
+
+/// Atomically replaces the state file with the given snapshot.
+///
+/// The snapshot contains our auth secret, thus the file is only readable by us.
+fn write_snapshot(path: &Path, snapshot: &Snapshot) -> Result<()> {
+    use std::os::unix::fs::OpenOptionsExt as _;
+
+    let tmp_path = path.with_extension("tmp");
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .with_context(|| format!("Failed to open {}", tmp_path.display()))?;
+    serde_json::to_writer(io::BufWriter::new(&mut file), snapshot)?;
+    file.sync_all()?;
+
+    std::fs::rename(&tmp_path, path)?;
+
+    Ok(())
 }
 
 fn fmt_human_throughput(mut throughput: f64) -> String {
@@ -562,24 +1015,71 @@ fn fmt_human_throughput(mut throughput: f64) -> String {
     format!("{throughput:.2} TB/s")
 }
 
-async fn main_udp_socket_task(
+/// Binds `num_workers` sockets to UDP port 3478 and spawns a task for each.
+///
+/// With `SO_REUSEPORT`, the kernel distributes incoming datagrams between the sockets by the sender's address, thus a client is always served by the same task.
+/// Channel data is relayed directly via the [`FastPath`], everything else is forwarded to the event-loop.
+///
+/// Everything the event-loop sends to UDP clients, i.e. STUN responses and data indications from peers without a channel, goes through a single task sending on the first socket.
+/// All channel data bypasses it via the [`FastPath`], so this task only carries control traffic and is not worth sharding.
+fn spawn_main_udp_sockets(
     family: AddressFamily,
+    num_workers: NonZeroUsize,
+    fast_path: &FastPath,
+    inbound_data_sender: mpsc::Sender<(Vec<u8>, ClientSocket)>,
+    outbound_data_receiver: mpsc::Receiver<(Vec<u8>, ClientSocket)>,
+) -> Result<()> {
+    let sockets = (0..num_workers.get())
+        .map(|_| UdpSocket::bind_reuse_port(family, 3478))
+        .collect::<Result<Vec<_>>>()?;
+
+    // All sockets are bound to the same address, thus it doesn't matter which one we send from.
+    let sender = sockets[0].sender();
+    fast_path.set_main_socket(family, sender.clone());
+    tokio::spawn(send_outbound_udp_data(sender, outbound_data_receiver));
+
+    for socket in sockets {
+        tokio::spawn(main_udp_socket_task(
+            socket,
+            fast_path.clone(),
+            inbound_data_sender.clone(),
+        ));
+    }
+
+    Ok(())
+}
+
+async fn main_udp_socket_task(
+    mut socket: UdpSocket,
+    fast_path: FastPath,
     mut inbound_data_sender: mpsc::Sender<(Vec<u8>, ClientSocket)>,
-    mut outbound_data_receiver: mpsc::Receiver<(Vec<u8>, ClientSocket)>,
 ) -> Result<Infallible> {
-    let mut socket = UdpSocket::bind(family, 3478)?;
-
     loop {
-        tokio::select! {
-            result = socket.recv() => {
-                let (data, sender) = result?;
-                inbound_data_sender.send((data.to_vec(), ClientSocket::new(sender))).await?;
-            }
-            maybe_item = outbound_data_receiver.next() => {
-                let (data, recipient) = maybe_item.context("Outbound data channel closed")?;
-                socket.send_to(data.as_ref(), recipient.into_socket()).await?;
-            }
+        let (data, sender) = socket.recv().await?;
+
+        if fast_path.relay_to_peer(data, sender) {
+            continue;
         }
+
+        inbound_data_sender
+            .send((data.to_vec(), ClientSocket::new(sender)))
+            .await?;
+    }
+}
+
+async fn send_outbound_udp_data(
+    socket: UdpSender,
+    mut outbound_data_receiver: mpsc::Receiver<(Vec<u8>, ClientSocket)>,
+) -> Result<Infallible> {
+    loop {
+        let (data, recipient) = outbound_data_receiver
+            .next()
+            .await
+            .context("Outbound data channel closed")?;
+
+        socket
+            .send_to(data.as_ref(), recipient.into_socket())
+            .await?;
     }
 }
 
@@ -594,4 +1094,36 @@ mod tests {
         assert_eq!(fmt_human_throughput(955_333_999.0), "955.33 MB/s");
         assert_eq!(fmt_human_throughput(100_000_000_000.0), "100.00 GB/s");
     }
+
+    #[test]
+    fn can_deserialize_rotate_stamp_secret() {
+        let msg = r#"{"event":"rotate_stamp_secret","payload":{"stamp_secret":"foobar","overlap_secs":300}}"#;
+
+        let IngressMessages::RotateStampSecret(rotate) =
+            serde_json::from_str::<IngressMessages>(msg).unwrap()
+        else {
+            panic!("Unexpected message");
+        };
+
+        assert_eq!(rotate.stamp_secret.expose_secret(), "foobar");
+        assert_eq!(rotate.overlap_secs, 300);
+    }
+
+    #[test]
+    fn can_deserialize_revoke_usernames() {
+        let msg = r#"{"event":"revoke_usernames","payload":{"usernames":["1685200000:n23JJ2wKKtt30oXi"]}}"#;
+
+        let IngressMessages::RevokeUsernames(revoke) =
+            serde_json::from_str::<IngressMessages>(msg).unwrap()
+        else {
+            panic!("Unexpected message");
+        };
+
+        assert_eq!(
+            revoke,
+            RevokeUsernames {
+                usernames: vec!["1685200000:n23JJ2wKKtt30oXi".to_owned()]
+            }
+        );
+    }
 }
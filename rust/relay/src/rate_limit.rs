@@ -0,0 +1,297 @@
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// The rate limits enforced by the relay.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RateLimits {
+    /// The limit of each individual allocation.
+    pub per_allocation: RateLimit,
+    /// The limit across all allocations created with the same username.
+    pub per_username: RateLimit,
+}
+
+impl RateLimits {
+    pub(crate) fn is_unlimited(&self) -> bool {
+        self.per_allocation.is_unlimited() && self.per_username.is_unlimited()
+    }
+}
+
+/// Limits how much data can be relayed, in both directions combined.
+///
+/// `None` means unlimited.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub bytes_per_sec: Option<u64>,
+    pub packets_per_sec: Option<u64>,
+}
+
+impl RateLimit {
+    pub(crate) fn is_unlimited(&self) -> bool {
+        self.bytes_per_sec.is_none() && self.packets_per_sec.is_none()
+    }
+}
+
+/// A [`Quota`] shared between the [`Server`](crate::Server) and the [`FastPath`](crate::FastPath).
+pub(crate) type SharedQuota = Arc<Mutex<Quota>>;
+
+/// The quotas that a packet relayed via a particular allocation is subject to.
+#[derive(Clone, Default)]
+pub(crate) struct Quotas {
+    pub(crate) allocation: Option<SharedQuota>,
+    pub(crate) username: Option<SharedQuota>,
+}
+
+/// Which limit a packet exceeded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Scope {
+    Allocation,
+    Username,
+}
+
+impl Scope {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Allocation => "allocation",
+            Scope::Username => "username",
+        }
+    }
+}
+
+impl Quotas {
+    /// Consumes the quotas for relaying a single packet of `num_bytes`.
+    ///
+    /// If any of the quotas doesn't allow the packet, none of them are consumed.
+    pub(crate) fn try_consume(&self, num_bytes: usize, now: SystemTime) -> Result<(), Scope> {
+        // Always lock the allocation's quota first to not deadlock with other socket tasks.
+        let mut allocation = self.allocation.as_ref().map(|q| lock(q));
+        let mut username = self.username.as_ref().map(|q| lock(q));
+
+        if allocation
+            .as_mut()
+            .is_some_and(|q| !q.allows(num_bytes, now))
+        {
+            return Err(Scope::Allocation);
+        }
+
+        if username.as_mut().is_some_and(|q| !q.allows(num_bytes, now)) {
+            return Err(Scope::Username);
+        }
+
+        if let Some(mut quota) = allocation {
+            quota.consume(num_bytes);
+        }
+        if let Some(mut quota) = username {
+            quota.consume(num_bytes);
+        }
+
+        Ok(())
+    }
+}
+
+fn lock(quota: &SharedQuota) -> std::sync::MutexGuard<'_, Quota> {
+    quota.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Tracks the remaining quota of an allocation or username according to a [`RateLimit`].
+pub(crate) struct Quota {
+    bytes: Option<TokenBucket>,
+    packets: Option<TokenBucket>,
+}
+
+impl Quota {
+    pub(crate) fn new(limit: RateLimit, now: SystemTime) -> Self {
+        Self {
+            bytes: limit.bytes_per_sec.map(|rate| TokenBucket::new(rate, now)),
+            packets: limit
+                .packets_per_sec
+                .map(|rate| TokenBucket::new(rate, now)),
+        }
+    }
+
+    /// Checks whether a single packet of `num_bytes` fits into the remaining quota.
+    pub(crate) fn allows(&mut self, num_bytes: usize, now: SystemTime) -> bool {
+        let has_bytes = self
+            .bytes
+            .as_mut()
+            .map_or(true, |b| b.has_tokens(num_bytes as f64, now));
+        let has_packets = self
+            .packets
+            .as_mut()
+            .map_or(true, |b| b.has_tokens(1.0, now));
+
+        has_bytes && has_packets
+    }
+
+    /// Consumes the quota for a single packet of `num_bytes`.
+    ///
+    /// Callers should check [`Quota::allows`] first.
+    pub(crate) fn consume(&mut self, num_bytes: usize) {
+        if let Some(bytes) = self.bytes.as_mut() {
+            bytes.consume(num_bytes as f64);
+        }
+        if let Some(packets) = self.packets.as_mut() {
+            packets.consume(1.0);
+        }
+    }
+}
+
+/// A classic token bucket that allows bursts of up to one second worth of tokens.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: SystemTime,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: SystemTime) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last_refill: now,
+        }
+    }
+
+    fn has_tokens(&mut self, tokens: f64, now: SystemTime) -> bool {
+        self.refill(now);
+
+        self.tokens >= tokens
+    }
+
+    fn consume(&mut self, tokens: f64) {
+        self.tokens -= tokens;
+    }
+
+    fn refill(&mut self, now: SystemTime) {
+        let Ok(elapsed) = now.duration_since(self.last_refill) else {
+            return; // Time went backwards, don't refill.
+        };
+
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+        self.last_refill = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn unlimited_quota_never_drops() {
+        let now = SystemTime::now();
+        let mut quota = Quota::new(RateLimit::default(), now);
+
+        for _ in 0..1000 {
+            assert!(try_consume(&mut quota, u16::MAX as usize, now));
+        }
+    }
+
+    #[test]
+    fn drops_packets_over_packet_limit() {
+        let now = SystemTime::now();
+        let mut quota = Quota::new(
+            RateLimit {
+                bytes_per_sec: None,
+                packets_per_sec: Some(2),
+            },
+            now,
+        );
+
+        assert!(try_consume(&mut quota, 100, now));
+        assert!(try_consume(&mut quota, 100, now));
+        assert!(!try_consume(&mut quota, 100, now));
+        assert!(try_consume(
+            &mut quota,
+            100,
+            now + Duration::from_millis(500)
+        ));
+    }
+
+    #[test]
+    fn rejected_packets_do_not_consume_quota() {
+        let now = SystemTime::now();
+        let mut quota = Quota::new(
+            RateLimit {
+                bytes_per_sec: Some(1000),
+                packets_per_sec: Some(10),
+            },
+            now,
+        );
+
+        assert!(!try_consume(&mut quota, 1001, now));
+        assert!(try_consume(&mut quota, 1000, now));
+    }
+
+    #[test]
+    fn bucket_does_not_grow_beyond_one_second_of_tokens() {
+        let now = SystemTime::now();
+        let mut quota = Quota::new(
+            RateLimit {
+                bytes_per_sec: Some(1000),
+                packets_per_sec: None,
+            },
+            now,
+        );
+
+        let later = now + Duration::from_secs(60);
+
+        assert!(try_consume(&mut quota, 1000, later));
+        assert!(!try_consume(&mut quota, 1, later));
+    }
+
+    #[test]
+    fn username_quota_is_shared_between_allocations() {
+        let now = SystemTime::now();
+        let limit = RateLimit {
+            bytes_per_sec: None,
+            packets_per_sec: Some(1),
+        };
+        let username = Arc::new(Mutex::new(Quota::new(limit, now)));
+
+        let first = Quotas {
+            allocation: None,
+            username: Some(username.clone()),
+        };
+        let second = Quotas {
+            allocation: Some(Arc::new(Mutex::new(Quota::new(RateLimit::default(), now)))),
+            username: Some(username),
+        };
+
+        assert_eq!(first.try_consume(100, now), Ok(()));
+        assert_eq!(second.try_consume(100, now), Err(Scope::Username));
+    }
+
+    #[test]
+    fn exceeding_username_quota_does_not_consume_allocation_quota() {
+        let now = SystemTime::now();
+        let quotas = Quotas {
+            allocation: Some(Arc::new(Mutex::new(Quota::new(
+                RateLimit {
+                    bytes_per_sec: None,
+                    packets_per_sec: Some(1),
+                },
+                now,
+            )))),
+            username: Some(Arc::new(Mutex::new(Quota::new(
+                RateLimit {
+                    bytes_per_sec: Some(10),
+                    packets_per_sec: None,
+                },
+                now,
+            )))),
+        };
+
+        assert_eq!(quotas.try_consume(100, now), Err(Scope::Username));
+        assert_eq!(quotas.try_consume(10, now), Ok(()));
+    }
+
+    fn try_consume(quota: &mut Quota, num_bytes: usize, now: SystemTime) -> bool {
+        if !quota.allows(num_bytes, now) {
+            return false;
+        }
+
+        quota.consume(num_bytes);
+
+        true
+    }
+}
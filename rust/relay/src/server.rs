@@ -1,24 +1,36 @@
 mod channel_data;
 mod client_message;
+mod snapshot;
 
 pub use crate::server::channel_data::ChannelData;
 pub use crate::server::client_message::{
-    Allocate, Binding, ChannelBind, ClientMessage, CreatePermission, Refresh,
+    Allocate, Binding, ChannelBind, ClientMessage, CreatePermission, Refresh, SendIndication,
 };
+pub use crate::server::snapshot::Snapshot;
 
-use crate::auth::{MessageIntegrityExt, Nonces, FIREZONE};
+use crate::auth::{AuthSecrets, MessageIntegrityExt, Nonces, FIREZONE};
+use crate::fast_path::FastPath;
 use crate::net_ext::IpAddrExt;
-use crate::{ClientSocket, IpStack, PeerSocket, TimeEvents};
+use crate::rate_limit::{Quota, Quotas, SharedQuota};
+use crate::server::snapshot::{
+    AllocationSnapshot, ChannelSnapshot, NonceSnapshot, PermissionSnapshot,
+};
+use crate::{ClientSocket, IpStack, PeerSocket, RateLimits, TimeEvents, Transport};
 use anyhow::Result;
 use bytecodec::EncodeExt;
 use core::fmt;
 use opentelemetry::metrics::{Counter, Unit, UpDownCounter};
 use opentelemetry::KeyValue;
 use rand::Rng;
-use secrecy::SecretString;
-use std::collections::{HashMap, VecDeque};
+use secrecy::{ExposeSecret, SecretString};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::hash::Hash;
-use std::net::{IpAddr, SocketAddr};
+use std::iter;
+use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::num::ParseIntError;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime};
 use stun_codec::rfc5389::attributes::{
     ErrorCode, MessageIntegrity, Nonce, Realm, Username, XorMappedAddress,
@@ -26,10 +38,11 @@ use stun_codec::rfc5389::attributes::{
 use stun_codec::rfc5389::errors::{BadRequest, StaleNonce, Unauthorized};
 use stun_codec::rfc5389::methods::BINDING;
 use stun_codec::rfc5766::attributes::{
-    ChannelNumber, Lifetime, RequestedTransport, XorPeerAddress, XorRelayAddress,
+    ChannelNumber, Data, DontFragment, Lifetime, RequestedTransport, XorPeerAddress,
+    XorRelayAddress,
 };
 use stun_codec::rfc5766::errors::{AllocationMismatch, InsufficientCapacity};
-use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, REFRESH};
+use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, DATA, REFRESH};
 use stun_codec::rfc8656::attributes::{
     AdditionalAddressFamily, AddressFamily, RequestedAddressFamily,
 };
@@ -41,9 +54,9 @@ use uuid::Uuid;
 
 /// A sans-IO STUN & TURN server.
 ///
-/// A [`Server`] is bound to an IPv4 address and assumes to only operate on UDP.
-/// Thus, 3 out of the 5 components of a "5-tuple" are unique to an instance of [`Server`] and
-/// we can index data simply by the sender's [`SocketAddr`].
+/// A [`Server`] is bound to an IPv4 address and accepts clients via UDP, TCP and TLS, each on its own port.
+/// Thus, the sender's [`SocketAddr`] together with its transport uniquely identifies a "5-tuple" and
+/// we can index data simply by the sender's [`ClientSocket`].
 ///
 /// Additionally, we assume to have complete ownership over the port range `lowest_port` - `highest_port`.
 pub struct Server<R> {
@@ -63,23 +76,41 @@ pub struct Server<R> {
     /// Channel numbers are unique by client, thus indexed by both.
     channels_by_client_and_number: HashMap<(ClientSocket, u16), Channel>,
     /// Channel numbers are unique between clients and peers, thus indexed by both.
-    channel_numbers_by_client_and_peer: HashMap<(ClientSocket, PeerSocket), u16>,
+    ///
+    /// Ordered, so that we can find all channels from a client to a peer IP, see [`Server::bound_channel_expiry`].
+    channel_numbers_by_client_and_peer: BTreeMap<(ClientSocket, PeerSocket), u16>,
+    /// Permissions are installed per peer IP (the port is ignored), thus indexed by client and IP.
+    permissions: HashMap<(ClientSocket, IpAddr), Permission>,
 
     pending_commands: VecDeque<Command>,
     next_allocation_id: AllocationId,
 
     rng: R,
 
-    auth_secret: SecretString,
+    auth_secrets: AuthSecrets,
 
     nonces: Nonces,
 
     time_events: TimeEvents<TimedAction>,
 
+    rate_limits: RateLimits,
+
+    drain_timeout: Duration,
+    /// Set once we started draining, see [`Server::start_draining`].
+    drain_deadline: Option<SystemTime>,
+
+    quotas_by_allocation: HashMap<AllocationId, SharedQuota>,
+    quotas_by_username: HashMap<String, SharedQuota>,
+
     allocations_up_down_counter: UpDownCounter<i64>,
-    data_relayed_counter: Counter<u64>,
-    data_relayed: u64, // Keep a separate counter because `Counter` doesn't expose the current value :(
+    channels_up_down_counter: UpDownCounter<i64>,
+    /// Bound channels of UDP clients, relayed directly by the socket tasks.
+    fast_path: FastPath,
+
     responses_counter: Counter<u64>,
+    rate_limited_packets_counter: Counter<u64>,
+    nonces_issued_counter: Counter<u64>,
+    stale_nonces_counter: Counter<u64>,
 }
 
 /// The commands returned from a [`Server`].
@@ -135,6 +166,38 @@ impl fmt::Display for AllocationId {
     }
 }
 
+impl FromStr for AllocationId {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let id = s.strip_prefix("AID-").unwrap_or(s).parse()?;
+
+        Ok(AllocationId(id))
+    }
+}
+
+/// A snapshot of an allocation, see [`Server::allocations`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct AllocationInfo {
+    pub id: AllocationId,
+    pub client: ClientSocket,
+    pub relay_addresses: Vec<SocketAddr>,
+    pub expires_at: SystemTime,
+    pub username: String,
+    pub channels: Vec<ChannelInfo>,
+    /// The number of bytes relayed in both directions.
+    pub relayed_bytes: u64,
+}
+
+/// A snapshot of a channel binding, see [`Server::allocations`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelInfo {
+    pub number: u16,
+    pub peer: PeerSocket,
+    pub expires_at: SystemTime,
+    pub bound: bool,
+}
+
 /// See <https://www.rfc-editor.org/rfc/rfc8656#name-requested-transport>.
 const UDP_TRANSPORT: u8 = 17;
 
@@ -143,6 +206,14 @@ const UDP_TRANSPORT: u8 = 17;
 /// See <https://www.rfc-editor.org/rfc/rfc8656#name-channels-2>.
 const CHANNEL_BINDING_DURATION: Duration = Duration::from_secs(600);
 
+/// The duration of a permission.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8656#name-permissions>.
+const PERMISSION_DURATION: Duration = Duration::from_secs(300);
+
+/// How long existing allocations are served at most once we start draining, unless configured otherwise.
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(3600);
+
 impl<R> Server<R>
 where
     R: Rng,
@@ -169,6 +240,10 @@ where
             .i64_up_down_counter("allocations_total")
             .with_description("The number of active allocations")
             .init();
+        let channels_up_down_counter = meter
+            .i64_up_down_counter("channels_total")
+            .with_description("The number of active channels")
+            .init();
         let responses_counter = meter
             .u64_counter("responses_total")
             .with_description("The number of responses")
@@ -178,6 +253,20 @@ where
             .with_description("The number of bytes relayed")
             .with_unit(Unit::new("b"))
             .init();
+        let rate_limited_packets_counter = meter
+            .u64_counter("rate_limited_packets_total")
+            .with_description("The number of packets dropped because of a rate limit")
+            .init();
+        let nonces_issued_counter = meter
+            .u64_counter("nonces_issued_total")
+            .with_description("The number of nonces handed out to clients")
+            .init();
+        let stale_nonces_counter = meter
+            .u64_counter("stale_nonces_total")
+            .with_description(
+                "The number of requests rejected because of an unknown or used up nonce",
+            )
+            .init();
 
         Self {
             decoder: Default::default(),
@@ -190,21 +279,313 @@ where
             highest_port,
             channels_by_client_and_number: Default::default(),
             channel_numbers_by_client_and_peer: Default::default(),
+            permissions: Default::default(),
             pending_commands: Default::default(),
             next_allocation_id: AllocationId(1),
-            auth_secret: SecretString::from(hex::encode(rng.gen::<[u8; 32]>())),
+            auth_secrets: AuthSecrets::new(SecretString::from(hex::encode(rng.gen::<[u8; 32]>()))),
             rng,
             time_events: TimeEvents::default(),
             nonces: Default::default(),
+            rate_limits: RateLimits::default(),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            drain_deadline: None,
+            quotas_by_allocation: Default::default(),
+            quotas_by_username: Default::default(),
             allocations_up_down_counter,
+            channels_up_down_counter,
             responses_counter,
-            data_relayed_counter,
-            data_relayed: 0,
+            fast_path: FastPath::new(data_relayed_counter, rate_limited_packets_counter.clone()),
+            rate_limited_packets_counter,
+            nonces_issued_counter,
+            stale_nonces_counter,
         }
     }
 
+    /// Limits how much data each allocation and each username can relay.
+    ///
+    /// Packets exceeding either limit are dropped.
+    pub fn with_rate_limits(mut self, rate_limits: RateLimits) -> Self {
+        self.rate_limits = rate_limits;
+
+        self
+    }
+
+    /// How long existing allocations are served at most once we start draining.
+    pub fn with_drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+
+        self
+    }
+
+    /// Starts draining the server.
+    ///
+    /// A draining server rejects new allocations with a 508 (Insufficient Capacity) response.
+    /// Existing allocations are served (and can be refreshed) until they expire or the drain timeout passes, whichever comes first.
+    ///
+    /// Returns the deadline after which all remaining allocations will be deleted.
+    /// Calling this on a server that is already draining has no effect.
+    pub fn start_draining(&mut self, now: SystemTime) -> SystemTime {
+        if let Some(deadline) = self.drain_deadline {
+            return deadline;
+        }
+
+        let deadline = now + self.drain_timeout;
+
+        tracing::info!(target: "relay", num_allocations = %self.allocations.len(), "Draining until {deadline:?}");
+
+        self.drain_deadline = Some(deadline);
+
+        let wake_deadline = self.time_events.add(deadline, TimedAction::DrainDeadline);
+        self.pending_commands.push_back(Command::Wake {
+            deadline: wake_deadline,
+        });
+
+        deadline
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.drain_deadline.is_some()
+    }
+
     pub fn auth_secret(&self) -> &SecretString {
-        &self.auth_secret
+        self.auth_secrets.current()
+    }
+
+    /// Replaces the secret used to verify the credentials of clients.
+    ///
+    /// Credentials stamped with the previous secret remain valid for `overlap`.
+    pub fn rotate_auth_secret(&mut self, secret: SecretString, overlap: Duration, now: SystemTime) {
+        self.auth_secrets.rotate(secret, now + overlap);
+
+        tracing::info!(target: "relay", "Rotated auth secret, previous secret remains valid for {overlap:?}");
+    }
+
+    /// Rejects all further requests authenticated with the given username and revokes its allocations.
+    ///
+    /// Returns the number of revoked allocations.
+    pub fn revoke_username(&mut self, username: &str, now: SystemTime) -> usize {
+        self.auth_secrets.revoke_username(username, now);
+
+        self.revoke_allocations_of_username(username)
+    }
+
+    /// Captures the state needed to continue serving the current allocations after a restart.
+    pub fn snapshot(&self, now: SystemTime) -> Snapshot {
+        let is_udp = |client: &ClientSocket| client.transport() == Transport::Udp;
+
+        Snapshot {
+            next_allocation_id: self.next_allocation_id.0,
+            auth_secret: self.auth_secrets.current().expose_secret().clone(),
+            previous_auth_secret: self
+                .auth_secrets
+                .previous(now)
+                .map(|(secret, valid_until)| (secret.expose_secret().clone(), valid_until)),
+            revoked_usernames: self
+                .auth_secrets
+                .revoked_usernames()
+                .map(ToOwned::to_owned)
+                .collect(),
+            nonces: self
+                .nonces
+                .iter()
+                .map(|(nonce, remaining_requests)| NonceSnapshot {
+                    nonce: nonce.as_u128(),
+                    remaining_requests,
+                })
+                .collect(),
+            allocations: self
+                .allocations
+                .iter()
+                .filter(|(client, _)| is_udp(client))
+                .map(|(client, allocation)| AllocationSnapshot {
+                    id: allocation.id.0,
+                    client: client.into_socket(),
+                    port: allocation.port,
+                    expires_at: allocation.expires_at,
+                    username: allocation.username.clone(),
+                    relayed_bytes: allocation.relayed_bytes.load(Ordering::Relaxed),
+                    first_relay_addr: allocation.first_relay_addr,
+                    second_relay_addr: allocation.second_relay_addr,
+                })
+                .collect(),
+            channels: self
+                .channels_by_client_and_number
+                .iter()
+                .filter(|((client, _), _)| is_udp(client))
+                .map(|((client, number), channel)| ChannelSnapshot {
+                    client: client.into_socket(),
+                    number: *number,
+                    peer: channel.peer_address.into_socket(),
+                    allocation: channel.allocation.0,
+                    expiry: channel.expiry,
+                    bound: channel.bound,
+                })
+                .collect(),
+            permissions: self
+                .permissions
+                .iter()
+                .filter(|((client, _), _)| is_udp(client))
+                .map(|((client, peer), permission)| PermissionSnapshot {
+                    client: client.into_socket(),
+                    peer: *peer,
+                    allocation: permission.allocation.0,
+                    expiry: permission.expiry,
+                })
+                .collect(),
+        }
+    }
+
+    /// Restores the state captured by [`Server::snapshot`].
+    ///
+    /// This must be called on a freshly created [`Server`], before handling any input.
+    /// Allocations that expired in the meantime or don't fit the current configuration are skipped.
+    /// For all others, [`Command::CreateAllocation`] is issued again so the caller re-binds their ports.
+    ///
+    /// Returns the number of restored allocations.
+    pub fn restore(&mut self, snapshot: Snapshot, now: SystemTime) -> usize {
+        debug_assert!(self.allocations.is_empty());
+
+        self.auth_secrets = AuthSecrets::restore(
+            SecretString::from(snapshot.auth_secret),
+            snapshot
+                .previous_auth_secret
+                .map(|(secret, valid_until)| (SecretString::from(secret), valid_until)),
+            snapshot.revoked_usernames,
+        );
+        for NonceSnapshot {
+            nonce,
+            remaining_requests,
+        } in snapshot.nonces
+        {
+            self.nonces
+                .restore(Uuid::from_u128(nonce), remaining_requests);
+        }
+        self.next_allocation_id = AllocationId(snapshot.next_allocation_id.max(1));
+
+        let mut wake_deadline = None;
+
+        for allocation in snapshot.allocations {
+            let id = AllocationId(allocation.id);
+            let client = ClientSocket::new(allocation.client);
+
+            if allocation.expires_at <= now {
+                continue;
+            }
+            if !(self.lowest_port..=self.highest_port).contains(&allocation.port)
+                || self.allocations_by_port.contains_key(&allocation.port)
+            {
+                tracing::warn!(target: "relay", allocation = %id, port = %allocation.port, "Port of allocation is no longer available, not restoring it");
+                continue;
+            }
+            let relay_addrs =
+                iter::once(allocation.first_relay_addr).chain(allocation.second_relay_addr);
+            if !relay_addrs.clone().all(|addr| self.is_public_address(addr)) {
+                tracing::warn!(target: "relay", allocation = %id, "Public address changed, not restoring allocation");
+                continue;
+            }
+
+            for addr in relay_addrs {
+                self.pending_commands.push_back(Command::CreateAllocation {
+                    id,
+                    family: addr.family(),
+                    port: allocation.port,
+                });
+            }
+            wake_deadline = Some(
+                self.time_events
+                    .add(allocation.expires_at, TimedAction::ExpireAllocation(id)),
+            );
+
+            self.next_allocation_id.0 = self.next_allocation_id.0.max(id.0 + 1);
+            self.allocations_by_port.insert(allocation.port, id);
+            self.clients_by_allocation.insert(id, client);
+            self.allocations.insert(
+                client,
+                Allocation {
+                    id,
+                    port: allocation.port,
+                    expires_at: allocation.expires_at,
+                    username: allocation.username,
+                    relayed_bytes: Arc::new(AtomicU64::new(allocation.relayed_bytes)),
+                    first_relay_addr: allocation.first_relay_addr,
+                    second_relay_addr: allocation.second_relay_addr,
+                },
+            );
+            self.allocations_up_down_counter.add(1, &[]);
+        }
+
+        for channel in snapshot.channels {
+            let client = ClientSocket::new(channel.client);
+            let peer = PeerSocket::new(channel.peer);
+            let id = AllocationId(channel.allocation);
+
+            if self.clients_by_allocation.get(&id) != Some(&client) {
+                continue;
+            }
+
+            let (deadline, action) = if channel.bound {
+                (
+                    channel.expiry,
+                    TimedAction::UnbindChannel((client, channel.number)),
+                )
+            } else {
+                // Unbound channels are kept around for another 5 minutes, see `TimedAction::UnbindChannel`.
+                (
+                    channel.expiry + Duration::from_secs(5 * 60),
+                    TimedAction::DeleteChannel((client, channel.number)),
+                )
+            };
+
+            if deadline <= now {
+                continue;
+            }
+
+            self.create_channel_binding(client, channel.number, peer, id, now);
+
+            let restored = self
+                .channels_by_client_and_number
+                .get_mut(&(client, channel.number))
+                .expect("channel was just created");
+            restored.expiry = channel.expiry;
+            restored.bound = channel.bound;
+
+            if !channel.bound {
+                self.fast_path.remove_channel(client, channel.number);
+            }
+
+            wake_deadline = Some(self.time_events.add(deadline, action));
+        }
+
+        for permission in snapshot.permissions {
+            let client = ClientSocket::new(permission.client);
+            let id = AllocationId(permission.allocation);
+
+            if self.clients_by_allocation.get(&id) != Some(&client) || permission.expiry <= now {
+                continue;
+            }
+
+            self.permissions.insert(
+                (client, permission.peer),
+                Permission {
+                    expiry: permission.expiry,
+                    allocation: id,
+                },
+            );
+            wake_deadline = Some(self.time_events.add(
+                permission.expiry,
+                TimedAction::ExpirePermission((client, permission.peer)),
+            ));
+        }
+
+        if let Some(deadline) = wake_deadline {
+            self.pending_commands.push_back(Command::Wake { deadline });
+        }
+
+        let num_restored = self.allocations.len();
+
+        tracing::info!(target: "relay", "Restored {num_restored} allocations");
+
+        num_restored
     }
 
     /// Registers a new, valid nonce.
@@ -212,10 +593,18 @@ where
     /// Each nonce is valid for 10 requests.
     pub fn add_nonce(&mut self, nonce: Uuid) {
         self.nonces.add_new(nonce);
+        self.nonces_issued_counter.add(1, &[]);
     }
 
     pub fn num_relayed_bytes(&self) -> u64 {
-        self.data_relayed
+        self.fast_path.num_relayed_bytes()
+    }
+
+    /// Returns the [`FastPath`] that the socket tasks use to relay channel data without going through the [`Server`].
+    ///
+    /// Channels are only relayed on the fast path if no rate limits are configured because those need to be enforced by the [`Server`].
+    pub fn fast_path(&self) -> FastPath {
+        self.fast_path.clone()
     }
 
     pub fn num_allocations(&self) -> usize {
@@ -226,6 +615,72 @@ where
         self.channels_by_client_and_number.len()
     }
 
+    /// Returns a snapshot of all active allocations.
+    pub fn allocations(&self) -> Vec<AllocationInfo> {
+        self.allocations
+            .iter()
+            .map(|(client, allocation)| {
+                let channels = self
+                    .channels_by_client_and_number
+                    .iter()
+                    .filter(|(_, channel)| channel.allocation == allocation.id)
+                    .map(|((_, number), channel)| ChannelInfo {
+                        number: *number,
+                        peer: channel.peer_address,
+                        expires_at: channel.expiry,
+                        bound: channel.bound,
+                    })
+                    .collect();
+
+                AllocationInfo {
+                    id: allocation.id,
+                    client: *client,
+                    relay_addresses: iter::once(allocation.first_relay_addr)
+                        .chain(allocation.second_relay_addr)
+                        .map(|ip| SocketAddr::new(ip, allocation.port))
+                        .collect(),
+                    expires_at: allocation.expires_at,
+                    username: allocation.username.clone(),
+                    channels,
+                    relayed_bytes: allocation.relayed_bytes.load(Ordering::Relaxed),
+                }
+            })
+            .collect()
+    }
+
+    /// Forcibly deletes an allocation, regardless of its lifetime.
+    ///
+    /// Returns `false` if there is no such allocation.
+    pub fn revoke_allocation(&mut self, id: AllocationId) -> bool {
+        if !self.clients_by_allocation.contains_key(&id) {
+            return false;
+        }
+
+        tracing::info!(target: "relay", allocation = %id, "Revoking allocation");
+
+        self.delete_allocation(id);
+
+        true
+    }
+
+    /// Forcibly deletes all allocations created with the given username.
+    ///
+    /// Returns the number of revoked allocations.
+    pub fn revoke_allocations_of_username(&mut self, username: &str) -> usize {
+        let ids = self
+            .allocations
+            .values()
+            .filter(|allocation| allocation.username == username)
+            .map(|allocation| allocation.id)
+            .collect::<Vec<_>>();
+
+        for id in &ids {
+            self.revoke_allocation(*id);
+        }
+
+        ids.len()
+    }
+
     /// Process the bytes received from a client.
     ///
     /// After calling this method, you should call [`Server::next_command`] until it returns `None`.
@@ -287,6 +742,10 @@ where
                 self.handle_channel_data_message(msg, sender, now);
                 return;
             }
+            ClientMessage::SendIndication(msg) => {
+                self.handle_send_indication(msg, sender, now);
+                return;
+            }
         };
 
         let Err(error_response) = result else {
@@ -330,6 +789,7 @@ where
         bytes: &[u8],
         sender: PeerSocket,
         allocation: AllocationId,
+        now: SystemTime,
     ) {
         if tracing::enabled!(target: "wire", tracing::Level::TRACE) {
             let hex_bytes = hex::encode(bytes);
@@ -343,11 +803,31 @@ where
 
         Span::current().record("recipient", field::display(&client));
 
+        let Some(permission) = self.permissions.get(&(client, sender.0.ip())) else {
+            tracing::debug!(target: "relay", "no permission installed for peer, refusing to relay {} bytes", bytes.len());
+            return;
+        };
+
+        if permission.allocation != allocation {
+            tracing::debug!(target: "relay", "permission is not associated with allocation");
+            return;
+        }
+
+        if permission.is_expired(now) {
+            tracing::debug!(target: "relay", "permission for peer expired, refusing to relay {} bytes", bytes.len());
+            return;
+        }
+
+        if !self.consume_quota(allocation, bytes.len(), now) {
+            return;
+        }
+
         let Some(channel_number) = self
             .channel_numbers_by_client_and_peer
             .get(&(client, sender))
+            .copied()
         else {
-            tracing::debug!(target: "relay", "no active channel, refusing to relay {} bytes", bytes.len());
+            self.send_data_indication(bytes, sender, client);
             return;
         };
 
@@ -355,7 +835,7 @@ where
 
         let Some(channel) = self
             .channels_by_client_and_number
-            .get(&(client, *channel_number))
+            .get(&(client, channel_number))
         else {
             debug_assert!(false, "unknown channel {}", channel_number);
             return;
@@ -363,6 +843,8 @@ where
 
         if !channel.bound {
             tracing::debug!(target: "relay", "channel existed but is unbound");
+
+            self.send_data_indication(bytes, sender, client);
             return;
         }
 
@@ -373,10 +855,9 @@ where
 
         tracing::debug!(target: "relay", "Relaying {} bytes", bytes.len());
 
-        self.data_relayed_counter.add(bytes.len() as u64, &[]);
-        self.data_relayed += bytes.len() as u64;
+        self.record_relayed_bytes(client, bytes.len());
 
-        let data = ChannelData::new(*channel_number, bytes).to_bytes();
+        let data = ChannelData::new(channel_number, bytes).to_bytes();
 
         if tracing::enabled!(target: "wire", tracing::Level::TRACE) {
             let hex_bytes = hex::encode(&data);
@@ -416,6 +897,7 @@ where
                         tracing::info!(target: "relay", "Channel {chan} is now expired");
 
                         channel.bound = false;
+                        self.fast_path.remove_channel(client, chan);
 
                         self.time_events.add(
                             now + Duration::from_secs(5 * 60),
@@ -426,10 +908,61 @@ where
                 TimedAction::DeleteChannel((client, chan)) => {
                     self.delete_channel_binding(client, chan);
                 }
+                TimedAction::ExpirePermission((client, peer)) => {
+                    let Some(permission) = self.permissions.get(&(client, peer)) else {
+                        tracing::debug!(target: "relay", "Cannot expire non-existing permission for {peer} of client {client}");
+
+                        continue;
+                    };
+
+                    if !permission.is_expired(now) {
+                        continue;
+                    }
+
+                    // Our clients only ever refresh channel bindings, thus a bound channel keeps its permission alive.
+                    if let Some(channel_expiry) = self.bound_channel_expiry(client, peer, now) {
+                        self.time_events.add(
+                            channel_expiry,
+                            TimedAction::ExpirePermission((client, peer)),
+                        );
+
+                        continue;
+                    }
+
+                    tracing::info!(target: "relay", "Permission for {peer} is now expired");
+
+                    self.permissions.remove(&(client, peer));
+                }
+                TimedAction::DrainDeadline => {
+                    let remaining = self
+                        .clients_by_allocation
+                        .keys()
+                        .copied()
+                        .collect::<Vec<_>>();
+
+                    tracing::info!(target: "relay", "Drain deadline reached, deleting {} remaining allocations", remaining.len());
+
+                    for id in remaining {
+                        self.delete_allocation(id);
+                    }
+                }
             }
         }
     }
 
+    /// The connection to a client was closed.
+    ///
+    /// For clients talking to us over TCP or TLS, the lifetime of the allocation is bound to the connection.
+    /// See <https://www.rfc-editor.org/rfc/rfc8656#section-3.1-6>.
+    #[tracing::instrument(skip(self), fields(%client), level = "error")]
+    pub fn handle_client_disconnected(&mut self, client: ClientSocket) {
+        let Some(allocation) = self.allocations.get(&client) else {
+            return;
+        };
+
+        self.delete_allocation(allocation.id)
+    }
+
     /// An allocation failed.
     #[tracing::instrument(skip(self), fields(%allocation), level = "error")]
     pub fn handle_allocation_failed(&mut self, allocation: AllocationId) {
@@ -476,6 +1009,13 @@ where
             return Err(error_response(AllocationMismatch, &request));
         }
 
+        if self.is_draining() {
+            tracing::info!(target: "relay", "Refusing new allocation because we are draining");
+
+            // We don't know of an alternate server so we cannot send 300 (Try Alternate).
+            return Err(error_response(InsufficientCapacity, &request));
+        }
+
         let max_available_ports = self.max_available_ports() as usize;
         if self.allocations_by_port.len() == max_available_ports {
             tracing::warn!(target: "relay", %max_available_ports, "No more ports available");
@@ -501,8 +1041,14 @@ where
         // TODO: Do we need to handle EVEN/ODD-PORT?
         let effective_lifetime = request.effective_lifetime();
 
+        let username = request
+            .username()
+            .map(|u| u.name().to_owned())
+            .unwrap_or_default(); // `verify_auth` ensures we have a username.
+
         let allocation = self.create_new_allocation(
             now,
+            username,
             &effective_lifetime,
             first_relay_address,
             maybe_second_relay_addr,
@@ -697,6 +1243,9 @@ where
                 channel.expiry,
                 TimedAction::UnbindChannel((sender, requested_channel)),
             );
+
+            let allocation_id = channel.allocation;
+            self.install_permission(sender, peer_address.0.ip(), allocation_id, now);
             self.send_message(
                 channel_bind_success_response(request.transaction_id()),
                 sender,
@@ -712,6 +1261,7 @@ where
 
         let allocation_id = allocation.id;
         self.create_channel_binding(sender, requested_channel, peer_address, allocation_id, now);
+        self.install_permission(sender, peer_address.0.ip(), allocation_id, now);
         self.send_message(
             channel_bind_success_response(request.transaction_id()),
             sender,
@@ -726,9 +1276,8 @@ where
     ///
     /// See <https://www.rfc-editor.org/rfc/rfc8656#name-receiving-a-createpermissio> for details.
     ///
-    /// This TURN server implementation does not support relaying data other than through channels.
-    /// Thus, creating a permission is a no-op that always succeeds.
-    #[tracing::instrument(skip(self, message, now), fields(%sender), level = "error")]
+    /// Installing a permission that already exists acts as a refresh of that permission.
+    #[tracing::instrument(skip(self, message, now), fields(%sender, allocation), level = "error")]
     fn handle_create_permission_request(
         &mut self,
         message: CreatePermission,
@@ -737,6 +1286,39 @@ where
     ) -> Result<(), Message<Attribute>> {
         self.verify_auth(&message, now)?;
 
+        let allocation = self
+            .allocations
+            .get(&sender)
+            .ok_or(error_response(AllocationMismatch, &message))?;
+
+        Span::current().record("allocation", display(&allocation.id));
+
+        // Either all permissions are installed or none.
+        if let Some(peer) = message
+            .xor_peer_addresses()
+            .iter()
+            .map(|a| PeerSocket(a.address()))
+            .find(|peer| !allocation.can_relay_to(*peer))
+        {
+            tracing::warn!(target: "relay", %peer, "Allocation cannot relay to peer");
+
+            return Err(error_response(PeerAddressFamilyMismatch, &message));
+        }
+
+        let allocation_id = allocation.id;
+        let mut wake_deadline = None;
+
+        for address in message.xor_peer_addresses() {
+            let peer = address.address().ip();
+
+            wake_deadline = Some(self.install_permission(sender, peer, allocation_id, now));
+
+            tracing::info!(target: "relay", %peer, "Installed permission");
+        }
+
+        if let Some(deadline) = wake_deadline {
+            self.pending_commands.push_back(Command::Wake { deadline });
+        }
         self.send_message(
             create_permission_success_response(message.transaction_id()),
             sender,
@@ -749,7 +1331,7 @@ where
         &mut self,
         message: ChannelData,
         sender: ClientSocket,
-        _: SystemTime,
+        now: SystemTime,
     ) {
         let channel_number = message.channel();
         let data = message.data();
@@ -770,14 +1352,20 @@ where
             return;
         }
 
-        Span::current().record("allocation", field::display(&channel.allocation));
-        Span::current().record("recipient", field::display(&channel.peer_address));
+        let allocation = channel.allocation;
+        let peer_address = channel.peer_address;
+
+        Span::current().record("allocation", field::display(&allocation));
+        Span::current().record("recipient", field::display(&peer_address));
         Span::current().record("channel", field::display(&channel_number));
 
+        if !self.consume_quota(allocation, data.len(), now) {
+            return;
+        }
+
         tracing::debug!(target: "relay", "Relaying {} bytes", data.len());
 
-        self.data_relayed_counter.add(data.len() as u64, &[]);
-        self.data_relayed += data.len() as u64;
+        self.record_relayed_bytes(sender, data.len());
 
         if tracing::enabled!(target: "wire", tracing::Level::TRACE) {
             let hex_bytes = hex::encode(data);
@@ -785,12 +1373,90 @@ where
         }
 
         self.pending_commands.push_back(Command::ForwardData {
-            id: channel.allocation,
+            id: allocation,
             data: data.to_vec(),
-            receiver: channel.peer_address,
+            receiver: peer_address,
         });
     }
 
+    /// Handle a TURN send indication.
+    ///
+    /// See <https://www.rfc-editor.org/rfc/rfc8656#name-receiving-a-send-indication> for details.
+    ///
+    /// Indications are never answered, thus all failures result in the data being silently discarded.
+    fn handle_send_indication(
+        &mut self,
+        message: SendIndication,
+        sender: ClientSocket,
+        now: SystemTime,
+    ) {
+        let Some(allocation) = self.allocations.get(&sender) else {
+            tracing::debug!(target: "relay", "Client has no allocation, refusing to forward data");
+            return;
+        };
+
+        let peer = PeerSocket(message.xor_peer_address().address());
+        let data = message.data();
+
+        Span::current().record("allocation", field::display(&allocation.id));
+        Span::current().record("recipient", field::display(&peer));
+
+        if !allocation.can_relay_to(peer) {
+            tracing::debug!(target: "relay", "Allocation cannot relay to peer");
+            return;
+        }
+
+        let id = allocation.id;
+
+        if !self.permissions.contains_key(&(sender, peer.0.ip())) {
+            tracing::debug!(target: "relay", "No permission installed for peer, refusing to forward data");
+            return;
+        }
+
+        if !self.consume_quota(id, data.len(), now) {
+            return;
+        }
+
+        tracing::debug!(target: "relay", "Relaying {} bytes", data.len());
+
+        self.record_relayed_bytes(sender, data.len());
+
+        if tracing::enabled!(target: "wire", tracing::Level::TRACE) {
+            let hex_bytes = hex::encode(data);
+            tracing::trace!(target: "wire", %hex_bytes, "sending bytes");
+        }
+
+        self.pending_commands.push_back(Command::ForwardData {
+            id,
+            data: data.to_vec(),
+            receiver: peer,
+        });
+    }
+
+    /// Relays data from a peer to a client that doesn't have a channel bound to this peer.
+    ///
+    /// See <https://www.rfc-editor.org/rfc/rfc8656#name-receiving-an-application-da>.
+    fn send_data_indication(&mut self, bytes: &[u8], sender: PeerSocket, recipient: ClientSocket) {
+        let Ok(data) = Data::new(bytes.to_vec()) else {
+            tracing::debug!(target: "relay", "{} bytes do not fit into a data indication", bytes.len());
+            return;
+        };
+
+        tracing::debug!(target: "relay", "Relaying {} bytes via data indication", bytes.len());
+
+        self.record_relayed_bytes(recipient, bytes.len());
+
+        let mut message = Message::new(
+            MessageClass::Indication,
+            DATA,
+            TransactionId::new(self.rng.gen()),
+        );
+        message.add_attribute(XorPeerAddress::new(sender.0));
+        message.add_attribute(data);
+
+        self.send_message(message, recipient);
+    }
+
     fn verify_auth(
         &mut self,
         request: &(impl StunRequest + ProtectedRequest),
@@ -811,12 +1477,14 @@ where
                 error_response(Unauthorized, request)
             })?;
 
-        self.nonces
-            .handle_nonce_used(nonce)
-            .map_err(|_| error_response(StaleNonce, request))?;
+        self.nonces.handle_nonce_used(nonce).map_err(|_| {
+            self.stale_nonces_counter.add(1, &[]);
+
+            error_response(StaleNonce, request)
+        })?;
 
         message_integrity
-            .verify(&self.auth_secret, username.name(), now)
+            .verify(&self.auth_secrets, username.name(), now)
             .map_err(|_| error_response(Unauthorized, request))?;
 
         Ok(())
@@ -825,6 +1493,7 @@ where
     fn create_new_allocation(
         &mut self,
         now: SystemTime,
+        username: String,
         lifetime: &Lifetime,
         first_relay_addr: IpAddr,
         second_relay_addr: Option<IpAddr>,
@@ -853,11 +1522,20 @@ where
             id,
             port,
             expires_at: now + lifetime.lifetime(),
+            username,
+            relayed_bytes: Default::default(),
             first_relay_addr,
             second_relay_addr,
         }
     }
 
+    fn is_public_address(&self, addr: IpAddr) -> bool {
+        match addr {
+            IpAddr::V4(ip4) => self.public_address.as_v4() == Some(&ip4),
+            IpAddr::V6(ip6) => self.public_address.as_v6() == Some(&ip6),
+        }
+    }
+
     fn max_available_ports(&self) -> u16 {
         self.highest_port - self.lowest_port
     }
@@ -886,11 +1564,71 @@ where
             .insert((client, peer), requested_channel);
 
         debug_assert!(existing.is_none());
+
+        let quotas = self.quotas_of(id, now);
+        if let Some(allocation) = self.allocations.get(&client) {
+            self.fast_path.add_channel(
+                client,
+                requested_channel,
+                id,
+                peer,
+                allocation.relayed_bytes.clone(),
+                quotas,
+            );
+        }
+
+        self.channels_up_down_counter.add(1, &[]);
+    }
+
+    /// Installs or refreshes the permission for the given peer IP.
+    ///
+    /// Returns the new wake deadline for convenience.
+    fn install_permission(
+        &mut self,
+        client: ClientSocket,
+        peer: IpAddr,
+        id: AllocationId,
+        now: SystemTime,
+    ) -> SystemTime {
+        let permission = self
+            .permissions
+            .entry((client, peer))
+            .or_insert(Permission {
+                expiry: now,
+                allocation: id,
+            });
+        permission.refresh(now);
+
+        self.time_events.add(
+            permission.expiry,
+            TimedAction::ExpirePermission((client, peer)),
+        )
+    }
+
+    /// Returns the latest expiry of all bound channels from the given client to the given peer IP.
+    fn bound_channel_expiry(
+        &self,
+        client: ClientSocket,
+        peer: IpAddr,
+        now: SystemTime,
+    ) -> Option<SystemTime> {
+        let (lowest, highest) = peer_sockets_of(peer);
+
+        self.channel_numbers_by_client_and_peer
+            .range((client, lowest)..=(client, highest))
+            .filter_map(|((c, _), number)| self.channels_by_client_and_number.get(&(*c, *number)))
+            .filter(|channel| channel.bound && !channel.is_expired(now))
+            .map(|channel| channel.expiry)
+            .max()
     }
 
     fn send_message(&mut self, message: Message<Attribute>, recipient: ClientSocket) {
         let method = message.method();
         let class = message.class();
+        let status_code = message
+            .get_attribute::<ErrorCode>()
+            .map(|e| e.code())
+            .unwrap_or(200);
         tracing::trace!(target: "relay",  method = %message.method(), class = %message.class(), "Sending message");
 
         let Ok(bytes) = self.encoder.encode_into_bytes(message) else {
@@ -927,10 +1665,74 @@ where
             &[
                 KeyValue::new("response_class", response_class),
                 KeyValue::new("message_type", message_type),
+                KeyValue::new("status_code", i64::from(status_code)),
             ],
         );
     }
 
+    /// Consumes the quota for relaying a single packet of `num_bytes` via the given allocation.
+    ///
+    /// Returns `false` if either the allocation or its username exceeded their rate limit, in which case the packet should be dropped.
+    fn consume_quota(&mut self, id: AllocationId, num_bytes: usize, now: SystemTime) -> bool {
+        let Err(scope) = self.quotas_of(id, now).try_consume(num_bytes, now) else {
+            return true;
+        };
+
+        tracing::debug!(target: "relay", "{} exceeded its rate limit, dropping {num_bytes} bytes", scope.as_str());
+        self.rate_limited_packets_counter
+            .add(1, &[KeyValue::new("scope", scope.as_str())]);
+
+        false
+    }
+
+    /// Returns the quotas of the given allocation, creating them on first use.
+    ///
+    /// The same quotas are handed to the [`FastPath`], so both enforce the same limits.
+    fn quotas_of(&mut self, id: AllocationId, now: SystemTime) -> Quotas {
+        if self.rate_limits.is_unlimited() {
+            return Quotas::default();
+        }
+
+        let RateLimits {
+            per_allocation,
+            per_username,
+        } = self.rate_limits;
+
+        let Some(username) = self
+            .clients_by_allocation
+            .get(&id)
+            .and_then(|client| self.allocations.get(client))
+            .map(|allocation| &allocation.username)
+        else {
+            return Quotas::default();
+        };
+
+        let allocation = (!per_allocation.is_unlimited()).then(|| {
+            self.quotas_by_allocation
+                .entry(id)
+                .or_insert_with(|| Arc::new(Mutex::new(Quota::new(per_allocation, now))))
+                .clone()
+        });
+        let username = (!per_username.is_unlimited()).then(|| {
+            self.quotas_by_username
+                .entry(username.clone())
+                .or_insert_with(|| Arc::new(Mutex::new(Quota::new(per_username, now))))
+                .clone()
+        });
+
+        Quotas {
+            allocation,
+            username,
+        }
+    }
+
+    fn record_relayed_bytes(&mut self, client: ClientSocket, num_bytes: usize) {
+        let allocation = self.allocations.get(&client);
+
+        self.fast_path
+            .record_relayed_bytes(num_bytes, allocation.map(|a| a.relayed_bytes.as_ref()));
+    }
+
     fn get_allocation(&self, id: &AllocationId) -> Option<&Allocation> {
         self.clients_by_allocation
             .get(id)
@@ -951,6 +1753,26 @@ where
         let port = allocation.port;
 
         self.allocations_by_port.remove(&port);
+        self.permissions
+            .retain(|_, permission| permission.allocation != id);
+        let channels = self
+            .channels_by_client_and_number
+            .iter()
+            .filter(|(_, channel)| channel.allocation == id)
+            .map(|((client, number), _)| (*client, *number))
+            .collect::<Vec<_>>();
+        for (client, number) in channels {
+            self.delete_channel_binding(client, number);
+        }
+        self.quotas_by_allocation.remove(&id);
+        self.fast_path.remove_allocation(id);
+        if !self
+            .allocations
+            .values()
+            .any(|a| a.username == allocation.username)
+        {
+            self.quotas_by_username.remove(&allocation.username);
+        }
 
         self.allocations_up_down_counter.add(-1, &[]);
         self.pending_commands.push_back(Command::FreeAllocation {
@@ -984,6 +1806,27 @@ where
         );
 
         self.channels_by_client_and_number.remove(&(client, chan));
+        self.fast_path.remove_channel(client, chan);
+        self.channels_up_down_counter.add(-1, &[]);
+    }
+}
+
+/// The lowest and highest [`PeerSocket`] with the given IP.
+fn peer_sockets_of(ip: IpAddr) -> (PeerSocket, PeerSocket) {
+    match ip {
+        IpAddr::V4(ip) => (
+            PeerSocket(SocketAddr::V4(SocketAddrV4::new(ip, 0))),
+            PeerSocket(SocketAddr::V4(SocketAddrV4::new(ip, u16::MAX))),
+        ),
+        IpAddr::V6(ip) => (
+            PeerSocket(SocketAddr::V6(SocketAddrV6::new(ip, 0, 0, 0))),
+            PeerSocket(SocketAddr::V6(SocketAddrV6::new(
+                ip,
+                u16::MAX,
+                u32::MAX,
+                u32::MAX,
+            ))),
+        ),
     }
 }
 
@@ -1014,6 +1857,12 @@ struct Allocation {
     /// Data arriving on this port will be forwarded to the client iff there is an active data channel.
     port: u16,
     expires_at: SystemTime,
+    /// The username the allocation was created with, used to enforce rate limits across allocations.
+    username: String,
+    /// The number of bytes relayed in both directions.
+    ///
+    /// Shared with the [`FastPath`] which relays data without going through the [`Server`].
+    relayed_bytes: Arc<AtomicU64>,
 
     first_relay_addr: IpAddr,
     second_relay_addr: Option<IpAddr>,
@@ -1051,10 +1900,28 @@ impl Channel {
     }
 }
 
+struct Permission {
+    /// When the permission expires.
+    expiry: SystemTime,
+
+    /// The allocation this permission belongs to.
+    allocation: AllocationId,
+}
+
+impl Permission {
+    fn refresh(&mut self, now: SystemTime) {
+        self.expiry = now + PERMISSION_DURATION;
+    }
+
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expiry <= now
+    }
+}
+
 impl Allocation {
     /// Checks whether this [`Allocation`] can relay to the given address.
     ///
-    /// This is called in the context of a channel binding with the requested peer address.
+    /// This is called in the context of a channel binding or permission with the requested peer address.
     /// We can only relay to the address if the allocation supports the same version of the IP protocol.
     fn can_relay_to(&self, addr: PeerSocket) -> bool {
         match addr.0 {
@@ -1078,6 +1945,8 @@ enum TimedAction {
     ExpireAllocation(AllocationId),
     UnbindChannel((ClientSocket, u16)),
     DeleteChannel((ClientSocket, u16)),
+    ExpirePermission((ClientSocket, IpAddr)),
+    DrainDeadline,
 }
 
 fn error_response(
@@ -1242,7 +2111,9 @@ stun_codec::define_attribute_enums!(
         Realm,
         Username,
         RequestedAddressFamily,
-        AdditionalAddressFamily
+        AdditionalAddressFamily,
+        Data,
+        DontFragment
     ]
 );
 
@@ -1306,4 +2177,13 @@ mod tests {
 
         assert_eq!(error_code.code(), BadRequest::CODEPOINT)
     }
+
+    #[test]
+    fn allocation_id_can_be_parsed_from_display() {
+        let id = AllocationId(42);
+
+        assert_eq!(id.to_string().parse::<AllocationId>().unwrap(), id);
+        assert_eq!("42".parse::<AllocationId>().unwrap(), id);
+        assert!("AID-foo".parse::<AllocationId>().is_err());
+    }
 }
@@ -10,9 +10,9 @@ use stun_codec::rfc5389::attributes::{ErrorCode, MessageIntegrity, Nonce, Userna
 use stun_codec::rfc5389::errors::BadRequest;
 use stun_codec::rfc5389::methods::BINDING;
 use stun_codec::rfc5766::attributes::{
-    ChannelNumber, Lifetime, RequestedTransport, XorPeerAddress,
+    ChannelNumber, Data, Lifetime, RequestedTransport, XorPeerAddress,
 };
-use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, REFRESH};
+use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, REFRESH, SEND};
 use stun_codec::rfc8656::attributes::{
     AdditionalAddressFamily, AddressFamily, RequestedAddressFamily,
 };
@@ -65,9 +65,19 @@ impl Decoder {
                     (CHANNEL_BIND, Request) => {
                         Ok(ChannelBind::parse(&message).map(ClientMessage::ChannelBind))
                     }
-                    (CREATE_PERMISSION, Request) => Ok(Ok(ClientMessage::CreatePermission(
-                        CreatePermission::parse(&message),
-                    ))),
+                    (CREATE_PERMISSION, Request) => {
+                        Ok(CreatePermission::parse(&message).map(ClientMessage::CreatePermission))
+                    }
+                    (SEND, Indication) => {
+                        let indication = SendIndication::parse(&message).ok_or_else(|| {
+                            Error::DecodeStun(bytecodec::Error::from(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "send indication is missing XOR-PEER-ADDRESS or DATA",
+                            )))
+                        })?;
+
+                        Ok(Ok(ClientMessage::SendIndication(indication)))
+                    }
                     (_, Request) => Ok(Err(bad_request(&message))),
                     (method, class) => {
                         Err(Error::DecodeStun(bytecodec::Error::from(io::Error::new(
@@ -95,6 +105,7 @@ pub enum ClientMessage<'a> {
     Refresh(Refresh),
     ChannelBind(ChannelBind),
     CreatePermission(CreatePermission),
+    SendIndication(SendIndication),
 }
 
 impl<'a> ClientMessage<'a> {
@@ -105,6 +116,7 @@ impl<'a> ClientMessage<'a> {
             ClientMessage::Refresh(request) => Some(request.transaction_id),
             ClientMessage::ChannelBind(request) => Some(request.transaction_id),
             ClientMessage::CreatePermission(request) => Some(request.transaction_id),
+            ClientMessage::SendIndication(indication) => Some(indication.transaction_id),
             ClientMessage::ChannelData(_) => None,
         }
     }
@@ -500,23 +512,71 @@ impl ChannelBind {
 pub struct CreatePermission {
     transaction_id: TransactionId,
     message_integrity: Option<MessageIntegrity>,
+    xor_peer_addresses: Vec<XorPeerAddress>,
     username: Option<Username>,
     nonce: Option<Nonce>,
 }
 
 impl CreatePermission {
-    pub fn parse(message: &Message<Attribute>) -> Self {
+    pub fn new(
+        transaction_id: TransactionId,
+        xor_peer_address: XorPeerAddress,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: Uuid,
+    ) -> Self {
+        let nonce = Nonce::new(nonce.as_hyphenated().to_string()).expect("len(uuid) < 128");
+
+        let mut message =
+            Message::<Attribute>::new(MessageClass::Request, CREATE_PERMISSION, transaction_id);
+        message.add_attribute(username.clone());
+        message.add_attribute(xor_peer_address.clone());
+        message.add_attribute(nonce.clone());
+
+        let (expiry, salt) = split_username(username.name()).expect("a valid username");
+        let expiry_systemtime = systemtime_from_unix(expiry);
+
+        let password = generate_password(relay_secret, expiry_systemtime, salt);
+
+        let message_integrity =
+            MessageIntegrity::new_long_term_credential(&message, &username, &FIREZONE, &password)
+                .unwrap();
+
+        Self {
+            transaction_id,
+            message_integrity: Some(message_integrity),
+            xor_peer_addresses: vec![xor_peer_address],
+            username: Some(username),
+            nonce: Some(nonce),
+        }
+    }
+
+    pub fn parse(message: &Message<Attribute>) -> Result<Self, Message<Attribute>> {
         let transaction_id = message.transaction_id();
         let message_integrity = message.get_attribute::<MessageIntegrity>().cloned();
         let username = message.get_attribute::<Username>().cloned();
         let nonce = message.get_attribute::<Nonce>().cloned();
 
-        CreatePermission {
+        // A single request may install permissions for several peers at once.
+        let xor_peer_addresses = message
+            .attributes()
+            .filter_map(|attribute| match attribute {
+                Attribute::XorPeerAddress(address) => Some(address.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        if xor_peer_addresses.is_empty() {
+            return Err(bad_request(message));
+        }
+
+        Ok(CreatePermission {
             transaction_id,
             message_integrity,
+            xor_peer_addresses,
             username,
             nonce,
-        }
+        })
     }
 
     pub fn transaction_id(&self) -> TransactionId {
@@ -527,6 +587,10 @@ impl CreatePermission {
         self.message_integrity.as_ref()
     }
 
+    pub fn xor_peer_addresses(&self) -> &[XorPeerAddress] {
+        &self.xor_peer_addresses
+    }
+
     pub fn username(&self) -> Option<&Username> {
         self.username.as_ref()
     }
@@ -536,6 +600,56 @@ impl CreatePermission {
     }
 }
 
+/// A TURN send indication.
+///
+/// Indications are not authenticated and never answered, thus we only extract the attributes required for relaying.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8656#name-send-and-data-methods>.
+#[derive(Debug)]
+pub struct SendIndication {
+    transaction_id: TransactionId,
+    xor_peer_address: XorPeerAddress,
+    data: Data,
+}
+
+impl SendIndication {
+    pub fn new(
+        transaction_id: TransactionId,
+        xor_peer_address: XorPeerAddress,
+        data: Vec<u8>,
+    ) -> Self {
+        Self {
+            transaction_id,
+            xor_peer_address,
+            data: Data::new(data).expect("data to fit into a single STUN attribute"),
+        }
+    }
+
+    pub fn parse(message: &Message<Attribute>) -> Option<Self> {
+        let transaction_id = message.transaction_id();
+        let xor_peer_address = message.get_attribute::<XorPeerAddress>()?.clone();
+        let data = message.get_attribute::<Data>()?.clone();
+
+        Some(SendIndication {
+            transaction_id,
+            xor_peer_address,
+            data,
+        })
+    }
+
+    pub fn transaction_id(&self) -> TransactionId {
+        self.transaction_id
+    }
+
+    pub fn xor_peer_address(&self) -> &XorPeerAddress {
+        &self.xor_peer_address
+    }
+
+    pub fn data(&self) -> &[u8] {
+        self.data.data()
+    }
+}
+
 /// Computes the effective lifetime of an allocation.
 fn compute_effective_lifetime(requested_lifetime: Option<&Lifetime>) -> Lifetime {
     let Some(requested) = requested_lifetime else {
@@ -0,0 +1,58 @@
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::time::SystemTime;
+
+/// The state of a [`Server`](crate::Server) that is needed to continue serving existing allocations after a restart.
+///
+/// Only allocations of UDP clients are included because the lifetime of allocations of TCP and TLS clients is bound to their connection.
+///
+/// The snapshot contains the secret used to verify the credentials of clients, thus it needs to be stored securely.
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub(super) next_allocation_id: u64,
+
+    pub(super) auth_secret: String,
+    pub(super) previous_auth_secret: Option<(String, SystemTime)>,
+    pub(super) revoked_usernames: Vec<String>,
+    pub(super) nonces: Vec<NonceSnapshot>,
+
+    pub(super) allocations: Vec<AllocationSnapshot>,
+    pub(super) channels: Vec<ChannelSnapshot>,
+    pub(super) permissions: Vec<PermissionSnapshot>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct NonceSnapshot {
+    pub(super) nonce: u128,
+    pub(super) remaining_requests: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct AllocationSnapshot {
+    pub(super) id: u64,
+    pub(super) client: SocketAddr,
+    pub(super) port: u16,
+    pub(super) expires_at: SystemTime,
+    pub(super) username: String,
+    pub(super) relayed_bytes: u64,
+    pub(super) first_relay_addr: IpAddr,
+    pub(super) second_relay_addr: Option<IpAddr>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct ChannelSnapshot {
+    pub(super) client: SocketAddr,
+    pub(super) number: u16,
+    pub(super) peer: SocketAddr,
+    pub(super) allocation: u64,
+    pub(super) expiry: SystemTime,
+    pub(super) bound: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct PermissionSnapshot {
+    pub(super) client: SocketAddr,
+    pub(super) peer: IpAddr,
+    pub(super) allocation: u64,
+    pub(super) expiry: SystemTime,
+}
@@ -0,0 +1,379 @@
+use crate::{AddressFamily, ClientSocket, Transport};
+use anyhow::{bail, Context as _, Result};
+use bytes::BytesMut;
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+use std::convert::Infallible;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::pki_types::{
+    CertificateDer, PrivateKeyDer, PrivatePkcs1KeyDer, PrivatePkcs8KeyDer, PrivateSec1KeyDer,
+};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+/// The length of a STUN message header.
+const STUN_HEADER_LEN: usize = 20;
+
+/// The length of a channel data header.
+const CHANNEL_DATA_HEADER_LEN: usize = 4;
+
+/// The maximum amount of messages that can be buffered for a single connection.
+const MAX_BUFFERED_MESSAGES: usize = 100;
+
+/// How long a client has to complete the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long we keep a connection open without receiving anything from the client.
+///
+/// This matches the default allocation lifetime: clients with an allocation need to refresh it within that time anyway.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Events emitted by the tasks that handle TCP & TLS connections.
+#[derive(Debug)]
+pub enum StreamEvent {
+    /// A new client connected.
+    ///
+    /// All messages for this client should be sent via the provided sender.
+    Connected {
+        client: ClientSocket,
+        sender: mpsc::Sender<Vec<u8>>,
+    },
+    /// The connection to a client was closed.
+    Disconnected { client: ClientSocket },
+}
+
+/// Accepts TURN over TCP or, if given a [`TlsAcceptor`], TURN over TLS.
+///
+/// Each connection is handled in a separate task.
+/// Messages received on a connection are framed and forwarded to `inbound_data_sender`, just like datagrams from the main UDP socket.
+pub async fn accept_streams(
+    family: AddressFamily,
+    port: u16,
+    tls_acceptor: Option<TlsAcceptor>,
+    inbound_data_sender: mpsc::Sender<(Vec<u8>, ClientSocket)>,
+    stream_event_sender: mpsc::Sender<StreamEvent>,
+) -> Result<Infallible> {
+    let listener = TcpListener::from_std(
+        make_wildcard_listener(family, port)
+            .with_context(|| format!("Failed to bind TCP listener for {family}"))?,
+    )?;
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+
+        tokio::spawn(handle_connection(
+            stream,
+            addr,
+            tls_acceptor.clone(),
+            inbound_data_sender.clone(),
+            stream_event_sender.clone(),
+        ));
+    }
+}
+
+/// Constructs a [`TlsAcceptor`] from PEM-encoded certificate chain and private key files.
+pub fn make_tls_acceptor(cert_file: &Path, key_file: &Path) -> Result<TlsAcceptor> {
+    let mut certs = Vec::new();
+    let mut key = None;
+
+    for item in read_pem_file(cert_file)?
+        .into_iter()
+        .chain(read_pem_file(key_file)?)
+    {
+        match item {
+            rustls_pemfile::Item::X509Certificate(der) => certs.push(CertificateDer::from(der)),
+            rustls_pemfile::Item::RSAKey(der) => {
+                key = Some(PrivateKeyDer::Pkcs1(PrivatePkcs1KeyDer::from(der)))
+            }
+            rustls_pemfile::Item::PKCS8Key(der) => {
+                key = Some(PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(der)))
+            }
+            rustls_pemfile::Item::ECKey(der) => {
+                key = Some(PrivateKeyDer::Sec1(PrivateSec1KeyDer::from(der)))
+            }
+            _ => {}
+        }
+    }
+
+    if certs.is_empty() {
+        bail!("No certificates found in {}", cert_file.display());
+    }
+    let key = key.with_context(|| format!("No private key found in {}", key_file.display()))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid TLS certificate or key")?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn read_pem_file(path: &Path) -> Result<Vec<rustls_pemfile::Item>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+
+    let items = rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+    Ok(items)
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    addr: SocketAddr,
+    tls_acceptor: Option<TlsAcceptor>,
+    inbound_data_sender: mpsc::Sender<(Vec<u8>, ClientSocket)>,
+    mut stream_event_sender: mpsc::Sender<StreamEvent>,
+) {
+    let transport = match tls_acceptor {
+        Some(_) => Transport::Tls,
+        None => Transport::Tcp,
+    };
+    let client = ClientSocket::with_transport(addr, transport);
+    let (sender, receiver) = mpsc::channel(MAX_BUFFERED_MESSAGES);
+
+    let result: Result<()> = async {
+        stream.set_nodelay(true)?;
+
+        // Register the connection before we read anything so responses can be routed back to it.
+        stream_event_sender
+            .send(StreamEvent::Connected { client, sender })
+            .await?;
+
+        match tls_acceptor {
+            Some(acceptor) => {
+                let stream = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+                    .await
+                    .context("TLS handshake timed out")?
+                    .context("TLS handshake failed")?;
+
+                relay_stream(stream, client, inbound_data_sender, receiver, IDLE_TIMEOUT).await
+            }
+            None => relay_stream(stream, client, inbound_data_sender, receiver, IDLE_TIMEOUT).await,
+        }
+    }
+    .await;
+
+    match result {
+        Ok(()) => tracing::debug!(target: "relay", %client, "Connection closed"),
+        Err(e) => tracing::debug!(target: "relay", %client, "Connection failed: {e:#}"),
+    }
+
+    let _ = stream_event_sender
+        .send(StreamEvent::Disconnected { client })
+        .await;
+}
+
+async fn relay_stream<S>(
+    stream: S,
+    client: ClientSocket,
+    mut inbound_data_sender: mpsc::Sender<(Vec<u8>, ClientSocket)>,
+    mut outbound_data_receiver: mpsc::Receiver<Vec<u8>>,
+    idle_timeout: Duration,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut buffer = BytesMut::new();
+
+    let idle = tokio::time::sleep(idle_timeout);
+    tokio::pin!(idle);
+
+    loop {
+        tokio::select! {
+            result = reader.read_buf(&mut buffer) => {
+                if result? == 0 {
+                    return Ok(());
+                }
+
+                idle.as_mut().reset(tokio::time::Instant::now() + idle_timeout);
+
+                while let Some(frame) = next_frame(&mut buffer)? {
+                    inbound_data_sender.send((frame.to_vec(), client)).await?;
+                }
+            }
+            maybe_message = outbound_data_receiver.next() => {
+                let Some(message) = maybe_message else {
+                    return Ok(());
+                };
+
+                writer.write_all(&pad_to_stream(message)).await?;
+            }
+            () = &mut idle => {
+                bail!("Connection was idle for {idle_timeout:?}");
+            }
+        }
+    }
+}
+
+/// Splits off the next complete message from a stream of bytes.
+///
+/// Over TCP, there are no datagram boundaries so we need to rely on the length fields in the STUN and channel data headers.
+/// See <https://www.rfc-editor.org/rfc/rfc8656#name-the-channeldata-message>.
+fn next_frame(buffer: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+    if buffer.len() < CHANNEL_DATA_HEADER_LEN {
+        return Ok(None);
+    }
+
+    let length = u16::from_be_bytes([buffer[2], buffer[3]]) as usize;
+
+    // De-multiplex as per <https://www.rfc-editor.org/rfc/rfc8656#name-channels-2>.
+    let (message_len, frame_len) = match buffer[0] {
+        0..=3 => (STUN_HEADER_LEN + length, STUN_HEADER_LEN + length),
+        64..=79 => {
+            let message_len = CHANNEL_DATA_HEADER_LEN + length;
+
+            (message_len, padded_len(message_len))
+        }
+        other => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown message type {other}"),
+            ))
+        }
+    };
+
+    if buffer.len() < frame_len {
+        return Ok(None);
+    }
+
+    let mut frame = buffer.split_to(frame_len);
+    frame.truncate(message_len);
+
+    Ok(Some(frame))
+}
+
+/// Pads a message to a multiple of 4 bytes.
+///
+/// STUN messages are always a multiple of 4 bytes but channel data messages MUST be padded when sent over a stream.
+fn pad_to_stream(mut message: Vec<u8>) -> Vec<u8> {
+    message.resize(padded_len(message.len()), 0);
+
+    message
+}
+
+fn padded_len(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Creates a [std::net::TcpListener] via the [socket2] library that is configured for our needs.
+///
+/// Most importantly, this sets the `IPV6_V6ONLY` flag to ensure we disallow IP4-mapped IPv6 addresses and can bind to IP4 and IP6 addresses on the same port.
+fn make_wildcard_listener(family: AddressFamily, port: u16) -> Result<std::net::TcpListener> {
+    use socket2::*;
+
+    let domain = match family {
+        AddressFamily::V4 => Domain::IPV4,
+        AddressFamily::V6 => Domain::IPV6,
+    };
+    let address = match family {
+        AddressFamily::V4 => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+        AddressFamily::V6 => IpAddr::from(Ipv6Addr::UNSPECIFIED),
+    };
+
+    let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+    if family == AddressFamily::V6 {
+        socket.set_only_v6(true)?;
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SockAddr::from(SocketAddr::new(address, port)))?;
+    socket.listen(1024)?;
+
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_for_complete_stun_message() {
+        let mut buffer = BytesMut::from(&[0x00, 0x01, 0x00, 0x04][..]);
+        buffer.extend_from_slice(&[0u8; 16]);
+
+        assert!(next_frame(&mut buffer).unwrap().is_none());
+
+        buffer.extend_from_slice(&[0u8; 4]);
+
+        let frame = next_frame(&mut buffer).unwrap().unwrap();
+
+        assert_eq!(frame.len(), 24);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn strips_padding_of_channel_data() {
+        let mut buffer = BytesMut::from(&[0x40, 0x00, 0x00, 0x01, 0xFF, 0x00, 0x00, 0x00][..]);
+        buffer.extend_from_slice(&[0x40, 0x01, 0x00, 0x00]);
+
+        let first = next_frame(&mut buffer).unwrap().unwrap();
+        let second = next_frame(&mut buffer).unwrap().unwrap();
+
+        assert_eq!(&first[..], &[0x40, 0x00, 0x00, 0x01, 0xFF]);
+        assert_eq!(&second[..], &[0x40, 0x01, 0x00, 0x00]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn unknown_message_type_is_an_error() {
+        let mut buffer = BytesMut::from(&[0xFF, 0x00, 0x00, 0x00][..]);
+
+        assert!(next_frame(&mut buffer).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closes_idle_connection() {
+        let (_client, server) = tokio::io::duplex(1024);
+        let (inbound_data_sender, _inbound_data_receiver) = mpsc::channel(1);
+        let (_outbound_data_sender, outbound_data_receiver) = mpsc::channel(1);
+
+        let result = relay_stream(
+            server,
+            ClientSocket::with_transport("127.0.0.1:1234".parse().unwrap(), Transport::Tcp),
+            inbound_data_sender,
+            outbound_data_receiver,
+            Duration::from_millis(10),
+        )
+        .await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn receiving_data_keeps_connection_open() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (inbound_data_sender, mut inbound_data_receiver) = mpsc::channel(10);
+        let (_outbound_data_sender, outbound_data_receiver) = mpsc::channel(1);
+
+        let relay = tokio::spawn(relay_stream(
+            server,
+            ClientSocket::with_transport("127.0.0.1:1234".parse().unwrap(), Transport::Tcp),
+            inbound_data_sender,
+            outbound_data_receiver,
+            Duration::from_millis(100),
+        ));
+
+        for _ in 0..5 {
+            tokio::time::advance(Duration::from_millis(50)).await;
+            client.write_all(&[0x40, 0x00, 0x00, 0x00]).await.unwrap();
+            inbound_data_receiver.next().await.unwrap();
+        }
+
+        assert!(!relay.is_finished());
+    }
+
+    #[test]
+    fn pads_messages_to_multiple_of_4() {
+        assert_eq!(pad_to_stream(vec![0x40, 0x00, 0x00, 0x01, 0xFF]).len(), 8);
+        assert_eq!(pad_to_stream(vec![0x40, 0x00, 0x00, 0x00]).len(), 4);
+    }
+}
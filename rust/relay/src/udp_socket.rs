@@ -1,6 +1,8 @@
 use crate::AddressFamily;
 use anyhow::{Context as _, Result};
+use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
 use std::task::{ready, Context, Poll};
 use tokio::io::ReadBuf;
 
@@ -8,21 +10,37 @@ const MAX_UDP_SIZE: usize = 65536;
 
 /// A thin wrapper around [`tokio::net::UdpSocket`] that provides a slightly more convenient API.
 pub struct UdpSocket {
-    inner: tokio::net::UdpSocket,
+    inner: Arc<tokio::net::UdpSocket>,
     recv_buf: [u8; MAX_UDP_SIZE],
 }
 
 impl UdpSocket {
     pub fn bind(family: AddressFamily, port: u16) -> Result<Self> {
-        let std_socket = make_wildcard_socket(family, port)
+        Self::bind_with(family, port, false)
+    }
+
+    /// Binds a socket with `SO_REUSEPORT` set.
+    ///
+    /// Binding several such sockets to the same port makes the kernel distribute incoming datagrams between them, hashed by the sender's address.
+    pub fn bind_reuse_port(family: AddressFamily, port: u16) -> Result<Self> {
+        Self::bind_with(family, port, true)
+    }
+
+    fn bind_with(family: AddressFamily, port: u16, reuse_port: bool) -> Result<Self> {
+        let std_socket = make_wildcard_socket(family, port, reuse_port)
             .with_context(|| format!("Failed to bind UDP socket for {family}"))?;
 
         Ok(Self {
-            inner: tokio::net::UdpSocket::from_std(std_socket)?,
+            inner: Arc::new(tokio::net::UdpSocket::from_std(std_socket)?),
             recv_buf: [0u8; MAX_UDP_SIZE],
         })
     }
 
+    /// Returns a handle for sending data through this socket from other tasks.
+    pub fn sender(&self) -> UdpSender {
+        UdpSender(self.inner.clone())
+    }
+
     pub async fn recv(&mut self) -> Result<(&[u8], SocketAddr)> {
         let (length, sender) = self.inner.recv_from(&mut self.recv_buf).await?;
 
@@ -57,10 +75,38 @@ impl UdpSocket {
     }
 }
 
+/// A cloneable handle for sending data through a [`UdpSocket`].
+///
+/// The socket stays open for as long as any handle to it exists.
+#[derive(Debug, Clone)]
+pub struct UdpSender(Arc<tokio::net::UdpSocket>);
+
+impl UdpSender {
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> Result<()> {
+        self.0.send_to(buf, target).await?;
+
+        Ok(())
+    }
+
+    /// Sends data without waiting for the socket to become writable.
+    ///
+    /// Fails with [`io::ErrorKind::WouldBlock`] if the socket's send buffer is full.
+    pub fn try_send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<()> {
+        let sent_bytes = self.0.try_send_to(buf, target)?;
+        debug_assert_eq!(sent_bytes, buf.len());
+
+        Ok(())
+    }
+}
+
 /// Creates an [std::net::UdpSocket] via the [socket2] library that is configured for our needs.
 ///
 /// Most importantly, this sets the `IPV6_V6ONLY` flag to ensure we disallow IP4-mapped IPv6 addresses and can bind to IP4 and IP6 addresses on the same port.
-fn make_wildcard_socket(family: AddressFamily, port: u16) -> Result<std::net::UdpSocket> {
+fn make_wildcard_socket(
+    family: AddressFamily,
+    port: u16,
+    reuse_port: bool,
+) -> Result<std::net::UdpSocket> {
     use socket2::*;
 
     let domain = match family {
@@ -76,6 +122,9 @@ fn make_wildcard_socket(family: AddressFamily, port: u16) -> Result<std::net::Ud
     if family == AddressFamily::V6 {
         socket.set_only_v6(true)?;
     }
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
 
     socket.set_nonblocking(true)?;
     socket.bind(&SockAddr::from(SocketAddr::new(address, port)))?;
@@ -1,10 +1,11 @@
 use bytecodec::{DecodeExt, EncodeExt};
 use firezone_relay::{
     AddressFamily, Allocate, AllocationId, Attribute, Binding, ChannelBind, ChannelData,
-    ClientMessage, ClientSocket, Command, IpStack, PeerSocket, Refresh, Server,
+    ClientMessage, ClientSocket, Command, CreatePermission, IpStack, PeerSocket, RateLimit,
+    RateLimits, Refresh, SendIndication, Server, Snapshot, Transport,
 };
 use rand::rngs::mock::StepRng;
-use secrecy::SecretString;
+use secrecy::{ExposeSecret as _, SecretString};
 use std::collections::HashMap;
 use std::iter;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
@@ -12,8 +13,12 @@ use std::time::{Duration, SystemTime};
 use stun_codec::rfc5389::attributes::{ErrorCode, Nonce, Realm, Username, XorMappedAddress};
 use stun_codec::rfc5389::errors::Unauthorized;
 use stun_codec::rfc5389::methods::BINDING;
-use stun_codec::rfc5766::attributes::{ChannelNumber, Lifetime, XorPeerAddress, XorRelayAddress};
-use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, REFRESH};
+use stun_codec::rfc5766::attributes::{
+    ChannelNumber, Data, Lifetime, XorPeerAddress, XorRelayAddress,
+};
+use stun_codec::rfc5766::errors::{AllocationMismatch, InsufficientCapacity};
+use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, DATA, REFRESH};
+use stun_codec::rfc8656::errors::PeerAddressFamilyMismatch;
 use stun_codec::{Message, MessageClass, MessageDecoder, MessageEncoder, TransactionId};
 use test_strategy::proptest;
 use uuid::Uuid;
@@ -368,7 +373,7 @@ fn ping_pong_relay(
     );
 
     server.assert_commands(
-        from_peer(peer, peer_to_client_ping.as_ref(), 49152),
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
         [send_channel_data(
             source,
             ChannelData::new(channel.value(), peer_to_client_ping.as_ref()),
@@ -460,7 +465,7 @@ fn ping_pong_ip6_relay(
     );
 
     server.assert_commands(
-        from_peer(peer, peer_to_client_ping.as_ref(), 49152),
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
         [send_channel_data(
             source,
             ChannelData::new(channel.value(), peer_to_client_ping.as_ref()),
@@ -468,119 +473,1321 @@ fn ping_pong_ip6_relay(
     );
 }
 
-struct TestServer {
-    server: Server<StepRng>,
-    id_to_port: HashMap<u16, AllocationId>,
-}
+#[proptest]
+fn can_create_permission_for_peer(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    permission_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+    let allocation_expiry = now + lifetime.lifetime();
 
-impl TestServer {
-    fn new(relay_public_addr: impl Into<IpStack>) -> Self {
-        Self {
-            server: Server::new(relay_public_addr, StepRng::new(0, 0), 49152, 65535),
-            id_to_port: Default::default(),
-        }
-    }
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(allocation_expiry),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
 
-    fn with_nonce(mut self, nonce: Uuid) -> Self {
-        self.server.add_nonce(nonce);
+    let now = now + Duration::from_secs(1);
 
-        self
-    }
+    server.assert_commands(
+        from_client(
+            source,
+            CreatePermission::new(
+                permission_transaction_id,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(allocation_expiry.min(now + Duration::from_secs(300))),
+            send_message(
+                source,
+                create_permission_response(permission_transaction_id),
+            ),
+        ],
+    );
+}
 
-    fn auth_secret(&self) -> &SecretString {
-        self.server.auth_secret()
-    }
+#[proptest]
+fn create_permission_without_allocation_fails(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
 
-    fn assert_commands<const N: usize>(&mut self, input: Input, output: [Output; N]) {
-        match input {
-            Input::Client(sender, message, now) => {
-                self.server.handle_client_message(message, sender, now);
-            }
-            Input::Time(now) => {
-                self.server.handle_deadline_reached(now);
-            }
-            Input::Peer(peer, data, port) => {
-                self.server
-                    .handle_peer_traffic(&data, peer, self.id_to_port[&port]);
-            }
-        }
+    server.assert_commands(
+        from_client(
+            source,
+            CreatePermission::new(
+                transaction_id,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            create_permission_error_response(transaction_id, AllocationMismatch),
+        )],
+    );
+}
 
-        for expected_output in output {
-            let Some(actual_output) = self.server.next_command() else {
-                let msg = match expected_output {
-                    Output::SendMessage((recipient, msg)) => {
-                        format!("to send message {:?} to {recipient}", msg)
-                    }
-                    Wake(time) => format!("to be woken at {time:?}"),
-                    CreateAllocation(port, family) => {
-                        format!("to create allocation on port {port} for address family {family}")
-                    }
-                    FreeAllocation(port, family) => {
-                        format!("to free allocation on port {port} for address family {family}")
-                    }
-                    Output::SendChannelData((peer, _)) => {
-                        format!("to send channel data from {peer} to client")
-                    }
-                    Output::Forward((peer, _, _)) => format!("to forward data to peer {peer}"),
-                };
+#[proptest]
+fn create_permission_for_other_address_family_fails(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    permission_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer: SocketAddrV6,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
 
-                panic!("No commands produced but expected {msg}");
-            };
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
 
-            match (expected_output, actual_output) {
-                (
-                    Output::SendMessage((to, message)),
-                    Command::SendMessage { payload, recipient },
-                ) => {
-                    let expected_bytes = MessageEncoder::new()
-                        .encode_into_bytes(message.clone())
-                        .unwrap();
+    server.assert_commands(
+        from_client(
+            source,
+            CreatePermission::new(
+                permission_transaction_id,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            create_permission_error_response(permission_transaction_id, PeerAddressFamilyMismatch),
+        )],
+    );
+}
 
-                    if expected_bytes != payload {
-                        let expected_message = format!("{:?}", message);
-                        let actual_message = format!("{:?}", parse_message(&payload));
+#[proptest]
+fn ping_pong_relay_via_indications(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    permission_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] send_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    peer_to_client_ping: [u8; 32],
+    client_to_peer_ping: [u8; 32],
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let _ = env_logger::try_init();
 
-                        difference::assert_diff!(&expected_message, &actual_message, "\n", 0);
-                    }
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+    let allocation_expiry = now + lifetime.lifetime();
 
-                    assert_eq!(recipient, to);
-                }
-                (Wake(when), Command::Wake { deadline }) => {
-                    assert_eq!(when, deadline);
-                }
-                (
-                    CreateAllocation(expected_port, expected_family),
-                    Command::CreateAllocation {
-                        id,
-                        family: actual_family,
-                        port: actual_port,
-                    },
-                ) => {
-                    self.id_to_port.insert(actual_port, id);
-                    assert_eq!(expected_port, actual_port);
-                    assert_eq!(expected_family, actual_family);
-                }
-                (
-                    FreeAllocation(port, family),
-                    Command::FreeAllocation {
-                        id,
-                        family: actual_family,
-                    },
-                ) => {
-                    let actual_id = self.id_to_port.remove(&port).expect("to have port in map");
-                    assert_eq!(id, actual_id);
-                    assert_eq!(family, actual_family);
-                }
-                (Wake(when), Command::SendMessage { payload, .. }) => {
-                    panic!(
-                        "Expected `Wake({})`, got `SendMessage({:?})`",
-                        when.duration_since(SystemTime::UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs(),
-                        parse_message(&payload)
-                    )
-                }
-                (
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(allocation_expiry),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    // Without a permission, neither direction is relayed.
+    server.assert_commands(
+        from_client(
+            source,
+            SendIndication::new(
+                send_transaction_id,
+                XorPeerAddress::new(peer.into()),
+                client_to_peer_ping.to_vec(),
+            ),
+            now,
+        ),
+        [],
+    );
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            CreatePermission::new(
+                permission_transaction_id,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(allocation_expiry.min(now + Duration::from_secs(300))),
+            send_message(
+                source,
+                create_permission_response(permission_transaction_id),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            SendIndication::new(
+                send_transaction_id,
+                XorPeerAddress::new(peer.into()),
+                client_to_peer_ping.to_vec(),
+            ),
+            now,
+        ),
+        [forward(peer, &client_to_peer_ping, 49152)],
+    );
+
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [send_message(
+            source,
+            data_indication(peer, &peer_to_client_ping),
+        )],
+    );
+}
+
+#[proptest]
+fn peer_traffic_is_dropped_once_permission_expired(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    permission_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    peer_to_client_ping: [u8; 32],
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+    let lifetime = Lifetime::new(Duration::from_secs(3600)).unwrap(); // Outlive the permission.
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            CreatePermission::new(
+                permission_transaction_id,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + Duration::from_secs(300)),
+            send_message(
+                source,
+                create_permission_response(permission_transaction_id),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [send_message(
+            source,
+            data_indication(peer, &peer_to_client_ping),
+        )],
+    );
+
+    let now = now + Duration::from_secs(301);
+
+    server.assert_commands(forward_time_to(now), []);
+
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [],
+    );
+}
+
+#[proptest]
+fn peer_traffic_is_dropped_once_permission_expired_before_deadline_is_handled(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    permission_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    peer_to_client_ping: [u8; 32],
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+    let lifetime = Lifetime::new(Duration::from_secs(3600)).unwrap(); // Outlive the permission.
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            CreatePermission::new(
+                permission_transaction_id,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + Duration::from_secs(300)),
+            send_message(
+                source,
+                create_permission_response(permission_transaction_id),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [send_message(
+            source,
+            data_indication(peer, &peer_to_client_ping),
+        )],
+    );
+
+    let now = now + Duration::from_secs(301);
+
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [],
+    );
+}
+
+#[proptest]
+fn peer_traffic_without_permission_is_dropped(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    peer_to_client_ping: [u8; 32],
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let _ = env_logger::try_init();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [],
+    );
+}
+
+#[proptest]
+fn closing_tcp_connection_deletes_allocation(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+    let client = ClientSocket::with_transport(source.into(), Transport::Tcp);
+
+    server.assert_commands(
+        Input::Client(
+            client,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            )
+            .into(),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            Output::SendMessage((
+                client,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            )),
+        ],
+    );
+
+    // A UDP client with the same address is a different client.
+    server.assert_commands(Input::Disconnect(ClientSocket::new(source.into())), []);
+
+    server.assert_commands(
+        Input::Disconnect(client),
+        [FreeAllocation(49152, AddressFamily::V4)],
+    );
+}
+
+#[proptest]
+fn packets_over_rate_limit_are_dropped(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    peer_to_client_ping: [u8; 32],
+    client_to_peer_ping: [u8; 32],
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let _ = env_logger::try_init();
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_rate_limits(RateLimits {
+            per_allocation: RateLimit {
+                bytes_per_sec: None,
+                packets_per_sec: Some(1),
+            },
+            per_username: RateLimit::default(),
+        });
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            channel_bind_response(channel_bind_transaction_id),
+        )],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelData::new(channel.value(), client_to_peer_ping.as_ref()),
+            now,
+        ),
+        [forward(peer, &client_to_peer_ping, 49152)],
+    );
+
+    // Both directions count towards the same limit.
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [],
+    );
+
+    let now = now + Duration::from_secs(1);
+
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [send_channel_data(
+            source,
+            ChannelData::new(channel.value(), peer_to_client_ping.as_ref()),
+        )],
+    );
+}
+
+#[proptest]
+fn packets_over_username_rate_limit_are_dropped(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    peer_to_client_ping: [u8; 32],
+    client_to_peer_ping: [u8; 32],
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let _ = env_logger::try_init();
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_rate_limits(RateLimits {
+            per_allocation: RateLimit::default(),
+            per_username: RateLimit {
+                bytes_per_sec: None,
+                packets_per_sec: Some(1),
+            },
+        });
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            channel_bind_response(channel_bind_transaction_id),
+        )],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelData::new(channel.value(), client_to_peer_ping.as_ref()),
+            now,
+        ),
+        [forward(peer, &client_to_peer_ping, 49152)],
+    );
+
+    // The allocation itself is unlimited, only the username's quota is exhausted.
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [],
+    );
+
+    let now = now + Duration::from_secs(1);
+
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [send_channel_data(
+            source,
+            ChannelData::new(channel.value(), peer_to_client_ping.as_ref()),
+        )],
+    );
+}
+
+#[proptest]
+fn revoking_allocation_frees_its_channels(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    #[filter(|other| other != &#peer)] other_peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+    let username = valid_username(now, &username_salt);
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                username.clone(),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                username.clone(),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            channel_bind_response(channel_bind_transaction_id),
+        )],
+    );
+
+    server.assert_commands(
+        Input::RevokeUsername(username.name().to_owned()),
+        [FreeAllocation(49152, AddressFamily::V4)],
+    );
+
+    // The timer of the revoked permission is still pending, it is a no-op once it fires.
+    let permission_expiry = now + Duration::from_secs(300);
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                username.clone(),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(permission_expiry.min(now + lifetime.lifetime())),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    // The channel number is free again, so we can bind it to a different peer.
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(other_peer.into()),
+                username,
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            channel_bind_response(channel_bind_transaction_id),
+        )],
+    );
+}
+
+#[proptest]
+fn revoking_allocations_of_username_frees_them(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+    let username = valid_username(now, &username_salt);
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                username.clone(),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+
+    let allocations = server.server.allocations();
+
+    assert_eq!(allocations.len(), 1);
+    assert_eq!(allocations[0].username, username.name());
+    assert_eq!(
+        allocations[0].relay_addresses,
+        vec![SocketAddr::new(public_relay_addr.into(), 49152)]
+    );
+
+    server.assert_commands(Input::RevokeUsername("unknown".to_owned()), []);
+
+    server.assert_commands(
+        Input::RevokeUsername(username.name().to_owned()),
+        [FreeAllocation(49152, AddressFamily::V4)],
+    );
+
+    assert!(server.server.allocations().is_empty());
+}
+
+#[proptest]
+fn restored_server_continues_relaying(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    peer_to_client_ping: [u8; 32],
+    client_to_peer_ping: [u8; 32],
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+    let lifetime = Lifetime::new(Duration::from_secs(3600)).unwrap(); // Outlive the restart.
+    let allocation_expiry = now + lifetime.lifetime();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(allocation_expiry),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    let now = now + Duration::from_secs(1);
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            channel_bind_response(channel_bind_transaction_id),
+        )],
+    );
+
+    let permission_expiry = now + Duration::from_secs(300);
+    let snapshot = serde_json::to_string(&server.server.snapshot(now)).unwrap();
+    let now = now + Duration::from_secs(1);
+
+    let mut server = TestServer::new(public_relay_addr);
+
+    server.assert_commands(
+        Input::Restore(serde_json::from_str(&snapshot).unwrap(), now),
+        [
+            CreateAllocation(49152, AddressFamily::V4),
+            Wake(allocation_expiry.min(permission_expiry)),
+        ],
+    );
+    assert_eq!(server.auth_secret().expose_secret(), secret.expose_secret());
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelData::new(channel.value(), client_to_peer_ping.as_ref()),
+            now,
+        ),
+        [forward(peer, &client_to_peer_ping, 49152)],
+    );
+
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [send_channel_data(
+            source,
+            ChannelData::new(channel.value(), peer_to_client_ping.as_ref()),
+        )],
+    );
+}
+
+#[proptest]
+fn allocate_is_rejected_while_draining(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_drain_timeout(Duration::from_secs(60));
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(Input::Drain(now), [Wake(now + Duration::from_secs(60))]);
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            allocate_error_response(transaction_id, InsufficientCapacity),
+        )],
+    );
+}
+
+#[proptest]
+fn remaining_allocations_are_deleted_at_drain_deadline(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let lifetime = Lifetime::new(Duration::from_secs(3600)).unwrap();
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_drain_timeout(Duration::from_secs(60));
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+
+    server.assert_commands(Input::Drain(now), [Wake(now + Duration::from_secs(60))]);
+
+    server.assert_commands(
+        forward_time_to(now + Duration::from_secs(61)),
+        [FreeAllocation(49152, AddressFamily::V4)],
+    );
+}
+
+#[proptest]
+fn previous_auth_secret_is_accepted_during_overlap(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let previous_secret = server.auth_secret().to_owned();
+
+    server.server.rotate_auth_secret(
+        SecretString::from("new-secret".to_owned()),
+        Duration::from_secs(60),
+        now,
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &previous_secret,
+                nonce,
+            ),
+            now + Duration::from_secs(59),
+        ),
+        [
+            Wake(now + Duration::from_secs(59) + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+}
+
+#[proptest]
+fn revoked_username_cannot_allocate(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    // Nonces are generated randomly and we control the randomness in the test, thus this is deterministic.
+    let next_nonce = Uuid::from_u128(0x0);
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+    let username = valid_username(now, &username_salt);
+
+    server.server.revoke_username(username.name(), now);
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                username,
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            unauthorized_allocate_response(transaction_id, next_nonce),
+        )],
+    );
+}
+
+struct TestServer {
+    server: Server<StepRng>,
+    id_to_port: HashMap<u16, AllocationId>,
+}
+
+impl TestServer {
+    fn new(relay_public_addr: impl Into<IpStack>) -> Self {
+        Self {
+            server: Server::new(relay_public_addr, StepRng::new(0, 0), 49152, 65535),
+            id_to_port: Default::default(),
+        }
+    }
+
+    fn with_rate_limits(mut self, rate_limits: RateLimits) -> Self {
+        self.server = self.server.with_rate_limits(rate_limits);
+
+        self
+    }
+
+    fn with_drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.server = self.server.with_drain_timeout(drain_timeout);
+
+        self
+    }
+
+    fn with_nonce(mut self, nonce: Uuid) -> Self {
+        self.server.add_nonce(nonce);
+
+        self
+    }
+
+    fn auth_secret(&self) -> &SecretString {
+        self.server.auth_secret()
+    }
+
+    fn assert_commands<const N: usize>(&mut self, input: Input, output: [Output; N]) {
+        match input {
+            Input::Client(sender, message, now) => {
+                self.server.handle_client_message(message, sender, now);
+            }
+            Input::Time(now) => {
+                self.server.handle_deadline_reached(now);
+            }
+            Input::Disconnect(client) => {
+                self.server.handle_client_disconnected(client);
+            }
+            Input::RevokeUsername(username) => {
+                self.server.revoke_allocations_of_username(&username);
+            }
+            Input::Drain(now) => {
+                self.server.start_draining(now);
+            }
+            Input::Restore(snapshot, now) => {
+                self.server.restore(snapshot, now);
+            }
+            Input::Peer(peer, data, port, now) => {
+                self.server
+                    .handle_peer_traffic(&data, peer, self.id_to_port[&port], now);
+            }
+        }
+
+        for expected_output in output {
+            let Some(actual_output) = self.server.next_command() else {
+                let msg = match expected_output {
+                    Output::SendMessage((recipient, msg)) => {
+                        format!("to send message {:?} to {recipient}", msg)
+                    }
+                    Wake(time) => format!("to be woken at {time:?}"),
+                    CreateAllocation(port, family) => {
+                        format!("to create allocation on port {port} for address family {family}")
+                    }
+                    FreeAllocation(port, family) => {
+                        format!("to free allocation on port {port} for address family {family}")
+                    }
+                    Output::SendChannelData((peer, _)) => {
+                        format!("to send channel data from {peer} to client")
+                    }
+                    Output::Forward((peer, _, _)) => format!("to forward data to peer {peer}"),
+                };
+
+                panic!("No commands produced but expected {msg}");
+            };
+
+            match (expected_output, actual_output) {
+                (
+                    Output::SendMessage((to, message)),
+                    Command::SendMessage { payload, recipient },
+                ) => {
+                    let expected_bytes = MessageEncoder::new()
+                        .encode_into_bytes(message.clone())
+                        .unwrap();
+
+                    if expected_bytes != payload {
+                        let expected_message = format!("{:?}", message);
+                        let actual_message = format!("{:?}", parse_message(&payload));
+
+                        difference::assert_diff!(&expected_message, &actual_message, "\n", 0);
+                    }
+
+                    assert_eq!(recipient, to);
+                }
+                (Wake(when), Command::Wake { deadline }) => {
+                    assert_eq!(when, deadline);
+                }
+                (
+                    CreateAllocation(expected_port, expected_family),
+                    Command::CreateAllocation {
+                        id,
+                        family: actual_family,
+                        port: actual_port,
+                    },
+                ) => {
+                    self.id_to_port.insert(actual_port, id);
+                    assert_eq!(expected_port, actual_port);
+                    assert_eq!(expected_family, actual_family);
+                }
+                (
+                    FreeAllocation(port, family),
+                    Command::FreeAllocation {
+                        id,
+                        family: actual_family,
+                    },
+                ) => {
+                    let actual_id = self.id_to_port.remove(&port).expect("to have port in map");
+                    assert_eq!(id, actual_id);
+                    assert_eq!(family, actual_family);
+                }
+                (Wake(when), Command::SendMessage { payload, .. }) => {
+                    panic!(
+                        "Expected `Wake({})`, got `SendMessage({:?})`",
+                        when.duration_since(SystemTime::UNIX_EPOCH)
+                            .unwrap()
+                            .as_secs(),
+                        parse_message(&payload)
+                    )
+                }
+                (
                     Output::SendChannelData((peer, channeldata)),
                     Command::SendMessage { recipient, payload },
                 ) => {
@@ -666,6 +1873,17 @@ fn unauthorized_allocate_response(
     message
 }
 
+fn allocate_error_response(
+    transaction_id: TransactionId,
+    error_code: impl Into<ErrorCode>,
+) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, ALLOCATE, transaction_id);
+    message.add_attribute(error_code.into());
+
+    message
+}
+
 fn refresh_response(transaction_id: TransactionId, lifetime: Lifetime) -> Message<Attribute> {
     let mut message =
         Message::<Attribute>::new(MessageClass::SuccessResponse, REFRESH, transaction_id);
@@ -678,6 +1896,38 @@ fn channel_bind_response(transaction_id: TransactionId) -> Message<Attribute> {
     Message::<Attribute>::new(MessageClass::SuccessResponse, CHANNEL_BIND, transaction_id)
 }
 
+fn create_permission_response(transaction_id: TransactionId) -> Message<Attribute> {
+    Message::<Attribute>::new(
+        MessageClass::SuccessResponse,
+        CREATE_PERMISSION,
+        transaction_id,
+    )
+}
+
+fn create_permission_error_response(
+    transaction_id: TransactionId,
+    error_code: impl Into<ErrorCode>,
+) -> Message<Attribute> {
+    let mut message = Message::<Attribute>::new(
+        MessageClass::ErrorResponse,
+        CREATE_PERMISSION,
+        transaction_id,
+    );
+    message.add_attribute(error_code.into());
+
+    message
+}
+
+fn data_indication(peer: impl Into<SocketAddr>, data: &[u8]) -> Message<Attribute> {
+    // The transaction ID is generated randomly and we control the randomness in the test, thus this is deterministic.
+    let mut message =
+        Message::<Attribute>::new(MessageClass::Indication, DATA, TransactionId::new([0; 12]));
+    message.add_attribute(XorPeerAddress::new(peer.into()));
+    message.add_attribute(Data::new(data.to_vec()).unwrap());
+
+    message
+}
+
 fn parse_message(message: &[u8]) -> Message<Attribute> {
     MessageDecoder::new()
         .decode_from_bytes(message)
@@ -687,8 +1937,12 @@ fn parse_message(message: &[u8]) -> Message<Attribute> {
 
 enum Input<'a> {
     Client(ClientSocket, ClientMessage<'a>, SystemTime),
-    Peer(PeerSocket, Vec<u8>, u16),
+    Peer(PeerSocket, Vec<u8>, u16, SystemTime),
     Time(SystemTime),
+    Disconnect(ClientSocket),
+    RevokeUsername(String),
+    Drain(SystemTime),
+    Restore(Snapshot, SystemTime),
 }
 
 fn from_client<'a>(
@@ -699,8 +1953,13 @@ fn from_client<'a>(
     Input::Client(ClientSocket::new(from.into()), message.into(), now)
 }
 
-fn from_peer<'a>(from: impl Into<SocketAddr>, data: &[u8], port: u16) -> Input<'a> {
-    Input::Peer(PeerSocket::new(from.into()), data.to_vec(), port)
+fn from_peer<'a>(
+    from: impl Into<SocketAddr>,
+    data: &[u8],
+    port: u16,
+    now: SystemTime,
+) -> Input<'a> {
+    Input::Peer(PeerSocket::new(from.into()), data.to_vec(), port, now)
 }
 
 fn forward_time_to<'a>(when: SystemTime) -> Input<'a> {